@@ -0,0 +1,58 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use ninja::Config;
+
+/// Command line flags, which take precedence over the configuration file.
+#[derive(clap::Parser, Clone)]
+#[command(version, about = "A video streaming server")]
+pub struct Args {
+    /// Configuration file, created with the defaults if it doesn't exist
+    #[arg(long, value_name = "PATH", default_value = "config.toml")]
+    pub config: PathBuf,
+
+    /// Port to listen on, replacing `listen`
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Address to listen on, replacing `listen`
+    #[arg(long, value_name = "IP")]
+    bind: Option<IpAddr>,
+
+    /// Directory of the video library
+    #[arg(long, value_name = "PATH")]
+    video_path: Option<PathBuf>,
+
+    /// Print the default configuration and exit
+    #[arg(long)]
+    pub print_default_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>
+}
+
+#[derive(clap::Subcommand, Clone)]
+pub enum Command {
+    /// Create an account, or reset its password. The password is read from
+    /// stdin so that it doesn't end up in the shell history.
+    AddUser {
+        name: Box<str>
+    }
+}
+
+impl Args {
+    pub fn apply(&self, config: &mut Config) {
+        if self.port.is_some() || self.bind.is_some() {
+            config.listen = Box::new([]);
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(ip) = self.bind {
+            config.ip = ip;
+        }
+        if let Some(video_path) = &self.video_path {
+            config.video_path = video_path.as_path().into();
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::{path::Path, process};
+
+use tokio::{fs, io::AsyncReadExt};
+
+use ninja::{Config, Ninja};
+
+mod cli;
+
+#[tokio::main]
+async fn main() {
+    let args = <cli::Args as clap::Parser>::parse();
+    if args.print_default_config {
+        print!("{}", toml::to_string_pretty(&Config::default()).unwrap());
+        return;
+    }
+
+    let config_path: &Path = &args.config;
+    let mut config = if let Ok(mut file) = fs::File::open(config_path).await {
+        let mut config_str = String::new();
+        if let Err(err) = file.read_to_string(&mut config_str).await {
+            eprintln!("ERROR: Failed to read configuration: {err}");
+            process::exit(1);
+        }
+
+        match Config::parse(&config_str) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("ERROR: Failed to parse configuration: {err}");
+                process::exit(1);
+            }
+        }
+    } else {
+        let config_str = toml::to_string_pretty(&Config::default()).unwrap();
+
+        if let Err(err) = fs::write(config_path, config_str).await {
+            eprintln!("ERROR: Failed to write default configuration: {err}");
+        }
+
+        match Config::parse("") {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("ERROR: Failed to parse configuration: {err}");
+                process::exit(1);
+            }
+        }
+    };
+    args.apply(&mut config);
+    ninja::init_logging(&config);
+
+    if let Some(cli::Command::AddUser { name }) = args.command {
+        let mut password = String::new();
+        if let Err(err) = std::io::stdin().read_line(&mut password) {
+            eprintln!("ERROR: Failed to read password: {err}");
+            process::exit(1);
+        }
+
+        let password = password.trim_end_matches(['\r', '\n']);
+        if password.is_empty() {
+            eprintln!("ERROR: Password can't be empty");
+            process::exit(1);
+        }
+
+        match ninja::set_password(&config, &name, password) {
+            Ok(()) => println!("Saved user `{name}`"),
+            Err(err) => {
+                eprintln!("ERROR: {err}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Flags carry over to reloaded configurations
+    let overrides = args.clone();
+    let ninja = match Ninja::start_with_overrides(config, Some(config_path), move |config| overrides.apply(config)).await {
+        Ok(ninja) => ninja,
+        Err(err) => {
+            tracing::error!("{err}");
+            process::exit(1);
+        }
+    };
+    ninja.serve().await;
+}
@@ -0,0 +1,358 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::body::{Body, Bytes, HttpBody};
+use axum::{extract, http, middleware, response};
+use http_body::{Frame, SizeHint};
+
+use crate::forwarded::Client;
+use crate::trace::RequestId;
+use crate::users::User;
+use crate::{unix_now, App};
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// The Common Log Format.
+    Common,
+    /// The Combined Log Format, which adds the referer and user agent.
+    #[default]
+    Combined,
+    /// One JSON object per line, which also has the duration, the range and
+    /// the request ID.
+    Json
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotate {
+    #[default]
+    Never,
+    Hourly,
+    Daily
+}
+
+/// Where and how requests are logged. Disabled while `path` isn't set.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AccessLog {
+    pub path: Option<Box<Path>>,
+    pub format: Format,
+    /// Starts a new file once the current one is this large, in MiB, or never
+    /// when zero.
+    pub max_size: u64,
+    pub rotate: Rotate,
+    /// How many rotated files are kept, as `access.log.1` and so on.
+    pub keep: usize
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        AccessLog { path: None, format: Format::default(), max_size: 0, rotate: Rotate::default(), keep: 7 }
+    }
+}
+
+/// The year, month and day of `days` since the Unix epoch, from Howard
+/// Hinnant's `civil_from_days`.
+pub fn civil(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    (era * 400 + year_of_era + u64::from(month <= 2), month, day)
+}
+
+/// Formats `time` like `10/Oct/2000:13:55:36 +0000`, or like
+/// `2000-10-10T13:55:36Z` for JSON.
+fn timestamp(time: u64, format: Format) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day) = civil(time / 86400);
+    let (hour, minute, second) = (time / 3600 % 24, time / 60 % 60, time % 60);
+    match format {
+        Format::Json => format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z"),
+        _ => format!("{day:02}/{}/{year}:{hour:02}:{minute:02}:{second:02} +0000", MONTHS[month as usize - 1])
+    }
+}
+
+/// Quotes `value` for the log formats derived from Apache's.
+fn quoted(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for char in value.chars() {
+        match char {
+            '"' | '\\' => { quoted.push('\\'); quoted.push(char); }
+            char if char.is_control() => quoted.push_str(&char.escape_default().to_string()),
+            char => quoted.push(char)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+struct Entry {
+    time: u64,
+    started: Instant,
+    client: Box<str>,
+    user: Option<Box<str>>,
+    method: http::Method,
+    target: Box<str>,
+    version: http::Version,
+    status: http::StatusCode,
+    range: Option<Box<str>>,
+    referer: Option<Box<str>>,
+    user_agent: Option<Box<str>>,
+    request_id: Option<Box<str>>
+}
+
+fn header(headers: &http::HeaderMap, name: http::HeaderName) -> Option<Box<str>> {
+    headers.get(name).and_then(|value| value.to_str().ok()).map(Into::into)
+}
+
+impl Entry {
+    fn format(&self, format: Format, bytes: u64) -> String {
+        let time = timestamp(self.time, format);
+        if format == Format::Json {
+            return serde_json::json!({
+                "time": time,
+                "client": self.client,
+                "user": self.user,
+                "method": self.method.as_str(),
+                "path": self.target,
+                "protocol": format!("{:?}", self.version),
+                "status": self.status.as_u16(),
+                "bytes": bytes,
+                "duration_ms": self.started.elapsed().as_millis() as u64,
+                "range": self.range,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "request_id": self.request_id
+            }).to_string();
+        }
+
+        let user = self.user.as_deref().unwrap_or("-");
+        let request = format!("{} {} {:?}", self.method, self.target, self.version);
+        let bytes = if bytes == 0 { "-".into() } else { bytes.to_string() };
+        let mut line = format!("{} - {user} [{time}] {} {} {bytes}", self.client, quoted(&request), self.status.as_u16());
+        if format == Format::Combined {
+            let referer = self.referer.as_deref().unwrap_or("-");
+            let user_agent = self.user_agent.as_deref().unwrap_or("-");
+            line.push_str(&format!(" {} {}", quoted(referer), quoted(user_agent)));
+        }
+        line
+    }
+}
+
+/// The open log file, rotated by size or time.
+struct LogFile {
+    path: PathBuf,
+    file: Option<File>,
+    size: u64,
+    period: u64,
+    /// In bytes, unlike in the configuration.
+    max_size: u64,
+    rotate: Rotate,
+    keep: usize
+}
+
+impl LogFile {
+    fn period(rotate: Rotate) -> u64 {
+        match rotate {
+            Rotate::Never => 0,
+            Rotate::Hourly => unix_now() / 3600,
+            Rotate::Daily => unix_now() / 86400
+        }
+    }
+
+    fn open(&mut self) {
+        match OpenOptions::new().create(true).append(true).open(&self.path) {
+            Ok(file) => {
+                self.size = file.metadata().map_or(0, |metadata| metadata.len());
+                self.period = Self::period(self.rotate);
+                self.file = Some(file);
+            }
+            Err(err) => tracing::error!(error = %err, "Failed to open access log `{}`", self.path.display())
+        }
+    }
+
+    /// Shifts `access.log.1` to `access.log.2` and so on, dropping the oldest,
+    /// and moves the current file to `access.log.1`.
+    fn shift(&mut self) {
+        let keep = self.keep;
+        self.file = None;
+        let rotated = |index: usize| {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(format!(".{index}"));
+            PathBuf::from(rotated)
+        };
+        if keep == 0 {
+            let _ = fs::remove_file(&self.path);
+            return;
+        }
+
+        let _ = fs::remove_file(rotated(keep));
+        for index in (1..keep).rev() {
+            let _ = fs::rename(rotated(index), rotated(index + 1));
+        }
+        if let Err(err) = fs::rename(&self.path, rotated(1)) {
+            tracing::error!(error = %err, "Failed to rotate access log `{}`", self.path.display());
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        let too_large = self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size;
+        if self.file.is_some() && (too_large || Self::period(self.rotate) != self.period) {
+            self.shift();
+        }
+        if self.file.is_none() {
+            self.open();
+        }
+
+        if let Some(file) = &mut self.file {
+            match writeln!(file, "{line}") {
+                Ok(()) => self.size += line.len() as u64 + 1,
+                Err(err) => tracing::error!(error = %err, "Failed to write access log `{}`", self.path.display())
+            }
+        }
+    }
+}
+
+/// Hands entries over to a thread that writes them, so that requests never
+/// wait on the disk.
+pub struct Writer {
+    sender: Option<mpsc::Sender<String>>,
+    format: Format
+}
+
+impl Writer {
+    pub fn new(config: &AccessLog) -> Self {
+        let Some(path) = &config.path else {
+            return Writer { sender: None, format: config.format };
+        };
+
+        let (sender, receiver) = mpsc::channel::<String>();
+        let mut file = LogFile {
+            path: path.to_path_buf(),
+            file: None,
+            size: 0,
+            period: 0,
+            max_size: config.max_size << 20,
+            rotate: config.rotate,
+            keep: config.keep
+        };
+        std::thread::spawn(move || {
+            for line in receiver {
+                file.write(&line);
+            }
+        });
+
+        Writer { sender: Some(sender), format: config.format }
+    }
+}
+
+/// Counts the bytes of a response body, and logs the request once the body is
+/// dropped, whether it was sent in full or the client went away.
+struct Counted {
+    body: Body,
+    bytes: u64,
+    entry: Entry,
+    app: &'static App
+}
+
+impl HttpBody for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.body).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.bytes += data.len() as u64;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        let writer = &self.app.access_log;
+        if let Some(sender) = &writer.sender {
+            let _ = sender.send(self.entry.format(writer.format, self.bytes));
+        }
+    }
+}
+
+/// Logs each request to the configured `access_log`.
+pub async fn record(
+    extract::State(app): extract::State<&'static App>,
+    request: extract::Request,
+    next: middleware::Next
+) -> response::Response {
+    if app.access_log.sender.is_none() {
+        return next.run(request).await;
+    }
+
+    let headers = request.headers();
+    let uri = request.extensions().get::<extract::OriginalUri>().map_or(request.uri(), |original| &original.0);
+    let client = request.extensions().get::<Client>().and_then(|client| client.ip);
+    let mut entry = Entry {
+        time: unix_now(),
+        started: Instant::now(),
+        client: client.map_or("-".into(), |ip| ip.to_string().into()),
+        user: None,
+        method: request.method().clone(),
+        target: uri.path_and_query().map_or(uri.path(), |target| target.as_str()).into(),
+        version: request.version(),
+        status: http::StatusCode::OK,
+        range: header(headers, http::header::RANGE),
+        referer: header(headers, http::header::REFERER),
+        user_agent: header(headers, http::header::USER_AGENT),
+        request_id: request.extensions().get::<RequestId>().map(|id| (*id.0).into())
+    };
+
+    let response = next.run(request).await;
+    entry.status = response.status();
+    entry.user = response.extensions().get::<User>().map(|user| user.name.clone());
+
+    let (parts, body) = response.into_parts();
+    response::Response::from_parts(parts, Body::new(Counted { body, bytes: 0, entry, app }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(0, Format::Common), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(timestamp(971_186_136, Format::Combined), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(timestamp(971_186_136, Format::Json), "2000-10-10T13:55:36Z");
+        // Leap days, and the end of a leap year
+        assert_eq!(timestamp(1_709_164_800, Format::Json), "2024-02-29T00:00:00Z");
+        assert_eq!(timestamp(1_735_689_599, Format::Json), "2024-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quoted("GET / HTTP/1.1"), r#""GET / HTTP/1.1""#);
+        assert_eq!(quoted(r#"say "hi" \o/"#), r#""say \"hi\" \\o/""#);
+        assert_eq!(quoted("line\nbreak"), r#""line\nbreak""#);
+    }
+}
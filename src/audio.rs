@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use axum::{extract, http, response};
+use tokio::process::Command;
+
+use crate::error::Code;
+use crate::{jail, transcode, App};
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Mp3,
+    Aac,
+    Opus
+}
+
+impl Format {
+    /// Encoder, bitrate, muxer and content type. Opus holds up well at much
+    /// lower bitrates, which is the point of listening over mobile data.
+    fn encoding(self) -> (&'static str, &'static str, &'static str, &'static str) {
+        match self {
+            Format::Mp3 => ("libmp3lame", "128k", "mp3", "audio/mpeg"),
+            Format::Aac => ("aac", "128k", "adts", "audio/aac"),
+            Format::Opus => ("libopus", "64k", "ogg", "audio/ogg")
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct AudioQuery {
+    #[serde(default)]
+    format: Format
+}
+
+pub async fn serve_audio(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::Query(params): extract::Query<AudioQuery>,
+    extract::Query(options): extract::Query<transcode::Options>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let config = &app.config;
+    let video_path = match jail::video(config, video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+
+    let (codec, bitrate, muxer, content_type) = params.format.encoding();
+    let body = match app.ffmpeg.stream(Command::new(&*config.ffmpeg_command).args([
+        "-v", "error",
+        "-i", video_path.to_str().unwrap(),
+        "-map", &options.audio_map("0:a:0"),
+        "-vn",
+        "-c:a", codec,
+        "-b:a", bitrate,
+        "-f", muxer,
+        "-"
+    ])).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to extract audio");
+            return err.into_response("Failed to extract audio");
+        }
+    };
+
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .body(body)
+        .unwrap()
+}
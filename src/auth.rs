@@ -0,0 +1,252 @@
+use std::path::Path;
+
+use axum::extract::FromRequestParts;
+use axum::{extract, http, middleware, response, Json};
+
+use crate::error::{ApiError, Code};
+use crate::forwarded::Client;
+use crate::users::User;
+use crate::{library, url, App, Config};
+
+/// The `?token=` fallback for clients that can't set headers, like `<video>`
+/// elements and native HLS players, and the `?share=` token of a shared link.
+#[derive(serde::Deserialize, Default)]
+pub struct TokenQuery {
+    pub token: Option<Box<str>>,
+    pub share: Option<Box<str>>
+}
+
+impl TokenQuery {
+    /// `query` with the tokens appended, so that URIs in generated playlists
+    /// stay authorized.
+    pub fn carry(&self, mut query: String) -> String {
+        for (name, value) in [("token", &self.token), ("share", &self.share)] {
+            if let Some(value) = value {
+                let separator = if query.is_empty() { '?' } else { '&' };
+                query = format!("{query}{separator}{name}={}", url::encode_component(value));
+            }
+        }
+        query
+    }
+}
+
+/// Compares without bailing out at the first difference, so that response
+/// times don't tell how much of a key was guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Name of the cookie holding the session token.
+const SESSION_COOKIE: &str = "ninja_session";
+
+/// The credential presented with `request`, from the `Authorization` header,
+/// the session cookie or the `token` query parameter. It can be either an API
+/// key or a session token.
+fn credential(request: &extract::Request) -> Option<Box<str>> {
+    let headers = request.headers();
+    let bearer = headers.get(http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(|key| key.trim().into());
+
+    let cookie = || headers.get_all(http::header::COOKIE).iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(SESSION_COOKIE)?.strip_prefix('=').map(Into::into));
+
+    bearer.or_else(cookie).or_else(|| {
+        let extract::Query(query) = extract::Query::<TokenQuery>::try_from_uri(request.uri()).ok()?;
+        query.token
+    })
+}
+
+/// The video or directory `request` is about, from its `video` or `path`
+/// parameter.
+async fn library_path(request: extract::Request) -> (Option<String>, extract::Request) {
+    let (mut parts, body) = request.into_parts();
+    let path = extract::RawPathParams::from_request_parts(&mut parts, &()).await.ok().and_then(|params| {
+        params.iter().find(|(name, _)| matches!(*name, "video" | "path")).map(|(_, value)| value.to_owned())
+    });
+    (path, extract::Request::from_parts(parts, body))
+}
+
+/// Whether `request` carries a share token for the video it asks for. Only
+/// playing the video is allowed, directly or over HLS.
+async fn is_shared(app: &App, request: extract::Request) -> (bool, extract::Request) {
+    let Ok(extract::Query(TokenQuery { share: Some(token), .. })) = extract::Query::try_from_uri(request.uri()) else {
+        return (false, request);
+    };
+    let Some(route) = request.extensions().get::<extract::MatchedPath>().map(|path| path.as_str().to_owned()) else {
+        return (false, request);
+    };
+
+    // Opening the video or its master playlist counts as a use of the share,
+    // the requests that follow while playing don't
+    let base_path = app.config.base_path.trim_end_matches('/');
+    let start = match route.strip_prefix(base_path).unwrap_or(&route) {
+        "/video/*video" => request.headers().get(http::header::RANGE)
+            .is_none_or(|range| range.to_str().is_ok_and(|range| range.starts_with("bytes=0-"))),
+        "/hls/:video/master.m3u8" => true,
+        "/hls/:video/:rendition/index.m3u8" | "/hls/:video/:rendition/:segment" => false,
+        _ => return (false, request)
+    };
+
+    let (video, request) = library_path(request).await;
+    (video.is_some_and(|video| app.shares.verify(&token, &video, start)), request)
+}
+
+/// Whether `user` may see `path`, a video or directory in the library. Users
+/// listed in `access` are limited to their folders, everyone else sees the
+/// whole library. Libraries with `users` are hidden from everyone else.
+pub fn can_access(config: &Config, user: &User, path: &str) -> bool {
+    let path = path.trim_matches('/');
+    let library = library::locate(config, Path::new(path)).and_then(|(root, _)| root.library);
+    if library.is_some_and(|library| !library.users.is_empty() && !library.users.contains(&user.name)) {
+        return false;
+    }
+
+    let Some(folders) = config.access.get(&user.name) else {
+        return true;
+    };
+
+    folders.iter().map(|folder| folder.trim_matches('/')).any(|folder| {
+        folder.is_empty() || path.strip_prefix(folder).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Whether the request may manage the server. API keys are for whoever runs
+/// it, and so is everyone when there are no keys or users at all. Logged in
+/// users need to be listed in `admins`.
+pub fn is_admin(config: &Config, user: Option<&User>) -> bool {
+    user.is_none_or(|user| config.admins.contains(&user.name))
+}
+
+fn unauthorized() -> response::Response {
+    response::IntoResponse::into_response(([(http::header::WWW_AUTHENTICATE, "Bearer")], ApiError::new(Code::Unauthorized, "Unauthorized")))
+}
+
+/// Rejects requests without one of the configured `api_keys` or a valid
+/// session, or a share token for the video being played. Everything is open
+/// when there are neither keys nor users. The logged in [`User`] is added to
+/// the request extensions, after checking that they can access the video or
+/// directory in the path.
+pub async fn authenticate(
+    extract::State(app): extract::State<&'static App>,
+    request: extract::Request,
+    next: middleware::Next
+) -> response::Response {
+    let keys = &app.config.api_keys;
+    let has_users = match app.users.any() {
+        Ok(has_users) => has_users,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to query users");
+            return unauthorized();
+        }
+    };
+
+    if keys.is_empty() && !has_users {
+        return next.run(request).await;
+    }
+
+    let (shared, request) = is_shared(app, request).await;
+    if shared {
+        return next.run(request).await;
+    }
+
+    let Some(credential) = credential(&request) else {
+        return unauthorized();
+    };
+
+    if keys.iter().any(|key| constant_time_eq(key.as_bytes(), credential.as_bytes())) {
+        return next.run(request).await;
+    }
+
+    match app.users.session(&credential) {
+        Ok(Some(user)) => {
+            let (path, mut request) = library_path(request).await;
+            if path.is_some_and(|path| !can_access(&app.config, &user, &path)) {
+                return ApiError::new(Code::Forbidden, "Forbidden").into();
+            }
+
+            // Passed back out on the response for the access log
+            request.extensions_mut().insert(user.clone());
+            let mut response = next.run(request).await;
+            response.extensions_mut().insert(user);
+            response
+        }
+        Ok(None) => unauthorized(),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to look up session");
+            unauthorized()
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct Login {
+    username: Box<str>,
+    password: Box<str>
+}
+
+/// The session cookie, scoped to `base_path` and only sent over HTTPS when
+/// the client connected over it.
+fn session_cookie(config: &Config, client: &Client, token: &str, max_age: u64) -> String {
+    let path = url::path(config, "/");
+    let secure = if client.https { "; Secure" } else { "" };
+    format!("{SESSION_COOKIE}={token}; Max-Age={max_age}; Path={path}; HttpOnly; SameSite=Lax{secure}")
+}
+
+pub async fn login(
+    extract::State(app): extract::State<&'static App>,
+    extract::Extension(client): extract::Extension<Client>,
+    Json(login): Json<Login>
+) -> response::Response {
+    let username = login.username.clone();
+    let verified = tokio::task::spawn_blocking(move || app.users.verify(&login.username, &login.password)).await;
+    let user = match verified {
+        Ok(Ok(Some(user))) => user,
+        Ok(Ok(None)) => {
+            tracing::warn!("Failed login for `{username}` from {client}");
+            return unauthorized();
+        }
+        Ok(Err(err)) => {
+            tracing::error!(error = %err, "Failed to verify login");
+            return unauthorized();
+        }
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to verify login");
+            return unauthorized();
+        }
+    };
+
+    let lifetime = app.config.session_lifetime;
+    let token = match app.users.create_session(&user, lifetime) {
+        Ok(token) => token,
+        Err(err) => return ApiError::database("Failed to create session", err).into()
+    };
+
+    let mut response = response::IntoResponse::into_response(Json(serde_json::json!({
+        "token": token,
+        "user": user
+    })));
+    response.headers_mut().insert(http::header::SET_COOKIE, session_cookie(&app.config, &client, &token, lifetime).parse().unwrap());
+    response
+}
+
+pub async fn logout(
+    extract::State(app): extract::State<&'static App>,
+    extract::Extension(client): extract::Extension<Client>,
+    request: extract::Request
+) -> response::Response {
+    if let Some(token) = credential(&request) {
+        if let Err(err) = app.users.delete_session(&token) {
+            tracing::error!(error = %err, "Failed to delete session");
+        }
+    }
+
+    response::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .header(http::header::SET_COOKIE, session_cookie(&app.config, &client, "", 0))
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tokio::fs;
+
+use crate::remote;
+
+/// Writes `data` next to `path` first so that readers never observe a
+/// partially written file.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", COUNTER.fetch_add(1, Ordering::Relaxed)));
+    fs::write(&temp, data).await?;
+    fs::rename(&temp, path).await
+}
+
+/// Whether the cached file at `path` exists and is newer than its source.
+pub async fn is_fresh(path: &Path, source_mtime: SystemTime) -> bool {
+    match fs::metadata(path).await.and_then(|metadata| metadata.modified()) {
+        Ok(mtime) => mtime >= source_mtime,
+        Err(_) => false
+    }
+}
+
+/// Cache key for `params` applied to the file at `source`, which changes
+/// along with the file itself, and the modification time of the file. `None`
+/// when there is no such file. Remote videos are assumed to never change, and
+/// keyed by their origin without the query string, which differs between
+/// presigned URLs, even when read through the chunk cache.
+pub async fn source_key(source: &Path, params: impl Hash) -> Option<(Box<str>, SystemTime)> {
+    if let Some(url) = remote::url(source) {
+        #[cfg(feature = "remote")]
+        let url = crate::chunks::origin(url);
+        #[cfg(not(feature = "remote"))]
+        let url = url.split_once('?').map_or(url, |(url, _)| url);
+        return Some((Lru::key((url, params)), SystemTime::UNIX_EPOCH));
+    }
+    let mtime = fs::metadata(source).await.ok()?.modified().ok()?;
+    Some((Lru::key((source, mtime, params)), mtime))
+}
+
+/// Removes the files under `dir` that `exists` says are left over from a
+/// source that is gone, given their path relative to `dir` with the extension
+/// stripped. Returns how many were removed.
+pub async fn remove_orphans(dir: &Path, exists: impl Fn(&str) -> bool) -> usize {
+    let mut removed = 0;
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        let Ok(mut entries) = fs::read_dir(&current).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            match entry.file_type().await {
+                Ok(file_type) if file_type.is_dir() => {
+                    dirs.push(path);
+                    continue;
+                }
+                Ok(file_type) if file_type.is_file() => {}
+                _ => continue
+            }
+            // Being written by `write_atomic`
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                continue;
+            }
+            let Some(source) = path.strip_prefix(dir).ok().map(|relative| relative.with_extension("")) else {
+                continue;
+            };
+            let Some(source) = source.to_str().map(|source| source.replace(std::path::MAIN_SEPARATOR, "/")) else {
+                continue;
+            };
+            if !exists(&source) && fs::remove_file(&path).await.is_ok() {
+                removed += 1;
+            }
+        }
+    }
+    removed
+}
+
+struct Entry {
+    size: u64,
+    last_used: u64
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Box<str>, Entry>,
+    /// Keys by last use, oldest first.
+    recency: BTreeMap<u64, Box<str>>,
+    size: u64,
+    clock: u64
+}
+
+impl State {
+    fn touch(&mut self, key: &str) -> bool {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        self.clock += 1;
+        let key = self.recency.remove(&entry.last_used).unwrap();
+        entry.last_used = self.clock;
+        self.recency.insert(self.clock, key);
+        true
+    }
+
+    fn insert(&mut self, key: Box<str>, size: u64) {
+        self.remove(&key);
+        self.clock += 1;
+        self.entries.insert(key.clone(), Entry { size, last_used: self.clock });
+        self.recency.insert(self.clock, key);
+        self.size += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+
+    /// Forgets least recently used entries until everything fits in
+    /// `max_size`, returning their keys.
+    fn evict(&mut self, max_size: u64) -> Vec<Box<str>> {
+        let mut evicted = Vec::new();
+        while self.size > max_size {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            self.size -= self.entries.remove(&key).unwrap().size;
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// A directory of generated files capped at `max_size` bytes, evicting the
+/// least recently used ones first. Entries are looked up by a hash of
+/// whatever determines their content, so stale entries are never hit and
+/// simply age out.
+pub struct Lru {
+    dir: PathBuf,
+    max_size: u64,
+    state: Mutex<State>
+}
+
+impl Lru {
+    /// Opens the cache in `dir`, picking up the files left by a previous run
+    /// in the order they were written.
+    pub async fn open(dir: PathBuf, max_size: u64) -> Self {
+        let mut files = Vec::new();
+        if let Ok(mut entries) = fs::read_dir(&dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let Ok(metadata) = entry.metadata().await else {
+                    continue;
+                };
+                match entry.file_name().into_string() {
+                    Ok(key) if metadata.is_file() && !key.ends_with(".tmp") => {
+                        files.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), key, metadata.len()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        files.sort();
+
+        let mut state = State::default();
+        for (_, key, size) in files {
+            state.insert(key.into(), size);
+        }
+
+        let lru = Lru { dir, max_size, state: Mutex::new(state) };
+        lru.evict().await;
+        lru
+    }
+
+    /// Cache key for anything hashable, typically a tuple of the source path,
+    /// its modification time and the request parameters.
+    pub fn key(value: impl Hash) -> Box<str> {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        format!("{:016x}", hasher.finish()).into()
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        if !self.state.lock().unwrap().touch(key) {
+            return None;
+        }
+
+        match fs::read(self.dir.join(key)).await {
+            Ok(data) => Some(data),
+            Err(_) => {
+                self.state.lock().unwrap().remove(key);
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, key: &str, data: &[u8]) {
+        if self.max_size == 0 {
+            return;
+        }
+
+        let path = self.dir.join(key);
+        if let Err(err) = write_atomic(&path, data).await {
+            tracing::error!(error = %err, "Failed to write cache entry `{}`", path.display());
+            return;
+        }
+
+        self.state.lock().unwrap().insert(key.into(), data.len() as u64);
+        self.evict().await;
+    }
+
+    async fn evict(&self) {
+        let evicted = self.state.lock().unwrap().evict(self.max_size);
+        for key in evicted {
+            let _ = fs::remove_file(self.dir.join(&*key)).await;
+        }
+    }
+}
@@ -0,0 +1,732 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{extract, http, response, Json};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::error::{ApiError, Code};
+use crate::users::User;
+use crate::{dlna, jail, mime, server, shares, url, App};
+
+const SERVICE: &str = "_googlecast._tcp.local";
+const MDNS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+
+/// How long devices get to answer a search.
+const DISCOVERY_TIME: Duration = Duration::from_millis(1500);
+/// Commands give up after this long, devices answer within a second.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The receiver built into every device, which plays a URL it is given.
+const DEFAULT_MEDIA_RECEIVER: &str = "CC1AD845";
+
+const CONNECTION: &str = "urn:x-cast:com.google.cast.tp.connection";
+const HEARTBEAT: &str = "urn:x-cast:com.google.cast.tp.heartbeat";
+const RECEIVER: &str = "urn:x-cast:com.google.cast.receiver";
+const MEDIA: &str = "urn:x-cast:com.google.cast.media";
+
+/// Casts get their own share, so that devices fetch the video without
+/// logging in. It outlasts any film.
+const SHARE_LIFETIME: u64 = 12 * 3600;
+
+#[derive(Clone, serde::Serialize)]
+pub struct Device {
+    id: Box<str>,
+    name: Box<str>,
+    #[serde(skip)]
+    address: SocketAddr
+}
+
+/// The cast devices found by the last search, as commands name them by ID.
+#[derive(Default)]
+pub struct Devices {
+    devices: Mutex<HashMap<Box<str>, Device>>
+}
+
+pub enum Error {
+    Io(io::Error),
+    /// The device answered with an error.
+    Rejected(Box<str>),
+    /// Nothing cast from here is playing on the device.
+    Idle
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl Error {
+    fn into_response(self, device: &Device) -> response::Response {
+        match self {
+            Error::Io(err) => {
+                tracing::error!(error = %err, "Failed to talk to cast device `{}`", device.name);
+                ApiError::new(Code::UpstreamFailed, "Failed to reach the cast device").into()
+            }
+            Error::Rejected(reason) => {
+                tracing::warn!("Cast device `{}` rejected a command: {reason}", device.name);
+                ApiError::new(Code::UpstreamFailed, format!("The cast device refused: {reason}")).into()
+            }
+            Error::Idle => ApiError::new(Code::NothingPlaying, "Nothing is playing on the cast device").into()
+        }
+    }
+}
+
+/// The mDNS question for cast devices, asking for unicast answers.
+fn query() -> Vec<u8> {
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in SERVICE.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend(label.as_bytes());
+    }
+    packet.extend([0, 0, 12, 0x80, 1]);
+    packet
+}
+
+fn u16_at(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(packet.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+/// The name at `offset` of a DNS packet, and where whatever follows it
+/// starts. Names are compressed by pointing back at earlier ones.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds pointer loops in malformed packets
+    for _ in 0..128 {
+        let length = *packet.get(offset)? as usize;
+        if length == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+        if length & 0xc0 == 0xc0 {
+            end.get_or_insert(offset + 2);
+            offset = ((length & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+        } else {
+            labels.push(String::from_utf8_lossy(packet.get(offset + 1..offset + 1 + length)?).into_owned());
+            offset += 1 + length;
+        }
+    }
+    None
+}
+
+/// What answers to the search tell, gathered across packets as devices split
+/// them.
+#[derive(Default)]
+struct Answers {
+    instances: Vec<String>,
+    services: HashMap<String, (String, u16)>,
+    texts: HashMap<String, HashMap<String, String>>,
+    addresses: HashMap<String, IpAddr>
+}
+
+impl Answers {
+    fn read(&mut self, packet: &[u8]) -> Option<()> {
+        let questions = u16_at(packet, 4)?;
+        let records = u16_at(packet, 6)? as usize + u16_at(packet, 8)? as usize + u16_at(packet, 10)? as usize;
+        let mut offset = 12;
+        for _ in 0..questions {
+            offset = read_name(packet, offset)?.1 + 4;
+        }
+
+        for _ in 0..records {
+            let (owner, start) = read_name(packet, offset)?;
+            let kind = u16_at(packet, start)?;
+            let length = u16_at(packet, start + 8)? as usize;
+            let data = start + 10;
+            offset = data + length;
+            let rdata = packet.get(data..offset)?;
+            match kind {
+                // PTR
+                12 if owner.eq_ignore_ascii_case(SERVICE) => {
+                    let instance = read_name(packet, data)?.0;
+                    if !self.instances.contains(&instance) {
+                        self.instances.push(instance);
+                    }
+                }
+                // SRV
+                33 => {
+                    let port = u16_at(packet, data + 4)?;
+                    self.services.insert(owner, (read_name(packet, data + 6)?.0.to_lowercase(), port));
+                }
+                // TXT
+                16 => {
+                    let mut texts = HashMap::new();
+                    let mut rest = rdata;
+                    while let Some((&length, tail)) = rest.split_first() {
+                        let text = String::from_utf8_lossy(tail.get(..length as usize)?);
+                        if let Some((key, value)) = text.split_once('=') {
+                            texts.insert(key.to_owned(), value.to_owned());
+                        }
+                        rest = &tail[length as usize..];
+                    }
+                    self.texts.insert(owner, texts);
+                }
+                // A, devices also answer over IPv6 but always have IPv4
+                1 if length == 4 => {
+                    let address: [u8; 4] = rdata.try_into().ok()?;
+                    self.addresses.insert(owner.to_lowercase(), IpAddr::from(address));
+                }
+                _ => {}
+            }
+        }
+        Some(())
+    }
+
+    fn devices(&self) -> Vec<Device> {
+        self.instances.iter().filter_map(|instance| {
+            let (host, port) = self.services.get(instance)?;
+            let ip = self.addresses.get(host)?;
+            let texts = self.texts.get(instance);
+            let text = |key: &str| texts.and_then(|texts| texts.get(key)).map(|value| value.as_str().into());
+            Some(Device {
+                id: text("id").unwrap_or_else(|| instance.as_str().into()),
+                name: text("fn").unwrap_or_else(|| instance.split('.').next().unwrap_or(instance).into()),
+                address: SocketAddr::new(*ip, *port)
+            })
+        }).collect()
+    }
+}
+
+/// Searches the local network for cast devices over mDNS.
+async fn discover() -> io::Result<Vec<Device>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.send_to(&query(), MDNS).await?;
+
+    let mut answers = Answers::default();
+    let mut buffer = [0; 9000];
+    let deadline = time::Instant::now() + DISCOVERY_TIME;
+    while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (length, _) = received?;
+        answers.read(&buffer[..length]);
+    }
+    Ok(answers.devices())
+}
+
+impl Devices {
+    /// The device with `id`, searching again when the last search didn't find
+    /// it.
+    async fn get(&self, id: &str) -> io::Result<Option<Device>> {
+        if let Some(device) = self.devices.lock().unwrap().get(id) {
+            return Ok(Some(device.clone()));
+        }
+        self.refresh(discover().await?);
+        Ok(self.devices.lock().unwrap().get(id).cloned())
+    }
+
+    fn refresh(&self, devices: Vec<Device>) {
+        let mut known = self.devices.lock().unwrap();
+        known.clear();
+        known.extend(devices.into_iter().map(|device| (device.id.clone(), device)));
+    }
+}
+
+/// Devices present certificates signed by Google for their own address,
+/// which can't be checked without bundling Google's roots. They're on the
+/// local network, and nothing secret is sent to them.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer,
+        _: &[CertificateDer],
+        _: &ServerName,
+        _: &[u8],
+        _: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        signature: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, signature, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        signature: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, signature, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(buffer: &[u8], offset: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *buffer.get(*offset)?;
+        *offset += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A `CastMessage` with a JSON payload. It's a small protobuf message, its
+/// fields are numbered 1 to 6: protocol version, source, destination,
+/// namespace, payload type and payload.
+fn encode(source: &str, destination: &str, namespace: &str, payload: &str) -> Vec<u8> {
+    let mut message = vec![0x08, 0];
+    for (tag, value) in [(0x12, source), (0x1a, destination), (0x22, namespace)] {
+        message.push(tag);
+        write_varint(&mut message, value.len() as u64);
+        message.extend(value.as_bytes());
+    }
+    message.extend([0x28, 0, 0x32]);
+    write_varint(&mut message, payload.len() as u64);
+    message.extend(payload.as_bytes());
+    message
+}
+
+/// The source, namespace and payload of a `CastMessage`.
+fn decode(message: &[u8]) -> Option<(String, String, String)> {
+    let (mut source, mut namespace, mut payload) = (String::new(), String::new(), String::new());
+    let mut offset = 0;
+    while offset < message.len() {
+        let key = read_varint(message, &mut offset)?;
+        match key & 7 {
+            0 => {
+                read_varint(message, &mut offset)?;
+            }
+            2 => {
+                let length = read_varint(message, &mut offset)? as usize;
+                let value = message.get(offset..offset.checked_add(length)?)?;
+                offset += length;
+                let field = match key >> 3 {
+                    2 => &mut source,
+                    4 => &mut namespace,
+                    6 => &mut payload,
+                    _ => continue
+                };
+                *field = String::from_utf8_lossy(value).into_owned();
+            }
+            _ => return None
+        }
+    }
+    Some((source, namespace, payload))
+}
+
+/// A connection to a device, speaking the cast protocol: length prefixed
+/// protobuf messages over TLS, each carrying JSON on a namespace.
+struct Connection {
+    stream: TlsStream<TcpStream>,
+    next_id: u64
+}
+
+impl Connection {
+    async fn open(address: SocketAddr) -> io::Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+            .with_no_client_auth();
+        let tcp = TcpStream::connect(address).await?;
+        let stream = TlsConnector::from(Arc::new(config)).connect(ServerName::from(address.ip()), tcp).await?;
+
+        let mut connection = Connection { stream, next_id: 1 };
+        connection.send("receiver-0", CONNECTION, json!({ "type": "CONNECT" })).await?;
+        Ok(connection)
+    }
+
+    async fn send(&mut self, destination: &str, namespace: &str, payload: Value) -> io::Result<()> {
+        let message = encode("sender-0", destination, namespace, &payload.to_string());
+        self.stream.write_all(&(message.len() as u32).to_be_bytes()).await?;
+        self.stream.write_all(&message).await?;
+        self.stream.flush().await
+    }
+
+    /// The next message that isn't a heartbeat, answering the heartbeats
+    /// meanwhile so that the device keeps the connection.
+    async fn receive(&mut self) -> io::Result<(String, Value)> {
+        loop {
+            let length = self.stream.read_u32().await? as usize;
+            if length > 1 << 16 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "cast message too long"));
+            }
+            let mut message = vec![0; length];
+            self.stream.read_exact(&mut message).await?;
+
+            let Some((source, namespace, payload)) = decode(&message) else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid cast message"));
+            };
+            let payload: Value = serde_json::from_str(&payload).unwrap_or_default();
+            if namespace == HEARTBEAT {
+                if payload["type"] == "PING" {
+                    self.send(&source, HEARTBEAT, json!({ "type": "PONG" })).await?;
+                }
+                continue;
+            }
+            return Ok((namespace, payload));
+        }
+    }
+
+    /// Sends `payload` and waits for the answer to it.
+    async fn request(&mut self, destination: &str, namespace: &str, mut payload: Value) -> Result<Value, Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+        payload["requestId"] = id.into();
+        self.send(destination, namespace, payload).await?;
+
+        loop {
+            let (_, answer) = self.receive().await?;
+            if answer["requestId"] != id {
+                continue;
+            }
+            return match answer["type"].as_str() {
+                Some("LOAD_FAILED" | "LOAD_CANCELLED" | "INVALID_REQUEST" | "INVALID_PLAYER_STATE" | "LAUNCH_ERROR") => {
+                    let reason = answer["reason"].as_str().or(answer["type"].as_str()).unwrap_or_default();
+                    Err(Error::Rejected(reason.into()))
+                }
+                _ => Ok(answer)
+            };
+        }
+    }
+
+    /// The session and transport of the default media receiver, if it is
+    /// running.
+    async fn receiver(&mut self) -> Result<Option<(String, String)>, Error> {
+        let status = self.request("receiver-0", RECEIVER, json!({ "type": "GET_STATUS" })).await?;
+        Ok(running_receiver(&status))
+    }
+
+    /// Connects to the running default media receiver and returns its
+    /// session, transport and the status of what it plays.
+    async fn media(&mut self) -> Result<(String, String, Value), Error> {
+        let Some((session, transport)) = self.receiver().await? else {
+            return Err(Error::Idle);
+        };
+        self.send(&transport, CONNECTION, json!({ "type": "CONNECT" })).await?;
+        let status = self.request(&transport, MEDIA, json!({ "type": "GET_STATUS" })).await?;
+        if status["status"][0]["mediaSessionId"].is_null() {
+            return Err(Error::Idle);
+        }
+        Ok((session, transport, status))
+    }
+}
+
+fn running_receiver(status: &Value) -> Option<(String, String)> {
+    status["status"]["applications"].as_array()?.iter()
+        .find(|application| application["appId"] == DEFAULT_MEDIA_RECEIVER)
+        .and_then(|application| Some((application["sessionId"].as_str()?.to_owned(), application["transportId"].as_str()?.to_owned())))
+}
+
+#[derive(serde::Serialize)]
+pub struct Status {
+    /// `PLAYING`, `PAUSED`, `BUFFERING` or `IDLE`.
+    state: Box<str>,
+    time: f64,
+    duration: Option<f64>
+}
+
+fn status(status: &Value) -> Status {
+    let media = &status["status"][0];
+    Status {
+        state: media["playerState"].as_str().unwrap_or("IDLE").into(),
+        time: media["currentTime"].as_f64().unwrap_or(0.0),
+        duration: media["media"]["duration"].as_f64()
+    }
+}
+
+/// Launches the default media receiver on the device, unless it's running
+/// already, and has it play `url`.
+async fn load(device: &Device, media: Value, start: f64) -> Result<Status, Error> {
+    let mut connection = Connection::open(device.address).await?;
+    let transport = match connection.receiver().await? {
+        Some((_, transport)) => transport,
+        None => {
+            let launched = connection.request("receiver-0", RECEIVER, json!({ "type": "LAUNCH", "appId": DEFAULT_MEDIA_RECEIVER })).await?;
+            running_receiver(&launched).ok_or_else(|| Error::Rejected("The media receiver didn't start".into()))?.1
+        }
+    };
+    connection.send(&transport, CONNECTION, json!({ "type": "CONNECT" })).await?;
+    let loaded = connection.request(&transport, MEDIA, json!({
+        "type": "LOAD",
+        "media": media,
+        "autoplay": true,
+        "currentTime": start
+    })).await?;
+    Ok(status(&loaded))
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Play,
+    Pause,
+    Seek,
+    Stop
+}
+
+async fn control(device: &Device, action: Action, time: Option<f64>) -> Result<Status, Error> {
+    let mut connection = Connection::open(device.address).await?;
+    let (session, transport, current) = connection.media().await?;
+    let media_session = current["status"][0]["mediaSessionId"].clone();
+
+    let command = match action {
+        Action::Play => json!({ "type": "PLAY", "mediaSessionId": media_session }),
+        Action::Pause => json!({ "type": "PAUSE", "mediaSessionId": media_session }),
+        Action::Seek => json!({ "type": "SEEK", "mediaSessionId": media_session, "currentTime": time.unwrap_or(0.0) }),
+        Action::Stop => {
+            connection.request("receiver-0", RECEIVER, json!({ "type": "STOP", "sessionId": session })).await?;
+            return Ok(Status { state: "IDLE".into(), time: 0.0, duration: None });
+        }
+    };
+    let answer = connection.request(&transport, MEDIA, command).await?;
+    Ok(status(&answer))
+}
+
+async fn media_status(device: &Device) -> Result<Status, Error> {
+    let mut connection = Connection::open(device.address).await?;
+    Ok(status(&connection.media().await?.2))
+}
+
+/// Runs a command against a device, giving up when it stops answering.
+async fn timed<T>(command: impl std::future::Future<Output = Result<T, Error>>) -> Result<T, Error> {
+    time::timeout(COMMAND_TIMEOUT, command).await
+        .unwrap_or_else(|_| Err(Error::Io(io::ErrorKind::TimedOut.into())))
+}
+
+async fn find_device(app: &App, id: &str) -> Result<Device, response::Response> {
+    match app.cast.get(id).await {
+        Ok(Some(device)) => Ok(device),
+        Ok(None) => Err(ApiError::new(Code::CastDeviceNotFound, "Cast device not found").into()),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to search for cast devices");
+            Err(ApiError::new(Code::InternalError, "Failed to search for cast devices").into())
+        }
+    }
+}
+
+/// Searches the network for cast devices.
+pub async fn list_devices(extract::State(app): extract::State<&App>) -> response::Response {
+    match discover().await {
+        Ok(mut devices) => {
+            devices.sort_by(|a, b| a.name.cmp(&b.name));
+            app.cast.refresh(devices.clone());
+            response::IntoResponse::into_response(Json(devices))
+        }
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to search for cast devices");
+            ApiError::new(Code::InternalError, "Failed to search for cast devices").into()
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct CastRequest {
+    device: Box<str>,
+    /// Where to start playing, in seconds.
+    #[serde(default)]
+    start: f64
+}
+
+/// The server as the device reaches it. Browsers on the same machine use a
+/// loopback address, which would point the device at itself.
+fn origin(app: &App, request: &http::request::Parts, device: &Device) -> String {
+    let origin = url::absolute(&app.config, request, "");
+    let host = origin.split_once("://").map_or("", |(_, rest)| rest.split(['/', ':']).next().unwrap_or(rest));
+    let loopback = host == "localhost" || host.trim_matches(['[', ']']).parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+    if !loopback {
+        return origin;
+    }
+
+    // The port the browser used may only be bound to loopback
+    match dlna::local_ip(device.address).ok().and_then(|ip| server::reachable(&app.config, ip)) {
+        Some(address) => {
+            let scheme = if app.config.tls_cert.is_some() { "https" } else { "http" };
+            format!("{scheme}://{address}{}", url::path(&app.config, ""))
+        }
+        None => {
+            tracing::warn!("Cast device `{}` can't reach the server, which doesn't listen on the network", device.name);
+            origin
+        }
+    }
+}
+
+/// Plays a video on a cast device. Devices play MP4 and WebM as they are,
+/// anything else goes through HLS.
+pub async fn cast_video(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    request: http::request::Parts,
+    Json(cast): Json<CastRequest>
+) -> response::Response {
+    let path = match jail::video(&app.config, &*video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    let device = match find_device(app, &cast.device).await {
+        Ok(device) => device,
+        Err(response) => return response
+    };
+
+    // Well within the longest lifetime of a share
+    let expires = shares::expiry(SHARE_LIFETIME).unwrap();
+    let token = match app.shares.create(&video, expires, None) {
+        Ok(token) => token,
+        Err(err) => return ApiError::database("Failed to create share", err).into()
+    };
+    let origin = origin(app, &request, &device);
+    let content_type = mime::from_extension(Path::new(&*video)).filter(|content_type| {
+        matches!(*content_type, "video/mp4" | "video/webm" | "audio/mpeg" | "audio/mp4") && !crate::needs_remux(&app.config, &path)
+    });
+    let (url, content_type) = match content_type {
+        Some(content_type) => (format!("{origin}/video/{}?share={token}", url::encode_path(&video)), content_type),
+        None => (format!("{origin}/hls/{}/master.m3u8?share={token}", url::encode_component(&video)), "application/x-mpegURL")
+    };
+
+    let title = app.index.get(&video).ok().flatten()
+        .map_or_else(|| video.rsplit('/').next().unwrap_or(&video).to_owned(), |indexed| indexed.display_title().to_owned());
+    let media = json!({
+        "contentId": url,
+        "contentType": content_type,
+        "streamType": "BUFFERED",
+        "metadata": { "metadataType": 0, "title": title }
+    });
+
+    match timed(load(&device, media, cast.start)).await {
+        Ok(status) => {
+            if let Some(extract::Extension(user)) = user {
+                tracing::info!("User `{}` cast `{video}` to `{}`", user.name, device.name);
+            }
+            response::IntoResponse::into_response(Json(status))
+        }
+        Err(err) => err.into_response(&device)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ControlRequest {
+    device: Box<str>,
+    action: Action,
+    /// Where to seek to, in seconds.
+    time: Option<f64>
+}
+
+/// Pauses, resumes, seeks or stops what is playing on a cast device.
+pub async fn control_device(
+    extract::State(app): extract::State<&App>,
+    Json(request): Json<ControlRequest>
+) -> response::Response {
+    let device = match find_device(app, &request.device).await {
+        Ok(device) => device,
+        Err(response) => return response
+    };
+    match timed(control(&device, request.action, request.time)).await {
+        Ok(status) => response::IntoResponse::into_response(Json(status)),
+        Err(err) => err.into_response(&device)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct StatusQuery {
+    device: Box<str>
+}
+
+/// What a cast device is playing, and where it is.
+pub async fn device_status(
+    extract::State(app): extract::State<&App>,
+    extract::Query(query): extract::Query<StatusQuery>
+) -> response::Response {
+    let device = match find_device(app, &query.device).await {
+        Ok(device) => device,
+        Err(response) => return response
+    };
+    match timed(media_status(&device)).await {
+        Ok(status) => response::IntoResponse::into_response(Json(status)),
+        Err(err) => err.into_response(&device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let message = encode("sender-0", "receiver-0", RECEIVER, "{\"type\":\"GET_STATUS\"}");
+        assert_eq!(
+            decode(&message),
+            Some(("sender-0".to_owned(), RECEIVER.to_owned(), "{\"type\":\"GET_STATUS\"}".to_owned()))
+        );
+        assert_eq!(decode(&[0x12, 5, b'a']), None);
+    }
+
+    #[test]
+    fn answers() {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 3];
+        let name = |packet: &mut Vec<u8>, name: &str| {
+            for label in name.split('.') {
+                packet.push(label.len() as u8);
+                packet.extend(label.as_bytes());
+            }
+            packet.push(0);
+        };
+        // PTR to the instance, pointing back at the service name for its tail
+        name(&mut packet, SERVICE);
+        packet.extend([0, 12, 0, 1, 0, 0, 0, 120, 0, 13, 10]);
+        packet.extend(b"Chromecast");
+        packet.extend([0xc0, 12]);
+        let instance = packet.len() - 13;
+        // SRV on port 8009 of `tv.local`
+        packet.extend([0xc0, instance as u8, 0, 33, 0, 1, 0, 0, 0, 120, 0, 16, 0, 0, 0, 0, 0x1f, 0x49]);
+        let host = packet.len();
+        name(&mut packet, "tv.local");
+        // TXT with the ID and friendly name
+        packet.extend([0xc0, instance as u8, 0, 16, 0, 1, 0, 0, 0, 120, 0, 22, 6]);
+        packet.extend(b"id=abc");
+        packet.push(14);
+        packet.extend(b"fn=Living room");
+        // A
+        packet.extend([0xc0, host as u8, 0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 192, 168, 1, 20]);
+
+        let mut answers = Answers::default();
+        assert_eq!(answers.read(&packet), Some(()));
+        let devices = answers.devices();
+        assert_eq!(devices.len(), 1);
+        assert_eq!((&*devices[0].id, &*devices[0].name), ("abc", "Living room"));
+        assert_eq!(devices[0].address, "192.168.1.20:8009".parse().unwrap());
+    }
+
+    #[test]
+    fn counts_past_u16() {
+        let packet = [0, 0, 0x84, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(Answers::default().read(&packet), None);
+    }
+}
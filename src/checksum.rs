@@ -0,0 +1,141 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use axum::{extract, http, response, Json};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::error::{ApiError, Code};
+use crate::encoding::{base64, hex};
+use crate::{encoding, jail, library, App};
+
+#[derive(serde::Serialize)]
+pub struct Checksum {
+    path: Box<str>,
+    size: u64,
+    mtime: u64,
+    sha256: Box<str>
+}
+
+/// Hashes all of `path`, on the blocking pool as that can take a while for
+/// large videos.
+async fn sha256(path: PathBuf) -> io::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 1 << 20];
+        loop {
+            match file.read(&mut buffer)? {
+                0 => return Ok(hex(&hasher.finalize())),
+                read => hasher.update(&buffer[..read])
+            }
+        }
+    }).await?
+}
+
+fn size_and_mtime(metadata: &std::fs::Metadata) -> (u64, u64) {
+    let mtime = metadata.modified().ok().and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok()).map_or(0, |mtime| mtime.as_secs());
+    (metadata.len(), mtime)
+}
+
+/// The checksum the index has for the video `relative`, if the file at
+/// `path` hasn't changed since it was computed.
+pub async fn cached(app: &App, relative: &str, path: &Path) -> Option<Box<str>> {
+    let (size, mtime) = size_and_mtime(&fs::metadata(path).await.ok()?);
+    let video = app.index.get(relative).ok()??;
+    video.sha256.filter(|_| video.size == size && video.mtime == mtime)
+}
+
+/// The checksum of the video `relative` at `path`, from the index or computed
+/// and stored there.
+pub async fn checksum(app: &App, relative: &str, path: &Path) -> io::Result<Checksum> {
+    let (size, mtime) = size_and_mtime(&fs::metadata(path).await?);
+    let known = app.index.get(relative).ok().flatten()
+        .filter(|video| video.size == size && video.mtime == mtime)
+        .and_then(|video| video.sha256);
+    let sha256 = match known {
+        Some(sha256) => sha256,
+        None => {
+            let sha256: Box<str> = self::sha256(path.to_path_buf()).await?.into();
+            if let Err(err) = app.index.set_checksum(relative, size, mtime, &sha256) {
+                tracing::error!(error = %err, "Failed to store checksum of `{relative}`");
+            }
+            sha256
+        }
+    };
+    Ok(Checksum { path: relative.into(), size, mtime, sha256 })
+}
+
+/// Hashes every video with a stored checksum again, returning how many were
+/// and the ones whose content changed even though their size and modification
+/// time didn't, a sign of a failing disk.
+pub async fn verify(app: &App) -> rusqlite::Result<(usize, Vec<Box<str>>)> {
+    let (mut verified, mut corrupted) = (0, Vec::new());
+    for video in app.index.all()? {
+        let Some(expected) = video.sha256 else {
+            continue;
+        };
+        let Some(path) = library::file(&app.config, &*video.path) else {
+            continue;
+        };
+        // Files changed since are hashed again by the next scan
+        match fs::metadata(&path).await {
+            Ok(metadata) if size_and_mtime(&metadata) == (video.size, video.mtime) => {}
+            _ => continue
+        }
+
+        match sha256(path).await {
+            Ok(sha256) if *sha256 == *expected => verified += 1,
+            Ok(_) => {
+                tracing::error!("Checksum of `{}` changed although the file didn't", video.path);
+                corrupted.push(video.path);
+            }
+            Err(err) => tracing::error!(error = %err, "Failed to hash `{}`", video.path)
+        }
+    }
+    Ok((verified + corrupted.len(), corrupted))
+}
+
+/// The `Repr-Digest` and legacy `Digest` headers for a file with this hex
+/// SHA-256.
+pub fn headers(sha256: &str) -> [(http::HeaderName, String); 2] {
+    let encoded = base64(&encoding::decode_hex(sha256).unwrap_or_default());
+    [
+        (http::HeaderName::from_static("repr-digest"), format!("sha-256=:{encoded}:")),
+        (http::HeaderName::from_static("digest"), format!("sha-256={encoded}"))
+    ]
+}
+
+/// The size, modification time and SHA-256 of a video, for clients to
+/// verify downloads or skip files they already have. Hashing a video for the
+/// first time reads all of it, unless `scan_checksums` did it already.
+pub async fn serve_checksum(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let path = match jail::video(&app.config, &*video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+
+    match checksum(app, &video, &path).await {
+        Ok(checksum) => response::IntoResponse::into_response(Json(checksum)),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to hash `{}`", path.display());
+            ApiError::new(Code::InternalError, "Failed to read video").into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests() {
+        let sha256 = hex(&Sha256::digest(b""));
+        assert_eq!(sha256, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(headers(&sha256)[0].1, "sha-256=:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=:");
+    }
+}
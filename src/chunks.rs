@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+
+use axum::body::{Body, Bytes};
+use axum::{extract, http, response, routing, Router};
+use futures_util::stream;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tokio::net::TcpListener;
+
+use crate::error::{ApiError, Code};
+use crate::{cache, coalesce, encoding, range, remote, App};
+
+/// Remote videos are fetched and cached this many bytes at a time.
+const CHUNK_SIZE: u64 = 1 << 20;
+
+/// A remote video read through the cache, as last resolved.
+struct Source {
+    /// Presigned URLs expire, this is replaced by the latest one.
+    url: Box<str>,
+    /// Learnt from the first chunk fetched.
+    size: Option<(u64, Box<str>)>,
+    /// The origin answered a range with all of the video, so it's proxied
+    /// instead.
+    unranged: bool
+}
+
+/// Keeps the chunks of remote videos on disk, so that seeking back and
+/// extracting frames don't fetch the same bytes from the origin again.
+///
+/// ffmpeg has to read through it too, so it is served on a loopback port
+/// that [`crate::jail::video`] points remote videos at. Only URLs with the
+/// secret generated at startup are answered there.
+pub struct Chunks {
+    cache: cache::Lru,
+    inflight: coalesce::Coalescer<Option<Bytes>>,
+    sources: Mutex<HashMap<Box<str>, Source>>,
+    secret: Box<str>,
+    address: OnceLock<SocketAddr>
+}
+
+/// Set at startup when `remote_cache_size` isn't zero, as resolving videos
+/// only has the configuration at hand.
+static CHUNKS: OnceLock<&'static Chunks> = OnceLock::new();
+
+/// The URL reading `url` through the cache, or `url` itself when caching is
+/// off.
+pub fn local_url(url: &str) -> String {
+    let Some((chunks, address)) = CHUNKS.get().and_then(|chunks| Some((chunks, chunks.address.get()?))) else {
+        return url.to_owned();
+    };
+
+    // Presigned URLs differ by their signature only
+    let key = cache::Lru::key(without_query(url));
+    let mut sources = chunks.sources.lock().unwrap();
+    let source = sources.entry(key.clone()).or_insert_with(|| Source { url: url.into(), size: None, unranged: false });
+    source.url = url.into();
+    format!("http://{address}/{}/{key}", chunks.secret)
+}
+
+fn without_query(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(url, _)| url)
+}
+
+/// The origin of the remote video read from `url`, without its query, for
+/// caches to key on. That stays the same across restarts, unlike the port
+/// and secret of the URL [`local_url`] gives.
+pub fn origin(url: &str) -> String {
+    let local = CHUNKS.get().and_then(|chunks| {
+        let key = url.strip_prefix(&format!("http://{}/{}/", chunks.address.get()?, chunks.secret))?;
+        Some(without_query(&chunks.sources.lock().unwrap().get(key)?.url).to_owned())
+    });
+    local.unwrap_or_else(|| without_query(url).to_owned())
+}
+
+impl Chunks {
+    pub async fn open(dir: std::path::PathBuf, max_size: u64) -> Self {
+        let mut bytes = [0; 16];
+        OsRng.fill_bytes(&mut bytes);
+        Chunks {
+            cache: cache::Lru::open(dir, max_size).await,
+            inflight: coalesce::Coalescer::new(),
+            sources: Mutex::new(HashMap::new()),
+            secret: encoding::hex(&bytes).into(),
+            address: OnceLock::new()
+        }
+    }
+
+    /// Chunk `index` of the video `key`, from the cache or its origin. The
+    /// first one fetched also tells the size and type of the video.
+    async fn chunk(&self, http: &reqwest::Client, key: &str, index: u64) -> Option<Bytes> {
+        let chunk_key = cache::Lru::key((key, index));
+        if let Some(data) = self.cache.get(&chunk_key).await {
+            return Some(data.into());
+        }
+
+        let url = self.sources.lock().unwrap().get(key)?.url.clone();
+        self.inflight.run(&chunk_key, async {
+            let start = index * CHUNK_SIZE;
+            let fetched = http.get(&*url)
+                .header(http::header::RANGE, format!("bytes={start}-{}", start + CHUNK_SIZE - 1))
+                .send().await
+                .and_then(|response| response.error_for_status());
+            let response = match fetched {
+                Ok(response) if response.status() == http::StatusCode::PARTIAL_CONTENT => response,
+                Ok(_) => {
+                    tracing::warn!("Remote video `{key}` doesn't support ranges, proxying it instead");
+                    if let Some(source) = self.sources.lock().unwrap().get_mut(key) {
+                        source.unranged = true;
+                    }
+                    return None;
+                }
+                Err(err) => {
+                    tracing::error!(error = %err.without_url(), "Failed to fetch remote video `{key}`");
+                    return None;
+                }
+            };
+
+            let total = response.headers().get(http::header::CONTENT_RANGE)
+                .and_then(|range| range.to_str().ok()?.rsplit_once('/')?.1.parse().ok());
+            let content_type = response.headers().get(http::header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .unwrap_or("application/octet-stream")
+                .into();
+            if let (Some(total), Some(source)) = (total, self.sources.lock().unwrap().get_mut(key)) {
+                source.size = Some((total, content_type));
+            }
+
+            let data = response.bytes().await.ok()?;
+            self.cache.insert(&chunk_key, &data).await;
+            Some(data)
+        }).await
+    }
+
+    async fn size(&self, http: &reqwest::Client, key: &str) -> Option<(u64, Box<str>)> {
+        if let Some(size) = self.sources.lock().unwrap().get(key)?.size.clone() {
+            return Some(size);
+        }
+        self.chunk(http, key, 0).await?;
+        self.sources.lock().unwrap().get(key)?.size.clone()
+    }
+
+    /// The URL of the video `key` when its origin doesn't answer ranges.
+    fn unranged(&self, key: &str) -> Option<Box<str>> {
+        self.sources.lock().unwrap().get(key).filter(|source| source.unranged).map(|source| source.url.clone())
+    }
+}
+
+/// Where the byte at `position` is: the index of its chunk, and the part of
+/// that chunk from it up to `end`, inclusive.
+fn span(position: u64, end: u64) -> (u64, std::ops::Range<usize>) {
+    let index = position / CHUNK_SIZE;
+    let start = index * CHUNK_SIZE;
+    (index, (position - start) as usize..(end + 1 - start).min(CHUNK_SIZE) as usize)
+}
+
+/// Answers reads of cached remote videos, ranges included, chunk after chunk.
+/// Origins that don't answer ranges are proxied as they are.
+async fn serve(
+    extract::Path((secret, key)): extract::Path<(Box<str>, Box<str>)>,
+    method: http::Method,
+    header: http::HeaderMap,
+    extract::State(app): extract::State<&'static App>
+) -> response::Response {
+    let chunks = &app.chunks;
+    if *secret != *chunks.secret {
+        return ApiError::new(Code::NotFound, "Not found").into();
+    }
+    if let Some(url) = chunks.unranged(&key) {
+        return remote::proxy(app, &url, &method, &header).await;
+    }
+
+    match respond(chunks, &app.remote, key.clone(), &method, &header).await {
+        Some(response) => response,
+        None => match chunks.unranged(&key) {
+            Some(url) => remote::proxy(app, &url, &method, &header).await,
+            None => ApiError::new(Code::UpstreamFailed, "Failed to fetch remote video").into()
+        }
+    }
+}
+
+/// Answers a read of the video `key` from its chunks, or `None` when its
+/// size can't be learnt.
+async fn respond(
+    chunks: &'static Chunks,
+    http: &'static reqwest::Client,
+    key: Box<str>,
+    method: &http::Method,
+    header: &http::HeaderMap
+) -> Option<response::Response> {
+    let (size, content_type) = chunks.size(http, &key).await?;
+
+    let range = header.get(http::header::RANGE).map(|range| range::parse(range.to_str().unwrap_or(""), size));
+    let (status, range) = match range {
+        // Several ranges are rare enough to be answered with all of it
+        Some(Ok(ranges)) if ranges.len() == 1 => (http::StatusCode::PARTIAL_CONTENT, ranges[0]),
+        Some(Err(range::Error::Invalid)) => return Some(ApiError::new(Code::InvalidRange, "Invalid Range").into()),
+        Some(Err(range::Error::Unsatisfiable)) => {
+            return Some(response::IntoResponse::into_response((
+                [(http::header::CONTENT_RANGE, format!("bytes */{size}"))],
+                ApiError::new(Code::RangeNotSatisfiable, "Range Not Satisfiable")
+            )));
+        }
+        _ if size == 0 => (http::StatusCode::OK, range::Range { start: 0, end: 0 }),
+        _ => (http::StatusCode::OK, range::Range { start: 0, end: size - 1 })
+    };
+    let length = if size == 0 { 0 } else { range.len() };
+
+    let mut response = response::Response::builder()
+        .status(status)
+        .header(http::header::ACCEPT_RANGES, "bytes")
+        .header(http::header::CONTENT_TYPE, &*content_type)
+        .header(http::header::CONTENT_LENGTH, length);
+    if status == http::StatusCode::PARTIAL_CONTENT {
+        response = response.header(http::header::CONTENT_RANGE, format!("bytes {}-{}/{size}", range.start, range.end));
+    }
+    if method == http::Method::HEAD || length == 0 {
+        return Some(response.body(Body::empty()).unwrap());
+    }
+
+    let body = stream::unfold(range.start, move |position| {
+        let key = key.clone();
+        async move {
+            if position > range.end {
+                return None;
+            }
+            let (index, span) = span(position, range.end);
+            let Some(chunk) = chunks.chunk(http, &key, index).await else {
+                return Some((Err(std::io::Error::other("Failed to fetch remote video")), u64::MAX));
+            };
+            let end = span.end.min(chunk.len());
+            if span.start >= end {
+                return Some((Err(std::io::ErrorKind::UnexpectedEof.into()), u64::MAX));
+            }
+            Some((Ok(chunk.slice(span.start..end)), position + (end - span.start) as u64))
+        }
+    });
+    Some(response.body(Body::from_stream(body)).unwrap())
+}
+
+/// Serves the cache on a loopback port, when `remote_cache_size` isn't zero.
+pub async fn listen(app: &'static App) {
+    if app.config.remote_cache_size == 0 {
+        return;
+    }
+
+    let listener = match TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to listen for the remote cache, remote videos won't be cached");
+            return;
+        }
+    };
+    if let Ok(address) = listener.local_addr() {
+        let _ = app.chunks.address.set(address);
+        let _ = CHUNKS.set(&app.chunks);
+    }
+
+    let router = Router::new()
+        .route("/:secret/:key", routing::get(serve))
+        .with_state(app);
+    if let Err(err) = axum::serve(listener, router).await {
+        tracing::error!(error = %err, "Remote cache stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans() {
+        assert_eq!(span(0, 99), (0, 0..100));
+        assert_eq!(span(10, CHUNK_SIZE * 3), (0, 10..CHUNK_SIZE as usize));
+        assert_eq!(span(CHUNK_SIZE + 5, CHUNK_SIZE * 3), (1, 5..CHUNK_SIZE as usize));
+        assert_eq!(span(CHUNK_SIZE * 2, CHUNK_SIZE * 2 + 9), (2, 0..10));
+    }
+
+    #[tokio::test]
+    async fn serves_cached_chunks() {
+        let dir = std::env::temp_dir().join(format!("ninja-chunks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let chunks: &'static Chunks = Box::leak(Box::new(Chunks::open(dir.clone(), 1 << 30).await));
+        let http: &'static reqwest::Client = Box::leak(Box::new(reqwest::Client::new()));
+
+        // Nothing is fetched, the origin doesn't exist
+        let size = CHUNK_SIZE + 10;
+        chunks.sources.lock().unwrap().insert("video".into(), Source {
+            url: "http://origin.invalid/video.mp4".into(),
+            size: Some((size, "video/mp4".into())),
+            unranged: false
+        });
+        let data: Vec<u8> = (0..size).map(|position| position as u8).collect();
+        chunks.cache.insert(&cache::Lru::key(("video", 0u64)), &data[..CHUNK_SIZE as usize]).await;
+        chunks.cache.insert(&cache::Lru::key(("video", 1u64)), &data[CHUNK_SIZE as usize..]).await;
+
+        let mut header = http::HeaderMap::new();
+        header.insert(http::header::RANGE, format!("bytes={}-{}", CHUNK_SIZE - 5, CHUNK_SIZE + 2).parse().unwrap());
+        let response = respond(chunks, http, "video".into(), &http::Method::GET, &header).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[http::header::CONTENT_RANGE], format!("bytes {}-{}/{size}", CHUNK_SIZE - 5, CHUNK_SIZE + 2));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, data[(CHUNK_SIZE - 5) as usize..=(CHUNK_SIZE + 2) as usize]);
+
+        let response = respond(chunks, http, "video".into(), &http::Method::GET, &http::HeaderMap::new()).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap(), data);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
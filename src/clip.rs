@@ -0,0 +1,115 @@
+use std::path::Path;
+
+use axum::{extract, http, response};
+use tokio::process::Command;
+
+use crate::error::{ApiError, Code};
+use crate::{hwaccel, jail, probe, transcode, App};
+
+/// Codecs that can be copied into an MP4 container without re-encoding.
+const MP4_VIDEO_CODECS: &[&str] = &["h264", "hevc", "av1", "mpeg4"];
+const MP4_AUDIO_CODECS: &[&str] = &["aac", "mp3", "ac3", "eac3", "opus", "alac", "flac"];
+
+#[derive(serde::Deserialize)]
+pub struct ClipQuery {
+    start: f64,
+    end: f64
+}
+
+/// A `Content-Disposition` value that survives quotes and non-ASCII names.
+pub fn attachment(filename: &str) -> String {
+    let fallback: String = filename.chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' })
+        .collect();
+
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+pub async fn serve_clip(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::Query(params): extract::Query<ClipQuery>,
+    extract::Query(options): extract::Query<transcode::Options>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let config = &app.config;
+    if !(params.start >= 0.0 && params.end > params.start) {
+        return ApiError::new(Code::BadRequest, "Clip must end after it starts").into();
+    }
+
+    if params.end - params.start > config.max_clip_duration as f64 {
+        return ApiError::new(Code::BadRequest, "Clip is too long").into();
+    }
+
+    let video_path = match jail::video(config, &video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    let Some(summary) = probe::summary(config, &video_path).await else {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into()
+    };
+
+    let copy = summary.video_codec.as_deref().is_some_and(|codec| MP4_VIDEO_CODECS.contains(&codec))
+        && summary.audio_codec.as_deref().is_none_or(|codec| MP4_AUDIO_CODECS.contains(&codec));
+
+    let mut command = Command::new(&*config.ffmpeg_command);
+    command.args(["-v", "error"]);
+    if !copy {
+        command.args(config.hwaccel.decode_args());
+    }
+    command.args([
+        "-ss", &params.start.to_string(),
+        "-t", &(params.end - params.start).to_string(),
+        "-i", video_path.to_str().unwrap(),
+        "-map", "0:v:0",
+        "-map", &options.audio_map("0:a:0?")
+    ]);
+    if copy {
+        command.args(["-c", "copy"]);
+    } else {
+        if let Some(upload) = config.hwaccel.upload_filter() {
+            command.args(["-vf", upload]);
+        }
+        // Hardware encoders don't support constant quality consistently
+        let quality = if config.hwaccel.kind == hwaccel::Kind::None { ["-crf", "20"] } else { ["-b:v", "8M"] };
+        command.args(config.hwaccel.encode_args()).args(quality).args(["-c:a", "aac", "-b:a", "160k"]);
+    }
+    command.args(["-movflags", "frag_keyframe+empty_moov", "-f", "mp4", "-"]);
+
+    let body = match app.ffmpeg.stream(&mut command).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to export clip");
+            return err.into_response("Failed to export clip");
+        }
+    };
+
+    let stem = video.file_stem().map_or("clip".into(), |stem| stem.to_string_lossy());
+    let filename = format!("{stem}-{}-{}.mp4", params.start, params.end);
+
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "video/mp4")
+        .header(http::header::CONTENT_DISPOSITION, attachment(&filename))
+        .body(body)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachments() {
+        assert_eq!(attachment("Season 1.zip"), "attachment; filename=\"Season 1.zip\"; filename*=UTF-8''Season%201.zip");
+        assert_eq!(attachment("Café \"Noir\".mp4"), "attachment; filename=\"Caf_ _Noir_.mp4\"; filename*=UTF-8''Caf%C3%A9%20%22Noir%22.mp4");
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+
+/// Runs identical work only once at a time: callers asking for a key that is
+/// already being worked on wait for that result instead of starting their own.
+pub struct Coalescer<T> {
+    inflight: Mutex<HashMap<Box<str>, watch::Receiver<Option<T>>>>
+}
+
+/// Forgets the work once its leader is done with it, or gave up on it because
+/// its client went away.
+struct Leader<'a, T> {
+    coalescer: &'a Coalescer<T>,
+    key: &'a str
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        self.coalescer.inflight.lock().unwrap().remove(self.key);
+    }
+}
+
+impl<T: Clone> Coalescer<T> {
+    pub fn new() -> Self {
+        Coalescer { inflight: Mutex::new(HashMap::new()) }
+    }
+
+    /// Runs `work`, unless work for `key` is already running, in which case
+    /// its result is shared.
+    pub async fn run(&self, key: &str, work: impl Future<Output = T>) -> T {
+        loop {
+            let joined = {
+                let mut inflight = self.inflight.lock().unwrap();
+                match inflight.get(key) {
+                    Some(receiver) => Ok(receiver.clone()),
+                    None => {
+                        let (sender, receiver) = watch::channel(None);
+                        inflight.insert(key.into(), receiver);
+                        Err(sender)
+                    }
+                }
+            };
+
+            let mut receiver = match joined {
+                Ok(receiver) => receiver,
+                Err(sender) => {
+                    let _leader = Leader { coalescer: self, key };
+                    let value = work.await;
+                    let _ = sender.send(Some(value.clone()));
+                    return value;
+                }
+            };
+
+            // The leader being dropped before it finished means its client
+            // disconnected, someone else has to take over
+            let value = receiver.wait_for(Option::is_some).await.map(|value| value.clone());
+            if let Ok(Some(value)) = value {
+                return value;
+            }
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use axum::{extract, http, response, Json};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+
+use crate::error::{ApiError, Code};
+use crate::users::User;
+use crate::{auth, jail, unix_now, App};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS collections (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        owner TEXT
+    );
+    CREATE TABLE IF NOT EXISTS collection_items (
+        collection_id INTEGER NOT NULL REFERENCES collections (id) ON DELETE CASCADE,
+        video TEXT NOT NULL,
+        added INTEGER NOT NULL,
+        PRIMARY KEY (collection_id, video)
+    );
+";
+
+#[derive(serde::Serialize)]
+pub struct Collection {
+    pub id: i64,
+    pub name: Box<str>,
+    pub items: u64
+}
+
+/// Named groups of videos, like tags, that don't depend on where the files
+/// are on disk. Items are video paths, and those that disappear from the
+/// index are skipped when listing. Only the user that created a collection
+/// and admins can change it.
+pub struct Collections {
+    conn: Mutex<Connection>
+}
+
+impl Collections {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        add_owner(&conn, "collections")?;
+        Ok(Collections { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Creates a collection, or returns `None` if one has the same name.
+    pub fn create(&self, name: &str, owner: Option<&str>) -> rusqlite::Result<Option<i64>> {
+        let conn = self.conn();
+        match conn.execute("INSERT INTO collections (name, owner) VALUES (?, ?)", params![name, owner]) {
+            Ok(_) => Ok(Some(conn.last_insert_rowid())),
+            Err(rusqlite::Error::SqliteFailure(err, _)) if err.code == ErrorCode::ConstraintViolation => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn list(&self) -> rusqlite::Result<Vec<Collection>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, name, (SELECT COUNT(*) FROM collection_items WHERE collection_id = id)
+             FROM collections ORDER BY name"
+        )?;
+        let collections = stmt.query_map([], |row| Ok(Collection {
+            id: row.get(0)?,
+            name: row.get::<_, String>(1)?.into(),
+            items: row.get(2)?
+        }))?.collect();
+        collections
+    }
+
+    pub fn name(&self, id: i64) -> rusqlite::Result<Option<Box<str>>> {
+        self.conn()
+            .query_row("SELECT name FROM collections WHERE id = ?", [id], |row| row.get::<_, String>(0))
+            .optional()
+            .map(|name| name.map(Into::into))
+    }
+
+    /// The user that created a collection, `Some(None)` when it was created
+    /// without users.
+    pub fn owner(&self, id: i64) -> rusqlite::Result<Option<Option<Box<str>>>> {
+        self.conn()
+            .query_row("SELECT owner FROM collections WHERE id = ?", [id], |row| row.get::<_, Option<String>>(0))
+            .optional()
+            .map(|owner| owner.map(|owner| owner.map(Into::into)))
+    }
+
+    fn items_in(&self, conn: &Connection, id: i64) -> rusqlite::Result<Vec<Box<str>>> {
+        let mut stmt = conn.prepare_cached("SELECT video FROM collection_items WHERE collection_id = ? ORDER BY added, rowid")?;
+        let items = stmt.query_map([id], |row| Ok(row.get::<_, String>(0)?.into()))?.collect();
+        items
+    }
+
+    /// The videos in a collection, in the order they were added.
+    pub fn items(&self, id: i64) -> rusqlite::Result<Vec<Box<str>>> {
+        self.items_in(&self.conn(), id)
+    }
+
+    /// Replaces the videos in a collection, keeping when the ones that stay
+    /// were added.
+    pub fn set_items(&self, id: i64, videos: &[Box<str>]) -> rusqlite::Result<()> {
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        for video in self.items_in(&transaction, id)? {
+            if !videos.contains(&video) {
+                transaction.execute("DELETE FROM collection_items WHERE collection_id = ? AND video = ?", params![id, video])?;
+            }
+        }
+
+        let now = unix_now();
+        for video in videos {
+            transaction.execute(
+                "INSERT OR IGNORE INTO collection_items (collection_id, video, added) VALUES (?, ?, ?)",
+                params![id, video, now]
+            )?;
+        }
+        transaction.commit()
+    }
+
+    /// Deletes a collection, returning whether it existed.
+    pub fn delete(&self, id: i64) -> rusqlite::Result<bool> {
+        Ok(self.conn().execute("DELETE FROM collections WHERE id = ?", [id])? > 0)
+    }
+
+    /// Keeps a video in its collections after it was moved.
+    pub fn move_video(&self, from: &str, to: &str) -> rusqlite::Result<()> {
+        self.conn().execute("UPDATE OR REPLACE collection_items SET video = ? WHERE video = ?", [to, from])?;
+        Ok(())
+    }
+}
+
+/// Adds the `owner` column to the `table` of an older database. Collections
+/// and playlists from before have none, so only admins can change them.
+pub fn add_owner(conn: &Connection, table: &str) -> rusqlite::Result<()> {
+    let exists = conn.prepare(&format!("SELECT 1 FROM pragma_table_info('{table}') WHERE name = 'owner'"))?.exists([])?;
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN owner TEXT"))?;
+    }
+    Ok(())
+}
+
+/// Whether `user` may change what `owner` created.
+pub fn may_change(app: &App, user: Option<&User>, owner: Option<&str>) -> bool {
+    auth::is_admin(&app.config, user) || user.is_some_and(|user| owner == Some(&*user.name))
+}
+
+fn no_content() -> response::Response {
+    response::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
+
+pub async fn list_collections(extract::State(app): extract::State<&App>) -> response::Response {
+    match app.collections.list() {
+        Ok(collections) => response::IntoResponse::into_response(Json(collections)),
+        Err(err) => ApiError::database("Failed to access collections", err).into()
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct CreateRequest {
+    name: Box<str>
+}
+
+pub async fn create_collection(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Json(request): Json<CreateRequest>
+) -> response::Response {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return ApiError::new(Code::BadRequest, "Collection name can't be empty").into();
+    }
+
+    match app.collections.create(name, user.as_ref().map(|user| &*user.name)) {
+        Ok(Some(id)) => {
+            let mut response = response::IntoResponse::into_response(Json(Collection { id, name: name.into(), items: 0 }));
+            *response.status_mut() = http::StatusCode::CREATED;
+            response
+        }
+        Ok(None) => ApiError::new(Code::AlreadyExists, "A collection with this name already exists").into(),
+        Err(err) => ApiError::database("Failed to access collections", err).into()
+    }
+}
+
+/// A collection with the videos in it that the user can access.
+pub async fn get_collection(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    let (name, items) = match app.collections.name(id).and_then(|name| Ok((name, app.collections.items(id)?))) {
+        Ok((Some(name), items)) => (name, items),
+        Ok((None, _)) => return ApiError::new(Code::CollectionNotFound, "Collection not found").into(),
+        Err(err) => return ApiError::database("Failed to access collections", err).into()
+    };
+
+    let mut videos = Vec::new();
+    for video in items.iter().filter(|video| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, video))) {
+        match app.index.get(video) {
+            Ok(Some(video)) => videos.push(video),
+            Ok(None) => {}
+            Err(err) => return ApiError::database("Failed to access collections", err).into()
+        }
+    }
+
+    response::IntoResponse::into_response(Json(serde_json::json!({
+        "id": id,
+        "name": name,
+        "items": videos
+    })))
+}
+
+#[derive(serde::Deserialize)]
+pub struct ItemsRequest {
+    videos: Box<[Box<str>]>
+}
+
+/// Checks that `videos` exist and that `user` can access them, returning
+/// them in the form used by the index.
+pub async fn validate(app: &App, user: Option<&User>, videos: &[Box<str>]) -> Result<Vec<Box<str>>, response::Response> {
+    let mut validated = Vec::with_capacity(videos.len());
+    for video in videos {
+        let video: Box<str> = video.trim_matches('/').into();
+        if user.is_some_and(|user| !auth::can_access(&app.config, user, &video)) {
+            return Err(ApiError::new(Code::Forbidden, "Forbidden").into());
+        }
+        if let Err(err) = jail::video(&app.config, &*video).await {
+            return Err(err.into_response(Code::VideoNotFound, "Video not found"));
+        }
+        validated.push(video);
+    }
+    Ok(validated)
+}
+
+/// Why `user` can't change the collection `id`, if anything stops them.
+fn refuse_change(app: &App, user: Option<&User>, id: i64) -> Option<response::Response> {
+    match app.collections.owner(id) {
+        Ok(Some(owner)) if may_change(app, user, owner.as_deref()) => None,
+        Ok(Some(_)) => Some(ApiError::new(Code::Forbidden, "Forbidden").into()),
+        Ok(None) => Some(ApiError::new(Code::CollectionNotFound, "Collection not found").into()),
+        Err(err) => Some(ApiError::database("Failed to access collections", err).into())
+    }
+}
+
+/// Replaces the videos in a collection. Videos the user can't access are
+/// rejected rather than dropped, so that a client notices, and those already
+/// in the collection stay since the user couldn't see them.
+pub async fn set_items(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Json(request): Json<ItemsRequest>
+) -> response::Response {
+    if let Some(response) = refuse_change(app, user.as_deref(), id) {
+        return response;
+    }
+
+    let mut videos = match validate(app, user.as_deref(), &request.videos).await {
+        Ok(videos) => videos,
+        Err(response) => return response
+    };
+    if let Some(user) = &user {
+        match app.collections.items(id) {
+            Ok(items) => videos.extend(items.into_iter().filter(|video| !auth::can_access(&app.config, user, video))),
+            Err(err) => return ApiError::database("Failed to access collections", err).into()
+        }
+    }
+    match app.collections.set_items(id, &videos) {
+        Ok(()) => no_content(),
+        Err(err) => ApiError::database("Failed to access collections", err).into()
+    }
+}
+
+pub async fn delete_collection(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if let Some(response) = refuse_change(app, user.as_deref(), id) {
+        return response;
+    }
+    match app.collections.delete(id) {
+        Ok(true) => no_content(),
+        Ok(false) => ApiError::new(Code::CollectionNotFound, "Collection not found").into(),
+        Err(err) => ApiError::database("Failed to access collections", err).into()
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{http, response};
+
+/// What identifies a version of a file: its entity tag, derived from the size
+/// and modification time, and the modification time itself.
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<SystemTime>
+}
+
+impl Validators {
+    pub fn new(metadata: &Metadata) -> Self {
+        let last_modified = metadata.modified().ok();
+        let mtime = last_modified
+            .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |mtime| mtime.as_nanos());
+
+        Validators {
+            etag: format!("\"{:x}-{mtime:x}\"", metadata.len()),
+            last_modified
+        }
+    }
+
+    /// Validators of content generated from a source file, which can't
+    /// promise to be byte for byte identical every time it is generated.
+    pub fn weak(tag: &str, last_modified: SystemTime) -> Self {
+        Validators {
+            etag: format!("W/\"{tag}\""),
+            last_modified: Some(last_modified)
+        }
+    }
+
+    /// Adds `ETag` and `Last-Modified` to `response`.
+    pub fn headers(&self, response: http::response::Builder) -> http::response::Builder {
+        let response = response.header(http::header::ETAG, &self.etag);
+        match self.last_modified {
+            Some(modified) => response.header(http::header::LAST_MODIFIED, httpdate::fmt_http_date(modified)),
+            None => response
+        }
+    }
+
+    fn matches_date(&self, date: &str) -> bool {
+        match (self.last_modified, httpdate::parse_http_date(date)) {
+            // HTTP dates only have a precision of a second
+            (Some(modified), Ok(date)) => httpdate::fmt_http_date(modified) == httpdate::fmt_http_date(date),
+            _ => false
+        }
+    }
+}
+
+/// Whether a `Range` request may be answered with a part of the file. A
+/// client resuming a download sends the validator of the version it already
+/// has in `If-Range`, and needs the whole file again if it has changed since.
+pub fn if_range(headers: &http::HeaderMap, validators: &Validators) -> bool {
+    let Some(condition) = headers.get(http::header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(condition) = condition.to_str() else {
+        return false;
+    };
+
+    let condition = condition.trim();
+    if condition.starts_with('"') {
+        condition == validators.etag
+    } else if condition.starts_with("W/") {
+        // Weak tags never match, a range needs the exact same bytes
+        false
+    } else {
+        validators.matches_date(condition)
+    }
+}
+
+fn opaque_tag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+/// Whether the client's cached copy, described by `If-None-Match` or
+/// `If-Modified-Since`, is still current.
+pub fn is_not_modified(headers: &http::HeaderMap, validators: &Validators) -> bool {
+    // If-None-Match takes precedence, and compares weakly
+    if let Some(condition) = headers.get(http::header::IF_NONE_MATCH) {
+        let Ok(condition) = condition.to_str() else {
+            return false;
+        };
+        return condition.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || opaque_tag(tag) == opaque_tag(&validators.etag));
+    }
+
+    let since = headers.get(http::header::IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(|since| httpdate::parse_http_date(since).ok());
+    match (since, validators.last_modified) {
+        (Some(since), Some(modified)) => seconds(modified) <= seconds(since),
+        _ => false
+    }
+}
+
+pub fn not_modified(validators: &Validators) -> response::Response {
+    validators.headers(response::Response::builder())
+        .status(http::StatusCode::NOT_MODIFIED)
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
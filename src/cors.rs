@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
+
+/// Cross-origin access for web players hosted elsewhere. Disabled while
+/// `origins` is empty, and `["*"]` allows any origin.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Cors {
+    pub origins: Box<[Box<str>]>,
+    pub methods: Box<[Box<str>]>,
+    /// Request headers the player may send, or `["*"]` for any.
+    pub headers: Box<[Box<str>]>,
+    /// Whether to allow cookies and `Authorization` headers.
+    pub credentials: bool,
+    /// How long browsers may cache the preflight response, in seconds.
+    pub max_age: u64
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            origins: Box::new([]),
+            methods: ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"].map(Into::into).into(),
+            headers: [
+                "authorization", "content-type", "range", "traceparent", "x-request-id",
+                "tus-resumable", "upload-offset", "upload-length", "upload-metadata"
+            ].map(Into::into).into(),
+            credentials: false,
+            max_age: 3600
+        }
+    }
+}
+
+/// Response headers players need to read, like `Content-Range` for seeking,
+/// those of resumable uploads, and the request ID to report failures with.
+const EXPOSE_HEADERS: &[&str] = &[
+    "accept-ranges", "content-length", "content-range", "etag", "location", "retry-after",
+    "tus-resumable", "tus-version", "tus-extension", "tus-max-size", "upload-offset", "upload-length",
+    "x-request-id"
+];
+
+fn is_any(values: &[Box<str>]) -> bool {
+    values.iter().any(|value| &**value == "*")
+}
+
+impl Cors {
+    pub fn layer(&self) -> Result<Option<CorsLayer>, String> {
+        if self.origins.is_empty() {
+            return Ok(None);
+        }
+
+        // Browsers don't accept a wildcard along with credentials, echoing the
+        // request back allows the same
+        let origins = if is_any(&self.origins) {
+            if self.credentials { AllowOrigin::mirror_request() } else { AllowOrigin::any() }
+        } else {
+            let origins = self.origins.iter()
+                .map(|origin| HeaderValue::from_str(origin).map_err(|_| format!("Invalid origin `{origin}`")))
+                .collect::<Result<Vec<_>, _>>()?;
+            AllowOrigin::list(origins)
+        };
+
+        let headers = if is_any(&self.headers) {
+            if self.credentials { AllowHeaders::mirror_request() } else { AllowHeaders::any() }
+        } else {
+            let headers = self.headers.iter()
+                .map(|header| HeaderName::from_bytes(header.as_bytes()).map_err(|_| format!("Invalid header `{header}`")))
+                .collect::<Result<Vec<_>, _>>()?;
+            AllowHeaders::list(headers)
+        };
+
+        let methods = self.methods.iter()
+            .map(|method| Method::from_bytes(method.as_bytes()).map_err(|_| format!("Invalid method `{method}`")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.credentials)
+            .expose_headers(EXPOSE_HEADERS.iter().map(|header| HeaderName::from_static(header)).collect::<Vec<_>>())
+            .max_age(Duration::from_secs(self.max_age))))
+    }
+}
@@ -0,0 +1,559 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::path::Path;
+use std::time::Duration;
+
+use axum::{extract, http, middleware, response};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+use crate::error::{ApiError, Code};
+use crate::forwarded::Client;
+use crate::index::{self, Video};
+use crate::{cache, feed, library, mime, nfo, server, url, App, Config};
+
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+
+/// How long announcements are valid, they're renewed well before.
+const MAX_AGE: u64 = 1800;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(MAX_AGE / 2);
+
+const MEDIA_SERVER: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+/// The ID of the top of the library. Everything else is identified by its
+/// path.
+const ROOT_ID: &str = "0";
+
+/// Announces the library to TVs and consoles on the local network, which
+/// then browse it without logging in. Only clients on private networks are
+/// answered, and libraries limited to some `users` are left out.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Dlna {
+    pub enabled: bool,
+    /// The name the server shows up as.
+    pub name: Box<str>
+}
+
+impl Default for Dlna {
+    fn default() -> Self {
+        Dlna { enabled: false, name: "ninja".into() }
+    }
+}
+
+/// Stays the same across restarts, so that clients recognize the server.
+fn uuid(config: &Config) -> String {
+    let (high, low) = (cache::Lru::key(("dlna", &config.dlna.name)), cache::Lru::key(("dlna", &config.dlna.name, 1)));
+    format!("{}-{}-{}-{}-{}", &high[..8], &high[8..12], &high[12..16], &low[..4], &low[4..16])
+}
+
+/// Whether `ip` is on a private network. Loopback is left to `is_same_host`.
+fn is_local(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unique_local() || ip.is_unicast_link_local()
+    }
+}
+
+/// Whether `request` comes from this machine itself rather than through a
+/// reverse proxy on it, which would make every client look like loopback.
+fn is_same_host(ip: IpAddr, request: &extract::Request) -> bool {
+    let headers = request.headers();
+    let proxied = ["forwarded", "x-forwarded-for", "x-forwarded-host", "x-real-ip"].iter().any(|name| headers.contains_key(*name));
+    ip.to_canonical().is_loopback() && !proxied
+}
+
+/// Keeps the DLNA routes to clients on the local network, and hides them
+/// entirely when DLNA is off. Clients without an address, like those over a
+/// Unix socket, are turned away since they're most likely behind a proxy.
+pub async fn only_local(
+    extract::State(app): extract::State<&'static App>,
+    request: extract::Request,
+    next: middleware::Next
+) -> response::Response {
+    let ip = request.extensions().get::<Client>().and_then(|client| client.ip);
+    let local = ip.is_some_and(|ip| is_local(ip) || is_same_host(ip, &request));
+    if !app.config.dlna.enabled || !local {
+        return ApiError::new(Code::NotFound, "Not found").into();
+    }
+    next.run(request).await
+}
+
+/// Whether renderers, which don't log in, may see `path`. Libraries limited
+/// to some users are left out.
+fn is_shared(config: &Config, path: &str) -> bool {
+    path.is_empty() || library::is_public(config, path)
+}
+
+/// Keeps the media and thumbnails served to renderers to what they may
+/// browse.
+pub async fn only_shared(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&'static App>,
+    request: extract::Request,
+    next: middleware::Next
+) -> response::Response {
+    if !is_shared(&app.config, video.trim_matches('/')) {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into();
+    }
+    next.run(request).await
+}
+
+/// The address this host is reached at from `peer`, as picked by routing.
+pub fn local_ip(peer: SocketAddr) -> io::Result<IpAddr> {
+    let socket = StdUdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(peer)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The device description as reached at `ip`, if the server listens there.
+fn location(config: &Config, ip: IpAddr) -> Option<String> {
+    let scheme = if config.tls_cert.is_some() { "https" } else { "http" };
+    let address = server::reachable(config, ip)?;
+    Some(format!("{scheme}://{address}/{}", url::path(config, "/dlna/description.xml").trim_start_matches('/')))
+}
+
+/// What the server answers searches for, with its unique name for each.
+fn targets(config: &Config) -> [(String, String); 5] {
+    let uuid = format!("uuid:{}", uuid(config));
+    [
+        ("upnp:rootdevice".into(), format!("{uuid}::upnp:rootdevice")),
+        (uuid.clone(), uuid.clone()),
+        (MEDIA_SERVER.into(), format!("{uuid}::{MEDIA_SERVER}")),
+        (CONTENT_DIRECTORY.into(), format!("{uuid}::{CONTENT_DIRECTORY}")),
+        (CONNECTION_MANAGER.into(), format!("{uuid}::{CONNECTION_MANAGER}"))
+    ]
+}
+
+fn server_header() -> String {
+    format!("{}/1.0 UPnP/1.0 ninja/{}", std::env::consts::OS, env!("CARGO_PKG_VERSION"))
+}
+
+/// The search target of an `M-SEARCH` request, if `message` is one.
+fn search_target(message: &str) -> Option<&str> {
+    let mut lines = message.lines();
+    if !lines.next()?.starts_with("M-SEARCH ") {
+        return None;
+    }
+    lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("ST").then(|| value.trim())
+    })
+}
+
+async fn announce(config: &Config, socket: &UdpSocket) {
+    let group = SocketAddr::from((SSDP_GROUP, SSDP_PORT));
+    let Some(location) = local_ip(group).ok().and_then(|ip| location(config, ip)) else {
+        return;
+    };
+    for (target, usn) in targets(config) {
+        let message = format!(
+            "NOTIFY * HTTP/1.1\r\nHOST: {group}\r\nCACHE-CONTROL: max-age={MAX_AGE}\r\nLOCATION: {}\r\nNT: {target}\r\nNTS: ssdp:alive\r\nSERVER: {}\r\nUSN: {usn}\r\n\r\n",
+            location, server_header()
+        );
+        if let Err(err) = socket.send_to(message.as_bytes(), group).await {
+            tracing::debug!(error = %err, "Failed to announce over SSDP");
+        }
+    }
+}
+
+async fn respond(config: &Config, socket: &UdpSocket, peer: SocketAddr, search: &str) {
+    let Some(location) = local_ip(peer).ok().and_then(|ip| location(config, ip)) else {
+        return;
+    };
+    for (target, usn) in targets(config).into_iter().filter(|(target, _)| search == "ssdp:all" || search == target) {
+        let message = format!(
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={MAX_AGE}\r\nEXT:\r\nLOCATION: {}\r\nSERVER: {}\r\nST: {target}\r\nUSN: {usn}\r\n\r\n",
+            location, server_header()
+        );
+        if let Err(err) = socket.send_to(message.as_bytes(), peer).await {
+            tracing::debug!(error = %err, "Failed to answer SSDP search from {peer}");
+        }
+    }
+}
+
+fn bind() -> io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    // Other media servers on the same host listen on the port too
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, SSDP_PORT)).into())?;
+    socket.join_multicast_v4(&SSDP_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Answers SSDP searches and announces the server every so often, when DLNA
+/// is enabled.
+pub async fn run(app: &'static App) {
+    if !app.config.dlna.enabled {
+        return;
+    }
+    let socket = match bind() {
+        Ok(socket) => socket,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to listen for SSDP, DLNA clients won't find the server");
+            return;
+        }
+    };
+    tracing::info!("Announcing `{}` over DLNA", app.config.dlna.name);
+
+    let mut announcements = time::interval(ANNOUNCE_INTERVAL);
+    let mut buffer = [0; 2048];
+    loop {
+        tokio::select! {
+            _ = announcements.tick() => announce(&app.config, &socket).await,
+            received = socket.recv_from(&mut buffer) => {
+                let Ok((length, peer)) = received else { continue };
+                if !is_local(peer.ip()) && !peer.ip().to_canonical().is_loopback() {
+                    continue;
+                }
+                if let Some(search) = std::str::from_utf8(&buffer[..length]).ok().and_then(search_target) {
+                    respond(&app.config, &socket, peer, search).await;
+                }
+            }
+        }
+    }
+}
+
+fn xml(body: String) -> response::Response {
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+        .body(body.into())
+        .unwrap()
+}
+
+pub async fn serve_description(extract::State(app): extract::State<&App>) -> response::Response {
+    let service = |kind: &str, name: &str| format!(
+        "<service><serviceType>{kind}</serviceType><serviceId>urn:upnp-org:serviceId:{name}</serviceId>\
+        <SCPDURL>{}</SCPDURL><controlURL>{}</controlURL><eventSubURL>{}</eventSubURL></service>",
+        url::path(&app.config, &format!("/dlna/{name}.xml")),
+        url::path(&app.config, &format!("/dlna/control/{name}")),
+        url::path(&app.config, &format!("/dlna/events/{name}"))
+    );
+    xml(format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <root xmlns=\"urn:schemas-upnp-org:device-1-0\"><specVersion><major>1</major><minor>0</minor></specVersion>\
+        <device><deviceType>{MEDIA_SERVER}</deviceType><friendlyName>{}</friendlyName>\
+        <manufacturer>ninja</manufacturer><modelName>ninja</modelName><modelNumber>{}</modelNumber>\
+        <UDN>uuid:{}</UDN><serviceList>{}{}</serviceList></device></root>",
+        feed::escape(&app.config.dlna.name), env!("CARGO_PKG_VERSION"), uuid(&app.config),
+        service(CONTENT_DIRECTORY, "ContentDirectory"), service(CONNECTION_MANAGER, "ConnectionManager")
+    ))
+}
+
+/// An argument of an action, as its name, direction and state variable.
+type Argument<'a> = (&'a str, &'a str, &'a str);
+
+/// Service descriptions, listing the actions that are answered.
+fn scpd(actions: &[(&str, &[Argument])], variables: &[(&str, &str)]) -> String {
+    let actions: String = actions.iter().map(|(name, arguments)| {
+        let arguments: String = arguments.iter()
+            .map(|(name, direction, variable)| format!(
+                "<argument><name>{name}</name><direction>{direction}</direction><relatedStateVariable>{variable}</relatedStateVariable></argument>"
+            ))
+            .collect();
+        format!("<action><name>{name}</name><argumentList>{arguments}</argumentList></action>")
+    }).collect();
+    let variables: String = variables.iter()
+        .map(|(name, kind)| format!("<stateVariable sendEvents=\"no\"><name>{name}</name><dataType>{kind}</dataType></stateVariable>"))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\
+        <specVersion><major>1</major><minor>0</minor></specVersion><actionList>{actions}</actionList>\
+        <serviceStateTable>{variables}</serviceStateTable></scpd>"
+    )
+}
+
+pub async fn serve_content_directory_scpd() -> response::Response {
+    xml(scpd(&[
+        ("Browse", &[
+            ("ObjectID", "in", "A_ARG_TYPE_ObjectID"),
+            ("BrowseFlag", "in", "A_ARG_TYPE_BrowseFlag"),
+            ("Filter", "in", "A_ARG_TYPE_Filter"),
+            ("StartingIndex", "in", "A_ARG_TYPE_Index"),
+            ("RequestedCount", "in", "A_ARG_TYPE_Count"),
+            ("SortCriteria", "in", "A_ARG_TYPE_SortCriteria"),
+            ("Result", "out", "A_ARG_TYPE_Result"),
+            ("NumberReturned", "out", "A_ARG_TYPE_Count"),
+            ("TotalMatches", "out", "A_ARG_TYPE_Count"),
+            ("UpdateID", "out", "A_ARG_TYPE_UpdateID")
+        ]),
+        ("GetSearchCapabilities", &[("SearchCaps", "out", "SearchCapabilities")]),
+        ("GetSortCapabilities", &[("SortCaps", "out", "SortCapabilities")]),
+        ("GetSystemUpdateID", &[("Id", "out", "SystemUpdateID")])
+    ], &[
+        ("A_ARG_TYPE_ObjectID", "string"),
+        ("A_ARG_TYPE_BrowseFlag", "string"),
+        ("A_ARG_TYPE_Filter", "string"),
+        ("A_ARG_TYPE_Index", "ui4"),
+        ("A_ARG_TYPE_Count", "ui4"),
+        ("A_ARG_TYPE_SortCriteria", "string"),
+        ("A_ARG_TYPE_Result", "string"),
+        ("A_ARG_TYPE_UpdateID", "ui4"),
+        ("SearchCapabilities", "string"),
+        ("SortCapabilities", "string"),
+        ("SystemUpdateID", "ui4")
+    ]))
+}
+
+pub async fn serve_connection_manager_scpd() -> response::Response {
+    xml(scpd(&[
+        ("GetProtocolInfo", &[("Source", "out", "SourceProtocolInfo"), ("Sink", "out", "SinkProtocolInfo")]),
+        ("GetCurrentConnectionIDs", &[("ConnectionIDs", "out", "CurrentConnectionIDs")])
+    ], &[
+        ("SourceProtocolInfo", "string"),
+        ("SinkProtocolInfo", "string"),
+        ("CurrentConnectionIDs", "string")
+    ]))
+}
+
+/// The action named by the `SOAPAction` header of a control request, like
+/// `Browse` for `"urn:schemas-upnp-org:service:ContentDirectory:1#Browse"`.
+fn action(header: &http::HeaderMap) -> Option<&str> {
+    let action = header.get("soapaction")?.to_str().ok()?.trim_matches('"');
+    Some(action.rsplit_once('#')?.1)
+}
+
+fn soap(service: &str, action: &str, arguments: &[(&str, &str)]) -> response::Response {
+    let arguments: String = arguments.iter().map(|(name, value)| format!("<{name}>{}</{name}>", feed::escape(value))).collect();
+    xml(format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body><u:{action}Response xmlns:u=\"{service}\">{arguments}</u:{action}Response></s:Body></s:Envelope>"
+    ))
+}
+
+fn soap_fault(code: u16, description: &str) -> response::Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail>\
+        <UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{code}</errorCode><errorDescription>{description}</errorDescription></UPnPError>\
+        </detail></s:Fault></s:Body></s:Envelope>"
+    );
+    response::Response::builder()
+        .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+        .header(http::header::CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+        .body(body.into())
+        .unwrap()
+}
+
+/// `H:MM:SS.mmm`, as DIDL-Lite wants durations.
+fn duration(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!("{}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+fn parent_id(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some((parent, _)) => parent,
+        None => ROOT_ID
+    }
+}
+
+fn container(id: &str, parent: &str, title: &str) -> String {
+    format!(
+        "<container id=\"{}\" parentID=\"{}\" restricted=\"1\"><dc:title>{}</dc:title>\
+        <upnp:class>object.container.storageFolder</upnp:class></container>",
+        feed::escape(id), feed::escape(parent), feed::escape(title)
+    )
+}
+
+/// A video with its stream and thumbnail, at `base`, the address the client
+/// reached the server at.
+fn item(base: &str, video: &Video) -> String {
+    let content_type = mime::from_extension(Path::new(&*video.filename)).unwrap_or("application/octet-stream");
+    let class = if content_type.starts_with("audio/") { "object.item.audioItem" } else { "object.item.videoItem" };
+    let path = url::encode_path(&video.path);
+
+    let mut attributes = format!(" size=\"{}\"", video.size);
+    if let Some(seconds) = video.duration {
+        attributes.push_str(&format!(" duration=\"{}\"", duration(seconds)));
+    }
+    if let (Some(width), Some(height)) = (video.width, video.height) {
+        attributes.push_str(&format!(" resolution=\"{width}x{height}\""));
+    }
+    let art = if class == "object.item.videoItem" {
+        format!("<upnp:albumArtURI>{}</upnp:albumArtURI>", feed::escape(&format!("{base}/dlna/thumb/{path}")))
+    } else {
+        String::new()
+    };
+    format!(
+        "<item id=\"{}\" parentID=\"{}\" restricted=\"1\"><dc:title>{}</dc:title><upnp:class>{class}</upnp:class>{art}\
+        <res protocolInfo=\"http-get:*:{content_type}:*\"{attributes}>{}</res></item>",
+        feed::escape(&video.path), feed::escape(parent_id(&video.path)), feed::escape(video.display_title()),
+        feed::escape(&format!("{base}/dlna/media/{path}"))
+    )
+}
+
+fn didl(entries: &[String]) -> String {
+    format!(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+        xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">{}</DIDL-Lite>",
+        entries.concat()
+    )
+}
+
+/// The folders and then the videos in `dir`, `count` of them from `start`,
+/// and how many there are in total.
+async fn children(app: &App, base: &str, dir: &str, start: u64, count: u64) -> rusqlite::Result<(Vec<String>, u64)> {
+    let mut folders = library::folders(app, None, dir).await;
+    folders.retain(|folder| is_shared(&app.config, &folder.path));
+    let parent = if dir.is_empty() { ROOT_ID } else { dir };
+    let mut entries: Vec<String> = folders.iter()
+        .skip(start as usize)
+        .take(count as usize)
+        .map(|folder| container(&folder.path, parent, &folder.name))
+        .collect();
+
+    let offset = start.saturating_sub(folders.len() as u64);
+    let limit = count - entries.len() as u64;
+    let (videos, total) = app.index.list(dir, &index::Listing {
+        sort: index::Sort::Name,
+        descending: false,
+        offset,
+        limit: Some(limit),
+        extensions: &[]
+    })?;
+    entries.extend(videos.iter().map(|video| item(base, video)));
+    Ok((entries, folders.len() as u64 + total))
+}
+
+/// `Browse` of ContentDirectory, over the folders and the index.
+async fn browse(app: &App, base: &str, body: &str) -> response::Response {
+    let id = nfo::element(body, "ObjectID").unwrap_or_else(|| ROOT_ID.into());
+    let start = nfo::element(body, "StartingIndex").and_then(|start| start.parse().ok()).unwrap_or(0);
+    // Zero asks for everything
+    let count = nfo::element(body, "RequestedCount").and_then(|count| count.parse().ok()).filter(|&count| count > 0).unwrap_or(u64::MAX);
+    let dir = if id == ROOT_ID { "" } else { id.trim_matches('/') };
+    let shared = is_shared(&app.config, dir);
+
+    let (entries, total) = match nfo::element(body, "BrowseFlag").as_deref() {
+        Some("BrowseMetadata") if dir.is_empty() => (vec![container(ROOT_ID, "-1", &app.config.dlna.name)], 1),
+        Some("BrowseMetadata") if !shared => return soap_fault(701, "No such object"),
+        Some("BrowseMetadata") => match app.index.get(dir) {
+            Ok(Some(video)) => (vec![item(base, &video)], 1),
+            Ok(None) if library::is_directory(&app.config, dir).await => {
+                (vec![container(dir, parent_id(dir), dir.rsplit('/').next().unwrap_or(dir))], 1)
+            }
+            Ok(None) => return soap_fault(701, "No such object"),
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to query index for `{dir}`");
+                return soap_fault(501, "Action failed");
+            }
+        },
+        Some("BrowseDirectChildren") => {
+            if !dir.is_empty() && (!shared || !library::is_directory(&app.config, dir).await) {
+                return soap_fault(710, "No such container");
+            }
+            match children(app, base, dir, start, count).await {
+                Ok(children) => children,
+                Err(err) => {
+                    tracing::error!(error = %err, "Failed to list directory `{dir}`");
+                    return soap_fault(501, "Action failed");
+                }
+            }
+        }
+        _ => return soap_fault(402, "Invalid args")
+    };
+
+    soap(CONTENT_DIRECTORY, "Browse", &[
+        ("Result", &didl(&entries)),
+        ("NumberReturned", &entries.len().to_string()),
+        ("TotalMatches", &total.to_string()),
+        ("UpdateID", "0")
+    ])
+}
+
+pub async fn control_content_directory(
+    extract::State(app): extract::State<&App>,
+    request: extract::Request
+) -> response::Response {
+    let (parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, 64 << 10).await else {
+        return soap_fault(402, "Invalid args");
+    };
+    let body = String::from_utf8_lossy(&body);
+
+    match action(&parts.headers) {
+        Some("Browse") => {
+            let base = url::absolute(&app.config, &parts, "");
+            browse(app, &base, &body).await
+        }
+        Some("GetSearchCapabilities") => soap(CONTENT_DIRECTORY, "GetSearchCapabilities", &[("SearchCaps", "")]),
+        Some("GetSortCapabilities") => soap(CONTENT_DIRECTORY, "GetSortCapabilities", &[("SortCaps", "")]),
+        Some("GetSystemUpdateID") => soap(CONTENT_DIRECTORY, "GetSystemUpdateID", &[("Id", "0")]),
+        _ => soap_fault(401, "Invalid action")
+    }
+}
+
+pub async fn control_connection_manager(header: http::HeaderMap) -> response::Response {
+    match action(&header) {
+        Some("GetProtocolInfo") => {
+            let mut types: Vec<&str> = ["mp4", "webm", "mkv", "mov", "avi", "ts", "mp3", "m4a", "flac", "ogg", "wav"].iter()
+                .filter_map(|extension| mime::from_extension(Path::new(&format!("a.{extension}"))))
+                .collect();
+            types.dedup();
+            let source: Vec<String> = types.iter().map(|content_type| format!("http-get:*:{content_type}:*")).collect();
+            soap(CONNECTION_MANAGER, "GetProtocolInfo", &[("Source", &source.join(",")), ("Sink", "")])
+        }
+        Some("GetCurrentConnectionIDs") => soap(CONNECTION_MANAGER, "GetCurrentConnectionIDs", &[("ConnectionIDs", "0")]),
+        _ => soap_fault(401, "Invalid action")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn searches() {
+        let search = "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nst: ssdp:all\r\n\r\n";
+        assert_eq!(search_target(search), Some("ssdp:all"));
+        assert_eq!(search_target("NOTIFY * HTTP/1.1\r\nNT: upnp:rootdevice\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn locality() {
+        let request = |proxied: bool| {
+            let builder = http::Request::builder();
+            let builder = if proxied { builder.header("x-forwarded-for", "203.0.113.7") } else { builder };
+            builder.body(axum::body::Body::empty()).unwrap()
+        };
+        assert!(is_local("192.168.1.20".parse().unwrap()));
+        assert!(is_local("::ffff:10.0.0.3".parse().unwrap()));
+        assert!(!is_local("203.0.113.7".parse().unwrap()));
+        assert!(!is_local("127.0.0.1".parse().unwrap()));
+        assert!(is_same_host("127.0.0.1".parse().unwrap(), &request(false)));
+        assert!(!is_same_host("::1".parse().unwrap(), &request(true)));
+    }
+
+    #[test]
+    fn private_libraries() {
+        let library = |name: &str, users: &[&str]| library::Library {
+            name: name.into(),
+            path: Path::new("/srv").join(name).into(),
+            read_only: false,
+            renditions: None,
+            users: users.iter().map(|&user| user.into()).collect()
+        };
+        let config = Config { libraries: [library("movies", &[]), library("home", &["ana"])].into(), ..Config::default() };
+        assert!(is_shared(&config, ""));
+        assert!(is_shared(&config, "movies"));
+        assert!(is_shared(&config, "movies/a.mp4"));
+        assert!(!is_shared(&config, "home"));
+        assert!(!is_shared(&config, "home/a.mp4"));
+        assert!(!is_shared(&config, "elsewhere/a.mp4"));
+        assert!(is_shared(&Config::default(), "a.mp4"));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration(3725.5), "1:02:05.500");
+        assert_eq!(duration(59.0), "0:00:59.000");
+    }
+}
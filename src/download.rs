@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use axum::body::Body;
+use axum::{extract, http, response};
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+
+use crate::error::{ApiError, Code};
+use crate::index::Video;
+use crate::users::User;
+use crate::clip::attachment;
+use crate::{auth, checksum, jail, library, subtitles, zip, App};
+
+/// A file to put in an archive, with its size and modification time read
+/// up front so that the length of the archive is known.
+struct File {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    mtime: u64
+}
+
+/// `video` and the subtitles next to it, named in the archive after `name`,
+/// the path of the video inside it.
+async fn files(app: &App, video: &Video, name: &str) -> Vec<File> {
+    let Some(path) = library::file(&app.config, &*video.path) else {
+        return Vec::new();
+    };
+    let mut paths = vec![(name.to_owned(), path.clone())];
+    if let Some(dir) = path.parent() {
+        let prefix = name.rsplit_once('/').map_or(String::new(), |(dir, _)| format!("{dir}/"));
+        for sidecar in subtitles::sidecars(&path, &subtitles::filenames(dir).await) {
+            paths.push((format!("{prefix}{}", sidecar.file), dir.join(&*sidecar.file)));
+        }
+    }
+
+    let mut files = Vec::new();
+    for (name, path) in paths {
+        if let Ok(metadata) = fs::metadata(&path).await {
+            let mtime = metadata.modified().ok().and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok()).map_or(0, |mtime| mtime.as_secs());
+            files.push(File { name, path, size: metadata.len(), mtime });
+        }
+    }
+    files
+}
+
+/// Streams a store-only ZIP of `files`, written on the fly. Files that shrink
+/// meanwhile end the response early, and clients notice from the
+/// `Content-Length` that the archive is incomplete.
+fn archive(filename: &str, files: Vec<File>) -> response::Response {
+    let length = zip::archive_size(files.iter().map(|file| (&*file.name, file.size)));
+    let (writer, reader) = tokio::io::duplex(1 << 16);
+    tokio::spawn(async move {
+        let mut zip = zip::Writer::new(writer);
+        for file in files {
+            let added = match fs::File::open(&file.path).await {
+                Ok(reader) => zip.add(&file.name, file.mtime, file.size, reader).await,
+                Err(err) => Err(err)
+            };
+            if let Err(err) = added {
+                tracing::debug!(error = %err, "Stopped archiving at `{}`", file.path.display());
+                return;
+            }
+        }
+        if let Err(err) = zip.finish().await {
+            tracing::debug!(error = %err, "Failed to finish archive");
+        }
+    });
+
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/zip")
+        .header(http::header::CONTENT_LENGTH, length)
+        .header(http::header::CONTENT_DISPOSITION, attachment(&format!("{filename}.zip")))
+        .body(Body::from_stream(ReaderStream::new(reader)))
+        .unwrap()
+}
+
+/// The original file of a video, saved under its own name rather than played
+/// inline. Ranges are supported, so that interrupted downloads resume, and the
+/// checksum is sent along once computed, so that they can be verified.
+pub async fn serve_video(
+    extract::Path((relative, )): extract::Path<(Box<str>, )>,
+    method: http::Method,
+    header: http::HeaderMap,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let path = match jail::video(&app.config, &*relative).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    let file = match fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to open video `{}`", path.display());
+            return ApiError::new(Code::VideoNotFound, "Video not found").into();
+        }
+    };
+
+    let mut response = crate::serve_file(&app.config, &path, file, &method, &header).await;
+    if !response.status().is_success() {
+        return response;
+    }
+    let filename = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    if let Ok(value) = http::HeaderValue::from_str(&attachment(&filename)) {
+        response.headers_mut().insert(http::header::CONTENT_DISPOSITION, value);
+    }
+    // Only when already known, hashing first would hold up the download
+    if let Some(sha256) = checksum::cached(app, &relative, &path).await {
+        for (name, value) in checksum::headers(&sha256) {
+            if let Ok(value) = http::HeaderValue::from_str(&value) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+    }
+    response
+}
+
+/// Every video below a folder, with its subtitles, as a ZIP named after the
+/// folder.
+pub async fn serve_dir(
+    extract::Path((path, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    let dir = path.trim_matches('/');
+    match jail::directory(&app.config, dir).await {
+        Ok(path) if fs::metadata(&path).await.is_ok_and(|metadata| metadata.is_dir()) => {}
+        Ok(_) => return ApiError::new(Code::DirectoryNotFound, "Directory not found").into(),
+        Err(err) => return err.into_response(Code::DirectoryNotFound, "Directory not found")
+    }
+
+    let videos = match app.index.all() {
+        Ok(videos) => videos,
+        Err(err) => return ApiError::database("Failed to list download", err).into()
+    };
+    // Entries are inside a folder of the same name, like the one downloaded
+    let name = dir.rsplit('/').next().unwrap_or(dir);
+    let mut files = Vec::new();
+    for video in videos.iter().filter(|video| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, &video.path))) {
+        if let Some(inside) = video.path.strip_prefix(dir).and_then(|rest| rest.strip_prefix('/')) {
+            files.extend(self::files(app, video, &format!("{name}/{inside}")).await);
+        }
+    }
+    archive(name, files)
+}
+
+/// The videos of a collection, with their subtitles, as a ZIP named after
+/// it. Videos are side by side, unless their names clash.
+pub async fn serve_collection(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    let (name, items) = match app.collections.name(id).and_then(|name| Ok((name, app.collections.items(id)?))) {
+        Ok((Some(name), items)) => (name, items),
+        Ok((None, _)) => return ApiError::new(Code::CollectionNotFound, "Collection not found").into(),
+        Err(err) => return ApiError::database("Failed to list download", err).into()
+    };
+
+    // Names are free form, but a slash would nest the entries
+    let name = name.replace(['/', '\\'], "_");
+    let mut names = HashSet::new();
+    let mut files = Vec::new();
+    for video in items.iter().filter(|video| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, video))) {
+        let video = match app.index.get(video) {
+            Ok(Some(video)) => video,
+            Ok(None) => continue,
+            Err(err) => return ApiError::database("Failed to list download", err).into()
+        };
+        let inside = if names.insert(video.filename.clone()) { &video.filename } else { &video.path };
+        files.extend(self::files(app, &video, &format!("{name}/{inside}")).await);
+    }
+    archive(&name, files)
+}
@@ -0,0 +1,221 @@
+use axum::{extract, response, Json};
+use tokio::process::Command;
+
+use crate::error::{ApiError, Code};
+use crate::index::Video;
+use crate::users::User;
+use crate::{auth, library, App};
+
+/// Where in the video, as a fraction of its duration, frames are sampled.
+/// The ends are skipped as intros and credits are shared across episodes.
+const SAMPLES: [f64; 4] = [0.2, 0.4, 0.6, 0.8];
+
+/// Most differing bits, out of the 64 of each sampled frame, for two videos to
+/// count as the same. Scaling and re-encoding flip a few, different content
+/// flips about half.
+const MAX_DISTANCE: u32 = 8 * SAMPLES.len() as u32;
+
+/// Stored for videos that can't be hashed, like audio with a cover image or
+/// those whose sampled frames are flat, so that scans don't try them again
+/// until they change.
+const UNHASHABLE: &str = "";
+
+/// Difference hash of a 9x8 grayscale frame: a bit per pixel, set when it is
+/// brighter than the one to its right.
+fn dhash(pixels: &[u8]) -> u64 {
+    pixels.chunks_exact(9).flat_map(|row| row.windows(2)).fold(0, |hash, pair| hash << 1 | u64::from(pair[0] > pair[1]))
+}
+
+/// The hex encoded hashes of the frames in `pixels`, stacked 9x8 grayscale
+/// frames. `None` when a frame is flat, like a black screen, which would match
+/// any other flat frame.
+fn fingerprint(pixels: &[u8]) -> Option<String> {
+    if pixels.len() != 72 * SAMPLES.len() {
+        return None;
+    }
+    let hashes: Vec<u64> = pixels.chunks_exact(72).map(dhash).collect();
+    if hashes.iter().any(|&hash| hash == 0 || hash == u64::MAX) {
+        return None;
+    }
+    Some(hashes.iter().map(|hash| format!("{hash:016x}")).collect())
+}
+
+fn parse(phash: &str) -> Option<Vec<u64>> {
+    (phash.len() == 16 * SAMPLES.len()).then_some(())?;
+    (0..SAMPLES.len()).map(|index| u64::from_str_radix(phash.get(index * 16..(index + 1) * 16)?, 16).ok()).collect()
+}
+
+fn distance(a: &[u64], b: &[u64]) -> u32 {
+    a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum()
+}
+
+/// Samples frames of `video` with a single ffmpeg run, seeking each input
+/// separately so only a handful of frames get decoded. [`UNHASHABLE`] when
+/// there's nothing to sample, `None` when ffmpeg failed.
+async fn compute(app: &App, video: &Video) -> Option<String> {
+    let (Some(duration), Some(_)) = (video.duration.filter(|&duration| duration > 0.0), video.width) else {
+        return Some(UNHASHABLE.into());
+    };
+    let path = library::file(&app.config, &*video.path)?;
+    let path = path.to_str()?;
+
+    let mut command = Command::new(&*app.config.ffmpeg_command);
+    command.args(["-v", "error"]);
+    for sample in SAMPLES {
+        command.args(["-ss", &format!("{:.3}", duration * sample), "-i", path]);
+    }
+    let scaled: String = (0..SAMPLES.len())
+        .map(|index| format!("[{index}:v:0]scale=9:8:flags=area,format=gray[f{index}];"))
+        .collect();
+    let stacked: String = (0..SAMPLES.len()).map(|index| format!("[f{index}]")).collect();
+    command.args([
+        "-filter_complex", &format!("{scaled}{stacked}vstack=inputs={}", SAMPLES.len()),
+        "-frames:v", "1",
+        "-f", "rawvideo",
+        "-"
+    ]);
+
+    match app.ffmpeg.output(&mut command).await {
+        Ok(pixels) => Some(fingerprint(&pixels).unwrap_or_else(|| UNHASHABLE.into())),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to sample frames of `{}`", video.path);
+            None
+        }
+    }
+}
+
+/// Computes and stores the perceptual hashes of a video, when `scan_phashes`
+/// is on.
+pub async fn hash(app: &App, video: &Video) {
+    if !app.config.scan_phashes {
+        return;
+    }
+    let Some(phash) = compute(app, video).await else {
+        return;
+    };
+    if let Err(err) = app.index.set_phash(&video.path, video.size, video.mtime, &phash) {
+        tracing::error!(error = %err, "Failed to store perceptual hash of `{}`", video.path);
+    }
+}
+
+/// Groups videos whose sampled frames look alike and whose durations match
+/// within a second or a percent, whichever is larger. Returns indices into
+/// `videos`, which must be sorted by duration.
+fn clusters(videos: &[(f64, Vec<u64>)]) -> Vec<Vec<usize>> {
+    fn find(parents: &mut [usize], index: usize) -> usize {
+        let mut root = index;
+        while parents[root] != root {
+            root = parents[root];
+        }
+        parents[index] = root;
+        root
+    }
+
+    let mut parents: Vec<usize> = (0..videos.len()).collect();
+    for (index, (duration, hashes)) in videos.iter().enumerate() {
+        let tolerance = (duration / 100.0).max(1.0);
+        for (other, (other_duration, other_hashes)) in videos.iter().enumerate().skip(index + 1) {
+            if other_duration - duration > tolerance {
+                break;
+            }
+            if distance(hashes, other_hashes) <= MAX_DISTANCE {
+                let (a, b) = (find(&mut parents, index), find(&mut parents, other));
+                parents[b] = a;
+            }
+        }
+    }
+
+    let mut clusters: Vec<Vec<usize>> = vec![Vec::new(); videos.len()];
+    for index in 0..videos.len() {
+        let root = find(&mut parents, index);
+        clusters[root].push(index);
+    }
+    clusters.retain(|cluster| cluster.len() > 1);
+    clusters
+}
+
+#[derive(serde::Serialize)]
+pub struct Cluster {
+    /// Bytes freed by keeping only the first video.
+    reclaimable: u64,
+    /// Best quality first, by height and then size.
+    videos: Vec<Video>
+}
+
+/// Lists groups of videos that are likely the same content at different
+/// qualities or in different containers, the largest savings first. Only
+/// videos hashed by `scan_phashes` are considered.
+pub async fn serve_duplicates(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if !auth::is_admin(&app.config, user.as_deref()) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+
+    let videos = match app.index.all() {
+        Ok(videos) => videos,
+        Err(err) => return ApiError::database("Failed to find duplicates", err).into()
+    };
+
+    let mut videos: Vec<_> = videos.into_iter()
+        .filter(|video| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, &video.path)))
+        .filter_map(|video| {
+            let hashes = parse(video.phash.as_deref()?)?;
+            Some((video.duration?, hashes, video))
+        })
+        .collect();
+    videos.sort_by(|(a, ..), (b, ..)| a.total_cmp(b));
+
+    let hashes: Vec<_> = videos.iter().map(|(duration, hashes, _)| (*duration, hashes.clone())).collect();
+    let mut videos: Vec<_> = videos.into_iter().map(|(.., video)| Some(video)).collect();
+    let mut clusters: Vec<Cluster> = clusters(&hashes).into_iter().map(|cluster| {
+        let mut videos: Vec<Video> = cluster.into_iter().filter_map(|index| videos[index].take()).collect();
+        videos.sort_by_key(|video| std::cmp::Reverse((video.height.unwrap_or(0), video.size)));
+        Cluster {
+            reclaimable: videos.iter().skip(1).map(|video| video.size).sum(),
+            videos
+        }
+    }).collect();
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.reclaimable));
+    response::IntoResponse::into_response(Json(clusters))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(seed: usize, brightness: u8) -> Vec<u8> {
+        (0..72 * SAMPLES.len()).map(|index| ((index * 7 + seed) % 17 * 10) as u8 + brightness).collect()
+    }
+
+    #[test]
+    fn fingerprints() {
+        let phash = fingerprint(&frames(0, 0)).unwrap();
+        assert_eq!(phash.len(), 64);
+        // Brightening the whole frame doesn't change which pixel is brighter
+        assert_eq!(fingerprint(&frames(0, 40)).unwrap(), phash);
+        assert!(distance(&parse(&phash).unwrap(), &parse(&fingerprint(&frames(5, 0)).unwrap()).unwrap()) > MAX_DISTANCE);
+        assert_eq!(fingerprint(&[0; 72 * SAMPLES.len()]), None);
+        assert_eq!(fingerprint(&[0; 72]), None);
+        assert_eq!(parse("abc"), None);
+        assert_eq!(parse(UNHASHABLE), None);
+    }
+
+    #[test]
+    fn clustering() {
+        let a = parse(&fingerprint(&frames(0, 0)).unwrap()).unwrap();
+        let b = parse(&fingerprint(&frames(5, 0)).unwrap()).unwrap();
+        let mut close = a.clone();
+        close[0] ^= 0b1011;
+        let videos = [
+            (600.0, a.clone()),
+            (600.5, b.clone()),
+            (601.0, close),
+            (620.0, a),
+            (3600.0, b.clone()),
+            (3630.0, b)
+        ];
+        assert_eq!(clusters(&videos), vec![vec![0, 2], vec![4, 5]]);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::Config;
+
+/// Prefix of the variables overriding configuration settings, like
+/// `NINJA_PORT=8080`. Nested settings are separated by a double underscore, as
+/// in `NINJA_RATE_LIMIT__BURST=20`.
+const PREFIX: &str = "NINJA_";
+
+/// Values are read as TOML, so that `NINJA_H2C=true` is a boolean and
+/// `NINJA_API_KEYS='["secret"]'` a list, and anything else is a string.
+fn value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| raw.into())
+}
+
+fn set(table: &mut toml::Table, key: &str, value: toml::Value) {
+    match key.split_once("__") {
+        Some((name, rest)) => {
+            let entry = table.entry(name).or_insert_with(|| toml::Table::new().into());
+            if let Some(inner) = entry.as_table_mut() {
+                set(inner, rest, value);
+            }
+        }
+        None => {
+            table.insert(key.into(), value);
+        }
+    }
+}
+
+fn apply(table: &mut toml::Table, vars: impl Iterator<Item = (String, String)>) {
+    for (name, raw) in vars {
+        if let Some(key) = name.strip_prefix(PREFIX) {
+            set(table, &key.to_lowercase(), value(&raw));
+        }
+    }
+}
+
+/// Parses the configuration file, with the `NINJA_` environment variables
+/// layered over it.
+pub fn parse(config_str: &str) -> Result<Config, String> {
+    let mut table: toml::Table = toml::from_str(config_str).map_err(|err| err.to_string())?;
+    let vars = std::env::vars_os().filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    apply(&mut table, vars);
+    table.try_into().map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(config_str: &str, vars: &[(&str, &str)]) -> toml::Table {
+        let mut table = toml::from_str(config_str).unwrap();
+        apply(&mut table, vars.iter().map(|(name, value)| (name.to_string(), value.to_string())));
+        table
+    }
+
+    #[test]
+    fn overrides_file() {
+        let table = applied("port = 3000\nh2c = false", &[("NINJA_PORT", "8080"), ("NINJA_H2C", "true")]);
+        assert_eq!(table["port"], toml::Value::Integer(8080));
+        assert_eq!(table["h2c"], toml::Value::Boolean(true));
+    }
+
+    #[test]
+    fn unquoted_strings() {
+        let table = applied("", &[("NINJA_FFMPEG_COMMAND", "/usr/bin/ffmpeg"), ("NINJA_LISTEN", "[::]:3000")]);
+        assert_eq!(table["ffmpeg_command"], toml::Value::from("/usr/bin/ffmpeg"));
+        assert_eq!(table["listen"], toml::Value::from("[::]:3000"));
+    }
+
+    #[test]
+    fn lists() {
+        let table = applied("", &[("NINJA_API_KEYS", r#"["a", "b"]"#)]);
+        assert_eq!(table["api_keys"], toml::Value::from(vec!["a", "b"]));
+    }
+
+    #[test]
+    fn nested() {
+        let table = applied("[rate_limit]\nburst = 100", &[("NINJA_RATE_LIMIT__REQUESTS_PER_SECOND", "5")]);
+        assert_eq!(table["rate_limit"]["burst"], toml::Value::Integer(100));
+        assert_eq!(table["rate_limit"]["requests_per_second"], toml::Value::Integer(5));
+    }
+
+    #[test]
+    fn ignores_other_variables() {
+        assert!(applied("", &[("PATH", "/usr/bin"), ("NINJAPORT", "1")]).is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+use std::borrow::Cow;
+
+use axum::{body, extract, http, middleware, response, Json};
+
+use crate::trace;
+
+/// Why a request failed, for clients to branch on. Sent in
+/// `SCREAMING_SNAKE_CASE`, like `VIDEO_NOT_FOUND`, and never renamed.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Code {
+    BadRequest,
+    InvalidRange,
+    InvalidFileName,
+    Unauthorized,
+    Forbidden,
+    UploadsDisabled,
+    /// The library is marked `read_only`.
+    LibraryReadOnly,
+    /// An administrator ended the stream, which can't be resumed.
+    StreamEnded,
+    NotFound,
+    VideoNotFound,
+    DirectoryNotFound,
+    LibraryNotFound,
+    FeedNotFound,
+    ProfileNotFound,
+    RenditionNotFound,
+    SegmentNotFound,
+    SubtitlesNotFound,
+    AudioNotFound,
+    ArtworkNotFound,
+    ThumbnailNotFound,
+    StoryboardNotFound,
+    CollectionNotFound,
+    PlaylistNotFound,
+    PlaylistItemNotFound,
+    ProgressNotFound,
+    FavoriteNotFound,
+    JobNotFound,
+    SessionNotFound,
+    UploadNotFound,
+    TrashEntryNotFound,
+    CastDeviceNotFound,
+    StreamNotFound,
+    StreamOffline,
+    MethodNotAllowed,
+    /// A file or a name is taken.
+    AlreadyExists,
+    Conflict,
+    JobNotCompleted,
+    NothingPlaying,
+    /// Another request is appending to the upload.
+    UploadBusy,
+    OffsetMismatch,
+    UnsupportedVersion,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    FileTypeNotAllowed,
+    RangeNotSatisfiable,
+    UnprocessableEntity,
+    /// The subtitles are in a format ffmpeg can't turn into WebVTT, like
+    /// bitmaps.
+    SubtitlesUnconvertible,
+    RateLimited,
+    TooManyStreams,
+    InternalError,
+    /// ffmpeg failed on the video.
+    ProcessingFailed,
+    /// A remote server or a cast device didn't answer as it should.
+    UpstreamFailed,
+    /// Every ffmpeg slot is taken, retry after `Retry-After`.
+    Busy,
+    StreamStalled,
+    ProcessingTimedOut
+}
+
+impl Code {
+    pub fn status(self) -> http::StatusCode {
+        use http::StatusCode;
+
+        match self {
+            Code::BadRequest | Code::InvalidRange | Code::InvalidFileName => StatusCode::BAD_REQUEST,
+            Code::Unauthorized => StatusCode::UNAUTHORIZED,
+            Code::Forbidden | Code::UploadsDisabled | Code::LibraryReadOnly | Code::StreamEnded => StatusCode::FORBIDDEN,
+            Code::NotFound | Code::VideoNotFound | Code::DirectoryNotFound | Code::LibraryNotFound | Code::FeedNotFound
+                | Code::ProfileNotFound | Code::RenditionNotFound | Code::SegmentNotFound | Code::SubtitlesNotFound
+                | Code::AudioNotFound | Code::ArtworkNotFound | Code::ThumbnailNotFound | Code::StoryboardNotFound
+                | Code::CollectionNotFound | Code::PlaylistNotFound | Code::PlaylistItemNotFound | Code::ProgressNotFound
+                | Code::FavoriteNotFound | Code::JobNotFound | Code::SessionNotFound | Code::UploadNotFound
+                | Code::TrashEntryNotFound | Code::CastDeviceNotFound | Code::StreamNotFound
+                | Code::StreamOffline => StatusCode::NOT_FOUND,
+            Code::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Code::AlreadyExists | Code::Conflict | Code::JobNotCompleted | Code::NothingPlaying | Code::UploadBusy
+                | Code::OffsetMismatch => StatusCode::CONFLICT,
+            Code::UnsupportedVersion => StatusCode::PRECONDITION_FAILED,
+            Code::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Code::UnsupportedMediaType | Code::FileTypeNotAllowed => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Code::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            Code::UnprocessableEntity | Code::SubtitlesUnconvertible => StatusCode::UNPROCESSABLE_ENTITY,
+            Code::RateLimited | Code::TooManyStreams => StatusCode::TOO_MANY_REQUESTS,
+            Code::InternalError | Code::ProcessingFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            Code::UpstreamFailed => StatusCode::BAD_GATEWAY,
+            Code::Busy | Code::StreamStalled => StatusCode::SERVICE_UNAVAILABLE,
+            Code::ProcessingTimedOut => StatusCode::GATEWAY_TIMEOUT
+        }
+    }
+
+    /// The code for errors axum answers with by itself, which only carry a
+    /// status.
+    fn generic(status: http::StatusCode) -> Option<Self> {
+        [Code::BadRequest, Code::NotFound, Code::MethodNotAllowed, Code::PayloadTooLarge, Code::UnsupportedMediaType, Code::UnprocessableEntity, Code::InternalError]
+            .into_iter()
+            .find(|code| code.status() == status)
+    }
+}
+
+/// A failed request, answered with `{"error": {"code": ..., "message": ...}}`
+/// and the ID of the request. The message is meant for people and may change.
+#[derive(Debug, serde::Serialize)]
+pub struct ApiError {
+    code: Code,
+    message: Cow<'static, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<Box<str>>,
+    /// Fields specific to the code, next to it.
+    #[serde(flatten)]
+    details: serde_json::Map<String, serde_json::Value>
+}
+
+impl ApiError {
+    pub fn new(code: Code, message: impl Into<Cow<'static, str>>) -> Self {
+        ApiError { code, message: message.into(), request_id: None, details: serde_json::Map::new() }
+    }
+
+    /// A store failed, which is logged with `context` and answered with it
+    /// alone, as the error may mention paths or queries.
+    pub fn database(context: &'static str, err: rusqlite::Error) -> Self {
+        tracing::error!(error = %err, "{context}");
+        ApiError::new(Code::InternalError, context)
+    }
+
+    pub fn detail(mut self, name: &str, value: impl serde::Serialize) -> Self {
+        self.details.insert(name.into(), serde_json::to_value(value).unwrap());
+        self
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Envelope<'a> {
+    error: &'a ApiError
+}
+
+impl response::IntoResponse for ApiError {
+    fn into_response(mut self) -> response::Response {
+        self.request_id = trace::current().map(|id| (*id).into());
+        let mut response = response::IntoResponse::into_response(Json(Envelope { error: &self }));
+        *response.status_mut() = self.code.status();
+        response
+    }
+}
+
+impl From<ApiError> for response::Response {
+    fn from(error: ApiError) -> Self {
+        response::IntoResponse::into_response(error)
+    }
+}
+
+/// Rejections of malformed requests are plain text.
+const MAX_REJECTION: usize = 4096;
+
+/// Puts the errors axum answers with by itself, like unparsable query strings
+/// or unknown routes, in the same body as the others.
+pub async fn wrap_rejections(request: extract::Request, next: middleware::Next) -> response::Response {
+    let response = next.run(request).await;
+    let status = response.status();
+    let plain = response.headers().get(http::header::CONTENT_TYPE).is_none_or(|content_type| content_type.as_bytes().starts_with(b"text/plain"));
+    let Some(code) = Code::generic(status).filter(|_| plain) else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let message = match body::to_bytes(body, MAX_REJECTION).await {
+        Ok(message) if !message.is_empty() => String::from_utf8_lossy(&message).into_owned(),
+        _ => status.canonical_reason().unwrap_or_default().to_owned()
+    };
+    let (wrapped, body) = response::IntoResponse::into_response(ApiError::new(code, message)).into_parts();
+    // Keeps headers like `Allow`
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    parts.headers.extend(wrapped.headers);
+    response::Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn envelope() {
+        let response = response::IntoResponse::into_response(ApiError::new(Code::VideoNotFound, "Video not found"));
+        assert_eq!(response.status(), http::StatusCode::NOT_FOUND);
+        let body = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&*body, br#"{"error":{"code":"VIDEO_NOT_FOUND","message":"Video not found"}}"#);
+    }
+
+    #[tokio::test]
+    async fn request_id() {
+        let response = trace::REQUEST_ID.scope("report-42".into(), async {
+            response::IntoResponse::into_response(ApiError::new(Code::Busy, "Server is busy").detail("retry", 5))
+        }).await;
+        let body = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&*body, br#"{"error":{"code":"BUSY","message":"Server is busy","request_id":"report-42","retry":5}}"#);
+    }
+
+    #[test]
+    fn generic_codes() {
+        assert_eq!(Code::generic(http::StatusCode::METHOD_NOT_ALLOWED), Some(Code::MethodNotAllowed));
+        assert_eq!(Code::generic(http::StatusCode::NOT_FOUND), Some(Code::NotFound));
+        assert_eq!(Code::generic(http::StatusCode::IM_A_TEAPOT), None);
+    }
+}
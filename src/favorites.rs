@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use axum::{extract, http, response, Json};
+use rusqlite::{params, Connection};
+
+use crate::error::{ApiError, Code};
+use crate::users::{Owner, User};
+use crate::{auth, jail, unix_now, App};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS favorites (
+        owner TEXT NOT NULL,
+        video TEXT NOT NULL,
+        added INTEGER NOT NULL,
+        PRIMARY KEY (owner, video)
+    );
+";
+
+/// The videos each user, or device, put on their list.
+pub struct Favorites {
+    conn: Mutex<Connection>
+}
+
+impl Favorites {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Favorites { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// The favorites of `owner`, most recently added first.
+    pub fn list(&self, owner: &str) -> rusqlite::Result<Vec<Box<str>>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached("SELECT video FROM favorites WHERE owner = ? ORDER BY added DESC, video")?;
+        let videos = stmt.query_map([owner], |row| Ok(row.get::<_, String>(0)?.into()))?.collect();
+        videos
+    }
+
+    /// The favorites of `owner`, for marking them in listings.
+    pub fn set(&self, owner: &str) -> rusqlite::Result<HashSet<Box<str>>> {
+        Ok(self.list(owner)?.into_iter().collect())
+    }
+
+    pub fn add(&self, owner: &str, video: &str) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO favorites (owner, video, added) VALUES (?, ?, ?)",
+            params![owner, video, unix_now()]
+        )?;
+        Ok(())
+    }
+
+    /// Keeps a video on everyone's list after it was moved.
+    pub fn move_video(&self, from: &str, to: &str) -> rusqlite::Result<()> {
+        self.conn().execute("UPDATE OR REPLACE favorites SET video = ? WHERE video = ?", [to, from])?;
+        Ok(())
+    }
+
+    /// Takes `video` off the list of `owner`, returning whether it was on it.
+    pub fn remove(&self, owner: &str, video: &str) -> rusqlite::Result<bool> {
+        Ok(self.conn().execute("DELETE FROM favorites WHERE owner = ? AND video = ?", [owner, video])? > 0)
+    }
+}
+
+fn no_content() -> response::Response {
+    response::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
+
+/// The favorite videos that are still in the index and that the user can
+/// access.
+pub async fn list_favorites(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Owner(owner): Owner
+) -> response::Response {
+    let favorites = match app.favorites.list(&owner) {
+        Ok(favorites) => favorites,
+        Err(err) => return ApiError::database("Failed to access favorites", err).into()
+    };
+
+    let mut videos = Vec::new();
+    for video in favorites.iter().filter(|video| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, video))) {
+        match app.index.get(video) {
+            Ok(Some(video)) => videos.push(video),
+            Ok(None) => {}
+            Err(err) => return ApiError::database("Failed to access favorites", err).into()
+        }
+    }
+    response::IntoResponse::into_response(Json(videos))
+}
+
+pub async fn add_favorite(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    Owner(owner): Owner
+) -> response::Response {
+    let video = video.trim_matches('/');
+    if let Err(err) = jail::video(&app.config, video).await {
+        return err.into_response(Code::VideoNotFound, "Video not found");
+    }
+
+    match app.favorites.add(&owner, video) {
+        Ok(()) => no_content(),
+        Err(err) => ApiError::database("Failed to access favorites", err).into()
+    }
+}
+
+pub async fn remove_favorite(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    Owner(owner): Owner
+) -> response::Response {
+    match app.favorites.remove(&owner, video.trim_matches('/')) {
+        Ok(true) => no_content(),
+        Ok(false) => ApiError::new(Code::FavoriteNotFound, "Video isn't a favorite").into(),
+        Err(err) => ApiError::database("Failed to access favorites", err).into()
+    }
+}
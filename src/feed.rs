@@ -0,0 +1,193 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use axum::{extract, http, response};
+
+use crate::auth::{self, TokenQuery};
+use crate::error::{ApiError, Code};
+use crate::index::Video;
+use crate::users::User;
+use crate::{jail, mime, url, App};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(serde::Deserialize)]
+pub struct FeedQuery {
+    /// Only videos with this container tag, like a genre.
+    tag: Option<Box<str>>,
+    /// Only videos in this collection.
+    collection: Option<i64>,
+    limit: Option<usize>
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            char => escaped.push(char)
+        }
+    }
+    escaped
+}
+
+fn is_under(path: &str, dir: &str) -> bool {
+    dir.is_empty() || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn has_tag(video: &Video, tag: &str) -> bool {
+    video.tags.as_deref().is_some_and(|tags| tags.split('\n').any(|value| value.eq_ignore_ascii_case(tag)))
+}
+
+/// Writes the `<item>` of `video`. URLs carry the `token` the feed was
+/// fetched with, podcast apps have no other way to authenticate.
+fn write_item(body: &mut String, origin: &str, token: &TokenQuery, video: &Video) {
+    let path = url::encode_path(&video.path);
+    let query = token.carry(String::new());
+    let published = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(video.mtime));
+    let content_type = mime::from_extension(Path::new(&*video.filename)).unwrap_or("application/octet-stream");
+    let image = if video.poster.is_some() || video.tmdb.is_some() { "artwork/poster" } else { "thumb" };
+
+    body.push_str("<item>\n");
+    writeln!(body, "<title>{}</title>", escape(video.display_title())).unwrap();
+    writeln!(body, "<guid isPermaLink=\"false\">{}</guid>", escape(&video.path)).unwrap();
+    writeln!(body, "<pubDate>{published}</pubDate>").unwrap();
+    writeln!(
+        body,
+        "<enclosure url=\"{}\" length=\"{}\" type=\"{content_type}\"/>",
+        escape(&format!("{origin}/video/{path}{query}")), video.size
+    ).unwrap();
+    if let Some(duration) = video.duration {
+        writeln!(body, "<itunes:duration>{}</itunes:duration>", duration.round() as u64).unwrap();
+    }
+    if let Some(plot) = &video.plot {
+        writeln!(body, "<description>{}</description>", escape(plot)).unwrap();
+    }
+    writeln!(body, "<itunes:image href=\"{}\"/>", escape(&format!("{origin}/{image}/{path}{query}"))).unwrap();
+    body.push_str("</item>\n");
+}
+
+async fn feed(
+    app: &App,
+    user: Option<&User>,
+    dir: &str,
+    query: &FeedQuery,
+    token: &TokenQuery,
+    origin: &str
+) -> response::Response {
+    let dir = dir.trim_matches('/');
+    if !dir.is_empty() {
+        let path = match jail::directory(&app.config, dir).await {
+            Ok(path) => path,
+            Err(err) => return err.into_response(Code::FeedNotFound, "Feed not found")
+        };
+        if !tokio::fs::metadata(path).await.is_ok_and(|metadata| metadata.is_dir()) {
+            return ApiError::new(Code::FeedNotFound, "Feed not found").into();
+        }
+        if user.is_some_and(|user| !auth::can_access(&app.config, user, dir)) {
+            return ApiError::new(Code::Forbidden, "Forbidden").into();
+        }
+    }
+
+    let collection = match query.collection {
+        Some(id) => match app.collections.name(id).and_then(|name| Ok((name, app.collections.items(id)?))) {
+            Ok((Some(name), items)) => Some((name, items)),
+            Ok((None, _)) => return ApiError::new(Code::CollectionNotFound, "Collection not found").into(),
+            Err(err) => return ApiError::database("Failed to build feed", err).into()
+        },
+        None => None
+    };
+
+    let mut videos: Vec<Video> = match app.index.all() {
+        Ok(videos) => videos.into_iter()
+            .filter(|video| is_under(&video.path, dir))
+            .filter(|video| user.is_none_or(|user| auth::can_access(&app.config, user, &video.path)))
+            .filter(|video| query.tag.as_deref().is_none_or(|tag| has_tag(video, tag)))
+            .filter(|video| collection.as_ref().is_none_or(|(_, items)| items.contains(&video.path)))
+            .collect(),
+        Err(err) => return ApiError::database("Failed to build feed", err).into()
+    };
+    // Newest first, the way podcast apps expect episodes
+    videos.sort_by_key(|video| std::cmp::Reverse(video.mtime));
+    videos.truncate(query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT));
+
+    let mut title = collection.map(|(name, _)| name)
+        .or_else(|| dir.rsplit('/').next().filter(|name| !name.is_empty()).map(Into::into))
+        .unwrap_or_else(|| "ninja".into())
+        .into_string();
+    if let Some(tag) = &query.tag {
+        write!(title, " - {tag}").unwrap();
+    }
+
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    body.push_str("<rss version=\"2.0\" xmlns:itunes=\"http://www.itunes.com/dtds/podcast-1.0.dtd\">\n<channel>\n");
+    writeln!(body, "<title>{}</title>", escape(&title)).unwrap();
+    writeln!(body, "<link>{}/</link>", escape(origin)).unwrap();
+    writeln!(body, "<description>Videos in {}</description>", escape(&title)).unwrap();
+    if let Some(latest) = videos.first() {
+        writeln!(body, "<lastBuildDate>{}</lastBuildDate>", httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(latest.mtime))).unwrap();
+    }
+    for video in &videos {
+        write_item(&mut body, origin, token, video);
+    }
+    body.push_str("</channel>\n</rss>\n");
+
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")
+        .body(body.into())
+        .unwrap()
+}
+
+/// A podcast feed of every video the user can see.
+pub async fn serve_root_feed(
+    extract::Query(query): extract::Query<FeedQuery>,
+    extract::Query(token): extract::Query<TokenQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    request: http::request::Parts
+) -> response::Response {
+    let origin = url::absolute(&app.config, &request, "");
+    feed(app, user.as_deref(), "", &query, &token, &origin).await
+}
+
+/// A podcast feed of the videos in a library or folder, named like
+/// `lectures.xml` or `lectures/2024.xml`, with enclosures pointing at
+/// `/video`.
+pub async fn serve_feed(
+    extract::Path((name, )): extract::Path<(Box<str>, )>,
+    extract::Query(query): extract::Query<FeedQuery>,
+    extract::Query(token): extract::Query<TokenQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    request: http::request::Parts
+) -> response::Response {
+    let Some(dir) = name.strip_suffix(".xml") else {
+        return ApiError::new(Code::FeedNotFound, "Feed not found").into();
+    };
+    let origin = url::absolute(&app.config, &request, "");
+    feed(app, user.as_deref(), dir, &query, &token, &origin).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape(r#"Tom & Jerry's <"Best">"#), "Tom &amp; Jerry&apos;s &lt;&quot;Best&quot;&gt;");
+    }
+
+    #[test]
+    fn nesting() {
+        assert!(is_under("lectures/2024/intro.mp4", "lectures"));
+        assert!(is_under("lectures/intro.mp4", ""));
+        assert!(!is_under("lectures-old/intro.mp4", "lectures"));
+    }
+}
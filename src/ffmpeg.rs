@@ -0,0 +1,388 @@
+use std::fmt;
+use std::ops;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{body::Body, http, response};
+use futures_util::{stream, StreamExt};
+use tokio::io::{self, AsyncReadExt};
+use tokio::process::{Child, Command};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tokio_util::io::ReaderStream;
+
+use crate::{remote, Config};
+use crate::error::{ApiError, Code};
+
+pub enum Error {
+    /// Every ffmpeg slot stayed taken for the whole queue timeout.
+    Busy,
+    Spawn(io::Error),
+    Failed(Box<str>),
+    /// ffmpeg ran past `ffmpeg_timeout` and was killed.
+    Timeout
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Busy => write!(f, "too many ffmpeg processes running"),
+            Error::Spawn(err) => write!(f, "failed to spawn ffmpeg: {err}"),
+            Error::Failed(stderr) => write!(f, "{}", remote::redact(stderr)),
+            Error::Timeout => write!(f, "ffmpeg timed out")
+        }
+    }
+}
+
+// Coalesced requests all get a copy of the same error
+impl Clone for Error {
+    fn clone(&self) -> Self {
+        match self {
+            Error::Busy => Error::Busy,
+            Error::Spawn(err) => Error::Spawn(io::Error::new(err.kind(), err.to_string())),
+            Error::Failed(stderr) => Error::Failed(stderr.clone()),
+            Error::Timeout => Error::Timeout
+        }
+    }
+}
+
+impl Error {
+    /// A 503 asking the client to retry when the server is saturated, a 504
+    /// when ffmpeg timed out, a 500 with `message` otherwise.
+    pub fn into_response(self, message: &'static str) -> response::Response {
+        match self {
+            Error::Busy => response::IntoResponse::into_response(([(http::header::RETRY_AFTER, "5")], ApiError::new(Code::Busy, "Server is busy"))),
+            Error::Timeout => ApiError::new(Code::ProcessingTimedOut, "Processing timed out").into(),
+            _ => ApiError::new(Code::ProcessingFailed, message).into()
+        }
+    }
+}
+
+/// A spawned ffmpeg, killed along with anything it spawned once dropped.
+pub struct Process(Child);
+
+impl ops::Deref for Process {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.0
+    }
+}
+
+impl ops::DerefMut for Process {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.0
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // The id is gone once the process has been waited on, so a group id
+        // that got reused can't be hit by accident
+        #[cfg(unix)]
+        if let Some(pid) = self.0.id() {
+            unsafe { libc::kill(-(pid as i32), libc::SIGKILL); }
+        }
+        let _ = self.0.start_kill();
+    }
+}
+
+/// Spawns `command` in its own process group, so that killing it takes down
+/// the whole tree.
+pub fn spawn(command: &mut Command) -> io::Result<Process> {
+    #[cfg(unix)]
+    command.process_group(0);
+    command.kill_on_drop(true).spawn().map(Process)
+}
+
+/// Runs ffmpeg processes, at most `max_ffmpeg_jobs` at a time. Requests
+/// beyond the limit wait in line for up to `ffmpeg_queue_timeout` seconds.
+pub struct Ffmpeg {
+    permits: Arc<Semaphore>,
+    queue_timeout: Duration,
+    pub timeout: Duration
+}
+
+impl Ffmpeg {
+    pub fn new(config: &Config) -> Self {
+        Ffmpeg {
+            permits: Arc::new(Semaphore::new(config.max_ffmpeg_jobs)),
+            queue_timeout: Duration::from_secs(config.ffmpeg_queue_timeout),
+            timeout: Duration::from_secs(config.ffmpeg_timeout)
+        }
+    }
+
+    async fn permit(&self) -> Result<OwnedSemaphorePermit, Error> {
+        match time::timeout(self.queue_timeout, self.permits.clone().acquire_owned()).await {
+            Ok(permit) => Ok(permit.unwrap()),
+            Err(_) => Err(Error::Busy)
+        }
+    }
+
+    /// Waits as long as it takes for a slot, for background work that isn't
+    /// tied to a request.
+    pub async fn wait(&self) -> OwnedSemaphorePermit {
+        self.permits.clone().acquire_owned().await.unwrap()
+    }
+
+    /// Spawns `command` and streams its stdout as a response body. The child
+    /// is killed as soon as the body is dropped, e.g. when the client
+    /// disconnects, or when it goes `ffmpeg_timeout` seconds without output.
+    pub async fn stream(&self, command: &mut Command) -> Result<Body, Error> {
+        let permit = self.permit().await?;
+        let mut process = spawn(command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+        ).map_err(Error::Spawn)?;
+
+        let chunks = ReaderStream::new(process.stdout.take().unwrap());
+        let timeout = self.timeout;
+        let stream = stream::unfold((chunks, process, permit), move |(mut chunks, process, permit)| async move {
+            let chunk = match time::timeout(timeout, chunks.next()).await {
+                Ok(chunk) => chunk?,
+                Err(_) => {
+                    tracing::warn!("ffmpeg stalled for {}s, killing it", timeout.as_secs());
+                    Err(io::Error::new(io::ErrorKind::TimedOut, Error::Timeout.to_string()))
+                }
+            };
+            Some((chunk, (chunks, process, permit)))
+        });
+
+        Ok(Body::from_stream(stream))
+    }
+
+    /// Runs `command` to completion and returns its stdout. A non-zero exit is
+    /// reported as an error carrying ffmpeg's stderr, and a run longer than
+    /// `ffmpeg_timeout` seconds is killed.
+    pub async fn output(&self, command: &mut Command) -> Result<Vec<u8>, Error> {
+        let _permit = self.permit().await?;
+        let mut process = spawn(command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+        ).map_err(Error::Spawn)?;
+
+        let mut stdout = process.stdout.take().unwrap();
+        let mut stderr = process.stderr.take().unwrap();
+        let run = async {
+            let (mut output, mut message) = (Vec::new(), Vec::new());
+            tokio::try_join!(stdout.read_to_end(&mut output), stderr.read_to_end(&mut message))?;
+            let status = process.wait().await?;
+            io::Result::Ok((status, output, message))
+        };
+
+        let (status, output, message) = match time::timeout(self.timeout, run).await {
+            Ok(result) => result.map_err(Error::Spawn)?,
+            Err(_) => return Err(Error::Timeout)
+        };
+
+        if status.success() {
+            Ok(output)
+        } else {
+            Err(Error::Failed(String::from_utf8_lossy(&message).trim().into()))
+        }
+    }
+
+    /// Runs `command` to completion, handing its stdout to `each` as it comes
+    /// in rather than keeping it, for output too large to hold. Fails like
+    /// [`Ffmpeg::output`] on a non-zero exit, and when ffmpeg goes
+    /// `ffmpeg_timeout` seconds without output like [`Ffmpeg::stream`].
+    pub async fn fold(&self, command: &mut Command, mut each: impl FnMut(&[u8])) -> Result<(), Error> {
+        let _permit = self.permit().await?;
+        let mut process = spawn(command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+        ).map_err(Error::Spawn)?;
+
+        let mut stdout = process.stdout.take().unwrap();
+        let mut stderr = process.stderr.take().unwrap();
+        let mut message = Vec::new();
+        let read = async {
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                match time::timeout(self.timeout, stdout.read(&mut buffer)).await {
+                    Ok(Ok(0)) => return Ok(()),
+                    Ok(Ok(length)) => each(&buffer[..length]),
+                    Ok(Err(err)) => return Err(Error::Spawn(err)),
+                    Err(_) => {
+                        tracing::warn!("ffmpeg stalled for {}s, killing it", self.timeout.as_secs());
+                        return Err(Error::Timeout);
+                    }
+                }
+            }
+        };
+        tokio::try_join!(read, async { stderr.read_to_end(&mut message).await.map_err(Error::Spawn) })?;
+
+        if process.wait().await.map_err(Error::Spawn)?.success() {
+            Ok(())
+        } else {
+            Err(Error::Failed(String::from_utf8_lossy(&message).trim().into()))
+        }
+    }
+}
+
+/// Escapes `value` for use as an option value inside a filtergraph, which
+/// takes one level of escaping for the option and another for the graph.
+pub fn escape_filter_value(value: &str) -> String {
+    let mut option = String::new();
+    for c in value.chars() {
+        if matches!(c, '\\' | '\'' | ':') {
+            option.push('\\');
+        }
+        option.push(c);
+    }
+
+    let mut graph = String::new();
+    for c in option.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            graph.push('\\');
+        }
+        graph.push(c);
+    }
+    graph
+}
+
+/// Raw ffmpeg arguments with `{input}`, `{start}` and `{output}` placeholders,
+/// for encoders and filters the configuration doesn't know about. The template
+/// is split like a shell would, quotes and backslashes included, but never run
+/// by one: placeholders are substituted inside the split arguments, so paths
+/// can't add arguments of their own.
+#[derive(Hash)]
+pub struct Template {
+    source: Box<str>,
+    args: Box<[Box<str>]>
+}
+
+const PLACEHOLDERS: &[&str] = &["input", "start", "output"];
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let args = split(source)?;
+        let mut used = Vec::new();
+        for arg in &args {
+            substitute(arg, |name| {
+                used.push(name.to_owned());
+                Some("")
+            })?;
+        }
+        for required in ["input", "output"] {
+            if !used.iter().any(|name| name == required) {
+                return Err(format!("ffmpeg template has no `{{{required}}}`"));
+            }
+        }
+
+        Ok(Template { source: source.into(), args: args.into_iter().map(Into::into).collect() })
+    }
+
+    /// The arguments for reading `input` from `start` seconds on and writing
+    /// to stdout.
+    pub fn render(&self, input: &str, start: &str) -> Vec<String> {
+        let value = |name: &str| match name {
+            "input" => Some(input),
+            "start" => Some(start),
+            "output" => Some("-"),
+            _ => None
+        };
+        // Placeholders were checked when parsing
+        self.args.iter().map(|arg| substitute(arg, value).unwrap()).collect()
+    }
+}
+
+impl serde::Serialize for Template {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Template {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = <Box<str> as serde::Deserialize>::deserialize(deserializer)?;
+        Template::parse(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Splits `source` on whitespace. Single quotes keep everything as is, double
+/// quotes and bare words take backslash escapes.
+fn split(source: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = source.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err("unterminated quote in ffmpeg template".into())
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => arg.push(chars.next().ok_or("unterminated quote in ffmpeg template")?),
+                        Some(c) => arg.push(c),
+                        None => return Err("unterminated quote in ffmpeg template".into())
+                    }
+                }
+            }
+            '\\' => arg.get_or_insert_with(String::new).push(chars.next().ok_or("trailing backslash in ffmpeg template")?),
+            c if c.is_whitespace() => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c)
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// Replaces `{name}` with `value(name)` in `arg`, with `{{` and `}}` standing
+/// for literal braces.
+fn substitute<'a>(arg: &str, mut value: impl FnMut(&str) -> Option<&'a str>) -> Result<String, String> {
+    let mut output = String::new();
+    let mut rest = arg;
+    while let Some(index) = rest.find(['{', '}']) {
+        output.push_str(&rest[..index]);
+        let brace = &rest[index..];
+        if brace.starts_with("{{") || brace.starts_with("}}") {
+            output.push_str(&brace[..1]);
+            rest = &brace[2..];
+            continue;
+        }
+
+        let name = brace.strip_prefix('{')
+            .and_then(|brace| brace.split_once('}'))
+            .map(|(name, _)| name)
+            .filter(|name| PLACEHOLDERS.contains(name))
+            .ok_or_else(|| format!("unknown placeholder in ffmpeg template argument `{arg}`"))?;
+        output.push_str(value(name).unwrap_or_default());
+        rest = &brace[name.len() + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates() {
+        let template = Template::parse(r#"-ss {start} -i {input} -vf "eq=gamma=1.2, hue=s=0" -metadata title='a "b"' a\ b {{x}} {output}"#).unwrap();
+        assert_eq!(template.render("my video; rm -rf.mp4", "12"), [
+            "-ss", "12", "-i", "my video; rm -rf.mp4", "-vf", "eq=gamma=1.2, hue=s=0", "-metadata", "title=a \"b\"", "a b", "{x}", "-"
+        ]);
+        assert_eq!(Template::parse("-i x={input}{start} {output}").unwrap().render("{output}", "1"), ["-i", "x={output}1", "-"]);
+
+        assert!(Template::parse("-i {input} {output} {nope}").is_err());
+        assert!(Template::parse("-i {input} -").is_err());
+        assert!(Template::parse("-i '{input} {output}").is_err());
+        assert!(Template::parse("-i {input} {output} {").is_err());
+    }
+}
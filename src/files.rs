@@ -0,0 +1,216 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{extract, http, response, Json};
+use tokio::fs;
+
+use crate::error::{ApiError, Code};
+use crate::users::User;
+use crate::{auth, jail, library, nfo, scanner, storyboard, subtitles, thumb, trash, upload, waveform, App};
+
+#[derive(serde::Deserialize)]
+pub struct MoveRequest {
+    /// The new path of the video, in the same library or another one.
+    to: Box<str>
+}
+
+fn no_content() -> response::Response {
+    response::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
+
+fn io_error(err: io::Error, path: &Path) -> response::Response {
+    tracing::error!(error = %err, "Failed to change `{}`", path.display());
+    ApiError::new(Code::InternalError, "Failed to change the file").into()
+}
+
+/// Renames `from` to `to`, copying across file systems, like between
+/// libraries on different disks. Copies are written under a hidden name first
+/// so that the scanner never sees half of one.
+pub async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    match fs::rename(from, to).await {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {}
+        renamed => return renamed
+    }
+
+    let name = to.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let temp = to.with_file_name(format!(".{name}.{}.tmp", COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let copied = async {
+        fs::copy(from, &temp).await?;
+        fs::rename(&temp, to).await
+    };
+    if let Err(err) = copied.await {
+        let _ = fs::remove_file(&temp).await;
+        return Err(err);
+    }
+    fs::remove_file(from).await
+}
+
+/// The subtitles, NFO and artwork next to `video` that belong to it alone,
+/// with what follows the name of the video in theirs.
+async fn sidecars(video: &Path) -> Vec<(PathBuf, String)> {
+    let (Some(dir), Some(stem)) = (video.parent(), video.file_stem().and_then(|stem| stem.to_str())) else {
+        return Vec::new();
+    };
+
+    subtitles::filenames(dir).await.into_iter()
+        .filter(|filename| subtitles::Sidecar::matching(stem, filename).is_some() || nfo::belongs_to(stem, filename))
+        .filter_map(|filename| Some((dir.join(&*filename), filename.get(stem.len()..)?.to_owned())))
+        .collect()
+}
+
+/// The thumbnail, storyboard and waveform generated for `relative`.
+fn cached(app: &App, relative: &str) -> [PathBuf; 4] {
+    let (sprite, vtt) = storyboard::cache_paths(app, relative);
+    [thumb::cache_path(app, relative), sprite, vtt, waveform::cache_path(app, relative)]
+}
+
+/// Renames or moves a video, along with its sidecars and cached thumbnails.
+/// Its place in the index, watch progress, favorites, collections and
+/// playlists follow it.
+pub async fn move_video(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Json(request): Json<MoveRequest>
+) -> response::Response {
+    if !auth::is_admin(&app.config, user.as_deref()) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+
+    let from = video.trim_matches('/');
+    if let Err(err) = jail::video(&app.config, from).await {
+        return err.into_response(Code::VideoNotFound, "Video not found");
+    }
+
+    let to = request.to.trim_matches('/');
+    if library::is_read_only(&app.config, from) || library::is_read_only(&app.config, to) {
+        return ApiError::new(Code::LibraryReadOnly, "Library is read-only").into();
+    }
+    let (dir, name) = to.rsplit_once('/').unwrap_or(("", to));
+    if upload::file_name(name) != Some(name) {
+        return ApiError::new(Code::InvalidFileName, "Invalid file name").into();
+    }
+    if !jail::is_allowed(&app.config, Path::new(name)) {
+        return ApiError::new(Code::FileTypeNotAllowed, "File type isn't allowed").into();
+    }
+    if user.as_ref().is_some_and(|user| !auth::can_access(&app.config, user, to)) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+    if let Err(err) = jail::directory(&app.config, dir).await {
+        return err.into_response(Code::DirectoryNotFound, "Directory not found");
+    }
+
+    // Symlinks are moved themselves, not what they point to
+    let (Some(source), Some(target), Some((root, _))) =
+        (library::file(&app.config, from), library::file(&app.config, to), library::locate(&app.config, Path::new(to))) else {
+        return ApiError::new(Code::DirectoryNotFound, "Directory not found").into();
+    };
+    if !fs::metadata(target.parent().unwrap_or(&target)).await.is_ok_and(|metadata| metadata.is_dir()) {
+        return ApiError::new(Code::DirectoryNotFound, "Directory not found").into();
+    }
+    if fs::symlink_metadata(&target).await.is_ok() {
+        return ApiError::new(Code::AlreadyExists, "File already exists").into();
+    }
+
+    let sidecars = sidecars(&source).await;
+    if let Err(err) = move_file(&source, &target).await {
+        return io_error(err, &source);
+    }
+    tracing::info!("Moved `{from}` to `{to}`");
+
+    let stem = target.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    for (sidecar, suffix) in sidecars {
+        let moved = target.with_file_name(format!("{stem}{suffix}"));
+        if fs::symlink_metadata(&moved).await.is_ok() {
+            tracing::warn!("Not moving `{}` over an existing file", sidecar.display());
+            continue;
+        }
+        if let Err(err) = move_file(&sidecar, &moved).await {
+            tracing::warn!(error = %err, "Failed to move `{}`", sidecar.display());
+        }
+    }
+
+    // Thumbnails are regenerated when missing, moving them only saves the
+    // work
+    for (cached, moved) in cached(app, from).into_iter().zip(cached(app, to)) {
+        if let Some(parent) = moved.parent() {
+            let _ = fs::create_dir_all(parent).await;
+        }
+        let _ = fs::rename(cached, moved).await;
+    }
+
+    let moved = app.index.move_video(from, to)
+        .and_then(|()| app.progress.move_video(from, to))
+        .and_then(|()| app.favorites.move_video(from, to))
+        .and_then(|()| app.collections.move_video(from, to))
+        .and_then(|()| app.playlists.move_video(from, to));
+    if let Err(err) = moved {
+        tracing::error!(error = %err, "Failed to update the database after moving `{from}` to `{to}`");
+    }
+    scanner::update(app, root, &target).await;
+
+    match app.index.get(to) {
+        Ok(Some(video)) => response::IntoResponse::into_response(Json(video)),
+        Ok(None) => no_content(),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to query index for `{to}`");
+            no_content()
+        }
+    }
+}
+
+/// Deletes a video, along with its sidecars and cached thumbnails. The files
+/// go to the trash for `trash_retention` seconds, unless that is zero.
+pub async fn delete_video(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if !auth::is_admin(&app.config, user.as_deref()) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+
+    let video = video.trim_matches('/');
+    if let Err(err) = jail::video(&app.config, video).await {
+        return err.into_response(Code::VideoNotFound, "Video not found");
+    }
+    if library::is_read_only(&app.config, video) {
+        return ApiError::new(Code::LibraryReadOnly, "Library is read-only").into();
+    }
+    let Some(path) = library::file(&app.config, video) else {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into();
+    };
+
+    let sidecars: Vec<_> = sidecars(&path).await.into_iter().map(|(sidecar, _)| sidecar).collect();
+    if app.config.trash_retention > 0 {
+        if let Err(err) = trash::trash(app, video, &path, &sidecars).await {
+            return io_error(err, &path);
+        }
+        tracing::info!("Moved `{video}` to the trash");
+    } else {
+        if let Err(err) = fs::remove_file(&path).await {
+            return io_error(err, &path);
+        }
+        tracing::info!("Deleted `{video}`");
+
+        for sidecar in sidecars {
+            if let Err(err) = fs::remove_file(&sidecar).await {
+                tracing::warn!(error = %err, "Failed to delete `{}`", sidecar.display());
+            }
+        }
+    }
+    for cached in cached(app, video) {
+        let _ = fs::remove_file(cached).await;
+    }
+    if let Err(err) = app.index.remove(video) {
+        tracing::error!(error = %err, "Failed to remove `{video}` from the index");
+    }
+
+    no_content()
+}
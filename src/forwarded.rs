@@ -0,0 +1,114 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{extract, middleware, response};
+
+use crate::Config;
+
+/// Where a request really came from, looking through trusted reverse
+/// proxies. Added to the extensions of every request.
+#[derive(Clone, Copy)]
+pub struct Client {
+    /// `None` for connections over a Unix socket without forwarded headers,
+    /// or when the server wasn't given the address of its peers.
+    pub ip: Option<IpAddr>,
+    pub https: bool
+}
+
+impl std::fmt::Display for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{ip}"),
+            None => write!(f, "unknown")
+        }
+    }
+}
+
+/// Marks requests that came over a Unix socket. Those can only come from the
+/// same machine, so they're trusted like a proxy.
+#[derive(Clone, Copy)]
+pub struct UnixSocket;
+
+fn is_trusted(config: &Config, peer: IpAddr) -> bool {
+    config.trusted_proxies.contains(&peer)
+}
+
+fn header<'a>(request: &'a extract::Request, name: &str) -> impl Iterator<Item = &'a str> {
+    request.headers().get_all(name).iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .map(str::trim)
+}
+
+/// The client of `request`. Each proxy appends the address it got the request
+/// from to `X-Forwarded-For`, so the client is the last address that isn't a
+/// trusted proxy, as anything before it could have been made up. Without
+/// the address of the peer, as when embedded without connection info, the
+/// headers are ignored.
+fn client(config: &Config, request: &extract::Request) -> Client {
+    let peer = request.extensions().get::<extract::ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    let https = config.tls_cert.is_some();
+    let trusted = match peer {
+        Some(peer) => is_trusted(config, peer),
+        None => request.extensions().get::<UnixSocket>().is_some()
+    };
+    if !trusted {
+        return Client { ip: peer, https };
+    }
+
+    let mut ip = peer;
+    let forwarded: Vec<IpAddr> = header(request, "x-forwarded-for").filter_map(|ip| ip.parse().ok()).collect();
+    for forwarded in forwarded.into_iter().rev() {
+        ip = Some(forwarded);
+        if !is_trusted(config, forwarded) {
+            break;
+        }
+    }
+
+    let https = header(request, "x-forwarded-proto").next().map_or(https, |proto| proto.eq_ignore_ascii_case("https"));
+    Client { ip, https }
+}
+
+pub async fn resolve(
+    extract::State(config): extract::State<&'static Config>,
+    mut request: extract::Request,
+    next: middleware::Next
+) -> response::Response {
+    let client = client(config, &request);
+    tracing::Span::current().record("client", tracing::field::display(&client));
+    request.extensions_mut().insert(client);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(forwarded: &str) -> extract::Request {
+        axum::http::Request::builder()
+            .header("x-forwarded-for", forwarded)
+            .header("x-forwarded-proto", "https")
+            .body(axum::body::Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn trust() {
+        let config = Config { trusted_proxies: ["10.0.0.1".parse().unwrap()].into(), ..Config::default() };
+        let forwarded = "203.0.113.7, 10.0.0.1";
+
+        // Embedded without connection info
+        let embedded = client(&config, &request(forwarded));
+        assert_eq!((embedded.ip, embedded.https), (None, false));
+
+        let mut unix = request(forwarded);
+        unix.extensions_mut().insert(UnixSocket);
+        let proxied = client(&config, &unix);
+        assert_eq!((proxied.ip, proxied.https), (Some("203.0.113.7".parse().unwrap()), true));
+
+        for (peer, expected) in [("10.0.0.1:1234", "203.0.113.7"), ("192.0.2.1:1234", "192.0.2.1")] {
+            let mut request = request(forwarded);
+            request.extensions_mut().insert(extract::ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+            assert_eq!(client(&config, &request).ip, Some(expected.parse().unwrap()));
+        }
+    }
+}
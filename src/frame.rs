@@ -0,0 +1,486 @@
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use axum::{body::Bytes, extract, http, response};
+use tokio::{fs, process::Command};
+
+use crate::error::{ApiError, Code};
+use crate::{cache, conditional, ffmpeg, jail, App};
+
+/// Largest width or height a frame can be scaled to.
+const MAX_DIMENSION: u32 = 4096;
+
+/// Most frames a single `/frames` request can ask for.
+const MAX_FRAMES: usize = 100;
+
+/// How far before the requested time precise extraction starts decoding.
+/// Input seeking jumps to the keyframe before that, output seeking then
+/// decodes its way to the exact frame.
+const PRECISE_WINDOW: f64 = 5.0;
+
+#[derive(serde::Deserialize, Clone, Copy, Default, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            Format::Png => "image/png",
+            Format::Webp => "image/webp"
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Webp => "webp"
+        }
+    }
+
+    /// Encoder arguments for a quality between 1 (worst) and 100 (best).
+    fn codec_args(self, quality: Option<u32>) -> Vec<String> {
+        match (self, quality) {
+            (Format::Jpeg, None) => vec!["-vcodec".into(), "mjpeg".into()],
+            (Format::Jpeg, Some(quality)) => {
+                // mjpeg's qscale goes from 2 (best) to 31 (worst)
+                let qscale = 2 + (100 - quality) * 29 / 99;
+                vec!["-vcodec".into(), "mjpeg".into(), "-q:v".into(), qscale.to_string()]
+            }
+            (Format::Png, _) => vec!["-vcodec".into(), "png".into()],
+            (Format::Webp, quality) => vec![
+                "-vcodec".into(), "libwebp".into(),
+                "-quality".into(), quality.unwrap_or(75).to_string()
+            ]
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct FrameQuery {
+    /// Seconds, fractions included.
+    t: f64,
+    w: Option<u32>,
+    h: Option<u32>,
+    q: Option<u32>,
+    #[serde(default)]
+    format: Format,
+    /// Seek to the exact frame rather than the keyframe closest to it.
+    /// Ignored with `frame_args`, whose template does the seeking.
+    #[serde(default)]
+    precise: bool
+}
+
+impl Hash for FrameQuery {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.t.to_bits(), self.w, self.h, self.q, self.format, self.precise).hash(state);
+    }
+}
+
+impl FrameQuery {
+    /// Arguments seeking to `t` and opening `input`.
+    fn seek_args(&self, input: &str) -> Vec<String> {
+        if !self.precise {
+            return ["-ss", &self.t.to_string(), "-i", input].map(String::from).into();
+        }
+
+        let before = f64::max(self.t - PRECISE_WINDOW, 0.0);
+        ["-ss", &before.to_string(), "-i", input, "-ss", &(self.t - before).to_string()].map(String::from).into()
+    }
+}
+
+/// The `scale` filter for the requested dimensions. When both are given the
+/// frame is fit inside the box without changing its aspect ratio.
+pub fn scale_filter(width: Option<u32>, height: Option<u32>) -> Option<String> {
+    match (width, height) {
+        (Some(width), Some(height)) => Some(format!("scale={width}:{height}:force_original_aspect_ratio=decrease")),
+        (Some(width), None) => Some(format!("scale={width}:-1")),
+        (None, Some(height)) => Some(format!("scale=-1:{height}")),
+        (None, None) => None
+    }
+}
+
+/// Why the requested size or quality can't be served, if it can't.
+fn invalid_output(width: Option<u32>, height: Option<u32>, quality: Option<u32>) -> Option<&'static str> {
+    if [width, height].into_iter().flatten().any(|dimension| dimension == 0 || dimension > MAX_DIMENSION) {
+        return Some("Invalid frame dimensions");
+    }
+    if quality.is_some_and(|quality| !(1..=100).contains(&quality)) {
+        return Some("Quality must be between 1 and 100");
+    }
+    None
+}
+
+fn image(content_type: &'static str, validators: &conditional::Validators, data: Bytes) -> response::Response {
+    validators.headers(response::Response::builder())
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .body(data.into())
+        .unwrap()
+}
+
+pub async fn serve_frame(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::Query(params): extract::Query<FrameQuery>,
+    headers: http::HeaderMap,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let config = &app.config;
+    if !(params.t.is_finite() && params.t >= 0.0) {
+        return ApiError::new(Code::BadRequest, "Invalid timestamp").into();
+    }
+
+    if let Some(message) = invalid_output(params.w, params.h, params.q) {
+        return ApiError::new(Code::BadRequest, message).into();
+    }
+
+    let video_path = match jail::video(config, video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    let Some((key, mtime)) = cache::source_key(&video_path, ("frame", &params, &config.frame_args)).await else {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into()
+    };
+
+    let validators = conditional::Validators::weak(&key, mtime);
+    if conditional::is_not_modified(&headers, &validators) {
+        return conditional::not_modified(&validators);
+    }
+
+    if let Some(data) = app.frames.get(&key).await {
+        return image(params.format.content_type(), &validators, data.into());
+    }
+
+    let mut command = Command::new(&*config.ffmpeg_command);
+    command.args(["-v", "error"]);
+    if let Some(template) = &config.frame_args {
+        command.args(template.render(video_path.to_str().unwrap(), &params.t.to_string()));
+    } else {
+        command
+            .args(config.hwaccel.decode_args())
+            .args(params.seek_args(video_path.to_str().unwrap()))
+            .args(["-vframes", "1"]);
+        if let Some(filter) = scale_filter(params.w, params.h) {
+            command.args(["-vf", &filter]);
+        }
+        command.args(params.format.codec_args(params.q)).args(["-f", "image2pipe", "-"]);
+    }
+
+    // Everyone scrubbing to the same spot gets the same ffmpeg run
+    let output = app.inflight.run(&key, async {
+        let stdout = Bytes::from(app.ffmpeg.output(&mut command).await?);
+        app.frames.insert(&key, &stdout).await;
+        Ok(stdout)
+    }).await;
+
+    let stdout = match output {
+        Ok(stdout) => stdout,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to extract frame");
+            return err.into_response("Failed to extract frame");
+        }
+    };
+
+    image(params.format.content_type(), &validators, stdout)
+}
+
+#[derive(serde::Deserialize)]
+pub struct FramesQuery {
+    /// Comma separated seconds.
+    t: Box<str>,
+    w: Option<u32>,
+    h: Option<u32>,
+    q: Option<u32>,
+    #[serde(default)]
+    format: Format
+}
+
+/// A `multipart/form-data` body with a part per frame, named after its time,
+/// so that browsers can take it apart with `Response.formData()`.
+fn multipart_body(key: &str, format: Format, frames: impl IntoIterator<Item = (f64, Vec<u8>)>) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (time, data) in frames {
+        body.extend(format!(
+            "--ninja-frames-{key}\r\nContent-Disposition: form-data; name=\"{time}\"; filename=\"{time}.{}\"\r\nContent-Type: {}\r\n\r\n",
+            format.extension(),
+            format.content_type()
+        ).into_bytes());
+        body.extend(data);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--ninja-frames-{key}--\r\n").into_bytes());
+    body
+}
+
+fn multipart(key: &str, validators: &conditional::Validators, data: Bytes) -> response::Response {
+    validators.headers(response::Response::builder())
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, format!("multipart/form-data; boundary=ninja-frames-{key}"))
+        .body(data.into())
+        .unwrap()
+}
+
+/// Splits the video into a branch per time of `times`, each selecting the
+/// frames from that time on, relative to `first` where the input was seeked
+/// to, and labelled `[o<index>]` for its output.
+fn frames_graph(times: &[f64], first: f64, scale: Option<String>) -> String {
+    let scale = scale.map_or(String::new(), |scale| format!(",{scale}"));
+    let mut graph = format!("[0:v:0]split={}", times.len());
+    graph.extend((0..times.len()).map(|index| format!("[s{index}]")));
+    for (index, time) in times.iter().enumerate() {
+        graph.push_str(&format!(";[s{index}]select='gte(t,{})'{scale}[o{index}]", time - first));
+    }
+    graph
+}
+
+/// Extracts a frame for each of `times` with a single ffmpeg run, decoding
+/// only keyframes like the storyboard does. Every time gets its own branch of
+/// the filter graph and its own output, which takes the first keyframe at or
+/// after it. Times past the end of the video are left out.
+async fn extract_frames(app: &App, video_path: &Path, key: &str, times: &[f64], params: &FramesQuery) -> Result<Bytes, ffmpeg::Error> {
+    let config = &app.config;
+    let first = times.iter().copied().fold(f64::INFINITY, f64::min);
+    let graph = frames_graph(times, first, scale_filter(params.w, params.h));
+
+    let dir = config.cache_path.join("tmp").join(format!("frames-{key}"));
+    fs::create_dir_all(&dir).await.map_err(|err| ffmpeg::Error::Failed(err.to_string().into()))?;
+    let extension = params.format.extension();
+    let mut command = Command::new(&*config.ffmpeg_command);
+    command
+        .args(["-v", "error", "-skip_frame", "nokey"])
+        .args(config.hwaccel.decode_args())
+        .args(["-ss", &first.to_string(), "-i", video_path.to_str().unwrap(), "-filter_complex", &graph]);
+    for index in 0..times.len() {
+        command
+            .args(["-map", &format!("[o{index}]"), "-frames:v", "1"])
+            .args(params.format.codec_args(params.q))
+            .args(["-f", "image2", "-update", "1"])
+            .arg(dir.join(format!("{index}.{extension}")));
+    }
+
+    let output = app.ffmpeg.output(&mut command).await;
+    let mut frames = Vec::new();
+    if output.is_ok() {
+        for (index, &time) in times.iter().enumerate() {
+            if let Ok(data) = fs::read(dir.join(format!("{index}.{extension}"))).await {
+                frames.push((time, data));
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(&dir).await;
+
+    output.map(|_| multipart_body(key, params.format, frames).into())
+}
+
+pub async fn serve_frames(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::Query(params): extract::Query<FramesQuery>,
+    headers: http::HeaderMap,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let config = &app.config;
+    let times: Option<Vec<f64>> = params.t.split(',')
+        .map(|time| time.trim().parse().ok().filter(|time: &f64| time.is_finite() && *time >= 0.0))
+        .collect();
+    let Some(times) = times else {
+        return ApiError::new(Code::BadRequest, "Invalid timestamp").into();
+    };
+    if times.len() > MAX_FRAMES {
+        return ApiError::new(Code::BadRequest, "Too many frames").into();
+    }
+
+    if let Some(message) = invalid_output(params.w, params.h, params.q) {
+        return ApiError::new(Code::BadRequest, message).into();
+    }
+
+    let video_path = match jail::video(config, video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    let bits: Vec<u64> = times.iter().map(|time| time.to_bits()).collect();
+    let Some((key, mtime)) = cache::source_key(&video_path, ("frames", bits, params.w, params.h, params.q, params.format)).await else {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into()
+    };
+
+    let validators = conditional::Validators::weak(&key, mtime);
+    if conditional::is_not_modified(&headers, &validators) {
+        return conditional::not_modified(&validators);
+    }
+
+    if let Some(data) = app.frames.get(&key).await {
+        return multipart(&key, &validators, data.into());
+    }
+
+    let output = app.inflight.run(&key, async {
+        let body = extract_frames(app, &video_path, &key, &times, &params).await?;
+        app.frames.insert(&key, &body).await;
+        Ok(body)
+    }).await;
+
+    let body = match output {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to extract frames");
+            return err.into_response("Failed to extract frames");
+        }
+    };
+
+    multipart(&key, &validators, body)
+}
+
+/// Longest animated preview that can be requested, in seconds.
+const MAX_PREVIEW_DURATION: u32 = 10;
+
+#[derive(serde::Deserialize, Clone, Copy, Default, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewFormat {
+    #[default]
+    Webp,
+    Gif
+}
+
+impl PreviewFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            PreviewFormat::Webp => "image/webp",
+            PreviewFormat::Gif => "image/gif"
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Hash)]
+pub struct PreviewQuery {
+    t: u32,
+    #[serde(default = "PreviewQuery::default_duration")]
+    d: u32,
+    #[serde(default = "PreviewQuery::default_width")]
+    w: u32,
+    #[serde(default)]
+    format: PreviewFormat
+}
+
+impl PreviewQuery {
+    fn default_duration() -> u32 { 3 }
+    fn default_width() -> u32 { 320 }
+}
+
+pub async fn serve_preview(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::Query(params): extract::Query<PreviewQuery>,
+    headers: http::HeaderMap,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let config = &app.config;
+    if params.d == 0 || params.d > MAX_PREVIEW_DURATION {
+        return ApiError::new(Code::BadRequest, "Invalid preview duration").into();
+    }
+
+    if params.w == 0 || params.w > MAX_DIMENSION {
+        return ApiError::new(Code::BadRequest, "Invalid preview width").into();
+    }
+
+    let video_path = match jail::video(config, video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    let Some((key, mtime)) = cache::source_key(&video_path, ("preview", &params)).await else {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into()
+    };
+
+    let validators = conditional::Validators::weak(&key, mtime);
+    if conditional::is_not_modified(&headers, &validators) {
+        return conditional::not_modified(&validators);
+    }
+
+    if let Some(data) = app.frames.get(&key).await {
+        return image(params.format.content_type(), &validators, data.into());
+    }
+
+    let scale = format!("fps=10,scale={}:-1", params.w);
+    let mut command = Command::new(&*config.ffmpeg_command);
+    command.args(["-v", "error"]).args(config.hwaccel.decode_args()).args([
+        "-ss", &params.t.to_string(),
+        "-t", &params.d.to_string(),
+        "-i", video_path.to_str().unwrap(),
+        "-an",
+        "-loop", "0"
+    ]);
+    match params.format {
+        PreviewFormat::Webp => {
+            command.args(["-vf", &scale, "-vcodec", "libwebp", "-f", "webp", "-"]);
+        }
+        PreviewFormat::Gif => {
+            // A palette generated from the clip itself looks far better than
+            // the default 256 color palette
+            let filter = format!("{scale},split[a][b];[a]palettegen[p];[b][p]paletteuse");
+            command.args(["-vf", &filter, "-f", "gif", "-"]);
+        }
+    }
+
+    let output = app.inflight.run(&key, async {
+        let stdout = Bytes::from(app.ffmpeg.output(&mut command).await?);
+        app.frames.insert(&key, &stdout).await;
+        Ok(stdout)
+    }).await;
+
+    let stdout = match output {
+        Ok(stdout) => stdout,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to generate preview");
+            return err.into_response("Failed to generate preview");
+        }
+    };
+
+    image(params.format.content_type(), &validators, stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(t: f64, precise: bool) -> FrameQuery {
+        FrameQuery { t, w: None, h: None, q: None, format: Format::Jpeg, precise }
+    }
+
+    #[test]
+    fn seeking() {
+        assert_eq!(query(42.5, false).seek_args("in.mkv"), ["-ss", "42.5", "-i", "in.mkv"]);
+        assert_eq!(query(42.5, true).seek_args("in.mkv"), ["-ss", "37.5", "-i", "in.mkv", "-ss", "5"]);
+        // Nothing to decode before the start
+        assert_eq!(query(2.0, true).seek_args("in.mkv"), ["-ss", "0", "-i", "in.mkv", "-ss", "2"]);
+    }
+
+    #[test]
+    fn graphs() {
+        assert_eq!(
+            frames_graph(&[10.0, 4.0, 30.5], 4.0, None),
+            "[0:v:0]split=3[s0][s1][s2];[s0]select='gte(t,6)'[o0];[s1]select='gte(t,0)'[o1];[s2]select='gte(t,26.5)'[o2]"
+        );
+        assert_eq!(
+            frames_graph(&[1.0], 1.0, scale_filter(Some(320), None)),
+            "[0:v:0]split=1[s0];[s0]select='gte(t,0)',scale=320:-1[o0]"
+        );
+    }
+
+    #[test]
+    fn multipart_bodies() {
+        let body = multipart_body("abc", Format::Png, [(1.5, b"one".to_vec()), (3.0, b"two".to_vec())]);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "--ninja-frames-abc\r\nContent-Disposition: form-data; name=\"1.5\"; filename=\"1.5.png\"\r\nContent-Type: image/png\r\n\r\none\r\n\
+             --ninja-frames-abc\r\nContent-Disposition: form-data; name=\"3\"; filename=\"3.png\"\r\nContent-Type: image/png\r\n\r\ntwo\r\n\
+             --ninja-frames-abc--\r\n"
+        );
+        assert_eq!(multipart_body("abc", Format::Jpeg, []), b"--ninja-frames-abc--\r\n");
+
+        let response = multipart("abc", &conditional::Validators::weak("abc", std::time::SystemTime::UNIX_EPOCH), Bytes::new());
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "multipart/form-data; boundary=ninja-frames-abc");
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use axum::{extract, http, response, Json};
+use tokio::{fs, process::Command, time};
+
+use crate::{library, App};
+
+/// How long the `-version` of ffmpeg and ffprobe may take before they're
+/// considered broken.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers as long as the process is up, for liveness probes.
+pub async fn serve_health() -> response::Response {
+    response::IntoResponse::into_response(Json(serde_json::json!({ "status": "ok" })))
+}
+
+async fn libraries(app: &App) -> Result<(), String> {
+    for root in library::roots(&app.config) {
+        if let Err(err) = fs::read_dir(root.path).await {
+            return Err(format!("Failed to read `{}`: {err}", root.path.display()));
+        }
+    }
+    Ok(())
+}
+
+async fn executable(command: &str) -> Result<(), String> {
+    match time::timeout(TIMEOUT, Command::new(command).arg("-version").kill_on_drop(true).output()).await {
+        Ok(Ok(output)) if output.status.success() => Ok(()),
+        Ok(Ok(output)) => Err(format!("`{command} -version` exited with {}", output.status)),
+        Ok(Err(err)) => Err(format!("Failed to run `{command}`: {err}")),
+        Err(_) => Err(format!("`{command} -version` timed out"))
+    }
+}
+
+fn check(result: Result<(), String>) -> serde_json::Value {
+    match result {
+        Ok(()) => serde_json::json!({ "status": "ok" }),
+        Err(error) => serde_json::json!({ "status": "error", "error": error })
+    }
+}
+
+/// Checks that the server can do its job, for readiness probes: the libraries
+/// can be read, ffmpeg and ffprobe run, and the database answers.
+pub async fn serve_ready(extract::State(app): extract::State<&App>) -> response::Response {
+    let (libraries, ffmpeg, ffprobe) = tokio::join!(
+        libraries(app),
+        executable(&app.config.ffmpeg_command),
+        executable(&app.config.ffprobe_command)
+    );
+    let database = app.index.ping().map_err(|err| err.to_string());
+
+    let ready = [&libraries, &ffmpeg, &ffprobe, &database].iter().all(|result| result.is_ok());
+    let mut response = response::IntoResponse::into_response(Json(serde_json::json!({
+        "status": if ready { "ok" } else { "error" },
+        "checks": {
+            "library": check(libraries),
+            "ffmpeg": check(ffmpeg),
+            "ffprobe": check(ffprobe),
+            "database": check(database)
+        }
+    })));
+    if !ready {
+        *response.status_mut() = http::StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
@@ -0,0 +1,312 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use axum::{body::Bytes, extract, http, response};
+use tokio::process::Command;
+
+use crate::error::{ApiError, Code};
+use crate::{auth, cache, ffmpeg, jail, keyframes, library, probe, transcode, App, Config, Rendition};
+
+/// The profile of an HLS request, falling back to the default one.
+fn profile<'a>(config: &'a Config, options: &transcode::Options) -> Result<Option<&'a transcode::Profile>, (Code, &'static str)> {
+    match options.profile(config, true) {
+        Ok(Some(profile)) if !profile.supports_hls() => Err((Code::BadRequest, "Profile can't be used for HLS")),
+        Ok(profile) => Ok(profile),
+        Err(()) => Err((Code::ProfileNotFound, "Profile not found"))
+    }
+}
+
+/// Video and audio bitrates of `rendition`, capped by the profile's.
+pub fn bitrates(rendition: &Rendition, profile: Option<&transcode::Profile>) -> (u32, u32) {
+    let cap = |bitrate: u32, cap: Option<u32>| cap.map_or(bitrate, |cap| bitrate.min(cap));
+    (
+        cap(rendition.video_bitrate, profile.and_then(|profile| profile.video_bitrate)),
+        cap(rendition.audio_bitrate, profile.and_then(|profile| profile.audio_bitrate))
+    )
+}
+
+/// Start times of the segments of a `duration` long video, every
+/// `segment_duration` seconds or on the keyframes closest after.
+async fn segment_starts(app: &App, video: &Path, video_path: &Path, duration: f64) -> Vec<f64> {
+    let config = &app.config;
+    let segment_duration = config.segment_duration as f64;
+    if config.keyframe_segments {
+        if let Some(keyframes) = keyframes::get(app, video, video_path).await {
+            return keyframes::segments(&keyframes, segment_duration, duration);
+        }
+    }
+
+    let segments = (duration / segment_duration).ceil() as u32;
+    (0..segments).map(|segment| segment as f64 * segment_duration).collect()
+}
+
+/// Start and length of `segment`, `None` past the end when cutting on
+/// keyframes.
+async fn segment_bounds(app: &App, video: &Path, video_path: &Path, segment: u32) -> Option<(f64, f64)> {
+    let segment_duration = app.config.segment_duration as f64;
+    if !app.config.keyframe_segments {
+        // No need to probe, ffmpeg stops at the end of the video anyway
+        return Some((segment as f64 * segment_duration, segment_duration));
+    }
+
+    let summary = probe::summary(&app.config, video_path).await?;
+    let starts = segment_starts(app, video, video_path, summary.duration).await;
+    let start = *starts.get(segment as usize)?;
+    Some((start, starts.get(segment as usize + 1).unwrap_or(&summary.duration) - start))
+}
+
+fn playlist(body: String) -> response::Response {
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/vnd.apple.mpegurl")
+        .body(body.into())
+        .unwrap()
+}
+
+fn find_rendition<'a>(config: &'a Config, video: &Path, name: &str) -> Option<&'a Rendition> {
+    library::renditions(config, video).iter().find(|rendition| &*rendition.name == name)
+}
+
+/// Renditions that don't upscale the source nor exceed `max_height`. The
+/// smallest rendition is always kept so that low resolution sources still get
+/// a playable ladder.
+fn ladder<'a>(config: &'a Config, video: &Path, max_height: u32) -> Vec<&'a Rendition> {
+    let renditions = library::renditions(config, video);
+    let mut ladder: Vec<_> = renditions.iter()
+        .filter(|rendition| rendition.height <= max_height)
+        .collect();
+
+    if ladder.is_empty() {
+        ladder.extend(renditions.iter().min_by_key(|rendition| rendition.height));
+    }
+
+    ladder
+}
+
+pub async fn serve_master(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::Query(options): extract::Query<transcode::Options>,
+    extract::Query(token): extract::Query<auth::TokenQuery>,
+    extract::State(config): extract::State<&Config>
+) -> response::Response {
+    let video_path = match jail::video(config, &video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    let profile = match profile(config, &options) {
+        Ok(profile) => profile,
+        Err((code, message)) => return ApiError::new(code, message).into()
+    };
+    let Some(summary) = probe::summary(config, &video_path).await else {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into();
+    };
+
+    let query = token.carry(options.query());
+    let max_height = profile.and_then(|profile| profile.height).map_or(summary.height, |height| height.min(summary.height));
+    let mut body = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    for rendition in ladder(config, &video, max_height) {
+        let (video_bitrate, audio_bitrate) = bitrates(rendition, profile);
+        let bandwidth = (video_bitrate + audio_bitrate) * 1000;
+        write!(body, "#EXT-X-STREAM-INF:BANDWIDTH={bandwidth}").unwrap();
+        if summary.width > 0 && summary.height > 0 {
+            let width = (summary.width * rendition.height / summary.height + 1) & !1;
+            write!(body, ",RESOLUTION={width}x{}", rendition.height).unwrap();
+        }
+        writeln!(body, ",NAME=\"{}\"\n{}/index.m3u8{query}", rendition.name, rendition.name).unwrap();
+    }
+
+    playlist(body)
+}
+
+pub async fn serve_playlist(
+    extract::Path((video, rendition)): extract::Path<(Box<Path>, Box<str>)>,
+    extract::Query(options): extract::Query<transcode::Options>,
+    extract::Query(token): extract::Query<auth::TokenQuery>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let config = &app.config;
+    if find_rendition(config, &video, &rendition).is_none() {
+        return ApiError::new(Code::RenditionNotFound, "Rendition not found").into();
+    }
+    if let Err((code, message)) = profile(config, &options) {
+        return ApiError::new(code, message).into();
+    }
+
+    let video_path = match jail::video(config, &video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    let Some(summary) = probe::summary(config, &video_path).await else {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into();
+    };
+
+    let starts = segment_starts(app, &video, &video_path, summary.duration).await;
+    let durations: Vec<f64> = starts.iter().enumerate()
+        .map(|(segment, start)| starts.get(segment + 1).unwrap_or(&summary.duration) - start)
+        .collect();
+    let target = durations.iter().copied().fold(config.segment_duration as f64, f64::max).ceil();
+
+    let mut body = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    writeln!(body, "#EXT-X-TARGETDURATION:{target}").unwrap();
+    body.push_str("#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n");
+    let query = token.carry(options.query());
+    for (segment, duration) in durations.iter().enumerate() {
+        writeln!(body, "#EXTINF:{duration:.3},\n{segment}.ts{query}").unwrap();
+    }
+    body.push_str("#EXT-X-ENDLIST\n");
+
+    playlist(body)
+}
+
+fn segment_response(output: Bytes) -> response::Response {
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "video/mp2t")
+        .body(output.into())
+        .unwrap()
+}
+
+/// Why a segment couldn't be produced.
+enum SegmentError {
+    NotFound(Code, &'static str),
+    /// Past the end of the video.
+    OutOfRange,
+    Transcode(ffmpeg::Error)
+}
+
+/// Segment `segment` of `video`, at `video_path`, from the segment cache or
+/// transcoded and stored there.
+async fn segment(
+    app: &App,
+    video: &Path,
+    video_path: &Path,
+    rendition: &Rendition,
+    profile: Option<&transcode::Profile>,
+    options: &transcode::Options,
+    segment: u32
+) -> Result<Bytes, SegmentError> {
+    let config = &app.config;
+    // Neither the default profile nor the tone mapping setting is in the
+    // query, and both can be changed without a restart
+    let params = (
+        ("segment", rendition, segment, config.segment_duration, config.keyframe_segments),
+        (options.query(), profile, options.tonemap(config))
+    );
+    let Some((key, _)) = cache::source_key(video_path, params).await else {
+        return Err(SegmentError::NotFound(Code::VideoNotFound, "Video not found"));
+    };
+
+    // Seeking back into a region that was already played
+    if let Some(output) = app.segments.get(&key).await {
+        return Ok(output.into());
+    }
+
+    let Some((start, duration)) = segment_bounds(app, video, video_path, segment).await else {
+        return Err(SegmentError::OutOfRange);
+    };
+
+    let scale = format!("scale=-2:{}", rendition.height);
+    let codec = profile.map_or(transcode::Codec::H264, |profile| profile.codec);
+    let Some(filter) = transcode::video_filter(config, video_path, options, start, &scale, codec).await else {
+        return Err(SegmentError::NotFound(Code::SubtitlesNotFound, "Subtitle track not found"));
+    };
+
+    let encode_args = match profile {
+        Some(profile) => profile.encode_args(config),
+        None => config.hwaccel.encode_args().map(String::from).into()
+    };
+    let (video_bitrate, audio_bitrate) = bitrates(rendition, profile);
+    let (start, duration) = (start.to_string(), duration.to_string());
+    let mut command = Command::new(&*config.ffmpeg_command);
+    command
+        .args(["-v", "error"])
+        .args(config.hwaccel.decode_args())
+        .args([
+            "-ss", &start,
+            "-t", &duration,
+            "-i", video_path.to_str().unwrap(),
+            "-filter_complex", &filter,
+            "-map", "[v]",
+            "-map", &options.audio_map("0:a:0?")
+        ])
+        .args(encode_args)
+        .args([
+            "-b:v", &format!("{video_bitrate}k"),
+            "-maxrate", &format!("{video_bitrate}k"),
+            "-bufsize", &format!("{}k", video_bitrate * 2)
+        ])
+        .args(profile.map_or(&[][..], |profile| profile.audio_filter_args()))
+        .args([
+            "-c:a", "aac",
+            "-ac", "2",
+            "-b:a", &format!("{audio_bitrate}k"),
+            "-output_ts_offset", &start,
+            "-f", "mpegts",
+            "-"
+        ]);
+
+    // Viewers watching together all request the same segments at once
+    app.inflight.run(&key, async {
+        let output = Bytes::from(app.ffmpeg.output(&mut command).await?);
+        app.segments.insert(&key, &output).await;
+        Ok(output)
+    }).await.map_err(SegmentError::Transcode)
+}
+
+pub async fn serve_segment(
+    extract::Path((video, rendition, segment)): extract::Path<(Box<Path>, Box<str>, Box<str>)>,
+    extract::Query(options): extract::Query<transcode::Options>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let config = &app.config;
+    let Some(rendition) = find_rendition(config, &video, &rendition) else {
+        return ApiError::new(Code::RenditionNotFound, "Rendition not found").into();
+    };
+    let profile = match profile(config, &options) {
+        Ok(profile) => profile,
+        Err((code, message)) => return ApiError::new(code, message).into()
+    };
+
+    let Some(segment) = segment.strip_suffix(".ts").and_then(|index| index.parse::<u32>().ok()) else {
+        return ApiError::new(Code::SegmentNotFound, "Segment not found").into();
+    };
+
+    let video_path = match jail::video(config, &video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+
+    match self::segment(app, &video, &video_path, rendition, profile, &options, segment).await {
+        Ok(output) => segment_response(output),
+        Err(SegmentError::NotFound(code, message)) => ApiError::new(code, message).into(),
+        Err(SegmentError::OutOfRange) => ApiError::new(Code::SegmentNotFound, "Segment not found").into(),
+        Err(SegmentError::Transcode(err)) => {
+            tracing::error!(error = %err, "Failed to transcode segment");
+            err.into_response("Failed to transcode segment")
+        }
+    }
+}
+
+/// Transcodes the first `count` segments of `video` at `rendition`, as a
+/// player with no options would request them, into the segment cache so that
+/// playback starts right away.
+pub async fn pretranscode(app: &App, video: &str, rendition: &str, count: u32) -> Result<(), Box<str>> {
+    let config = &app.config;
+    let Some(rendition) = find_rendition(config, Path::new(video), rendition) else {
+        return Err("Rendition not found".into());
+    };
+    let options = transcode::Options::default();
+    let profile = profile(config, &options).map_err(|(_, message)| message)?;
+    let video_path = jail::video(config, video).await.map_err(|_| "Video not found")?;
+
+    for index in 0..count {
+        match segment(app, Path::new(video), &video_path, rendition, profile, &options, index).await {
+            Ok(_) => {}
+            // Shorter than `count` segments
+            Err(SegmentError::OutOfRange) => break,
+            Err(SegmentError::NotFound(_, message)) => return Err(message.into()),
+            Err(SegmentError::Transcode(err)) => return Err(err.to_string().into())
+        }
+    }
+    Ok(())
+}
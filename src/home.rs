@@ -0,0 +1,82 @@
+use axum::{extract, response, Json};
+
+use crate::error::ApiError;
+use crate::index::Video;
+use crate::users::{Owner, User};
+use crate::{auth, App};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+#[derive(serde::Deserialize)]
+pub struct HomeQuery {
+    /// How many videos each section holds at most.
+    limit: Option<usize>
+}
+
+#[derive(serde::Serialize)]
+struct Started {
+    #[serde(flatten)]
+    video: Video,
+    /// Where playback stopped, in seconds.
+    position: f64,
+    updated: u64
+}
+
+#[derive(serde::Serialize)]
+struct Played {
+    #[serde(flatten)]
+    video: Video,
+    plays: u64
+}
+
+#[derive(serde::Serialize)]
+struct Home {
+    recently_added: Vec<Video>,
+    continue_watching: Vec<Started>,
+    most_played: Vec<Played>
+}
+
+/// The sections of a dashboard in one document: the videos added to the
+/// library last, the ones the user stopped halfway through, and the ones
+/// played the most by everyone.
+pub async fn serve_home(
+    extract::Query(query): extract::Query<HomeQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Owner(owner): Owner
+) -> response::Response {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let allowed = |path: &str| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, path));
+
+    let recently_added = match app.index.recent(limit, |video| allowed(&video.path)) {
+        Ok(videos) => videos,
+        Err(err) => return ApiError::database("Failed to build home page", err).into()
+    };
+
+    let progress = match app.progress.list(&owner) {
+        Ok(progress) => progress,
+        Err(err) => return ApiError::database("Failed to build home page", err).into()
+    };
+    let mut continue_watching = Vec::new();
+    for progress in progress.into_iter().filter(|progress| !progress.watched && progress.position > 0.0) {
+        if continue_watching.len() >= limit {
+            break;
+        }
+        if !allowed(&progress.video) {
+            continue;
+        }
+        match app.index.get(&progress.video) {
+            Ok(Some(video)) => continue_watching.push(Started { video, position: progress.position, updated: progress.updated }),
+            Ok(None) => {}
+            Err(err) => return ApiError::database("Failed to build home page", err).into()
+        }
+    }
+
+    let most_played = match app.index.most_played(limit, |video| allowed(&video.path)) {
+        Ok(videos) => videos.into_iter().map(|(video, plays)| Played { video, plays }).collect(),
+        Err(err) => return ApiError::database("Failed to build home page", err).into()
+    };
+
+    response::IntoResponse::into_response(Json(Home { recently_added, continue_watching, most_played }))
+}
@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::{extract, http, Router};
+use bytes::{Buf, Bytes};
+use futures_util::StreamExt;
+use h3::error::{Code, StreamError};
+use h3::server::RequestResolver;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceExt;
+
+fn server_config(cert: &Path, key: &Path) -> Result<quinn::ServerConfig, Box<dyn Error>> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+
+    let mut tls = rustls::ServerConfig::builder().with_no_client_auth().with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Serves `app` over QUIC on `socket`, alongside the TCP listener. Once
+/// `shutdown` is cancelled, open connections get up to `drain` to finish.
+pub async fn run(socket: UdpSocket, cert: &Path, key: &Path, app: Router, shutdown: CancellationToken, drain: Duration) {
+    let addr = socket.local_addr();
+    let endpoint = match server_config(cert, key).map(|config| {
+        quinn::Endpoint::new(quinn::EndpointConfig::default(), Some(config), socket, Arc::new(quinn::TokioRuntime))
+    }) {
+        Ok(Ok(endpoint)) => endpoint,
+        Ok(Err(err)) => {
+            tracing::error!(error = %err, "Failed to start HTTP/3 endpoint");
+            return;
+        }
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to load TLS certificate for HTTP/3");
+            return;
+        }
+    };
+
+    if let Ok(addr) = addr {
+        tracing::info!("HTTP/3 listening on udp://{addr}");
+    }
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => tokio::spawn(connection(incoming, app.clone(), shutdown.clone())),
+                None => return
+            },
+            _ = shutdown.cancelled() => break
+        };
+    }
+
+    endpoint.set_server_config(None);
+    if time::timeout(drain, endpoint.wait_idle()).await.is_err() {
+        endpoint.close(Code::H3_NO_ERROR.value().try_into().unwrap(), b"");
+    }
+}
+
+async fn connection(incoming: quinn::Incoming, app: Router, shutdown: CancellationToken) {
+    let Ok(conn) = incoming.await else {
+        return;
+    };
+    let remote = conn.remote_address();
+
+    let mut conn = match h3::server::Connection::new(h3_quinn::Connection::new(conn)).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to establish HTTP/3 connection");
+            return;
+        }
+    };
+
+    let mut closing = false;
+    loop {
+        let accepted = tokio::select! {
+            accepted = conn.accept() => accepted,
+            _ = shutdown.cancelled(), if !closing => {
+                // Sends GOAWAY, the requests already in flight still finish
+                closing = true;
+                let _ = conn.shutdown(0).await;
+                continue;
+            }
+        };
+
+        match accepted {
+            Ok(Some(resolver)) => {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = request(resolver, remote, app).await {
+                        if !err.is_h3_no_error() {
+                            tracing::error!(error = %err, "HTTP/3 request failed");
+                        }
+                    }
+                });
+            }
+            Ok(None) => break,
+            Err(err) => {
+                if !err.is_h3_no_error() {
+                    tracing::error!(error = %err, "HTTP/3 connection failed");
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// Runs a single request through the router. Request bodies are small JSON
+/// documents, so they're read whole, while responses are streamed.
+async fn request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    remote: SocketAddr,
+    app: Router
+) -> Result<(), StreamError> {
+    let (mut request, mut stream) = resolver.resolve_request().await?;
+    request.extensions_mut().insert(extract::ConnectInfo(remote));
+
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            body.extend_from_slice(bytes);
+            let read = bytes.len();
+            chunk.advance(read);
+        }
+    }
+
+    let Ok(response) = app.oneshot(request.map(|()| Body::from(body))).await;
+    let (parts, body) = response.into_parts();
+    stream.send_response(http::Response::from_parts(parts, ())).await?;
+
+    let mut body = body.into_data_stream();
+    while let Some(chunk) = body.next().await {
+        match chunk {
+            Ok(chunk) => stream.send_data(chunk).await?,
+            Err(err) => {
+                // Resetting the stream tells the client that the response
+                // was cut short
+                tracing::error!(error = %err, "Failed to stream HTTP/3 response");
+                stream.stop_stream(Code::H3_INTERNAL_ERROR);
+                return Ok(());
+            }
+        }
+    }
+    stream.finish().await
+}
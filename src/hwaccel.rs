@@ -0,0 +1,107 @@
+use crate::transcode::Codec;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    #[default]
+    None,
+    Vaapi,
+    Nvenc,
+    Qsv,
+    Videotoolbox
+}
+
+/// Hardware acceleration settings. Frames are decoded on the GPU but
+/// downloaded to system memory, so that every software filter (subtitles,
+/// overlays, tone mapping) keeps working, then uploaded again for encoders
+/// that need it.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+#[serde(default)]
+pub struct HwAccel {
+    pub kind: Kind,
+    /// Render node for VAAPI/QSV (e.g. `/dev/dri/renderD128`) or GPU index
+    /// for NVENC.
+    pub device: Option<Box<str>>
+}
+
+impl HwAccel {
+    /// Arguments to put before `-i` for hardware decoding.
+    pub fn decode_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = Vec::new();
+        match (self.kind, &self.device) {
+            (Kind::None, _) => {}
+            (Kind::Vaapi, device) => {
+                args.extend(["-vaapi_device".into(), device.as_deref().unwrap_or("/dev/dri/renderD128").into()]);
+                args.extend(["-hwaccel".into(), "vaapi".into()]);
+            }
+            (Kind::Nvenc, device) => {
+                args.extend(["-hwaccel".into(), "cuda".into()]);
+                if let Some(device) = device {
+                    args.extend(["-hwaccel_device".into(), device.to_string()]);
+                }
+            }
+            (Kind::Qsv, Some(device)) => args.extend([
+                "-init_hw_device", &format!("vaapi=va:{device}"),
+                "-init_hw_device", "qsv=hw@va",
+                "-filter_hw_device", "hw",
+                "-hwaccel", "qsv"
+            ].map(String::from)),
+            (Kind::Qsv, None) => args.extend([
+                "-init_hw_device", "qsv=hw",
+                "-filter_hw_device", "hw",
+                "-hwaccel", "qsv"
+            ].map(String::from)),
+            (Kind::Videotoolbox, _) => args.extend(["-hwaccel".into(), "videotoolbox".into()])
+        }
+        args
+    }
+
+    /// H.264 encoder arguments.
+    pub fn encode_args(&self) -> [&'static str; 4] {
+        match self.kind {
+            Kind::None => ["-c:v", "libx264", "-preset", "veryfast"],
+            Kind::Vaapi => ["-c:v", "h264_vaapi", "-rc_mode", "VBR"],
+            Kind::Nvenc => ["-c:v", "h264_nvenc", "-preset", "p4"],
+            Kind::Qsv => ["-c:v", "h264_qsv", "-preset", "veryfast"],
+            Kind::Videotoolbox => ["-c:v", "h264_videotoolbox", "-realtime", "1"]
+        }
+    }
+
+    /// Encoder arguments for `codec`. VP9 and AV1 are always encoded in
+    /// software, hardware encoders for them are still too rare to rely on.
+    pub fn encoder_args(&self, codec: Codec) -> Vec<&'static str> {
+        match (codec, self.kind) {
+            (Codec::H264, _) => self.encode_args().into(),
+            (Codec::Hevc, Kind::None) => vec!["-c:v", "libx265", "-preset", "veryfast"],
+            (Codec::Hevc, Kind::Vaapi) => vec!["-c:v", "hevc_vaapi", "-rc_mode", "VBR"],
+            (Codec::Hevc, Kind::Nvenc) => vec!["-c:v", "hevc_nvenc", "-preset", "p4"],
+            (Codec::Hevc, Kind::Qsv) => vec!["-c:v", "hevc_qsv", "-preset", "veryfast"],
+            (Codec::Hevc, Kind::Videotoolbox) => vec!["-c:v", "hevc_videotoolbox", "-realtime", "1"],
+            (Codec::Vp9, _) => vec!["-c:v", "libvpx-vp9", "-deadline", "realtime", "-cpu-used", "8", "-row-mt", "1"],
+            (Codec::Av1, _) => vec!["-c:v", "libsvtav1", "-preset", "10"]
+        }
+    }
+
+    /// Whether `codec` is encoded on the GPU.
+    pub fn encodes(&self, codec: Codec) -> bool {
+        self.kind != Kind::None && matches!(codec, Codec::H264 | Codec::Hevc)
+    }
+
+    /// Filter appended to the video chain to move frames back to the GPU for
+    /// encoders that only accept hardware frames.
+    pub fn upload_filter(&self) -> Option<&'static str> {
+        match self.kind {
+            Kind::Vaapi => Some("format=nv12,hwupload"),
+            Kind::Qsv => Some("format=nv12,hwupload=extra_hw_frames=64"),
+            _ => None
+        }
+    }
+
+    /// `filter` followed by the upload filter, if any.
+    pub fn with_upload(&self, filter: &str) -> String {
+        match self.upload_filter() {
+            Some(upload) => format!("{filter},{upload}"),
+            None => filter.into()
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::unix_now;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS videos (
+        path TEXT PRIMARY KEY,
+        dir TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL,
+        duration REAL,
+        width INTEGER,
+        height INTEGER,
+        video_codec TEXT,
+        audio_codec TEXT,
+        generation INTEGER NOT NULL,
+        title TEXT,
+        tags TEXT,
+        year INTEGER,
+        plot TEXT,
+        poster TEXT,
+        fanart TEXT,
+        tmdb TEXT,
+        added INTEGER,
+        sha256 TEXT,
+        phash TEXT
+    );
+    CREATE INDEX IF NOT EXISTS videos_dir ON videos (dir);
+    CREATE TABLE IF NOT EXISTS plays (
+        path TEXT PRIMARY KEY,
+        count INTEGER NOT NULL,
+        last INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS keyframes (
+        path TEXT PRIMARY KEY,
+        mtime INTEGER NOT NULL,
+        times BLOB NOT NULL
+    );
+";
+
+/// How videos indexed before a column was added get a value for it.
+#[derive(Clone, Copy)]
+enum Fill {
+    /// By probing them again with the next scan.
+    Probe,
+    /// From another column.
+    From(&'static str),
+    /// They don't, it's a cache filled as needed.
+    Empty
+}
+
+/// Columns added since the table was first created, which older databases
+/// lack.
+const ADDED_COLUMNS: &[(&str, &str, Fill)] = &[
+    ("title", "TEXT", Fill::Probe),
+    ("tags", "TEXT", Fill::Probe),
+    ("year", "INTEGER", Fill::Probe),
+    ("plot", "TEXT", Fill::Probe),
+    ("poster", "TEXT", Fill::Probe),
+    ("fanart", "TEXT", Fill::Probe),
+    ("tmdb", "TEXT", Fill::Probe),
+    ("added", "INTEGER", Fill::From("generation")),
+    ("sha256", "TEXT", Fill::Empty),
+    ("phash", "TEXT", Fill::Empty)
+];
+
+/// A video as stored in the index. `path` is relative to `video_path` and
+/// always uses `/` as the separator.
+#[derive(serde::Serialize)]
+pub struct Video {
+    pub path: Box<str>,
+    pub filename: Box<str>,
+    pub size: u64,
+    pub mtime: u64,
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub video_codec: Option<Box<str>>,
+    pub audio_codec: Option<Box<str>>,
+    pub title: Option<Box<str>>,
+    /// Container tags like the genre or show, one per line.
+    pub tags: Option<Box<str>>,
+    pub year: Option<u32>,
+    pub plot: Option<Box<str>>,
+    /// Filenames of the artwork next to the video.
+    pub poster: Option<Box<str>>,
+    pub fanart: Option<Box<str>>,
+    /// The TMDB movie or show the video matched, when its poster is cached.
+    pub tmdb: Option<Box<str>>,
+    /// When the scanner first saw the video, kept by the index across
+    /// rescans.
+    pub added: Option<u64>,
+    /// The hex SHA-256 of the file, once computed, kept by the index across
+    /// rescans that don't find it changed.
+    pub sha256: Option<Box<str>>,
+    /// Perceptual hashes of frames sampled across the video, in hex, kept
+    /// like the checksum.
+    pub phash: Option<Box<str>>
+}
+
+impl Video {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Video {
+            path: row.get::<_, String>("path")?.into(),
+            filename: row.get::<_, String>("filename")?.into(),
+            size: row.get("size")?,
+            mtime: row.get("mtime")?,
+            duration: row.get("duration")?,
+            width: row.get("width")?,
+            height: row.get("height")?,
+            video_codec: row.get::<_, Option<String>>("video_codec")?.map(Into::into),
+            audio_codec: row.get::<_, Option<String>>("audio_codec")?.map(Into::into),
+            title: row.get::<_, Option<String>>("title")?.map(Into::into),
+            tags: row.get::<_, Option<String>>("tags")?.map(Into::into),
+            year: row.get("year")?,
+            plot: row.get::<_, Option<String>>("plot")?.map(Into::into),
+            poster: row.get::<_, Option<String>>("poster")?.map(Into::into),
+            fanart: row.get::<_, Option<String>>("fanart")?.map(Into::into),
+            tmdb: row.get::<_, Option<String>>("tmdb")?.map(Into::into),
+            added: row.get("added")?,
+            sha256: row.get::<_, Option<String>>("sha256")?.map(Into::into),
+            phash: row.get::<_, Option<String>>("phash")?.map(Into::into)
+        })
+    }
+
+    /// The title, or the filename without its extension for videos that
+    /// have none.
+    pub fn display_title(&self) -> &str {
+        self.title.as_deref()
+            .unwrap_or_else(|| self.filename.rsplit_once('.').map_or(&*self.filename, |(stem, _)| stem))
+    }
+
+    pub fn dir(&self) -> &str {
+        self.path.rsplit_once('/').map_or("", |(dir, _)| dir)
+    }
+}
+
+/// Adds the [`ADDED_COLUMNS`] missing from an older database, filling them
+/// in for the videos indexed before. Those that need probing are marked as
+/// changed to be probed again by the next scan.
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('videos')")?;
+    let columns: Vec<String> = stmt.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+
+    let mut reprobe = false;
+    for &(name, kind, fill) in ADDED_COLUMNS {
+        if columns.iter().any(|column| column == name) {
+            continue;
+        }
+        conn.execute_batch(&format!("ALTER TABLE videos ADD COLUMN {name} {kind}"))?;
+        match fill {
+            Fill::Probe => reprobe = true,
+            Fill::From(column) => conn.execute_batch(&format!("UPDATE videos SET {name} = {column}"))?,
+            Fill::Empty => {}
+        }
+    }
+    if reprobe {
+        conn.execute("UPDATE videos SET mtime = 0", [])?;
+    }
+    Ok(())
+}
+
+/// Drops keyframes keyed by the absolute path of their source, as they were
+/// before, which are probed again when needed.
+fn rekey_keyframes(conn: &Connection) -> rusqlite::Result<()> {
+    if conn.prepare("SELECT 1 FROM pragma_table_info('keyframes') WHERE name = 'source'")?.exists([])? {
+        conn.execute_batch("DROP TABLE keyframes")?;
+        conn.execute_batch(SCHEMA)?;
+    }
+    Ok(())
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    Name,
+    Mtime,
+    Size,
+    Duration
+}
+
+impl Sort {
+    fn column(self) -> &'static str {
+        match self {
+            Sort::Name => "filename",
+            Sort::Mtime => "mtime",
+            Sort::Size => "size",
+            Sort::Duration => "duration"
+        }
+    }
+}
+
+/// Which videos of a directory to list, and in which order.
+#[derive(Default)]
+pub struct Listing<'a> {
+    pub sort: Sort,
+    pub descending: bool,
+    pub offset: u64,
+    pub limit: Option<u64>,
+    /// Only videos with one of these extensions, or all when empty.
+    pub extensions: &'a [&'a str]
+}
+
+pub struct Index {
+    conn: Mutex<Connection>
+}
+
+impl Index {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        migrate(&conn)?;
+        rekey_keyframes(&conn)?;
+        Ok(Index { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Whether the database answers queries.
+    pub fn ping(&self) -> rusqlite::Result<()> {
+        self.conn().query_row("SELECT COUNT(*) FROM videos", [], |_| Ok(()))
+    }
+
+    /// A page of the videos directly inside `dir`, along with how many there
+    /// are in total.
+    pub fn list(&self, dir: &str, listing: &Listing) -> rusqlite::Result<(Vec<Video>, u64)> {
+        // Extensions match as suffixes, which needs no escaping unlike LIKE
+        let mut filter = String::from("dir = ?");
+        let mut params = vec![dir.to_owned()];
+        if !listing.extensions.is_empty() {
+            let suffixes = vec!["substr(lower(filename), -length(?)) = ?"; listing.extensions.len()];
+            filter.push_str(&format!(" AND ({})", suffixes.join(" OR ")));
+            for extension in listing.extensions {
+                let suffix = format!(".{}", extension.trim_start_matches('.').to_lowercase());
+                params.extend([suffix.clone(), suffix]);
+            }
+        }
+
+        let conn = self.conn();
+        let total = conn.prepare_cached(&format!("SELECT COUNT(*) FROM videos WHERE {filter}"))?
+            .query_row(rusqlite::params_from_iter(&params), |row| row.get(0))?;
+
+        let order = if listing.descending { "DESC" } else { "ASC" };
+        let limit = listing.limit.map_or(-1, |limit| limit.min(i64::MAX as u64) as i64);
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT * FROM videos WHERE {filter} ORDER BY {} {order}, filename {order} LIMIT {limit} OFFSET {}",
+            listing.sort.column(), listing.offset.min(i64::MAX as u64)
+        ))?;
+        let videos = stmt.query_map(rusqlite::params_from_iter(&params), Video::from_row)?.collect::<rusqlite::Result<_>>()?;
+        Ok((videos, total))
+    }
+
+    /// Every video in the index, sorted by path.
+    pub fn all(&self) -> rusqlite::Result<Vec<Video>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached("SELECT * FROM videos ORDER BY path")?;
+        let videos = stmt.query_map([], Video::from_row)?.collect();
+        videos
+    }
+
+    pub fn get(&self, path: &str) -> rusqlite::Result<Option<Video>> {
+        self.conn()
+            .query_row("SELECT * FROM videos WHERE path = ?", [path], Video::from_row)
+            .optional()
+    }
+
+    /// The videos passing `filter` that were added to the library last, at
+    /// most `limit` of them.
+    pub fn recent(&self, limit: usize, mut filter: impl FnMut(&Video) -> bool) -> rusqlite::Result<Vec<Video>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached("SELECT * FROM videos ORDER BY added DESC, mtime DESC, path")?;
+        let mut rows = stmt.query([])?;
+        let mut videos = Vec::new();
+        while videos.len() < limit {
+            let Some(row) = rows.next()? else { break };
+            let video = Video::from_row(row)?;
+            if filter(&video) {
+                videos.push(video);
+            }
+        }
+        Ok(videos)
+    }
+
+    /// Counts a play of `path`.
+    pub fn count_play(&self, path: &str) -> rusqlite::Result<()> {
+        let now = unix_now();
+        self.conn().execute(
+            "INSERT INTO plays (path, count, last) VALUES (?, 1, ?)
+                ON CONFLICT (path) DO UPDATE SET count = count + 1, last = excluded.last",
+            params![path, now]
+        )?;
+        Ok(())
+    }
+
+    /// The indexed videos passing `filter` that were played the most, with
+    /// how often, at most `limit` of them.
+    pub fn most_played(&self, limit: usize, mut filter: impl FnMut(&Video) -> bool) -> rusqlite::Result<Vec<(Video, u64)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT videos.*, plays.count AS plays FROM plays JOIN videos USING (path)
+                ORDER BY plays.count DESC, plays.last DESC, path"
+        )?;
+        let mut rows = stmt.query([])?;
+        let mut videos = Vec::new();
+        while videos.len() < limit {
+            let Some(row) = rows.next()? else { break };
+            let video = Video::from_row(row)?;
+            if filter(&video) {
+                videos.push((video, row.get("plays")?));
+            }
+        }
+        Ok(videos)
+    }
+
+    /// Marks an unchanged video as seen by the scan `generation`.
+    pub fn touch(&self, path: &str, generation: u64) -> rusqlite::Result<()> {
+        self.conn().execute("UPDATE videos SET generation = ? WHERE path = ?", params![generation, path])?;
+        Ok(())
+    }
+
+    pub fn upsert(&self, video: &Video, generation: u64) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO videos
+                (path, dir, filename, size, mtime, duration, width, height, video_codec, audio_codec, generation,
+                 title, tags, year, plot, poster, fanart, tmdb, added, sha256, phash)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18,
+                    COALESCE((SELECT added FROM videos WHERE path = ?1), ?11),
+                    COALESCE(?19, (SELECT sha256 FROM videos WHERE path = ?1 AND size = ?4 AND mtime = ?5)),
+                    COALESCE(?20, (SELECT phash FROM videos WHERE path = ?1 AND size = ?4 AND mtime = ?5)))",
+            params![
+                video.path, video.dir(), video.filename, video.size, video.mtime, video.duration,
+                video.width, video.height, video.video_codec, video.audio_codec, generation,
+                video.title, video.tags, video.year, video.plot, video.poster, video.fanart, video.tmdb,
+                video.sha256, video.phash
+            ]
+        )?;
+        Ok(())
+    }
+
+    /// Stores the checksum of `path`, unless it changed from `size` and
+    /// `mtime` since it was hashed.
+    pub fn set_checksum(&self, path: &str, size: u64, mtime: u64, sha256: &str) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "UPDATE videos SET sha256 = ? WHERE path = ? AND size = ? AND mtime = ?",
+            params![sha256, path, size, mtime]
+        )?;
+        Ok(())
+    }
+
+    /// The keyframes probed from the video `path` while it was last modified
+    /// at `mtime`.
+    pub fn keyframes(&self, path: &str, mtime: u64) -> rusqlite::Result<Option<Vec<f64>>> {
+        let times: Option<Vec<u8>> = self.conn().query_row(
+            "SELECT times FROM keyframes WHERE path = ? AND mtime = ?",
+            params![path, mtime],
+            |row| row.get(0)
+        ).optional()?;
+        Ok(times.map(|times| times.chunks_exact(8).map(|time| f64::from_le_bytes(time.try_into().unwrap())).collect()))
+    }
+
+    pub fn set_keyframes(&self, path: &str, mtime: u64, keyframes: &[f64]) -> rusqlite::Result<()> {
+        let times: Vec<u8> = keyframes.iter().flat_map(|time| time.to_le_bytes()).collect();
+        self.conn().execute(
+            "INSERT OR REPLACE INTO keyframes (path, mtime, times) VALUES (?, ?, ?)",
+            params![path, mtime, times]
+        )?;
+        Ok(())
+    }
+
+    /// Stores the perceptual hashes of `path`, unless it changed from `size`
+    /// and `mtime` since they were computed.
+    pub fn set_phash(&self, path: &str, size: u64, mtime: u64, phash: &str) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "UPDATE videos SET phash = ? WHERE path = ? AND size = ? AND mtime = ?",
+            params![phash, path, size, mtime]
+        )?;
+        Ok(())
+    }
+
+    /// Moves a video to `to`, keeping when it was added, how often it was
+    /// played and its keyframes.
+    pub fn move_video(&self, from: &str, to: &str) -> rusqlite::Result<()> {
+        let (dir, filename) = to.rsplit_once('/').unwrap_or(("", to));
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        transaction.execute("DELETE FROM videos WHERE path = ?", [to])?;
+        transaction.execute("UPDATE videos SET path = ?, dir = ?, filename = ? WHERE path = ?", [to, dir, filename, from])?;
+        transaction.execute("UPDATE OR REPLACE plays SET path = ? WHERE path = ?", [to, from])?;
+        transaction.execute("UPDATE OR REPLACE keyframes SET path = ? WHERE path = ?", [to, from])?;
+        transaction.commit()
+    }
+
+    /// Removes a video, or every video below a directory, along with their
+    /// keyframes.
+    pub fn remove(&self, path: &str) -> rusqlite::Result<usize> {
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        transaction.execute("DELETE FROM keyframes WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'", [path])?;
+        let removed = transaction.execute(
+            "DELETE FROM videos WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+            [path]
+        )?;
+        transaction.commit()?;
+        Ok(removed)
+    }
+
+    /// Drops every video that wasn't seen by the scan `generation`, along
+    /// with their keyframes.
+    pub fn prune(&self, generation: u64) -> rusqlite::Result<usize> {
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        transaction.execute("DELETE FROM keyframes WHERE path IN (SELECT path FROM videos WHERE generation != ?)", [generation])?;
+        let pruned = transaction.execute("DELETE FROM videos WHERE generation != ?", [generation])?;
+        transaction.commit()?;
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyframes_follow_videos() {
+        let index = Index::open(Path::new(":memory:")).unwrap();
+        index.set_keyframes("a/b.mp4", 1, &[0.0, 2.5]).unwrap();
+        index.set_keyframes("a/c.mp4", 1, &[0.0]).unwrap();
+
+        index.move_video("a/b.mp4", "d.mp4").unwrap();
+        assert_eq!(index.keyframes("a/b.mp4", 1).unwrap(), None);
+        assert_eq!(index.keyframes("d.mp4", 1).unwrap(), Some(vec![0.0, 2.5]));
+        assert_eq!(index.keyframes("d.mp4", 2).unwrap(), None);
+
+        index.remove("a").unwrap();
+        assert_eq!(index.keyframes("a/c.mp4", 1).unwrap(), None);
+        assert!(index.keyframes("d.mp4", 1).unwrap().is_some());
+    }
+}
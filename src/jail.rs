@@ -0,0 +1,338 @@
+use std::path::{Component, Path, PathBuf};
+
+use axum::response;
+use tokio::fs;
+
+use crate::error::{ApiError, Code};
+use crate::{library, remote, s3, Config};
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The path tries to leave the library, or is outside the folders the
+    /// user has access to.
+    Forbidden,
+    NotFound
+}
+
+impl Error {
+    /// A 403 for paths that escape the library, a 404 with `code` and
+    /// `message` otherwise.
+    pub fn into_response(self, code: Code, message: &'static str) -> response::Response {
+        match self {
+            Error::Forbidden => ApiError::new(Code::Forbidden, "Forbidden").into(),
+            Error::NotFound => ApiError::new(code, message).into()
+        }
+    }
+}
+
+/// Whether `path` is made of plain names only, with no `..`, root or drive
+/// prefix. Backslashes are refused as well, they are separators on Windows
+/// and never appear in legitimate names.
+fn is_plain(path: &Path) -> bool {
+    path.components().all(|component| match component {
+        Component::Normal(name) => name.to_str().is_some_and(|name| !name.contains(['\\', '\0'])),
+        Component::CurDir => true,
+        _ => false
+    })
+}
+
+/// How symlinks inside the library are treated.
+#[derive(Clone, Copy)]
+pub struct Symlinks {
+    /// Whether symlinks are followed at all. When they aren't, they are as
+    /// good as missing.
+    pub follow: bool,
+    /// Whether symlinks may point outside of the library.
+    pub external: bool
+}
+
+impl Symlinks {
+    pub fn new(config: &Config) -> Self {
+        Symlinks { follow: config.follow_symlinks, external: config.external_symlinks }
+    }
+}
+
+/// Whether any component of `path` below `root` is a symlink.
+async fn has_symlink(root: &Path, path: &Path) -> bool {
+    let mut current = root.to_path_buf();
+    for component in path.components() {
+        current.push(component);
+        if fs::symlink_metadata(&current).await.is_ok_and(|metadata| metadata.is_symlink()) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Resolves `path`, as requested by a client, inside `root`. Besides refusing
+/// `..` and absolute paths outright, the result is canonicalized to make sure
+/// that no symlink leads out of the library either, unless `symlinks` allows
+/// it.
+pub async fn resolve(root: &Path, path: impl AsRef<Path>, symlinks: Symlinks) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    if !is_plain(path) {
+        return Err(Error::Forbidden);
+    }
+
+    if !symlinks.follow && has_symlink(root, path).await {
+        return Err(Error::NotFound);
+    }
+
+    let joined = root.join(path);
+    let (Ok(root), Ok(canonical)) = (fs::canonicalize(root).await, fs::canonicalize(&joined).await) else {
+        return Err(Error::NotFound);
+    };
+
+    if symlinks.external || canonical.starts_with(root) {
+        Ok(joined)
+    } else {
+        Err(Error::Forbidden)
+    }
+}
+
+/// Whether the walk over the library should descend into the symlink at
+/// `path`, which must be under the canonical `root`.
+pub async fn follows(config: &Config, root: &Path, path: &Path) -> bool {
+    let symlinks = Symlinks::new(config);
+    if !symlinks.follow {
+        return false;
+    }
+
+    symlinks.external || fs::canonicalize(path).await.is_ok_and(|target| target.starts_with(root))
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.components().any(|component| match component {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => false
+    })
+}
+
+/// Whether `path` is a file that may be served: not hidden, and with one of
+/// the `allowed_extensions`.
+pub fn is_allowed(config: &Config, path: &Path) -> bool {
+    let Some(extension) = path.extension().and_then(|extension| extension.to_str()) else {
+        return false;
+    };
+
+    !is_hidden(path) && config.allowed_extensions.iter().any(|allowed| allowed.eq_ignore_ascii_case(extension))
+}
+
+/// Resolves `path` inside the library named by its first component, or inside
+/// `video_path` when no libraries are declared.
+async fn in_library(config: &Config, path: &Path) -> Result<PathBuf, Error> {
+    if !is_plain(path) {
+        return Err(Error::Forbidden);
+    }
+
+    let (root, relative) = library::locate(config, path).ok_or(Error::NotFound)?;
+    resolve(root.path, relative, Symlinks::new(config)).await
+}
+
+/// Resolves the video at `path` inside the library. Files that aren't
+/// allowed are reported as missing rather than forbidden, so that clients
+/// can't probe for their existence. Remote videos resolve to their URL,
+/// unless a file in the library has the same path.
+pub async fn video(config: &Config, path: impl AsRef<Path>) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    match (local_video(config, path).await, remote::find(config, path)) {
+        (Err(Error::NotFound), Some(remote)) => Ok(remote::resolve(&remote.url)),
+        (resolved, _) => resolved
+    }
+}
+
+async fn local_video(config: &Config, path: &Path) -> Result<PathBuf, Error> {
+    if is_plain(path) && !is_allowed(config, path) {
+        return Err(Error::NotFound);
+    }
+    if let Some((bucket, relative)) = s3::locate(config, path) {
+        return match s3::presign(bucket, &relative).await {
+            Ok(url) => Ok(remote::resolve(&url)),
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to sign URL for `{}`", path.display());
+                Err(Error::NotFound)
+            }
+        };
+    }
+
+    in_library(config, path).await
+}
+
+/// Resolves the directory at `path` inside the library, skipping hidden ones.
+pub async fn directory(config: &Config, path: impl AsRef<Path>) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    if is_plain(path) && is_hidden(path) {
+        return Err(Error::NotFound);
+    }
+
+    in_library(config, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A library with `movie.mp4` and `shows/episode.mkv`, next to a `secret`
+    /// file that must stay out of reach.
+    struct Library {
+        dir: PathBuf
+    }
+
+    impl Library {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("ninja-jail-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("library/shows")).unwrap();
+            std::fs::write(dir.join("library/movie.mp4"), "").unwrap();
+            std::fs::write(dir.join("library/shows/episode.mkv"), "").unwrap();
+            std::fs::write(dir.join("secret"), "").unwrap();
+            Library { dir }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.dir.join("library")
+        }
+
+        async fn resolve(&self, path: &str) -> Result<PathBuf, Error> {
+            resolve(&self.root(), path, Symlinks { follow: true, external: false }).await
+        }
+    }
+
+    impl Drop for Library {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn files_inside() {
+        let library = Library::new("inside");
+        assert_eq!(library.resolve("movie.mp4").await, Ok(library.root().join("movie.mp4")));
+        assert_eq!(library.resolve("shows/episode.mkv").await, Ok(library.root().join("shows/episode.mkv")));
+        assert_eq!(library.resolve("./shows/episode.mkv").await, Ok(library.root().join("shows/episode.mkv")));
+        assert_eq!(library.resolve("shows").await, Ok(library.root().join("shows")));
+    }
+
+    #[tokio::test]
+    async fn missing() {
+        let library = Library::new("missing");
+        assert_eq!(library.resolve("nothing.mp4").await, Err(Error::NotFound));
+        assert_eq!(library.resolve("shows/nothing.mkv").await, Err(Error::NotFound));
+    }
+
+    #[tokio::test]
+    async fn parent_directories() {
+        let library = Library::new("parent");
+        // Percent-encoded dots and slashes (`..%2F`) arrive here decoded
+        assert_eq!(library.resolve("../secret").await, Err(Error::Forbidden));
+        assert_eq!(library.resolve("shows/../../secret").await, Err(Error::Forbidden));
+        assert_eq!(library.resolve("shows/../movie.mp4").await, Err(Error::Forbidden));
+        assert_eq!(library.resolve("..").await, Err(Error::Forbidden));
+        assert_eq!(library.resolve("../../../../../../etc/passwd").await, Err(Error::Forbidden));
+    }
+
+    #[tokio::test]
+    async fn encoded_dots_stay_literal() {
+        let library = Library::new("encoded");
+        // Decoding happens exactly once, a second layer is just an odd name
+        assert_eq!(library.resolve("%2e%2e/secret").await, Err(Error::NotFound));
+        assert_eq!(library.resolve("..%2fsecret").await, Err(Error::NotFound));
+    }
+
+    #[tokio::test]
+    async fn absolute_paths() {
+        let library = Library::new("absolute");
+        assert_eq!(library.resolve("/etc/passwd").await, Err(Error::Forbidden));
+        let secret = library.dir.join("secret");
+        assert_eq!(library.resolve(secret.to_str().unwrap()).await, Err(Error::Forbidden));
+    }
+
+    #[tokio::test]
+    async fn backslashes() {
+        let library = Library::new("backslash");
+        assert_eq!(library.resolve("..\\secret").await, Err(Error::Forbidden));
+        assert_eq!(library.resolve("shows\\episode.mkv").await, Err(Error::Forbidden));
+        assert_eq!(library.resolve("\\etc\\passwd").await, Err(Error::Forbidden));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_out_of_the_library() {
+        let library = Library::new("symlink");
+        std::os::unix::fs::symlink(library.dir.join("secret"), library.root().join("link.mp4")).unwrap();
+        std::os::unix::fs::symlink(library.root().join("movie.mp4"), library.root().join("alias.mp4")).unwrap();
+        assert_eq!(library.resolve("link.mp4").await, Err(Error::Forbidden));
+        assert_eq!(library.resolve("alias.mp4").await, Ok(library.root().join("alias.mp4")));
+
+        let strict = Symlinks { follow: false, external: false };
+        assert_eq!(resolve(&library.root(), "alias.mp4", strict).await, Err(Error::NotFound));
+        assert_eq!(resolve(&library.root(), "movie.mp4", strict).await, Ok(library.root().join("movie.mp4")));
+
+        let permissive = Symlinks { follow: true, external: true };
+        assert_eq!(resolve(&library.root(), "link.mp4", permissive).await, Ok(library.root().join("link.mp4")));
+        assert_eq!(resolve(&library.root(), "../secret", permissive).await, Err(Error::Forbidden));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinked_directories() {
+        let library = Library::new("symlink-dir");
+        std::fs::create_dir(library.dir.join("drive")).unwrap();
+        std::fs::write(library.dir.join("drive/film.mp4"), "").unwrap();
+        std::os::unix::fs::symlink(library.dir.join("drive"), library.root().join("drive")).unwrap();
+
+        assert_eq!(library.resolve("drive/film.mp4").await, Err(Error::Forbidden));
+        let strict = Symlinks { follow: false, external: true };
+        assert_eq!(resolve(&library.root(), "drive/film.mp4", strict).await, Err(Error::NotFound));
+        let permissive = Symlinks { follow: true, external: true };
+        assert_eq!(resolve(&library.root(), "drive/film.mp4", permissive).await, Ok(library.root().join("drive/film.mp4")));
+    }
+
+    #[tokio::test]
+    async fn named_libraries() {
+        let library = Library::new("named");
+        let config = Config {
+            libraries: Box::new([crate::library::Library {
+                name: "movies".into(),
+                path: library.root().into(),
+                read_only: false,
+                renditions: None,
+                users: Box::new([])
+            }]),
+            ..Config::default()
+        };
+
+        assert_eq!(video(&config, "movies/movie.mp4").await, Ok(library.root().join("movie.mp4")));
+        assert_eq!(directory(&config, "movies/shows").await, Ok(library.root().join("shows")));
+        assert_eq!(directory(&config, "movies").await, Ok(library.root().join("")));
+        assert_eq!(video(&config, "movie.mp4").await, Err(Error::NotFound));
+        assert_eq!(video(&config, "shows/movie.mp4").await, Err(Error::NotFound));
+        assert_eq!(video(&config, "movies/../secret.mp4").await, Err(Error::Forbidden));
+    }
+
+    #[tokio::test]
+    async fn remotes_behind_local_files() {
+        let library = Library::new("remotes");
+        let remote = |name: &str| crate::remote::Remote { name: name.into(), url: format!("https://example.com/{name}").into() };
+        let config = Config {
+            video_path: library.root().into(),
+            remotes: Box::new([remote("movie.mp4"), remote("trailer")]),
+            ..Config::default()
+        };
+
+        assert_eq!(video(&config, "movie.mp4").await, Ok(library.root().join("movie.mp4")));
+        assert_eq!(video(&config, "trailer").await, Ok(PathBuf::from("https://example.com/trailer")));
+    }
+
+    #[test]
+    fn allowed_extensions() {
+        let config = Config::default();
+        assert!(is_allowed(&config, Path::new("movie.mp4")));
+        assert!(is_allowed(&config, Path::new("shows/Episode.MKV")));
+        assert!(!is_allowed(&config, Path::new("config.toml.bak")));
+        assert!(!is_allowed(&config, Path::new("movie.mp4.part")));
+        assert!(!is_allowed(&config, Path::new("README")));
+        assert!(!is_allowed(&config, Path::new(".hidden.mp4")));
+        assert!(!is_allowed(&config, Path::new(".trash/movie.mp4")));
+    }
+}
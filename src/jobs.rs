@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::{extract, http, response, Json};
+use futures_util::stream;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::sync::{watch, Semaphore};
+use tokio::{fs, process::Command, time};
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{ApiError, Code};
+use crate::users::User;
+use crate::{auth, ffmpeg, hls, jail, library, probe, transcode, url, webhooks, App, Config};
+
+/// Progress events are sent at most this often, ffmpeg reports every value on
+/// its own line.
+const EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What to transcode. The output is always an MP4 at one of the configured
+/// renditions, optionally trimmed to `start..end` and encoded with the
+/// settings of a `profile`.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct Spec {
+    pub video: Box<str>,
+    pub rendition: Box<str>,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub audio: Option<u32>,
+    pub profile: Option<Box<str>>,
+    /// Overrides the `tonemap` setting.
+    pub tonemap: Option<bool>
+}
+
+impl Spec {
+    fn options(&self) -> transcode::Options {
+        transcode::Options { audio: self.audio, profile: self.profile.clone(), tonemap: self.tonemap, ..Default::default() }
+    }
+}
+
+/// The profile of a job, if it asks for one. Profiles with their own `args`
+/// only stream, so they can't write a job's file.
+fn profile<'a>(config: &'a Config, options: &transcode::Options) -> Result<Option<&'a transcode::Profile>, (Code, &'static str)> {
+    match options.profile(config, false) {
+        Ok(Some(profile)) if profile.args.is_some() => Err((Code::BadRequest, "Profile can't be used for jobs")),
+        Ok(profile) => Ok(profile),
+        Err(()) => Err((Code::ProfileNotFound, "Profile not found"))
+    }
+}
+
+#[derive(serde::Serialize, Clone, Default)]
+#[serde(rename_all = "lowercase", tag = "state")]
+pub enum State {
+    #[default]
+    Queued,
+    Running,
+    Completed,
+    Failed { error: Box<str> },
+    Cancelled
+}
+
+impl State {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, State::Completed | State::Failed { .. } | State::Cancelled)
+    }
+}
+
+/// Progress as reported by ffmpeg's `-progress` output.
+#[derive(serde::Serialize, Clone, Default)]
+pub struct Progress {
+    pub percent: f64,
+    pub fps: f64,
+    pub speed: f64,
+    pub eta: Option<f64>
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct Status {
+    pub id: u64,
+    #[serde(flatten)]
+    pub state: State,
+    pub progress: Progress
+}
+
+pub struct Job {
+    pub id: u64,
+    pub spec: Spec,
+    pub status: watch::Sender<Status>,
+    cancel: CancellationToken
+}
+
+impl Job {
+    fn set_state(&self, state: State) {
+        self.status.send_modify(|status| status.state = state);
+    }
+
+    fn to_response(&self) -> response::Response {
+        response::IntoResponse::into_response(Json(JobResponse {
+            spec: &self.spec,
+            status: self.status.borrow().clone()
+        }))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct JobResponse<'a> {
+    #[serde(flatten)]
+    spec: &'a Spec,
+    #[serde(flatten)]
+    status: Status
+}
+
+/// Transcodes run in the background, at most `max_jobs` at a time.
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<u64, Arc<Job>>>,
+    workers: Semaphore
+}
+
+impl Jobs {
+    pub fn new(max_jobs: usize) -> Self {
+        Jobs {
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(HashMap::new()),
+            workers: Semaphore::new(max_jobs)
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// The job `id`, if `user` can access its video.
+    fn get_for(&self, app: &App, user: Option<&User>, id: u64) -> Option<Arc<Job>> {
+        self.get(id).filter(|job| user.is_none_or(|user| auth::can_access(&app.config, user, &job.spec.video)))
+    }
+
+    /// Cancels every queued and running job, and waits for them to stop.
+    pub async fn cancel_all(&self) {
+        let jobs: Vec<_> = self.jobs.lock().unwrap().values().cloned().collect();
+        for job in &jobs {
+            job.cancel.cancel();
+        }
+        for job in jobs {
+            let _ = job.status.subscribe().wait_for(|status| status.state.is_finished()).await;
+        }
+    }
+
+    fn enqueue(&self, spec: Spec) -> Arc<Job> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (status, _) = watch::channel(Status { id, state: State::Queued, progress: Progress::default() });
+        let job = Arc::new(Job { id, spec, status, cancel: CancellationToken::new() });
+        self.jobs.lock().unwrap().insert(id, job.clone());
+        job
+    }
+}
+
+fn output_path(app: &App, id: u64) -> PathBuf {
+    app.config.cache_path.join("jobs").join(format!("{id}.mp4"))
+}
+
+/// Applies one `key=value` line of ffmpeg's progress, returning whether it
+/// was one of the values tracked.
+fn update_progress(progress: &mut Progress, key: &str, value: &str, duration: f64) -> bool {
+    match key {
+        "out_time_us" | "out_time_ms" => if let Ok(time) = value.parse::<f64>() {
+            // Despite its name, `out_time_ms` is in microseconds as well
+            let time = time / 1_000_000.0;
+            if duration > 0.0 {
+                progress.percent = f64::min(100.0, time / duration * 100.0);
+            }
+            if progress.speed > 0.0 {
+                progress.eta = Some(f64::max(0.0, (duration - time) / progress.speed));
+            }
+        },
+        "fps" => progress.fps = value.parse().unwrap_or(progress.fps),
+        "speed" => progress.speed = value.trim_end_matches('x').trim().parse().unwrap_or(progress.speed),
+        _ => return false
+    }
+    true
+}
+
+async fn transcode(app: &App, job: &Job) -> Result<(), Box<str>> {
+    let spec = &job.spec;
+    let config = &app.config;
+    let video_path = library::file(config, &*spec.video).ok_or("Video not found")?;
+    let rendition = library::renditions(config, Path::new(&*spec.video)).iter()
+        .find(|rendition| rendition.name == spec.rendition)
+        .ok_or("Rendition not found")?;
+
+    let summary = probe::summary(config, &video_path).await.ok_or("Failed to probe video")?;
+    let duration = summary.duration;
+    let start = spec.start.unwrap_or(0.0);
+    let end = spec.end.map_or(duration, |end| f64::min(end, duration));
+
+    let output = output_path(app, job.id);
+    let partial = output.with_extension("mp4.part");
+    fs::create_dir_all(output.parent().unwrap()).await.map_err(|err| err.to_string())?;
+
+    // Counts toward the global ffmpeg limit, but waits for as long as it takes
+    // instead of failing like interactive requests do.
+    let _ffmpeg = app.ffmpeg.wait().await;
+
+    let options = spec.options();
+    let profile = profile(config, &options).map_err(|(_, message)| message)?;
+    let scale = format!("scale=-2:{}", rendition.height);
+    let codec = profile.map_or(transcode::Codec::H264, |profile| profile.codec);
+    let filter = transcode::video_filter(config, &video_path, &options, start, &scale, codec).await.ok_or("Failed to build filter")?;
+    let encode_args = match profile {
+        Some(profile) => profile.encode_args(config),
+        None => config.hwaccel.encode_args().map(String::from).into()
+    };
+    let (video_bitrate, audio_bitrate) = hls::bitrates(rendition, profile);
+    let mut child = ffmpeg::spawn(Command::new(&*config.ffmpeg_command)
+        .args(["-v", "error", "-nostats", "-progress", "pipe:1", "-y"])
+        .args(config.hwaccel.decode_args())
+        .args([
+            "-ss", &start.to_string(),
+            "-t", &(end - start).to_string(),
+            "-i", video_path.to_str().unwrap(),
+            "-filter_complex", &filter,
+            "-map", "[v]",
+            "-map", &options.audio_map("0:a:0?")
+        ])
+        .args(encode_args)
+        .args(["-b:v", &format!("{video_bitrate}k")])
+        .args(profile.map_or(&[][..], |profile| profile.audio_filter_args()))
+        .args([
+            "-c:a", "aac",
+            "-b:a", &format!("{audio_bitrate}k"),
+            "-movflags", "+faststart",
+            "-f", "mp4"
+        ])
+        .arg(&partial)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+    ).map_err(|err| err.to_string())?;
+
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = tokio::spawn(async move {
+        let mut message = String::new();
+        let _ = stderr.read_to_string(&mut message).await;
+        message
+    });
+
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let total = end - start;
+    loop {
+        // ffmpeg reports progress every half a second or so, silence means
+        // it got stuck
+        tokio::select! {
+            line = time::timeout(app.ffmpeg.timeout, lines.next_line()) => match line {
+                Ok(Ok(Some(line))) => if let Some((key, value)) = line.split_once('=') {
+                    job.status.send_if_modified(|status| update_progress(&mut status.progress, key, value, total));
+                },
+                Ok(_) => break,
+                Err(_) => {
+                    drop(child);
+                    let _ = fs::remove_file(&partial).await;
+                    return Err(ffmpeg::Error::Timeout.to_string().into());
+                }
+            },
+            _ = job.cancel.cancelled() => {
+                let _ = child.kill().await;
+                let _ = fs::remove_file(&partial).await;
+                return Err("Cancelled".into());
+            }
+        }
+    }
+
+    let exit = child.wait().await.map_err(|err| err.to_string())?;
+    if !exit.success() {
+        let _ = fs::remove_file(&partial).await;
+        return Err(stderr.await.unwrap_or_default().trim().into());
+    }
+
+    fs::rename(&partial, &output).await.map_err(|err| err.to_string().into())
+}
+
+async fn run(app: &'static App, job: Arc<Job>) {
+    let _permit = tokio::select! {
+        permit = app.jobs.workers.acquire() => permit.unwrap(),
+        _ = job.cancel.cancelled() => {
+            job.set_state(State::Cancelled);
+            return;
+        }
+    };
+
+    job.set_state(State::Running);
+    match transcode(app, &job).await {
+        Ok(()) => {
+            job.status.send_modify(|status| {
+                status.state = State::Completed;
+                status.progress.percent = 100.0;
+                status.progress.eta = Some(0.0);
+            });
+        }
+        Err(_) if job.cancel.is_cancelled() => {
+            job.set_state(State::Cancelled);
+            return;
+        }
+        Err(error) => {
+            tracing::error!(%error, "Job {} failed", job.id);
+            job.set_state(State::Failed { error });
+        }
+    }
+
+    let error = match &job.status.borrow().state {
+        State::Failed { error } => Some(error.clone()),
+        _ => None
+    };
+    webhooks::emit(app, webhooks::Event::JobFinished { id: job.id, video: &job.spec.video, rendition: &job.spec.rendition, error: error.as_deref() });
+}
+
+fn not_found() -> response::Response {
+    ApiError::new(Code::JobNotFound, "Job not found").into()
+}
+
+/// Why a job can't be queued.
+pub enum Invalid {
+    Rendition,
+    Profile(Code, &'static str),
+    Video(jail::Error)
+}
+
+impl From<Invalid> for crate::Error {
+    fn from(invalid: Invalid) -> Self {
+        match invalid {
+            Invalid::Rendition => crate::Error::RenditionNotFound,
+            Invalid::Profile(_, message) => crate::Error::Profile(message),
+            Invalid::Video(_) => crate::Error::VideoNotFound
+        }
+    }
+}
+
+/// Checks that the rendition of `spec` applies to its video, that its
+/// profile can be used, and that the video exists.
+pub async fn validate(app: &App, spec: &Spec) -> Result<(), Invalid> {
+    if !library::renditions(&app.config, Path::new(&*spec.video)).iter().any(|rendition| rendition.name == spec.rendition) {
+        return Err(Invalid::Rendition);
+    }
+    profile(&app.config, &spec.options()).map_err(|(code, message)| Invalid::Profile(code, message))?;
+    jail::video(&app.config, &*spec.video).await.map_err(Invalid::Video)?;
+    Ok(())
+}
+
+/// Queues `spec`, to run once a worker is free.
+pub fn start(app: &'static App, spec: Spec) -> Arc<Job> {
+    let job = app.jobs.enqueue(spec);
+    tokio::spawn(run(app, job.clone()));
+    job
+}
+
+pub async fn create_job(
+    extract::State(app): extract::State<&'static App>,
+    user: Option<extract::Extension<User>>,
+    Json(spec): Json<Spec>
+) -> response::Response {
+    if user.is_some_and(|extract::Extension(user)| !auth::can_access(&app.config, &user, &spec.video)) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+
+    match validate(app, &spec).await {
+        Ok(()) => {}
+        Err(Invalid::Rendition) => {
+            return ApiError::new(Code::RenditionNotFound, "Rendition not found").into();
+        }
+        Err(Invalid::Profile(code, message)) => return ApiError::new(code, message).into(),
+        Err(Invalid::Video(err)) => return err.into_response(Code::VideoNotFound, "Video not found")
+    }
+
+    let job = start(app, spec);
+
+    let mut response = job.to_response();
+    *response.status_mut() = http::StatusCode::ACCEPTED;
+    response.headers_mut().insert(http::header::LOCATION, url::path(&app.config, &format!("/jobs/{}", job.id)).parse().unwrap());
+    response
+}
+
+pub async fn list_jobs(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    let jobs = app.jobs.jobs.lock().unwrap();
+    let mut statuses: Vec<_> = jobs.values()
+        .filter(|job| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, &job.spec.video)))
+        .map(|job| JobResponse { spec: &job.spec, status: job.status.borrow().clone() })
+        .collect();
+    statuses.sort_by_key(|job| job.status.id);
+
+    response::IntoResponse::into_response(Json(statuses))
+}
+
+pub async fn get_job(
+    extract::Path((id, )): extract::Path<(u64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    match app.jobs.get_for(app, user.as_deref(), id) {
+        Some(job) => job.to_response(),
+        None => not_found()
+    }
+}
+
+/// Cancels a queued or running job. Finished jobs are forgotten along with
+/// their output.
+pub async fn delete_job(
+    extract::Path((id, )): extract::Path<(u64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    let Some(job) = app.jobs.get_for(app, user.as_deref(), id) else {
+        return not_found();
+    };
+
+    if job.status.borrow().state.is_finished() {
+        app.jobs.jobs.lock().unwrap().remove(&id);
+        let _ = fs::remove_file(output_path(app, id)).await;
+    } else {
+        job.cancel.cancel();
+    }
+
+    response::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
+
+/// Streams the status of a job as server-sent events: a `progress` event
+/// whenever it changes, and a last `done` event once the job has finished.
+pub async fn job_events(
+    extract::Path((id, )): extract::Path<(u64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    let Some(job) = app.jobs.get_for(app, user.as_deref(), id) else {
+        return not_found();
+    };
+
+    let events = stream::unfold((Some(job.status.subscribe()), true), |(receiver, first)| async move {
+        let mut receiver = receiver?;
+        if !first {
+            time::sleep(EVENT_INTERVAL).await;
+            // The sender is gone once the job is deleted
+            receiver.changed().await.ok()?;
+        }
+
+        let status = receiver.borrow_and_update().clone();
+        let finished = status.state.is_finished();
+        let event = Event::default().event(if finished { "done" } else { "progress" }).json_data(status);
+        Some((event, ((!finished).then_some(receiver), false)))
+    });
+
+    response::IntoResponse::into_response(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+pub async fn serve_output(
+    extract::Path((id, )): extract::Path<(u64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    let Some(job) = app.jobs.get_for(app, user.as_deref(), id) else {
+        return not_found();
+    };
+
+    if !matches!(job.status.borrow().state, State::Completed) {
+        return ApiError::new(Code::JobNotCompleted, "Job hasn't completed").into();
+    }
+
+    let file = match fs::File::open(output_path(app, id)).await {
+        Ok(file) => file,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to open output of job {id}");
+            return not_found();
+        }
+    };
+
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "video/mp4")
+        .body(axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles() {
+        let raw = transcode::Profile {
+            name: "raw".into(),
+            codec: transcode::Codec::H264,
+            height: None,
+            video_bitrate: None,
+            audio_bitrate: None,
+            preset: None,
+            loudnorm: false,
+            container: transcode::Container::Mkv,
+            args: Some(ffmpeg::Template::parse("-i {input} -c copy -f matroska {output}").unwrap())
+        };
+        let mut config = Config::default();
+        config.profiles = config.profiles.into_vec().into_iter().chain([raw]).collect();
+        let options = |name: Option<&str>| transcode::Options { profile: name.map(Into::into), ..Default::default() };
+
+        assert!(matches!(profile(&config, &options(None)), Ok(None)));
+        assert!(matches!(profile(&config, &options(Some("low"))), Ok(Some(profile)) if &*profile.name == "low"));
+        assert!(matches!(profile(&config, &options(Some("raw"))), Err((Code::BadRequest, _))));
+        assert!(matches!(profile(&config, &options(Some("missing"))), Err((Code::ProfileNotFound, _))));
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use axum::{extract, response, Json};
+
+use crate::error::{ApiError, Code};
+use crate::{cache, jail, probe, App};
+
+/// The keyframes of `video`, at `path`, probed once per version of the file
+/// and kept in the index next to it.
+pub async fn get(app: &App, video: &Path, path: &Path) -> Option<Arc<[f64]>> {
+    let (key, mtime) = cache::source_key(path, "keyframes").await?;
+    let mtime = mtime.duration_since(UNIX_EPOCH).map_or(0, |mtime| mtime.as_secs());
+    let video = video.to_str()?.trim_matches('/');
+    match app.index.keyframes(video, mtime) {
+        Ok(Some(keyframes)) => return Some(keyframes.into()),
+        Ok(None) => {}
+        Err(err) => tracing::error!(error = %err, "Failed to read keyframes of `{video}`")
+    }
+
+    // Reading every packet of a movie takes a while, a player asking for the
+    // playlist and the keyframes at once shouldn't do it twice
+    app.keyframes.run(&key, async {
+        let keyframes: Arc<[f64]> = probe::keyframes(&app.config, path).await?.into();
+        if let Err(err) = app.index.set_keyframes(video, mtime, &keyframes) {
+            tracing::error!(error = %err, "Failed to store keyframes of `{video}`");
+        }
+        Some(keyframes)
+    }).await
+}
+
+/// Segment start times, cutting on the first keyframe at least `target`
+/// seconds after the previous cut. The first segment starts at zero wherever
+/// the first keyframe is.
+pub fn segments(keyframes: &[f64], target: f64, duration: f64) -> Vec<f64> {
+    let mut starts = vec![0.0];
+    for &keyframe in keyframes.iter().take_while(|&&keyframe| keyframe < duration) {
+        if keyframe - starts.last().unwrap() >= target {
+            starts.push(keyframe);
+        }
+    }
+    starts
+}
+
+pub async fn serve_keyframes(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let video_path = match jail::video(&app.config, &video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    match get(app, &video, &video_path).await {
+        Some(keyframes) => response::IntoResponse::into_response(Json(&*keyframes)),
+        None => ApiError::new(Code::VideoNotFound, "Video not found").into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cuts() {
+        let keyframes = [0.5, 2.0, 4.0, 6.5, 7.0, 13.0, 20.0];
+        assert_eq!(segments(&keyframes, 6.0, 20.0), [0.0, 6.5, 13.0]);
+        assert_eq!(segments(&keyframes, 2.0, 21.0), [0.0, 2.0, 4.0, 6.5, 13.0, 20.0]);
+        assert_eq!(segments(&[], 6.0, 20.0), [0.0]);
+    }
+}
@@ -0,0 +1,971 @@
+use std::{fmt, path::Path};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{body::Bytes, extract, http, middleware, response, routing, Router};
+use futures_util::{stream, StreamExt};
+use tokio::{fs, process::Command};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, self};
+use tokio_util::io::ReaderStream;
+
+use error::{ApiError, Code};
+
+mod access_log;
+mod audio;
+mod auth;
+mod cache;
+mod cast;
+mod checksum;
+#[cfg(feature = "remote")]
+mod chunks;
+mod clip;
+mod coalesce;
+mod collections;
+mod conditional;
+mod cors;
+mod dlna;
+mod duplicates;
+mod download;
+mod encoding;
+mod environment;
+mod error;
+mod favorites;
+mod feed;
+mod files;
+mod ffmpeg;
+mod forwarded;
+mod frame;
+mod health;
+mod home;
+mod hls;
+#[cfg(feature = "http3")]
+mod http3;
+mod hwaccel;
+mod index;
+mod jail;
+mod jobs;
+mod keyframes;
+mod library;
+mod live;
+mod logging;
+mod m3u;
+mod mime;
+mod nfo;
+mod openapi;
+mod party;
+mod playlists;
+mod probe;
+mod progress;
+mod range;
+mod rate_limit;
+mod reload;
+mod rtmp;
+mod remote;
+mod s3;
+mod scanner;
+mod scheduler;
+mod search;
+mod server;
+mod shares;
+mod storyboard;
+mod streams;
+mod subtitles;
+mod thumb;
+mod throttle;
+#[cfg(feature = "tmdb")]
+mod tmdb;
+mod trace;
+mod transcode;
+mod trash;
+mod tus;
+mod ui;
+mod upload;
+mod url;
+mod users;
+mod watcher;
+mod waveform;
+mod webhooks;
+mod zip;
+
+pub use index::Video;
+pub use jobs::{Progress as JobProgress, Spec as JobSpec, State as JobState, Status as JobStatus};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub video_path: Box<Path>,
+    #[serde(rename = "library")]
+    libraries: Box<[library::Library]>,
+    #[serde(rename = "remote")]
+    remotes: Box<[remote::Remote]>,
+    #[serde(rename = "bucket")]
+    buckets: Box<[s3::Bucket]>,
+    live: Box<[live::Stream]>,
+    pub ip: IpAddr,
+    pub port: u16,
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Box<[Box<str>]>,
+    socket_mode: u32,
+    tls_cert: Option<Box<Path>>,
+    tls_key: Option<Box<Path>>,
+    redirect_port: Option<u16>,
+    h2c: bool,
+    http3: bool,
+    shutdown_timeout: u64,
+    trusted_proxies: Box<[IpAddr]>,
+    base_path: Box<str>,
+    cors: cors::Cors,
+    log: logging::Log,
+    telemetry: trace::Telemetry,
+    access_log: access_log::AccessLog,
+    rate_limit: rate_limit::RateLimit,
+    api_keys: Box<[Box<str>]>,
+    session_lifetime: u64,
+    access: BTreeMap<Box<str>, Box<[Box<str>]>>,
+    admins: Box<[Box<str>]>,
+    chunk_size: u64,
+    max_stream_bitrate: u64,
+    max_streams: usize,
+    ffmpeg_command: Box<str>,
+    ffprobe_command: Box<str>,
+    index_path: Box<Path>,
+    cache_path: Box<Path>,
+    scan_interval: u64,
+    scan_thumbnails: bool,
+    scan_checksums: bool,
+    /// Hashes frames sampled from each video during scans, for `/duplicates`.
+    scan_phashes: bool,
+    thumbnail_height: u32,
+    storyboard_interval: u32,
+    /// Replaces the arguments of `/frame`, which then ignores the requested
+    /// size and quality, and `precise` as the template decides how to seek.
+    frame_args: Option<ffmpeg::Template>,
+    max_clip_duration: u32,
+    max_upload_size: u64,
+    trash_retention: u64,
+    max_jobs: usize,
+    max_ffmpeg_jobs: usize,
+    ffmpeg_queue_timeout: u64,
+    ffmpeg_timeout: u64,
+    frame_cache_size: u64,
+    segment_cache_size: u64,
+    remote_cache_size: u64,
+    hwaccel: hwaccel::HwAccel,
+    /// Tone map HDR sources to SDR when transcoding, unless `?tonemap=0`.
+    tonemap: bool,
+    dlna: dlna::Dlna,
+    watch: bool,
+    follow_symlinks: bool,
+    external_symlinks: bool,
+    tmdb_api_key: Option<Box<str>>,
+    tmdb_language: Box<str>,
+    allowed_extensions: Box<[Box<str>]>,
+    remux_extensions: Box<[Box<str>]>,
+    segment_duration: u32,
+    /// Cut HLS segments on the keyframes of the source rather than every
+    /// `segment_duration` seconds. Finding them reads each video once.
+    keyframe_segments: bool,
+    renditions: Box<[Rendition]>,
+    #[serde(rename = "profile")]
+    profiles: Box<[transcode::Profile]>,
+    /// Profile for HLS requests that don't pick one. Direct play is only ever
+    /// transcoded when a profile is asked for.
+    default_profile: Option<Box<str>>,
+    #[serde(rename = "task")]
+    tasks: Box<[scheduler::Task]>,
+    #[serde(rename = "webhook")]
+    webhooks: Box<[webhooks::Webhook]>
+}
+
+impl Config {
+    /// Parses a configuration file, with the `NINJA_` environment variables
+    /// layered over it.
+    pub fn parse(config_str: &str) -> Result<Config, String> {
+        environment::parse(config_str)
+    }
+}
+
+/// Seconds since the Unix epoch, as the stores record times.
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+/// Accepts a single string where a list is expected, like `listen = "[::]:3000"`.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Box<[Box<str>]>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Box<str>),
+        Many(Box<[Box<str>]>)
+    }
+
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        OneOrMany::One(one) => Box::new([one]),
+        OneOrMany::Many(many) => many
+    })
+}
+
+/// A single variant of the HLS bitrate ladder. Bitrates are in kbit/s.
+#[derive(serde::Serialize, serde::Deserialize, Hash)]
+struct Rendition {
+    name: Box<str>,
+    height: u32,
+    video_bitrate: u32,
+    audio_bitrate: u32
+}
+
+impl Rendition {
+    fn new(name: &str, height: u32, video_bitrate: u32, audio_bitrate: u32) -> Self {
+        Rendition { name: name.into(), height, video_bitrate, audio_bitrate }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            video_path: Path::new("videos/").into(),
+            libraries: Box::new([]),
+            remotes: Box::new([]),
+            buckets: Box::new([]),
+            live: Box::new([]),
+            ip: [0, 0, 0, 0].into(),
+            port: 3000,
+            listen: Box::new([]),
+            socket_mode: 0o660,
+            tls_cert: None,
+            tls_key: None,
+            redirect_port: None,
+            h2c: false,
+            http3: false,
+            shutdown_timeout: 30,
+            trusted_proxies: Box::new([]),
+            base_path: "".into(),
+            cors: cors::Cors::default(),
+            log: logging::Log::default(),
+            telemetry: trace::Telemetry::default(),
+            access_log: access_log::AccessLog::default(),
+            rate_limit: rate_limit::RateLimit::default(),
+            api_keys: Box::new([]),
+            session_lifetime: 30 * 24 * 3600,
+            access: BTreeMap::new(),
+            admins: Box::new([]),
+            chunk_size: 65536,
+            max_stream_bitrate: 0,
+            max_streams: 0,
+            ffmpeg_command: "ffmpeg".into(),
+            ffprobe_command: "ffprobe".into(),
+            index_path: Path::new("ninja.db").into(),
+            cache_path: Path::new("cache/").into(),
+            scan_interval: 3600,
+            scan_thumbnails: true,
+            scan_checksums: false,
+            scan_phashes: false,
+            thumbnail_height: 360,
+            storyboard_interval: 10,
+            frame_args: None,
+            max_clip_duration: 600,
+            max_upload_size: 0,
+            trash_retention: 30 * 24 * 3600,
+            max_jobs: 1,
+            max_ffmpeg_jobs: 4,
+            ffmpeg_queue_timeout: 10,
+            ffmpeg_timeout: 300,
+            frame_cache_size: 256,
+            segment_cache_size: 4096,
+            remote_cache_size: 1024,
+            hwaccel: hwaccel::HwAccel::default(),
+            tonemap: false,
+            dlna: dlna::Dlna::default(),
+            watch: true,
+            follow_symlinks: true,
+            external_symlinks: false,
+            tmdb_api_key: None,
+            tmdb_language: "en-US".into(),
+            allowed_extensions: [
+                "mp4", "m4v", "mkv", "webm", "mov", "avi", "ts", "m2ts", "mpg", "mpeg", "ogv", "flv", "wmv",
+                "mp3", "m4a", "flac", "ogg", "opus", "wav"
+            ].map(Into::into).into(),
+            remux_extensions: ["mkv", "avi", "ts"].map(Into::into).into(),
+            segment_duration: 6,
+            keyframe_segments: false,
+            renditions: [
+                Rendition::new("1080p", 1080, 6000, 192),
+                Rendition::new("720p", 720, 3000, 128),
+                Rendition::new("480p", 480, 1000, 96)
+            ].into(),
+            profiles: [
+                transcode::Profile {
+                    name: "low".into(),
+                    codec: transcode::Codec::H264,
+                    height: Some(480),
+                    video_bitrate: Some(800),
+                    audio_bitrate: Some(96),
+                    preset: None,
+                    loudnorm: false,
+                    container: transcode::Container::Mp4,
+                    args: None
+                }
+            ].into(),
+            default_profile: None,
+            tasks: [].into(),
+            webhooks: [].into()
+        }
+    }
+}
+
+struct App {
+    config: reload::Live,
+    index: index::Index,
+    users: users::Users,
+    shares: shares::Shares,
+    collections: collections::Collections,
+    playlists: playlists::Playlists,
+    progress: progress::Store,
+    favorites: favorites::Favorites,
+    #[cfg(feature = "tmdb")]
+    tmdb: tmdb::Tmdb,
+    #[cfg(feature = "remote")]
+    remote: reqwest::Client,
+    #[cfg(feature = "remote")]
+    chunks: chunks::Chunks,
+    limiter: rate_limit::Limiter,
+    streams: streams::Streams,
+    parties: party::Parties,
+    cast: cast::Devices,
+    uploads: tus::Uploads,
+    access_log: access_log::Writer,
+    jobs: jobs::Jobs,
+    ffmpeg: ffmpeg::Ffmpeg,
+    frames: cache::Lru,
+    segments: cache::Lru,
+    inflight: coalesce::Coalescer<Result<axum::body::Bytes, ffmpeg::Error>>,
+    keyframes: coalesce::Coalescer<Option<Arc<[f64]>>>,
+    tasks: scheduler::Tasks,
+    #[cfg(feature = "webhooks")]
+    webhooks: reqwest::Client
+}
+
+impl extract::FromRef<&'static App> for &'static Config {
+    fn from_ref(app: &&'static App) -> Self {
+        app.config.get()
+    }
+}
+
+/// What can keep the server from starting, or a service from doing its work.
+#[derive(Debug)]
+pub enum Error {
+    /// One of the databases in `index_path` couldn't be opened or queried.
+    Database(&'static str, rusqlite::Error),
+    Password(argon2::password_hash::Error),
+    Cors(String),
+    VideoNotFound,
+    RenditionNotFound,
+    /// The profile doesn't exist or can't be used for the job.
+    Profile(&'static str)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Database(action, err) => write!(f, "Failed to {action}: {err}"),
+            Error::Password(err) => write!(f, "Failed to hash password: {err}"),
+            Error::Cors(err) => write!(f, "Invalid CORS configuration: {err}"),
+            Error::VideoNotFound => f.write_str("Video not found"),
+            Error::RenditionNotFound => f.write_str("Rendition not found"),
+            Error::Profile(message) => f.write_str(message)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Logs the settings that are ignored, or that ask for features ninja wasn't
+/// built with.
+fn check(config: &Config) {
+    #[cfg(not(feature = "tmdb"))]
+    if config.tmdb_api_key.is_some() {
+        tracing::error!("A TMDB API key is set, but ninja was built without the `tmdb` feature");
+    }
+    for remote in config.remotes.iter().filter(|remote| !remote.is_valid()) {
+        tracing::error!("Ignoring remote `{}`, its URL isn't HTTP", remote.name);
+    }
+    #[cfg(not(feature = "remote"))]
+    if !config.remotes.is_empty() {
+        tracing::warn!("Remote videos are remuxed by ffmpeg, as ninja was built without the `remote` feature");
+    }
+    for profile in config.profiles.iter().filter(|profile| !profile.is_valid()) {
+        tracing::error!("Ignoring profile `{}`, its name isn't URL safe or WebM doesn't take its codec", profile.name);
+    }
+    if let Some(name) = &config.default_profile {
+        if !config.profiles.iter().any(|profile| profile.name == *name) {
+            tracing::error!("The default profile `{name}` isn't declared");
+        }
+    }
+    for (index, task) in config.tasks.iter().enumerate() {
+        if config.tasks[..index].iter().any(|other| other.name() == task.name()) {
+            tracing::error!("Several tasks are named `{}`, only one of them runs at a time", task.name());
+        }
+    }
+    for webhook in config.webhooks.iter().filter(|webhook| !webhook.is_valid()) {
+        tracing::error!("Ignoring webhook `{}`, its URL isn't HTTP or it asks for unknown events", webhook.url);
+    }
+    #[cfg(not(feature = "webhooks"))]
+    if !config.webhooks.is_empty() {
+        tracing::error!("Webhooks are declared, but ninja was built without the `webhooks` feature");
+    }
+    #[cfg(not(feature = "s3"))]
+    if !config.buckets.is_empty() {
+        tracing::error!("Buckets are declared, but ninja was built without the `s3` feature");
+    }
+    if !config.telemetry.is_valid() {
+        tracing::error!("Not exporting spans, the telemetry endpoint isn't HTTP");
+    }
+    #[cfg(not(feature = "otel"))]
+    if config.telemetry.endpoint.is_some() {
+        tracing::error!("A telemetry endpoint is set, but ninja was built without the `otel` feature");
+    }
+}
+
+/// Logs to stderr, or as configured by `log`, and exports spans to the
+/// `telemetry` endpoint from within the Tokio runtime. Applications embedding
+/// ninja set up their own subscriber instead.
+pub fn init_logging(config: &Config) {
+    logging::init(&config.log, &config.telemetry);
+}
+
+/// Creates an account, or resets its password.
+pub fn set_password(config: &Config, name: &str, password: &str) -> Result<(), Error> {
+    let users = users::Users::open(&config.index_path).map_err(|err| Error::Database("open users", err))?;
+    let hash = users::hash_password(password).map_err(Error::Password)?;
+    users.set(name, &hash).map_err(|err| Error::Database("save user", err))
+}
+
+/// A media server: its databases, caches and background tasks, which live
+/// until the process exits.
+#[derive(Clone)]
+pub struct Ninja {
+    app: &'static App,
+    cors: Option<tower_http::cors::CorsLayer>
+}
+
+impl Ninja {
+    /// Opens the databases and caches, and starts scanning along with the
+    /// other background tasks. The configuration is reloaded whenever
+    /// `config_path` changes, if given.
+    pub async fn start(config: Config, config_path: Option<&Path>) -> Result<Self, Error> {
+        Self::start_with_overrides(config, config_path, |_| {}).await
+    }
+
+    /// Like `start`, with `overrides` applied to each configuration reloaded
+    /// from `config_path` as it was to `config`, like command line flags.
+    pub async fn start_with_overrides(
+        config: Config,
+        config_path: Option<&Path>,
+        overrides: impl Fn(&mut Config) + Send + Sync + 'static
+    ) -> Result<Self, Error> {
+        check(&config);
+        let cors = config.cors.layer().map_err(Error::Cors)?;
+
+        let open = |what| move |err| Error::Database(what, err);
+        let index = index::Index::open(&config.index_path).map_err(open("open the index"))?;
+        let users = users::Users::open(&config.index_path).map_err(open("open users"))?;
+        let shares = shares::Shares::open(&config.index_path).map_err(open("open shares"))?;
+        let collections = collections::Collections::open(&config.index_path).map_err(open("open collections"))?;
+        let playlists = playlists::Playlists::open(&config.index_path).map_err(open("open playlists"))?;
+        let progress = progress::Store::open(&config.index_path).map_err(open("open watch progress"))?;
+        let favorites = favorites::Favorites::open(&config.index_path).map_err(open("open favorites"))?;
+        #[cfg(feature = "tmdb")]
+        let tmdb = tmdb::Tmdb::open(&config.index_path).map_err(open("open the TMDB cache"))?;
+
+        let jobs = jobs::Jobs::new(config.max_jobs);
+        let ffmpeg = ffmpeg::Ffmpeg::new(&config);
+        // Cache sizes are configured in MiB
+        let frames = cache::Lru::open(config.cache_path.join("frames"), config.frame_cache_size << 20).await;
+        let segments = cache::Lru::open(config.cache_path.join("segments"), config.segment_cache_size << 20).await;
+        #[cfg(feature = "remote")]
+        let chunks = chunks::Chunks::open(config.cache_path.join("remote"), config.remote_cache_size << 20).await;
+        let inflight = coalesce::Coalescer::new();
+        let keyframes = coalesce::Coalescer::new();
+        let access_log = access_log::Writer::new(&config.access_log);
+        let config_path = config_path.map(|path| &*Box::leak(path.into()));
+        let app_ref: &'static App = Box::leak(App { config: reload::Live::new(config_path, Box::new(overrides), config), index, users, shares, collections, playlists, progress, favorites, #[cfg(feature = "tmdb")] tmdb, #[cfg(feature = "remote")] remote: remote::client(), #[cfg(feature = "remote")] chunks, limiter: rate_limit::Limiter::default(), streams: streams::Streams::default(), parties: party::Parties::default(), cast: cast::Devices::default(), uploads: tus::Uploads::default(), access_log, jobs, ffmpeg, frames, segments, inflight, keyframes, tasks: scheduler::Tasks::default(), #[cfg(feature = "webhooks")] webhooks: reqwest::Client::new() }.into());
+        tokio::spawn(scanner::run(app_ref));
+        tokio::spawn(reload::run(app_ref));
+        tokio::spawn(trash::run(app_ref));
+        tokio::spawn(scheduler::run(app_ref));
+        tokio::spawn(streams::run(app_ref));
+        tokio::spawn(dlna::run(app_ref));
+        tokio::spawn(live::run(app_ref));
+        #[cfg(feature = "remote")]
+        tokio::spawn(chunks::listen(app_ref));
+        if app_ref.config.watch {
+            tokio::spawn(watcher::run(app_ref));
+        }
+
+        Ok(Ninja { app: app_ref, cors })
+    }
+
+    /// The configuration in effect.
+    pub fn config(&self) -> &'static Config {
+        self.app.config.get()
+    }
+
+    /// Every route, under `base_path`.
+    pub fn router(&self) -> Router {
+        let app_ref = self.app;
+        let throttled = middleware::map_response_with_state(app_ref, throttle::throttle);
+        let counted = middleware::from_fn_with_state(app_ref, streams::track);
+        let shared = middleware::from_fn_with_state(app_ref, dlna::only_shared);
+        let app = Router::new()
+            .route("/video/*video", routing::get(serve_video).layer(throttled.clone()).layer(counted.clone()))
+            .route("/frame/*video", routing::get(frame::serve_frame))
+            .route("/frames/*video", routing::get(frame::serve_frames))
+            .route("/thumb/*video", routing::get(thumb::serve_thumb))
+            .route("/storyboard/:video/storyboard.vtt", routing::get(storyboard::serve_vtt))
+            .route("/storyboard/:video/sprite.jpg", routing::get(storyboard::serve_sprite))
+            .route("/waveform/*video", routing::get(waveform::serve_waveform))
+            .route("/info/*video", routing::get(probe::serve_info))
+            .route("/checksum/*video", routing::get(checksum::serve_checksum))
+            .route("/artwork/:kind/*video", routing::get(nfo::serve_artwork))
+            .route("/chapters/*video", routing::get(probe::serve_chapters))
+            .route("/keyframes/*video", routing::get(keyframes::serve_keyframes))
+            .route("/libraries", routing::get(library::serve_libraries))
+            .route("/library", routing::get(library::serve_root))
+            .route("/library/*path", routing::get(library::serve_dir))
+            .route("/search", routing::get(search::serve_search))
+            .route("/duplicates", routing::get(duplicates::serve_duplicates))
+            .route("/browse", routing::get(library::serve_browse_root))
+            .route("/browse/*path", routing::get(library::serve_browse))
+            .route("/preview/*video", routing::get(frame::serve_preview))
+            .route("/subtitles/*video", routing::get(subtitles::serve_subtitles))
+            .route("/audio/*video", routing::get(audio::serve_audio).layer(counted.clone()))
+            .route("/clip/*video", routing::get(clip::serve_clip))
+            .route("/jobs", routing::get(jobs::list_jobs).post(jobs::create_job))
+            .route("/jobs/:id", routing::get(jobs::get_job).delete(jobs::delete_job))
+            .route("/jobs/:id/events", routing::get(jobs::job_events))
+            .route("/jobs/:id/output", routing::get(jobs::serve_output).layer(throttled.clone()))
+            .route("/hls/:video/master.m3u8", routing::get(hls::serve_master).layer(counted.clone()))
+            .route("/hls/:video/:rendition/index.m3u8", routing::get(hls::serve_playlist).layer(counted.clone()))
+            .route("/hls/:video/:rendition/:segment", routing::get(hls::serve_segment).layer(counted))
+            .route("/zip/dir/*path", routing::get(download::serve_dir))
+            .route("/zip/collection/:id", routing::get(download::serve_collection))
+            .route("/download/*video", routing::get(download::serve_video).layer(throttled.clone()))
+            .route("/files/*video", routing::patch(files::move_video).delete(files::delete_video))
+            .route("/trash", routing::get(trash::list_trash))
+            .route("/trash/:id/restore", routing::post(trash::restore))
+            .route("/upload", routing::post(upload::upload).layer(extract::DefaultBodyLimit::disable()))
+            .route("/upload/tus", routing::post(tus::create).options(tus::options))
+            .route("/upload/tus/:id", routing::head(tus::head).patch(tus::patch).delete(tus::delete).layer(extract::DefaultBodyLimit::disable()))
+            .route("/share/*video", routing::post(shares::create_share))
+            .route("/collections", routing::get(collections::list_collections).post(collections::create_collection))
+            .route("/collections/:id", routing::get(collections::get_collection).delete(collections::delete_collection))
+            .route("/collections/:id/items", routing::put(collections::set_items))
+            .route("/collections/:id/playlist.m3u", routing::get(m3u::serve_collection_playlist))
+            .route("/playlist.m3u", routing::get(m3u::serve_playlist))
+            .route("/playlists", routing::get(playlists::list_playlists).post(playlists::create_playlist))
+            .route("/playlists/:id", routing::get(playlists::get_playlist).patch(playlists::rename_playlist).delete(playlists::delete_playlist))
+            .route("/playlists/:id/items", routing::put(playlists::set_items).post(playlists::add_items))
+            .route("/playlists/:id/items/:position", routing::delete(playlists::remove_item))
+            .route("/playlists/:id/items/:position/move", routing::post(playlists::move_item))
+            .route("/playlists/:id/playlist.m3u", routing::get(playlists::serve_playlist_m3u))
+            .route("/feed.xml", routing::get(feed::serve_root_feed))
+            .route("/feed/*feed", routing::get(feed::serve_feed))
+            .route("/home", routing::get(home::serve_home))
+            .route("/progress", routing::get(progress::list_progress))
+            .route("/favorites", routing::get(favorites::list_favorites))
+            .route("/favorites/*video", routing::post(favorites::add_favorite).delete(favorites::remove_favorite))
+            .route("/progress/*video", routing::get(progress::get_progress).put(progress::set_progress).delete(progress::delete_progress))
+            .route("/party/:room", routing::get(party::serve_party))
+            .route("/live", routing::get(live::list_streams))
+            .route("/live/:stream/playlist.m3u8", routing::get(live::serve_playlist))
+            .route("/live/:stream/:segment", routing::get(live::serve_segment))
+            .route("/cast/devices", routing::get(cast::list_devices))
+            .route("/cast/status", routing::get(cast::device_status))
+            .route("/cast/control", routing::post(cast::control_device))
+            .route("/cast/*video", routing::post(cast::cast_video))
+            .route("/admin/sessions", routing::get(streams::list_sessions))
+            .route("/admin/sessions/:id", routing::delete(streams::delete_session))
+            .route("/admin/tasks", routing::get(scheduler::list_tasks))
+            .route("/logout", routing::post(auth::logout))
+            .layer(middleware::from_fn_with_state(app_ref, auth::authenticate))
+            .route("/login", routing::post(auth::login))
+            // The UI has to load before anyone can log in with it
+            .route("/", routing::get(ui::serve_index))
+            .route("/ui/app.js", routing::get(ui::serve_script))
+            .route("/ui/style.css", routing::get(ui::serve_style))
+            .route("/openapi.json", routing::get(openapi::serve_spec))
+            .route("/docs", routing::get(openapi::serve_docs))
+            // Renderers on the network don't log in, they're told apart by address
+            .nest("/dlna", Router::new()
+                .route("/description.xml", routing::get(dlna::serve_description))
+                .route("/ContentDirectory.xml", routing::get(dlna::serve_content_directory_scpd))
+                .route("/ConnectionManager.xml", routing::get(dlna::serve_connection_manager_scpd))
+                .route("/control/ContentDirectory", routing::post(dlna::control_content_directory))
+                .route("/control/ConnectionManager", routing::post(dlna::control_connection_manager))
+                .route("/media/*video", routing::get(serve_video).layer(throttled).layer(shared.clone()))
+                .route("/thumb/*video", routing::get(thumb::serve_thumb).layer(shared))
+                .layer(middleware::from_fn_with_state(app_ref, dlna::only_local)))
+            .layer(middleware::from_fn_with_state(app_ref, rate_limit::limit))
+            .layer(middleware::from_fn_with_state(app_ref, access_log::record))
+            // Probes run often and without credentials, so they skip
+            // authentication, rate limiting and the access log
+            .route("/healthz", routing::get(health::serve_health))
+            .route("/readyz", routing::get(health::serve_ready))
+            .layer(middleware::from_fn_with_state(app_ref, forwarded::resolve))
+            .with_state(app_ref);
+        let app = url::mount(app_ref.config.get(), app)
+            .layer(middleware::from_fn(error::wrap_rejections))
+            .layer(middleware::from_fn(trace::assign));
+        match &self.cors {
+            Some(cors) => app.layer(cors.clone()),
+            None => app
+        }
+    }
+
+    /// Serves on the configured addresses until the process is told to
+    /// stop.
+    pub async fn serve(&self) {
+        server::run(self.config(), self.router().layer(logging::layer())).await;
+        self.shutdown().await;
+    }
+
+    /// Cancels transcode jobs, which would be left with half written output
+    /// otherwise. Killing them cleans up after ffmpeg.
+    pub async fn shutdown(&self) {
+        self.app.jobs.cancel_all().await;
+    }
+
+    /// Walks the libraries and brings the index up to date, as the periodic
+    /// scan does.
+    pub async fn scan(&self) {
+        scanner::scan(self.app).await;
+    }
+
+    /// Every indexed video.
+    pub fn videos(&self) -> Result<Vec<Video>, Error> {
+        self.app.index.all().map_err(|err| Error::Database("read the index", err))
+    }
+
+    /// The indexed video at `path`, relative to its library.
+    pub fn video(&self, path: &str) -> Result<Option<Video>, Error> {
+        self.app.index.get(path).map_err(|err| Error::Database("read the index", err))
+    }
+
+    /// Queues a transcode, returning the id of its job.
+    pub async fn transcode(&self, spec: JobSpec) -> Result<u64, Error> {
+        jobs::validate(self.app, &spec).await?;
+        Ok(jobs::start(self.app, spec).id)
+    }
+
+    /// The state and progress of job `id`.
+    pub fn job(&self, id: u64) -> Option<JobStatus> {
+        Some(self.app.jobs.get(id)?.status.borrow().clone())
+    }
+}
+
+/// Starts a server and returns its routes, to be merged into another axum
+/// application. Set `base_path` to where they're merged rather than nesting
+/// them, so that the URLs handed out point back at them. Client addresses come
+/// from `ConnectInfo<SocketAddr>`, when served with
+/// `into_make_service_with_connect_info`.
+pub async fn router(config: Config) -> Result<Router, Error> {
+    Ok(Ninja::start(config, None).await?.router())
+}
+
+async fn serve_video(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::Query(options): extract::Query<transcode::Options>,
+    method: http::Method,
+    header: http::HeaderMap,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let config = &app.config;
+    let video_path = match jail::video(config, video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+
+    let Ok(profile) = options.profile(config, false) else {
+        return ApiError::new(Code::ProfileNotFound, "Profile not found").into();
+    };
+
+    // Picking an audio track requires remuxing, even for MP4 sources, and so
+    // do remote videos that can't be proxied
+    let remote = remote::url(&video_path).is_some() && !cfg!(feature = "remote");
+    if profile.is_some() || options.audio.is_some() || needs_remux(config, &video_path) || remote {
+        if method == http::Method::HEAD {
+            // An empty stream rather than an empty body, the length isn't known
+            // and mustn't be reported as zero
+            let body = futures_util::stream::empty::<io::Result<axum::body::Bytes>>();
+            let content_type = profile.map_or("video/mp4", |profile| profile.container.content_type());
+            return remuxed_response(content_type).body(axum::body::Body::from_stream(body)).unwrap();
+        }
+        if let Some(profile) = profile {
+            return serve_transcoded(app, &video_path, &options, profile).await;
+        }
+        return serve_remuxed(app, &video_path, &options).await;
+    }
+
+    #[cfg(feature = "remote")]
+    if let Some(url) = remote::url(&video_path) {
+        return remote::proxy(app, url, &method, &header).await;
+    }
+
+    let video = match fs::File::open(&video_path).await {
+        Ok(video) => video,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to open video `{}`", video_path.display());
+            return ApiError::new(Code::VideoNotFound, "Video not found").into();
+        }
+    };
+
+    serve_file(config, &video_path, video, &method, &header).await
+}
+
+/// Serves the file at `video_path` as is, answering conditional and range
+/// requests.
+async fn serve_file(
+    config: &Config,
+    video_path: &Path,
+    mut video: fs::File,
+    method: &http::Method,
+    header: &http::HeaderMap
+) -> response::Response {
+    let content_type = mime::detect(video_path, &mut video).await.unwrap_or("application/octet-stream");
+    let metadata = match video.metadata().await {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to read metadata of `{}`", video_path.display());
+            return ApiError::new(Code::InternalError, "Failed to read video").into();
+        }
+    };
+    let validators = conditional::Validators::new(&metadata);
+    let size = metadata.len();
+
+    if conditional::is_not_modified(header, &validators) {
+        return conditional::not_modified(&validators);
+    }
+
+    // Answered without touching the file, players use it to learn the size
+    // before they start requesting ranges
+    if *method == http::Method::HEAD {
+        return file_response(content_type, &validators)
+            .status(http::StatusCode::OK)
+            .header(http::header::CONTENT_LENGTH, size)
+            .body(axum::body::Body::empty())
+            .unwrap();
+    }
+
+    let range = header.get(http::header::RANGE).filter(|_| conditional::if_range(header, &validators));
+    let ranges = match range.map(|range| range::parse(range.to_str().unwrap_or(""), size)) {
+        Some(Ok(ranges)) => ranges,
+        Some(Err(range::Error::Invalid)) => {
+            return ApiError::new(Code::InvalidRange, "Invalid Range").into();
+        }
+        Some(Err(range::Error::Unsatisfiable)) => {
+            return response::IntoResponse::into_response((
+                [(http::header::CONTENT_RANGE, format!("bytes */{size}"))],
+                ApiError::new(Code::RangeNotSatisfiable, "Range Not Satisfiable")
+            ));
+        }
+        None => {
+            video.seek(io::SeekFrom::Start(0)).await.unwrap();
+            let body = axum::body::Body::from_stream(read_chunks(video, size, config.chunk_size));
+
+            return file_response(content_type, &validators)
+                .status(http::StatusCode::OK)
+                .header(http::header::CONTENT_LENGTH, size)
+                .body(body)
+                .unwrap()
+        }
+    };
+
+    let [range] = ranges[..] else {
+        return serve_ranges(config, video_path, &ranges, size, content_type, &validators).await;
+    };
+
+    if let Err(err) = video.seek(io::SeekFrom::Start(range.start)).await {
+        tracing::error!(error = %err, "Failed to seek video `{}`", video_path.display());
+        return ApiError::new(Code::InternalError, "Failed to read video").into();
+    }
+
+    file_response(content_type, &validators)
+        .status(http::StatusCode::PARTIAL_CONTENT)
+        .header(http::header::CONTENT_RANGE, format!("bytes {}-{}/{size}", range.start, range.end))
+        .header(http::header::CONTENT_LENGTH, range.len())
+        .body(axum::body::Body::from_stream(read_chunks(video, range.len(), config.chunk_size)))
+        .unwrap()
+}
+
+/// Streams the next `length` bytes of `file`, `chunk_size` bytes at a time.
+fn read_chunks(file: fs::File, length: u64, chunk_size: u64) -> ReaderStream<io::Take<fs::File>> {
+    ReaderStream::with_capacity(file.take(length), chunk_size as usize)
+}
+
+/// Answers a request for several ranges with a `multipart/byteranges` body,
+/// each range in its own part.
+async fn serve_ranges(
+    config: &Config,
+    path: &Path,
+    ranges: &[range::Range],
+    size: u64,
+    content_type: &'static str,
+    validators: &conditional::Validators
+) -> response::Response {
+    let boundary = format!("ninja-byteranges-{}", cache::Lru::key((&validators.etag, ranges)));
+
+    let mut length = 0;
+    let mut parts = Vec::new();
+    for range in ranges {
+        let header = format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {}-{}/{size}\r\n\r\n",
+            range.start, range.end
+        );
+
+        // Every part reads through its own handle, as they are streamed one
+        // after the other only once the response is sent
+        let mut file = match fs::File::open(path).await {
+            Ok(file) => file,
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to open video `{}`", path.display());
+                return ApiError::new(Code::InternalError, "Failed to read video").into();
+            }
+        };
+        if let Err(err) = file.seek(io::SeekFrom::Start(range.start)).await {
+            tracing::error!(error = %err, "Failed to seek video `{}`", path.display());
+            return ApiError::new(Code::InternalError, "Failed to read video").into();
+        }
+
+        length += header.len() as u64 + range.len();
+        parts.push(stream::once(async { Ok(Bytes::from(header)) })
+            .chain(read_chunks(file, range.len(), config.chunk_size))
+            .boxed());
+    }
+
+    let trailer = format!("\r\n--{boundary}--\r\n");
+    length += trailer.len() as u64;
+    parts.push(stream::once(async { Ok(Bytes::from(trailer)) }).boxed());
+
+    validators.headers(response::Response::builder())
+        .status(http::StatusCode::PARTIAL_CONTENT)
+        .header(http::header::ACCEPT_RANGES, "bytes")
+        .header(http::header::CONTENT_TYPE, format!("multipart/byteranges; boundary={boundary}"))
+        .header(http::header::CONTENT_LENGTH, length)
+        .body(axum::body::Body::from_stream(stream::iter(parts).flatten()))
+        .unwrap()
+}
+
+/// Headers shared by every response that serves the file as is.
+fn file_response(content_type: &'static str, validators: &conditional::Validators) -> http::response::Builder {
+    validators.headers(response::Response::builder())
+        .header(http::header::ACCEPT_RANGES, "bytes")
+        .header(http::header::CONTENT_TYPE, content_type)
+}
+
+fn needs_remux(config: &Config, path: &Path) -> bool {
+    let Some(extension) = path.extension().and_then(|extension| extension.to_str()) else {
+        return false;
+    };
+
+    config.remux_extensions.iter().any(|remux| remux.eq_ignore_ascii_case(extension))
+}
+
+/// Containers that browsers can't play are remuxed into fragmented MP4 on the
+/// fly. The output has no known size, so range requests are not supported.
+async fn serve_remuxed(app: &App, path: &Path, options: &transcode::Options) -> response::Response {
+    let body = match app.ffmpeg.stream(Command::new(&*app.config.ffmpeg_command).args([
+        "-v", "error",
+        "-i", path.to_str().unwrap(),
+        "-map", "0:v:0",
+        "-map", &options.audio_map("0:a?"),
+        "-sn",
+        "-dn",
+        "-c", "copy",
+        "-movflags", "frag_keyframe+empty_moov",
+        "-f", "mp4",
+        "-"
+    ])).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to remux video `{}`", remote::display(path));
+            return err.into_response("Failed to remux video");
+        }
+    };
+
+    remuxed_response("video/mp4").body(body).unwrap()
+}
+
+/// Re-encodes the whole video with `profile`, for clients that can't take the
+/// source's codecs or bitrate.
+async fn serve_transcoded(
+    app: &App,
+    path: &Path,
+    options: &transcode::Options,
+    profile: &transcode::Profile
+) -> response::Response {
+    let config = &app.config;
+    let mut command = Command::new(&*config.ffmpeg_command);
+    command.args(["-v", "error"]);
+    if let Some(template) = &profile.args {
+        command.args(template.render(path.to_str().unwrap(), "0"));
+    } else if let Err(err) = transcode_args(config, &mut command, path, options, profile).await {
+        return err;
+    }
+
+    let body = match app.ffmpeg.stream(&mut command).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to transcode video `{}`", remote::display(path));
+            return err.into_response("Failed to transcode video");
+        }
+    };
+
+    remuxed_response(profile.container.content_type()).body(body).unwrap()
+}
+
+/// The arguments for encoding `path` with the settings of `profile`.
+async fn transcode_args(
+    config: &Config,
+    command: &mut Command,
+    path: &Path,
+    options: &transcode::Options,
+    profile: &transcode::Profile
+) -> Result<(), response::Response> {
+    let scale = match profile.height {
+        Some(height) => format!("scale=-2:'min(ih,{height})'"),
+        None => "null".into()
+    };
+    let Some(filter) = transcode::video_filter(config, path, options, 0.0, &scale, profile.codec).await else {
+        return Err(ApiError::new(Code::SubtitlesNotFound, "Subtitle track not found").into());
+    };
+
+    command
+        .args(config.hwaccel.decode_args())
+        .args([
+            "-i", path.to_str().unwrap(),
+            "-filter_complex", &filter,
+            "-map", "[v]",
+            "-map", &options.audio_map("0:a:0?"),
+            "-sn",
+            "-dn"
+        ])
+        .args(profile.encode_args(config));
+    if let Some(bitrate) = profile.video_bitrate {
+        command.args(["-b:v", &format!("{bitrate}k"), "-maxrate", &format!("{bitrate}k"), "-bufsize", &format!("{}k", bitrate * 2)]);
+    }
+    command.args(profile.audio_filter_args()).args(["-c:a", profile.audio_codec(), "-ac", "2"]);
+    if let Some(bitrate) = profile.audio_bitrate {
+        command.args(["-b:a", &format!("{bitrate}k")]);
+    }
+    command.args(profile.container.format_args()).arg("-");
+    Ok(())
+}
+
+fn remuxed_response(content_type: &'static str) -> http::response::Builder {
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::ACCEPT_RANGES, "none")
+        .header(http::header::CONTENT_TYPE, content_type)
+}
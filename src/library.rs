@@ -0,0 +1,335 @@
+use std::path::{Component, Path, PathBuf};
+
+use axum::{extract, response, Json};
+
+use crate::error::{ApiError, Code};
+use crate::index::{self, Video};
+use crate::subtitles::{self, Sidecar};
+use crate::users::{Owner, User};
+use crate::{auth, jail, App, Config, Rendition};
+
+/// A library declared with `[[library]]`. Its videos are addressed with the
+/// library name as the first path component, like `movies/Alien.mkv`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Library {
+    pub name: Box<str>,
+    pub path: Box<Path>,
+    /// Marks the library as off limits for changes. Moves, deletions,
+    /// uploads and restores into it are refused, and clients hide editing.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Replaces the HLS `renditions` for the videos in this library.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renditions: Option<Box<[Rendition]>>,
+    /// Users that may see the library, everyone when empty.
+    #[serde(default)]
+    pub users: Box<[Box<str>]>
+}
+
+/// A directory holding videos, with the `name` prefixed to its paths in the
+/// index. `video_path` is the only root, with an empty name, when no
+/// libraries are declared.
+#[derive(Clone, Copy)]
+pub struct Root<'a> {
+    pub name: &'a str,
+    pub path: &'a Path,
+    pub library: Option<&'a Library>
+}
+
+impl Root<'_> {
+    /// The path in the index of `relative`, a `/` separated path inside the
+    /// root.
+    pub fn prefixed(&self, relative: &str) -> Box<str> {
+        match (self.name, relative) {
+            ("", relative) => relative.into(),
+            (name, "") => name.into(),
+            (name, relative) => format!("{name}/{relative}").into()
+        }
+    }
+}
+
+pub fn roots(config: &Config) -> Vec<Root<'_>> {
+    if config.libraries.is_empty() {
+        return vec![Root { name: "", path: &config.video_path, library: None }];
+    }
+
+    config.libraries.iter()
+        .map(|library| Root { name: &library.name, path: &library.path, library: Some(library) })
+        .collect()
+}
+
+/// Splits `path`, as used by clients and the index, into its root and the
+/// path inside it.
+pub fn locate<'a, 'b>(config: &'a Config, path: &'b Path) -> Option<(Root<'a>, &'b Path)> {
+    if config.libraries.is_empty() {
+        return Some((roots(config)[0], path));
+    }
+
+    let mut components = path.components();
+    let name = loop {
+        match components.next()? {
+            Component::CurDir => continue,
+            Component::Normal(name) => break name,
+            _ => return None
+        }
+    };
+    let library = config.libraries.iter().find(|library| *library.name == *name)?;
+    Some((Root { name: &library.name, path: &library.path, library: Some(library) }, components.as_path()))
+}
+
+/// Whether `path` is in a library marked `read_only`.
+pub fn is_read_only(config: &Config, path: impl AsRef<Path>) -> bool {
+    locate(config, path.as_ref()).is_some_and(|(root, _)| root.library.is_some_and(|library| library.read_only))
+}
+
+/// Whether `path` is in a library everyone may see, rather than one limited
+/// to its `users`.
+pub fn is_public(config: &Config, path: impl AsRef<Path>) -> bool {
+    locate(config, path.as_ref()).is_some_and(|(root, _)| root.library.is_none_or(|library| library.users.is_empty()))
+}
+
+/// Where `path` is on disk, without any of the checks of [`jail`].
+pub fn file(config: &Config, path: impl AsRef<Path>) -> Option<PathBuf> {
+    let (root, relative) = locate(config, path.as_ref())?;
+    Some(root.path.join(relative))
+}
+
+/// The HLS ladder for the video at `path`.
+pub fn renditions<'a>(config: &'a Config, path: &Path) -> &'a [Rendition] {
+    locate(config, path)
+        .and_then(|(root, _)| root.library?.renditions.as_deref())
+        .unwrap_or(&config.renditions)
+}
+
+#[derive(serde::Serialize)]
+struct Entry {
+    #[serde(flatten)]
+    video: Video,
+    subtitles: Vec<Sidecar>,
+    /// Whether the video is on the list of whoever asked.
+    favorite: bool
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    #[default]
+    Asc,
+    Desc
+}
+
+/// Sorting, filtering and paging of listings, all videos by name by default.
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+pub struct ListQuery {
+    sort: index::Sort,
+    order: Order,
+    offset: u64,
+    limit: Option<u64>,
+    /// Comma separated extensions, like `mp4,mkv`.
+    ext: Option<Box<str>>
+}
+
+/// The videos directly in `dir` picked by `query`, with their sidecar
+/// subtitles and whether they're favorites of `owner`, and how many match in
+/// total.
+async fn entries(app: &App, owner: &str, dir: &str, query: &ListQuery) -> rusqlite::Result<(Vec<Entry>, u64)> {
+    let extensions: Vec<_> = query.ext.as_deref().unwrap_or_default().split(',').map(str::trim).filter(|extension| !extension.is_empty()).collect();
+    let (videos, total) = app.index.list(dir, &index::Listing {
+        sort: query.sort,
+        descending: query.order == Order::Desc,
+        offset: query.offset,
+        limit: query.limit,
+        extensions: &extensions
+    })?;
+    let filenames = match file(&app.config, dir) {
+        Some(path) => subtitles::filenames(&path).await,
+        None => Vec::new()
+    };
+    let favorites = app.favorites.set(owner)?;
+    let entries = videos.into_iter().map(|video| Entry {
+        subtitles: subtitles::sidecars(Path::new(&*video.filename), &filenames),
+        favorite: favorites.contains(&video.path),
+        video
+    }).collect();
+    Ok((entries, total))
+}
+
+/// Lists a directory, with the number of videos before paging in
+/// `X-Total-Count`.
+async fn list(app: &App, owner: &str, dir: &str, query: &ListQuery) -> response::Response {
+    match entries(app, owner, dir, query).await {
+        Ok((entries, total)) => {
+            let mut response = response::IntoResponse::into_response(Json(entries));
+            response.headers_mut().insert("x-total-count", total.into());
+            response
+        }
+        Err(err) => ApiError::database("Failed to list directory", err).into()
+    }
+}
+
+pub async fn serve_root(
+    extract::Query(query): extract::Query<ListQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Owner(owner): Owner
+) -> response::Response {
+    if user.is_some_and(|extract::Extension(user)| !auth::can_access(&app.config, &user, "")) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+
+    list(app, &owner, "", &query).await
+}
+
+pub async fn serve_dir(
+    extract::Path((dir, )): extract::Path<(Box<str>, )>,
+    extract::Query(query): extract::Query<ListQuery>,
+    extract::State(app): extract::State<&App>,
+    Owner(owner): Owner
+) -> response::Response {
+    let dir = dir.trim_matches('/');
+    if let Err(err) = jail::directory(&app.config, dir).await {
+        return err.into_response(Code::DirectoryNotFound, "Directory not found");
+    }
+
+    list(app, &owner, dir, &query).await
+}
+
+#[derive(serde::Serialize)]
+struct Listing<'a> {
+    name: &'a str,
+    read_only: bool
+}
+
+/// The libraries declared with `[[library]]` that the user may see.
+pub async fn serve_libraries(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    let libraries: Vec<_> = app.config.libraries.iter()
+        .filter(|library| user.as_ref().is_none_or(|extract::Extension(user)| auth::can_access(&app.config, user, &library.name)))
+        .map(|library| Listing { name: &library.name, read_only: library.read_only })
+        .collect();
+
+    response::IntoResponse::into_response(Json(libraries))
+}
+
+#[derive(serde::Serialize)]
+pub struct Folder {
+    pub name: Box<str>,
+    pub path: Box<str>
+}
+
+#[derive(serde::Serialize)]
+struct Browse {
+    path: Box<str>,
+    /// The folders leading to `path`, from the top without the root itself.
+    breadcrumbs: Vec<Folder>,
+    folders: Vec<Folder>,
+    files: Vec<Entry>,
+    /// How many files there are before paging.
+    total: u64
+}
+
+fn child(dir: &str, name: &str) -> Box<str> {
+    if dir.is_empty() { name.into() } else { format!("{dir}/{name}").into() }
+}
+
+/// Whether `dir` is a directory that may be browsed. Going through the jail
+/// skips hidden folders and symlinks that aren't followed.
+pub async fn is_directory(config: &Config, dir: &str) -> bool {
+    match jail::directory(config, dir).await {
+        Ok(path) => tokio::fs::metadata(path).await.is_ok_and(|metadata| metadata.is_dir()),
+        Err(_) => false
+    }
+}
+
+/// The folders in `dir` that can be browsed into. The libraries are the
+/// folders at the top when there are several.
+pub async fn folders(app: &App, user: Option<&User>, dir: &str) -> Vec<Folder> {
+    let config = &app.config;
+    let names: Vec<Box<str>> = if dir.is_empty() && !config.libraries.is_empty() {
+        config.libraries.iter().map(|library| library.name.clone()).collect()
+    } else {
+        let mut names = Vec::new();
+        if let Some(path) = file(config, dir) {
+            if let Ok(mut entries) = tokio::fs::read_dir(path).await {
+                while let Ok(Some(entry)) = entries.next_entry().await {
+                    if let Some(name) = entry.file_name().to_str() {
+                        names.push(name.into());
+                    }
+                }
+            }
+        }
+        names
+    };
+
+    let mut folders = Vec::new();
+    for name in names {
+        let path = child(dir, &name);
+        if user.is_some_and(|user| !auth::can_access(config, user, &path)) || !is_directory(config, &path).await {
+            continue;
+        }
+        folders.push(Folder { name, path });
+    }
+    folders.sort_by(|a, b| a.name.cmp(&b.name));
+    folders
+}
+
+async fn browse(app: &App, user: Option<&User>, owner: &str, dir: &str, query: &ListQuery) -> response::Response {
+    let dir = dir.trim_matches('/');
+    if !dir.is_empty() {
+        let path = match jail::directory(&app.config, dir).await {
+            Ok(path) => path,
+            Err(err) => return err.into_response(Code::DirectoryNotFound, "Directory not found")
+        };
+        if !tokio::fs::metadata(path).await.is_ok_and(|metadata| metadata.is_dir()) {
+            return jail::Error::NotFound.into_response(Code::DirectoryNotFound, "Directory not found");
+        }
+    }
+
+    // Access is granted by folder, so the files are visible along with the
+    // directory, except at the top for users limited to some folders
+    let (files, total) = if user.is_none_or(|user| auth::can_access(&app.config, user, dir)) {
+        match entries(app, owner, dir, query).await {
+            Ok(files) => files,
+            Err(err) => return ApiError::database("Failed to list directory", err).into()
+        }
+    } else {
+        (Vec::new(), 0)
+    };
+    let mut breadcrumbs = Vec::new();
+    for name in dir.split('/').filter(|name| !name.is_empty()) {
+        let path = child(breadcrumbs.last().map_or("", |parent: &Folder| &parent.path), name);
+        breadcrumbs.push(Folder { name: name.into(), path });
+    }
+
+    response::IntoResponse::into_response(Json(Browse {
+        path: dir.into(),
+        breadcrumbs,
+        folders: folders(app, user, dir).await,
+        files,
+        total
+    }))
+}
+
+/// The folders and videos at the top of the library.
+pub async fn serve_browse_root(
+    extract::Query(query): extract::Query<ListQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Owner(owner): Owner
+) -> response::Response {
+    browse(app, user.as_deref(), &owner, "", &query).await
+}
+
+/// The folders and videos in a directory, with the way back up to the top.
+pub async fn serve_browse(
+    extract::Path((dir, )): extract::Path<(Box<str>, )>,
+    extract::Query(query): extract::Query<ListQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Owner(owner): Owner
+) -> response::Response {
+    browse(app, user.as_deref(), &owner, &dir, &query).await
+}
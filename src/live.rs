@@ -0,0 +1,530 @@
+use std::fmt::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use axum::{extract, http, response, Json};
+use tokio::fs;
+use tokio::io::{self, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+use tokio::time;
+
+use crate::error::{ApiError, Code};
+use crate::{auth, ffmpeg, rtmp, url, App, Config};
+
+/// Segments kept in the rolling playlist. Older ones are deleted, so viewers
+/// can only rewind this far.
+const PLAYLIST_SIZE: u32 = 6;
+
+/// A publisher that stops sending for this long is dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Publishers have this long from connecting to give the stream key. Only one
+/// is served at a time, and one that never does would hold the port.
+const KEY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// In low latency mode ffmpeg cuts a part at the first keyframe past this,
+/// so publishers should send a keyframe every second.
+const PART_DURATION: f64 = 1.0;
+
+/// Parts making up each full segment of a low latency playlist.
+const PARTS_PER_SEGMENT: u64 = 4;
+
+/// How long requests for parts and playlists that aren't there yet wait,
+/// three segments as the spec suggests.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(3 * PARTS_PER_SEGMENT);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Longest wait before listening again when ffmpeg keeps failing straight
+/// away, like when the port is taken.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ingest {
+    #[default]
+    Rtmp,
+    Srt
+}
+
+/// A live stream, declared with `[[live]]`. OBS and the like publish to
+/// `rtmp://host:port/live` with `key` as the stream key, or to
+/// `srt://host:port` with `key` as the passphrase. ffmpeg takes what they
+/// send and cuts it into a rolling HLS playlist.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Stream {
+    pub name: Box<str>,
+    pub key: Box<str>,
+    #[serde(default)]
+    pub ingest: Ingest,
+    #[serde(default = "Stream::default_port")]
+    pub port: u16,
+    /// Serves LL-HLS, with parts of a second that players fetch as soon as
+    /// they're cut, for a few seconds of latency rather than tens.
+    #[serde(default)]
+    pub low_latency: bool
+}
+
+impl Stream {
+    fn default_port() -> u16 {
+        1935
+    }
+
+    /// Names become directories, and SRT only takes passphrases of 10 to 79
+    /// characters.
+    pub fn is_valid(&self) -> bool {
+        let name = !self.name.is_empty() && self.name.chars().all(|char| char.is_ascii_alphanumeric() || matches!(char, '-' | '_'));
+        let key = match self.ingest {
+            Ingest::Rtmp => !self.key.is_empty(),
+            Ingest::Srt => (10..=79).contains(&self.key.len())
+        };
+        name && key
+    }
+
+    /// Where ffmpeg listens. SRT checks the passphrase itself, while ffmpeg
+    /// takes RTMP publishers whatever their stream name, so they come through
+    /// `rtmp::relay` on `relay_port` instead.
+    fn input(&self, config: &Config, relay_port: u16) -> String {
+        let key = url::encode_component(&self.key);
+        match self.ingest {
+            Ingest::Rtmp => format!("rtmp://{}/live/{key}", SocketAddr::from((Ipv4Addr::LOCALHOST, relay_port))),
+            Ingest::Srt => format!("srt://{}?mode=listener&passphrase={key}", SocketAddr::new(config.ip, self.port))
+        }
+    }
+}
+
+fn find<'a>(config: &'a Config, name: &str) -> Option<&'a Stream> {
+    config.live.iter().find(|stream| *stream.name == *name && stream.is_valid())
+}
+
+fn dir(config: &Config, name: &str) -> PathBuf {
+    config.cache_path.join("live").join(name)
+}
+
+/// A loopback port for ffmpeg to listen on.
+fn free_port() -> std::io::Result<u16> {
+    Ok(std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?.port())
+}
+
+/// Connects to ffmpeg once it listens on `port`.
+async fn connect(port: u16) -> io::Result<TcpStream> {
+    let deadline = time::Instant::now() + READ_TIMEOUT;
+    loop {
+        match TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await {
+            Ok(ffmpeg) => return Ok(ffmpeg),
+            Err(err) if time::Instant::now() >= deadline => return Err(err),
+            Err(_) => time::sleep(POLL_INTERVAL).await
+        }
+    }
+}
+
+/// Waits for a publisher on `stream` and writes its playlist and segments
+/// until it stops, then listens again. The playlist only exists while
+/// someone is publishing.
+async fn ingest(app: &App, stream: &Stream) {
+    let listener = match stream.ingest {
+        Ingest::Rtmp => match TcpListener::bind(SocketAddr::new(app.config.ip, stream.port)).await {
+            Ok(listener) => Some(listener),
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to listen on port {}, live stream `{}` is off", stream.port, stream.name);
+                return;
+            }
+        },
+        Ingest::Srt => None
+    };
+
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        // RTMP publishers are only handed to ffmpeg once they connect
+        let publisher = match &listener {
+            Some(listener) => match listener.accept().await {
+                Ok((publisher, _)) => Some(publisher),
+                Err(err) => {
+                    // Like running out of file descriptors, which takes a
+                    // while to go away
+                    tracing::warn!(error = %err, "Failed to accept a publisher of live stream `{}`", stream.name);
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            },
+            None => None
+        };
+        let relay_port = match publisher.as_ref().map(|_| free_port()).transpose() {
+            Ok(port) => port.unwrap_or_default(),
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to find a port for ffmpeg, live stream `{}` is off", stream.name);
+                return;
+            }
+        };
+
+        let config = &app.config;
+        let dir = dir(config, &stream.name);
+        let _ = fs::remove_dir_all(&dir).await;
+        if let Err(err) = fs::create_dir_all(&dir).await {
+            tracing::error!(error = %err, "Failed to create `{}`, live stream `{}` is off", dir.display(), stream.name);
+            return;
+        }
+
+        let mut command = Command::new(&*config.ffmpeg_command);
+        command.args(["-v", "error", "-nostats", "-rw_timeout", &READ_TIMEOUT.as_micros().to_string()]);
+        if stream.ingest == Ingest::Rtmp {
+            command.args(["-listen", "1"]);
+        }
+        // Low latency playlists are put together from ffmpeg's, where every
+        // segment is a part. Parts only appear once complete, and start on a
+        // keyframe as they're copied.
+        let (duration, size, flags) = if stream.low_latency {
+            (PART_DURATION.to_string(), PLAYLIST_SIZE as u64 * PARTS_PER_SEGMENT + PARTS_PER_SEGMENT, "delete_segments+independent_segments+temp_file")
+        } else {
+            (config.segment_duration.to_string(), PLAYLIST_SIZE as u64, "delete_segments+independent_segments")
+        };
+        command
+            .args([
+                "-i", &stream.input(config, relay_port),
+                "-c", "copy",
+                "-f", "hls",
+                "-hls_time", &duration,
+                "-hls_list_size", &size.to_string(),
+                "-hls_flags", flags,
+                "-hls_segment_filename"
+            ])
+            .arg(dir.join("%d.ts"))
+            .arg(dir.join("playlist.m3u8"))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        // Not counted toward `max_ffmpeg_jobs`, it's idle until someone
+        // publishes and a stream can't wait in line
+        let started = time::Instant::now();
+        let mut child = match ffmpeg::spawn(&mut command) {
+            Ok(child) => child,
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to start ffmpeg, live stream `{}` is off", stream.name);
+                return;
+            }
+        };
+        let relay = async {
+            let publisher = publisher?;
+            match connect(relay_port).await {
+                Ok(ffmpeg) => Some(rtmp::relay(publisher, ffmpeg, &stream.key, KEY_TIMEOUT).await),
+                Err(err) => {
+                    tracing::error!(error = %err, "Failed to pass a publisher of live stream `{}` to ffmpeg", stream.name);
+                    None
+                }
+            }
+        };
+        let exit = async {
+            let mut message = String::new();
+            if let Some(mut stderr) = child.stderr.take() {
+                let _ = stderr.read_to_string(&mut message).await;
+            }
+            (message, child.wait().await)
+        };
+        let (relayed, (message, status)) = tokio::join!(relay, exit);
+        if let Some(Err(err)) = relayed {
+            tracing::warn!("Turned away a publisher of live stream `{}`: {err}", stream.name);
+            continue;
+        }
+
+        let published = fs::try_exists(dir.join("playlist.m3u8")).await.unwrap_or(false);
+        match status {
+            Ok(status) if status.success() || published => tracing::info!("Live stream `{}` ended", stream.name),
+            Ok(_) => tracing::error!("Failed to listen for live stream `{}`: {}", stream.name, message.trim()),
+            Err(err) => tracing::error!(error = %err, "Failed to wait for ffmpeg of live stream `{}`", stream.name)
+        }
+
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = Duration::from_secs(1);
+        } else {
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+/// Listens for every valid stream.
+pub async fn run(app: &'static App) {
+    let config = app.config.get();
+    for (index, stream) in config.live.iter().enumerate() {
+        if !stream.is_valid() {
+            tracing::error!("Ignoring live stream `{}`, its name or key is invalid", stream.name);
+            continue;
+        }
+        if config.live[..index].iter().any(|other| other.port == stream.port && other.ingest == stream.ingest) {
+            tracing::error!("Ignoring live stream `{}`, its port is taken by another stream", stream.name);
+            continue;
+        }
+        tokio::spawn(async move {
+            tracing::info!("Listening for live stream `{}` on port {}", stream.name, stream.port);
+            ingest(app, stream).await;
+        });
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct Status {
+    name: Box<str>,
+    live: bool
+}
+
+/// The declared streams, and which ones are being published.
+pub async fn list_streams(extract::State(config): extract::State<&Config>) -> response::Response {
+    let mut streams = Vec::new();
+    for stream in config.live.iter().filter(|stream| stream.is_valid()) {
+        let live = fs::try_exists(dir(config, &stream.name).join("playlist.m3u8")).await.unwrap_or(false);
+        streams.push(Status { name: stream.name.clone(), live });
+    }
+    response::IntoResponse::into_response(Json(streams))
+}
+
+fn playlist_response(body: String) -> response::Response {
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/vnd.apple.mpegurl")
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .body(body.into())
+        .unwrap()
+}
+
+/// The parts listed in the playlist ffmpeg writes in low latency mode, by the
+/// number in their file name and with their duration, and whether the stream
+/// has ended.
+fn parts(playlist: &str) -> (Vec<(u64, f64)>, bool) {
+    let mut parts = Vec::new();
+    let mut duration = None;
+    for line in playlist.lines() {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            duration = info.split(',').next().and_then(|duration| duration.parse().ok());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let index = line.rsplit('/').next().and_then(|name| name.strip_suffix(".ts")?.parse().ok());
+            if let (Some(duration), Some(index)) = (duration.take(), index) {
+                parts.push((index, duration));
+            }
+        }
+    }
+    (parts, playlist.contains("#EXT-X-ENDLIST"))
+}
+
+/// An LL-HLS playlist over `parts`, grouped into segments. Parts are listed
+/// for the segments close to the live edge, followed by a hint for the one
+/// being cut.
+fn low_latency_playlist(parts: &[(u64, f64)], ended: bool, query: &str) -> String {
+    let next = parts.last().map_or(0, |(index, _)| index + 1);
+    // The first segment may have lost parts to deletion already
+    let parts = &parts[parts.iter().position(|(index, _)| index % PARTS_PER_SEGMENT == 0).unwrap_or(parts.len())..];
+    let segments: Vec<_> = parts.chunk_by(|a, b| a.0 / PARTS_PER_SEGMENT == b.0 / PARTS_PER_SEGMENT).collect();
+    let complete = segments.iter().filter(|segment| ended || segment.len() as u64 == PARTS_PER_SEGMENT).count();
+
+    let duration = |parts: &[(u64, f64)]| parts.iter().map(|(_, duration)| duration).sum::<f64>();
+    let part_target = parts.iter().map(|(_, duration)| *duration).fold(PART_DURATION, f64::max);
+    let target = segments[..complete].iter().map(|segment| duration(segment)).fold(PARTS_PER_SEGMENT as f64 * part_target, f64::max).ceil();
+
+    let mut body = String::from("#EXTM3U\n#EXT-X-VERSION:6\n");
+    writeln!(body, "#EXT-X-TARGETDURATION:{target}").unwrap();
+    writeln!(body, "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}", 3.0 * part_target).unwrap();
+    writeln!(body, "#EXT-X-PART-INF:PART-TARGET={part_target:.3}").unwrap();
+    writeln!(body, "#EXT-X-MEDIA-SEQUENCE:{}", parts.first().map_or(next, |(index, _)| *index) / PARTS_PER_SEGMENT).unwrap();
+    for (position, segment) in segments.iter().enumerate() {
+        if position + 3 >= segments.len() {
+            for (index, duration) in segment.iter() {
+                writeln!(body, "#EXT-X-PART:DURATION={duration:.3},URI=\"{index}.ts{query}\",INDEPENDENT=YES").unwrap();
+            }
+        }
+        if position < complete {
+            writeln!(body, "#EXTINF:{:.3},\nsegment{}.ts{query}", duration(segment), segment[0].0 / PARTS_PER_SEGMENT).unwrap();
+        }
+    }
+    if ended {
+        body.push_str("#EXT-X-ENDLIST\n");
+    } else {
+        writeln!(body, "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{next}.ts{query}\"").unwrap();
+    }
+    body
+}
+
+/// The `_HLS_msn` and `_HLS_part` of a blocking playlist reload, asking for
+/// the playlist once it has that segment or part.
+#[derive(serde::Deserialize)]
+pub struct Reload {
+    #[serde(rename = "_HLS_msn")]
+    msn: Option<u64>,
+    #[serde(rename = "_HLS_part")]
+    part: Option<u64>
+}
+
+/// The rolling playlist as ffmpeg last wrote it, with the token carried to
+/// its segments. Low latency streams hold reloads until what they ask for is
+/// there.
+pub async fn serve_playlist(
+    extract::Path((stream, )): extract::Path<(Box<str>, )>,
+    extract::Query(token): extract::Query<auth::TokenQuery>,
+    extract::Query(reload): extract::Query<Reload>,
+    extract::State(config): extract::State<&Config>
+) -> response::Response {
+    let Some(declared) = find(config, &stream) else {
+        return ApiError::new(Code::StreamNotFound, "Stream not found").into();
+    };
+    let path = dir(config, &stream).join("playlist.m3u8");
+    let query = token.carry(String::new());
+
+    if !declared.low_latency {
+        let Ok(playlist) = fs::read_to_string(&path).await else {
+            return ApiError::new(Code::StreamOffline, "Stream is offline").into();
+        };
+        let body: String = playlist.lines()
+            .map(|line| if line.is_empty() || line.starts_with('#') { format!("{line}\n") } else { format!("{line}{query}\n") })
+            .collect();
+        return playlist_response(body);
+    }
+
+    if reload.part.is_some_and(|part| part >= PARTS_PER_SEGMENT) || (reload.part.is_some() && reload.msn.is_none()) {
+        return ApiError::new(Code::BadRequest, "Invalid _HLS_part").into();
+    }
+    // Without a part, the whole segment is waited for
+    let wanted = match reload.msn.map(|msn| part_index(msn, reload.part.unwrap_or(PARTS_PER_SEGMENT - 1))) {
+        Some(None) => return ApiError::new(Code::BadRequest, "Invalid _HLS_msn").into(),
+        wanted => wanted.flatten()
+    };
+    let deadline = time::Instant::now() + BLOCK_TIMEOUT;
+    loop {
+        let Ok(playlist) = fs::read_to_string(&path).await else {
+            return ApiError::new(Code::StreamOffline, "Stream is offline").into();
+        };
+        let (parts, ended) = self::parts(&playlist);
+        let last = parts.last().map(|(index, _)| *index);
+        match wanted {
+            Some(wanted) if !ended && last.is_none_or(|last| last < wanted) => {
+                // Further than two segments ahead is a confused client
+                if wanted / PARTS_PER_SEGMENT > last.map_or(0, |last| last / PARTS_PER_SEGMENT) + 2 {
+                    return ApiError::new(Code::BadRequest, "_HLS_msn is too far ahead").into();
+                }
+                if time::Instant::now() >= deadline {
+                    return ApiError::new(Code::StreamStalled, "Stream stalled").into();
+                }
+                time::sleep(POLL_INTERVAL).await;
+            }
+            _ => return playlist_response(low_latency_playlist(&parts, ended, &query))
+        }
+    }
+}
+
+/// The index of `part` of segment `msn`, unless clients ask past what parts
+/// can be numbered.
+fn part_index(msn: u64, part: u64) -> Option<u64> {
+    msn.checked_mul(PARTS_PER_SEGMENT)?.checked_add(part)
+}
+
+/// Waits for the part `index` to be cut, for requests following a preload
+/// hint.
+async fn read_part(dir: &std::path::Path, index: u64) -> Option<Vec<u8>> {
+    let path = dir.join(format!("{index}.ts"));
+    let deadline = time::Instant::now() + BLOCK_TIMEOUT;
+    loop {
+        // ffmpeg renames parts into place once they're complete
+        match fs::read(&path).await {
+            Ok(data) => return Some(data),
+            Err(_) if time::Instant::now() < deadline => time::sleep(POLL_INTERVAL).await,
+            Err(_) => return None
+        }
+    }
+}
+
+pub async fn serve_segment(
+    extract::Path((stream, segment)): extract::Path<(Box<str>, Box<str>)>,
+    extract::State(config): extract::State<&Config>
+) -> response::Response {
+    let Some(declared) = find(config, &stream) else {
+        return ApiError::new(Code::SegmentNotFound, "Segment not found").into();
+    };
+    let dir = dir(config, &stream);
+    // Only the names ffmpeg writes, and the segments of low latency
+    // playlists, which keeps requests inside the directory
+    let Some(name) = segment.strip_suffix(".ts") else {
+        return ApiError::new(Code::SegmentNotFound, "Segment not found").into();
+    };
+
+    let data = match (name.strip_prefix("segment").map(str::parse::<u64>), name.parse::<u64>()) {
+        (Some(Ok(msn)), _) if declared.low_latency => {
+            let Some(last) = part_index(msn, PARTS_PER_SEGMENT - 1) else {
+                return ApiError::new(Code::SegmentNotFound, "Segment not found").into();
+            };
+            let mut data = Vec::new();
+            for index in last + 1 - PARTS_PER_SEGMENT..=last {
+                match fs::read(dir.join(format!("{index}.ts"))).await {
+                    // MPEG-TS concatenates as is
+                    Ok(part) => data.extend(part),
+                    Err(_) => return ApiError::new(Code::SegmentNotFound, "Segment not found").into()
+                }
+            }
+            Some(data)
+        }
+        (_, Ok(index)) if declared.low_latency => {
+            // Only the part being cut is worth waiting for
+            let (parts, _) = fs::read_to_string(dir.join("playlist.m3u8")).await.map(|playlist| parts(&playlist)).unwrap_or_default();
+            let next = parts.last().map_or(0, |(index, _)| index + 1);
+            if index <= next { read_part(&dir, index).await } else { None }
+        }
+        // Segments roll off the playlist, slow clients ask for deleted ones
+        (_, Ok(_)) => fs::read(dir.join(&*segment)).await.ok(),
+        _ => None
+    };
+    match data {
+        Some(data) => response::Response::builder()
+            .status(http::StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "video/mp2t")
+            .body(data.into())
+            .unwrap(),
+        None => ApiError::new(Code::SegmentNotFound, "Segment not found").into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        let stream = |name: &str, key: &str, ingest| Stream { name: name.into(), key: key.into(), ingest, port: 1935, low_latency: false };
+        assert!(stream("studio", "secret", Ingest::Rtmp).is_valid());
+        assert!(!stream("../studio", "secret", Ingest::Rtmp).is_valid());
+        assert!(!stream("studio", "", Ingest::Rtmp).is_valid());
+        assert!(!stream("studio", "short", Ingest::Srt).is_valid());
+        assert_eq!(
+            stream("studio", "a b", Ingest::Rtmp).input(&Config::default(), 40000),
+            "rtmp://127.0.0.1:40000/live/a%20b"
+        );
+        assert_eq!(
+            stream("studio", "0123456789", Ingest::Srt).input(&Config::default(), 0),
+            "srt://0.0.0.0:1935?mode=listener&passphrase=0123456789"
+        );
+    }
+
+    #[test]
+    fn part_indices() {
+        assert_eq!(part_index(2, 3), Some(11));
+        assert_eq!(part_index(u64::MAX / PARTS_PER_SEGMENT, PARTS_PER_SEGMENT - 1), Some(u64::MAX));
+        assert_eq!(part_index(u64::MAX / PARTS_PER_SEGMENT + 1, 0), None);
+        assert_eq!(part_index(u64::MAX / PARTS_PER_SEGMENT, PARTS_PER_SEGMENT), None);
+    }
+
+    #[test]
+    fn low_latency() {
+        let playlist = "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:3\n#EXTINF:1.0,\n3.ts\n#EXTINF:1.0,\n4.ts\n#EXTINF:1.0,\n5.ts\n\
+            #EXTINF:1.0,\n6.ts\n#EXTINF:1.0,\n7.ts\n#EXTINF:0.5,\n8.ts\n";
+        let (parts, ended) = parts(playlist);
+        assert_eq!((parts.len(), ended), (6, false));
+
+        let playlist = low_latency_playlist(&parts, ended, "?token=a");
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:1\n"));
+        // The first segment lost part 3, so it's left out
+        assert!(!playlist.contains("\"3.ts"));
+        assert!(playlist.contains("#EXTINF:4.000,\nsegment1.ts?token=a\n"));
+        assert!(playlist.contains("#EXT-X-PART:DURATION=0.500,URI=\"8.ts?token=a\",INDEPENDENT=YES\n"));
+        assert!(!playlist.contains("segment2.ts"));
+        assert!(playlist.ends_with("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"9.ts?token=a\"\n"));
+    }
+}
@@ -0,0 +1,99 @@
+use std::io::{self, IsTerminal};
+use std::process;
+
+use axum::extract::Request;
+use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
+use tower_http::trace::{MakeSpan, TraceLayer};
+#[cfg(feature = "otel")]
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+#[cfg(feature = "otel")]
+use crate::trace;
+use crate::trace::Telemetry;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One line per event
+    #[default]
+    Text,
+    /// Several lines per event, with the span context spelled out
+    Pretty,
+    /// One JSON object per line, for log collectors
+    Json
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Log {
+    /// Filter directives like `info` or `warn,ninja=debug`, overridden by
+    /// `RUST_LOG` when it is set.
+    pub level: Box<str>,
+    pub format: Format
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log { level: "info".into(), format: Format::default() }
+    }
+}
+
+/// Installs the global subscriber. Everything logged before this is lost, so
+/// it should run as soon as the configuration is read.
+pub fn init(log: &Log, telemetry: &Telemetry) {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(filter) if !filter.is_empty() => EnvFilter::try_new(filter),
+        _ => EnvFilter::try_new(&*log.level)
+    };
+    let filter = match filter {
+        Ok(filter) => filter,
+        Err(err) => {
+            eprintln!("ERROR: Invalid log level: {err}");
+            process::exit(1);
+        }
+    };
+
+    // Colors only make sense on a terminal, not in journald or a file
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    let fmt = match log.format {
+        Format::Text => fmt.boxed(),
+        Format::Pretty => fmt.pretty().boxed(),
+        Format::Json => fmt.json().boxed()
+    };
+
+    // The level only filters the logs, traces get everything from `info` up
+    let registry = tracing_subscriber::registry().with(fmt.with_filter(filter));
+    #[cfg(feature = "otel")]
+    let registry = registry.with(trace::export::layer(telemetry).with_filter(LevelFilter::INFO));
+    #[cfg(not(feature = "otel"))]
+    let _ = telemetry;
+    registry.init();
+}
+
+/// Opens a span per request, so that everything logged while handling it
+/// carries the method and path. The client, the request ID and the status
+/// are filled in once they're known.
+#[derive(Clone)]
+pub struct RequestSpan;
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> tracing::Span {
+        tracing::info_span!(
+            "request",
+            method = %request.method(),
+            path = %request.uri().path(),
+            client = tracing::field::Empty,
+            request_id = tracing::field::Empty,
+            status = tracing::field::Empty
+        )
+    }
+}
+
+pub fn layer() -> TraceLayer<SharedClassifier<ServerErrorsAsFailures>, RequestSpan> {
+    TraceLayer::new_for_http().make_span_with(RequestSpan)
+}
@@ -0,0 +1,89 @@
+use std::fmt::Write;
+
+use axum::{extract, http, response};
+
+use crate::auth::{self, TokenQuery};
+use crate::error::{ApiError, Code};
+use crate::index::Video;
+use crate::users::User;
+use crate::{library, url, App};
+
+#[derive(serde::Deserialize)]
+pub struct PlaylistQuery {
+    #[serde(default)]
+    dir: Box<str>
+}
+
+/// An extended M3U playlist of `videos` with absolute URLs, carrying the
+/// `token` it was fetched with for players that can't log in.
+pub fn playlist(request: &http::request::Parts, app: &App, token: &TokenQuery, videos: &[Video]) -> response::Response {
+    let origin = url::absolute(&app.config, request, "");
+    let query = token.carry(String::new());
+
+    let mut body = String::from("#EXTM3U\n");
+    for video in videos {
+        let duration = video.duration.map_or(-1, |duration| duration.round() as i64);
+        // Commas are fine in the title, it's everything after the first one,
+        // but a line break would end the entry
+        let title = video.display_title().replace(['\r', '\n'], " ");
+        writeln!(body, "#EXTINF:{duration},{title}").unwrap();
+        writeln!(body, "{origin}/video/{}{query}", url::encode_path(&video.path)).unwrap();
+    }
+
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "audio/x-mpegurl; charset=utf-8")
+        .body(body.into())
+        .unwrap()
+}
+
+/// Every video below `dir`, in path order, so that a folder plays as a queue.
+pub async fn serve_playlist(
+    extract::Query(query): extract::Query<PlaylistQuery>,
+    extract::Query(token): extract::Query<TokenQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    request: http::request::Parts
+) -> response::Response {
+    let dir = query.dir.trim_matches('/');
+    if !dir.is_empty() && !library::is_directory(&app.config, dir).await {
+        return ApiError::new(Code::DirectoryNotFound, "Directory not found").into();
+    }
+    if user.as_ref().is_some_and(|user| !auth::can_access(&app.config, user, dir)) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+
+    let videos: Vec<Video> = match app.index.all() {
+        Ok(videos) => videos.into_iter()
+            .filter(|video| dir.is_empty() || video.path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/')))
+            .filter(|video| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, &video.path)))
+            .collect(),
+        Err(err) => return ApiError::database("Failed to build playlist", err).into()
+    };
+    playlist(&request, app, &token, &videos)
+}
+
+/// The videos in a collection, in the order they were added.
+pub async fn serve_collection_playlist(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::Query(token): extract::Query<TokenQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    request: http::request::Parts
+) -> response::Response {
+    let items = match app.collections.name(id).and_then(|name| Ok((name, app.collections.items(id)?))) {
+        Ok((Some(_), items)) => items,
+        Ok((None, _)) => return ApiError::new(Code::CollectionNotFound, "Collection not found").into(),
+        Err(err) => return ApiError::database("Failed to build playlist", err).into()
+    };
+
+    let mut videos = Vec::new();
+    for video in items.iter().filter(|video| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, video))) {
+        match app.index.get(video) {
+            Ok(Some(video)) => videos.push(video),
+            Ok(None) => {}
+            Err(err) => return ApiError::database("Failed to build playlist", err).into()
+        }
+    }
+    playlist(&request, app, &token, &videos)
+}
@@ -0,0 +1,113 @@
+use std::path::Path;
+
+use tokio::fs;
+use tokio::io::{self, AsyncReadExt, AsyncSeekExt};
+
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+    ("ts", "video/mp2t"),
+    ("m2ts", "video/mp2t"),
+    ("mpg", "video/mpeg"),
+    ("mpeg", "video/mpeg"),
+    ("ogv", "video/ogg"),
+    ("flv", "video/x-flv"),
+    ("wmv", "video/x-ms-wmv"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("flac", "audio/flac"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav")
+];
+
+pub fn from_extension(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?;
+    EXTENSIONS.iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map(|(_, mime)| *mime)
+}
+
+/// Guesses the type from the first bytes of a file.
+pub fn sniff(header: &[u8]) -> Option<&'static str> {
+    match header {
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => {
+            // Matroska and WebM share the EBML header, the doctype tells them apart
+            let webm = header.windows(4).any(|window| window == b"webm");
+            Some(if webm { "video/webm" } else { "video/x-matroska" })
+        }
+        [_, _, _, _, b'f', b't', b'y', b'p', b'q', b't', ..] => Some("video/quicktime"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("video/mp4"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'A', b'V', b'I', b' ', ..] => Some("video/x-msvideo"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some("audio/wav"),
+        [b'O', b'g', b'g', b'S', ..] => Some("video/ogg"),
+        [b'F', b'L', b'V', ..] => Some("video/x-flv"),
+        // MPEG program streams start with a pack header
+        [0x00, 0x00, 0x01, 0xba, ..] => Some("video/mpeg"),
+        [b'f', b'L', b'a', b'C', ..] => Some("audio/flac"),
+        [b'I', b'D', b'3', ..] => Some("audio/mpeg"),
+        [0xff, second, ..] if second & 0xe0 == 0xe0 => Some("audio/mpeg"),
+        // Transport stream packets are 188 bytes long and start with a sync byte
+        [0x47, ..] if header.get(188) == Some(&0x47) => Some("video/mp2t"),
+        _ => None
+    }
+}
+
+/// Content type of `file`, from its extension or, failing that, its content.
+/// The read position is left at the start of the file.
+pub async fn detect(path: &Path, file: &mut fs::File) -> io::Result<&'static str> {
+    if let Some(mime) = from_extension(path) {
+        return Ok(mime);
+    }
+
+    let mut header = [0; 256];
+    file.seek(io::SeekFrom::Start(0)).await?;
+    let length = file.read(&mut header).await?;
+    file.seek(io::SeekFrom::Start(0)).await?;
+
+    Ok(sniff(&header[..length]).unwrap_or("application/octet-stream"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions() {
+        assert_eq!(from_extension(Path::new("a/movie.MPG")), Some("video/mpeg"));
+        assert_eq!(from_extension(Path::new("movie.mpeg")), Some("video/mpeg"));
+        assert_eq!(from_extension(Path::new("movie")), None);
+    }
+
+    #[test]
+    fn sniffing() {
+        let mut mkv = vec![0x1a, 0x45, 0xdf, 0xa3];
+        assert_eq!(sniff(&mkv), Some("video/x-matroska"));
+        mkv.extend_from_slice(b"\x42\x82\x84webm");
+        assert_eq!(sniff(&mkv), Some("video/webm"));
+        assert_eq!(sniff(b"\0\0\0\x20ftypisom"), Some("video/mp4"));
+        assert_eq!(sniff(b"\0\0\0\x14ftypqt  "), Some("video/quicktime"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0AVI LIST"), Some("video/x-msvideo"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVEfmt "), Some("audio/wav"));
+        assert_eq!(sniff(b"OggS\0"), Some("video/ogg"));
+        assert_eq!(sniff(b"FLV\x01"), Some("video/x-flv"));
+        assert_eq!(sniff(b"fLaC"), Some("audio/flac"));
+        assert_eq!(sniff(b"ID3\x04"), Some("audio/mpeg"));
+        assert_eq!(sniff(&[0xff, 0xfb, 0x90]), Some("audio/mpeg"));
+        assert_eq!(sniff(&[0x00, 0x00, 0x01, 0xba, 0x44]), Some("video/mpeg"));
+
+        let mut ts = vec![0; 189];
+        ts[0] = 0x47;
+        assert_eq!(sniff(&ts), None);
+        ts[188] = 0x47;
+        assert_eq!(sniff(&ts), Some("video/mp2t"));
+
+        assert_eq!(sniff(b"plain text"), None);
+        assert_eq!(sniff(&[]), None);
+    }
+}
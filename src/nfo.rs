@@ -0,0 +1,280 @@
+use std::path::Path;
+
+use axum::{extract, http, response};
+use tokio::fs;
+
+use crate::error::{ApiError, Code};
+use crate::{jail, subtitles, App};
+
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp"];
+
+fn image_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase).as_deref() {
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        _ => "image/jpeg"
+    }
+}
+
+/// What Kodi-style sidecars next to a video say about it.
+#[derive(serde::Serialize, Default, PartialEq, Debug)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<Box<str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plot: Option<Box<str>>,
+    /// Filenames of the artwork, in the directory of the video.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poster: Option<Box<str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fanart: Option<Box<str>>
+}
+
+#[derive(serde::Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Artwork {
+    Poster,
+    Fanart
+}
+
+impl Artwork {
+    fn name(self) -> &'static str {
+        match self {
+            Artwork::Poster => "poster",
+            Artwork::Fanart => "fanart"
+        }
+    }
+}
+
+/// Whether a change to `path` can change the metadata of the videos next to
+/// it.
+pub fn is_sidecar(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("nfo") || IMAGE_EXTENSIONS.iter().any(|image| image.eq_ignore_ascii_case(extension)))
+}
+
+/// Whether `filename` is the NFO or artwork of the video named `stem` alone,
+/// rather than shared by the whole directory.
+pub fn belongs_to(stem: &str, filename: &str) -> bool {
+    let Some((name, extension)) = filename.rsplit_once('.') else {
+        return false;
+    };
+    if extension.eq_ignore_ascii_case("nfo") {
+        return name.eq_ignore_ascii_case(stem);
+    }
+
+    IMAGE_EXTENSIONS.iter().any(|image| image.eq_ignore_ascii_case(extension))
+        && [Artwork::Poster, Artwork::Fanart].iter().any(|artwork| name.eq_ignore_ascii_case(&format!("{stem}-{}", artwork.name())))
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let char = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity.strip_prefix('#')?.parse().ok().and_then(char::from_u32)
+            }
+        });
+        match (entity, char) {
+            (Some(entity), Some(char)) => {
+                decoded.push(char);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The text of the first `<name>` element in `xml`, which is all the
+/// structure NFO files need: they're flat lists of fields.
+pub fn element(xml: &str, name: &str) -> Option<String> {
+    let open = format!("<{name}");
+    let mut search = xml;
+    let content = loop {
+        let start = search.find(&open)?;
+        let after = &search[start + open.len()..];
+        // `<title>` but not `<titles>` or `<originaltitle>`
+        match after.chars().next()? {
+            '>' => break &after[1..],
+            char if char.is_whitespace() => break &after[after.find('>')? + 1..],
+            '/' => return None,
+            _ => search = after
+        }
+    };
+    let content = &content[..content.find(&format!("</{name}>"))?];
+
+    let text = match content.trim().strip_prefix("<![CDATA[") {
+        Some(cdata) => cdata.strip_suffix("]]>")?.to_owned(),
+        None => decode_entities(content)
+    };
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_owned())
+}
+
+/// Reads the title, year and plot of a movie or episode NFO. Files that only
+/// hold a link to a scraper site have none of them.
+pub fn parse(xml: &str) -> Metadata {
+    let year = element(xml, "year")
+        .or_else(|| element(xml, "premiered"))
+        .or_else(|| element(xml, "aired"))
+        .and_then(|year| year.get(..4)?.parse().ok());
+
+    Metadata {
+        title: element(xml, "title").map(Into::into),
+        year,
+        plot: element(xml, "plot").map(Into::into),
+        poster: None,
+        fanart: None
+    }
+}
+
+/// The first of `candidates` in `filenames`, ignoring case.
+fn find<'a>(filenames: &'a [Box<str>], candidates: &[String]) -> Option<&'a str> {
+    candidates.iter().find_map(|candidate| {
+        filenames.iter().find(|filename| filename.eq_ignore_ascii_case(candidate)).map(|filename| &**filename)
+    })
+}
+
+/// Reads the sidecars of the video at `path`: `movie.nfo` or one named after
+/// the video, and artwork like `movie-poster.jpg` or `poster.jpg`.
+pub async fn read(path: &Path) -> Metadata {
+    let (Some(dir), Some(stem)) = (path.parent(), path.file_stem().and_then(|stem| stem.to_str())) else {
+        return Metadata::default();
+    };
+    let filenames = subtitles::filenames(dir).await;
+
+    let nfo = find(&filenames, &[format!("{stem}.nfo"), "movie.nfo".into()]);
+    let mut metadata = match nfo {
+        Some(nfo) => match fs::read(dir.join(nfo)).await {
+            Ok(xml) => parse(&String::from_utf8_lossy(&xml)),
+            Err(err) => {
+                tracing::warn!(error = %err, "Failed to read `{}`", dir.join(nfo).display());
+                Metadata::default()
+            }
+        },
+        None => Metadata::default()
+    };
+
+    let artwork = |names: &[&str]| {
+        let candidates: Vec<_> = IMAGE_EXTENSIONS.iter()
+            .flat_map(|extension| names.iter().map(move |name| (name, extension)))
+            .map(|(name, extension)| format!("{name}.{extension}"))
+            .collect();
+        find(&filenames, &candidates).map(Into::into)
+    };
+    metadata.poster = artwork(&[&format!("{stem}-poster"), "poster", "folder"]);
+    metadata.fanart = artwork(&[&format!("{stem}-fanart"), "fanart"]);
+    metadata
+}
+
+/// Serves the poster or fanart found next to a video, or the poster cached
+/// from TMDB.
+pub async fn serve_artwork(
+    extract::Path((kind, video)): extract::Path<(Artwork, Box<Path>)>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let config = &*app.config;
+    let not_found = || ApiError::new(Code::ArtworkNotFound, "Artwork not found").into();
+
+    let video_path = match jail::video(config, &video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    let metadata = read(&video_path).await;
+    let filename = match kind {
+        Artwork::Poster => metadata.poster,
+        Artwork::Fanart => metadata.fanart
+    };
+    let path = match filename {
+        Some(filename) => video_path.with_file_name(&*filename),
+        #[cfg(feature = "tmdb")]
+        None if matches!(kind, Artwork::Poster) => {
+            let key = video.to_str().and_then(|video| app.index.get(video.trim_matches('/')).ok().flatten()?.tmdb);
+            match key {
+                Some(key) => crate::tmdb::poster_path(config, &key),
+                None => return not_found()
+            }
+        }
+        None => return not_found()
+    };
+    match fs::read(&path).await {
+        Ok(image) => response::Response::builder()
+            .status(http::StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, image_type(&path))
+            .body(image.into())
+            .unwrap(),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to read {} `{}`", kind.name(), path.display());
+            not_found()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_sidecars() {
+        assert!(belongs_to("movie", "movie.nfo"));
+        assert!(belongs_to("movie", "movie-poster.jpg"));
+        assert!(belongs_to("movie", "Movie-Fanart.PNG"));
+        assert!(!belongs_to("movie", "poster.jpg"));
+        assert!(!belongs_to("movie", "movie.nfo.bak"));
+        assert!(!belongs_to("movie", "other.nfo"));
+    }
+
+    #[test]
+    fn movie() {
+        let metadata = parse(r#"<?xml version="1.0" encoding="UTF-8" standalone="yes" ?>
+            <movie>
+                <title>Alien</title>
+                <originaltitle>Alien (Director's Cut)</originaltitle>
+                <year>1979</year>
+                <plot>The crew of the &quot;Nostromo&quot; &amp; a stowaway.</plot>
+            </movie>"#);
+        assert_eq!(metadata, Metadata {
+            title: Some("Alien".into()),
+            year: Some(1979),
+            plot: Some(r#"The crew of the "Nostromo" & a stowaway."#.into()),
+            ..Metadata::default()
+        });
+    }
+
+    #[test]
+    fn episode() {
+        let metadata = parse("<episodedetails><title lang=\"en\"><![CDATA[Pilot <1>]]></title><aired>2004-09-22</aired></episodedetails>");
+        assert_eq!(metadata.title.as_deref(), Some("Pilot <1>"));
+        assert_eq!(metadata.year, Some(2004));
+        assert_eq!(metadata.plot, None);
+    }
+
+    #[test]
+    fn links_only() {
+        assert_eq!(parse("https://www.imdb.com/title/tt0078748/"), Metadata::default());
+        assert_eq!(parse("<movie><title/></movie>"), Metadata::default());
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(decode_entities("&#65;&#x42;&unknown; & &lt;"), "AB&unknown; & <");
+    }
+}
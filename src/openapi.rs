@@ -0,0 +1,640 @@
+use axum::{extract, http, response, Json};
+use serde_json::{json, Map, Value};
+
+use crate::{url, Config};
+
+/// Swagger UI, loaded from a CDN so that the binary doesn't carry it. The
+/// version is pinned, and the page is sandboxed by [`DOCS_POLICY`], so that
+/// whatever the CDN serves never runs with the session of whoever opens it.
+const SWAGGER_UI: &str = "https://unpkg.com/swagger-ui-dist@5.17.14";
+
+const DOCS: &str = r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>ninja API</title>
+<link rel="stylesheet" href="%SWAGGER_UI%/swagger-ui.css" crossorigin="anonymous">
+</head>
+<body>
+<div id="docs"></div>
+<script src="%SWAGGER_UI%/swagger-ui-bundle.js" crossorigin="anonymous"></script>
+<script>SwaggerUIBundle({ spec: %SPEC%, dom_id: "#docs" });</script>
+</body>
+</html>
+"##;
+
+/// Gives the docs an opaque origin, without the cookies and storage of the
+/// server's own, and keeps them to the CDN. The spec is inlined since the
+/// page can't fetch it with credentials anyway.
+const DOCS_POLICY: &str = "sandbox allow-scripts allow-popups; default-src 'none'; \
+    script-src 'unsafe-inline' https://unpkg.com; style-src 'unsafe-inline' https://unpkg.com; \
+    img-src data: https://unpkg.com; connect-src 'self'";
+
+#[derive(Clone, Copy, PartialEq)]
+enum In {
+    Path,
+    Query
+}
+
+struct Param {
+    name: &'static str,
+    location: In,
+    /// A JSON schema type, or an enum as values separated by `|`.
+    schema: &'static str,
+    required: bool,
+    description: &'static str
+}
+
+/// A path parameter. Those named `video` or `path` take the path of a video
+/// or directory in the library, slashes included.
+const fn path(name: &'static str, schema: &'static str, description: &'static str) -> Param {
+    Param { name, location: In::Path, schema, required: true, description }
+}
+
+const fn query(name: &'static str, schema: &'static str, description: &'static str) -> Param {
+    Param { name, location: In::Query, schema, required: false, description }
+}
+
+const fn required(name: &'static str, schema: &'static str, description: &'static str) -> Param {
+    Param { name, location: In::Query, schema, required: true, description }
+}
+
+const VIDEO: Param = path("video", "string", "Path of the video in the library, slashes included");
+const ID: Param = path("id", "integer", "");
+
+/// The transcoding options of `transcode::Options`.
+const SUBS: Param = query("subs", "burn", "Burns the subtitles into the picture");
+const TRACK: Param = query("track", "integer", "Subtitle track to burn in, or the sidecar's index");
+const AUDIO: Param = query("audio", "integer", "Audio track to keep");
+const PROFILE: Param = query("profile", "string", "Transcode profile, the default one for HLS");
+const TONEMAP: Param = query("tonemap", "boolean", "Tone maps HDR to SDR, on by default when `tonemap` is set");
+
+/// What a successful response holds.
+enum Returns {
+    /// JSON matching a schema, see [`schema`].
+    Json(u16, &'static str),
+    /// Anything else, by content type.
+    Media(&'static str),
+    Empty(u16)
+}
+
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    params: &'static [Param],
+    /// Schema of the JSON body, or `multipart` for a form upload.
+    body: Option<&'static str>,
+    returns: Returns,
+    /// Whether it's open without credentials.
+    public: bool
+}
+
+const fn op(method: &'static str, path: &'static str, tag: &'static str, summary: &'static str, params: &'static [Param], returns: Returns) -> Operation {
+    Operation { method, path, tag, summary, params, body: None, returns, public: false }
+}
+
+impl Operation {
+    const fn body(self, body: &'static str) -> Self {
+        Operation { body: Some(body), ..self }
+    }
+
+    const fn public(self) -> Self {
+        Operation { public: true, ..self }
+    }
+}
+
+use Returns::{Empty, Json as Ok, Media};
+
+/// Every route, in the order of the router. The web UI's assets aren't part of
+/// the API and are left out.
+const OPERATIONS: &[Operation] = &[
+    op("get", "/video/{video}", "media", "Streams a video, remuxed or transcoded by a profile when needed. Supports ranges", &[VIDEO, SUBS, TRACK, AUDIO, PROFILE, TONEMAP], Media("video/*")),
+    op("get", "/frame/{video}", "media", "Extracts a single frame", &[
+        VIDEO,
+        required("t", "number", "Timestamp in seconds"),
+        query("w", "integer", "Width"),
+        query("h", "integer", "Height"),
+        query("q", "integer", "Quality from 1 to 100"),
+        query("format", "jpeg|png|webp", ""),
+        query("precise", "boolean", "Decodes up to the exact timestamp rather than the keyframe before it, unless the server sets `frame_args`")
+    ], Media("image/*")),
+    op("get", "/frames/{video}", "media", "Extracts several frames in one go, as the parts of a multipart body named after their timestamps", &[
+        VIDEO,
+        required("t", "string", "Timestamps in seconds, separated by commas"),
+        query("w", "integer", "Width"),
+        query("h", "integer", "Height"),
+        query("q", "integer", "Quality from 1 to 100"),
+        query("format", "jpeg|png|webp", "")
+    ], Media("multipart/form-data")),
+    op("get", "/thumb/{video}", "media", "The poster of a video", &[VIDEO], Media("image/jpeg")),
+    op("get", "/storyboard/{video}/storyboard.vtt", "media", "Scrubbing previews as a WebVTT track pointing into the sprite", &[VIDEO], Media("text/vtt")),
+    op("get", "/storyboard/{video}/sprite.jpg", "media", "The sprite sheet of the storyboard", &[VIDEO], Media("image/jpeg")),
+    op("get", "/waveform/{video}", "media", "Peaks of the first audio track, from 0 to 1", &[
+        VIDEO,
+        query("samples", "integer", "Number of peaks, 1000 by default")
+    ], Ok(200, "Waveform")),
+    op("get", "/info/{video}", "library", "Container and stream details", &[VIDEO], Ok(200, "Info")),
+    op("get", "/checksum/{video}", "library", "SHA-256 of the file, also sent as `Repr-Digest`", &[VIDEO], Ok(200, "Checksum")),
+    op("get", "/artwork/{kind}/{video}", "media", "Artwork next to the video, or from TMDB", &[path("kind", "poster|fanart", ""), VIDEO], Media("image/*")),
+    op("get", "/chapters/{video}", "library", "Chapters of the video", &[VIDEO], Ok(200, "[Chapter]")),
+    op("get", "/keyframes/{video}", "library", "Timestamps of the keyframes, in seconds", &[VIDEO], Ok(200, "[number]")),
+    op("get", "/libraries", "library", "Libraries the user can access", &[], Ok(200, "[Library]")),
+    op("get", "/library", "library", "Videos at the root of the library, the total sent as `X-Total-Count`", &[
+        query("sort", "name|mtime|size|duration", ""),
+        query("order", "asc|desc", ""),
+        query("offset", "integer", ""),
+        query("limit", "integer", ""),
+        query("ext", "string", "Only files with this extension")
+    ], Ok(200, "[Entry]")),
+    op("get", "/library/{path}", "library", "Videos in a directory, the total sent as `X-Total-Count`", &[
+        path("path", "string", "Directory in the library, slashes included"),
+        query("sort", "name|mtime|size|duration", ""),
+        query("order", "asc|desc", ""),
+        query("offset", "integer", ""),
+        query("limit", "integer", ""),
+        query("ext", "string", "Only files with this extension")
+    ], Ok(200, "[Entry]")),
+    op("get", "/search", "library", "Searches titles, filenames and tags, best matches first", &[
+        required("q", "string", "Search terms, all of which have to match"),
+        query("limit", "integer", "")
+    ], Ok(200, "[SearchResult]")),
+    op("get", "/duplicates", "admin", "Groups of videos that look like the same content", &[], Ok(200, "[Duplicates]")),
+    op("get", "/browse", "library", "Folders and videos at the root of the library", &[], Ok(200, "Browse")),
+    op("get", "/browse/{path}", "library", "Folders and videos in a directory", &[path("path", "string", "Directory in the library, slashes included")], Ok(200, "Browse")),
+    op("get", "/preview/{video}", "media", "A short animated preview", &[
+        VIDEO,
+        required("t", "integer", "Start in seconds"),
+        query("d", "integer", "Duration in seconds"),
+        query("w", "integer", "Width"),
+        query("format", "webp|gif", "")
+    ], Media("image/*")),
+    op("get", "/subtitles/{video}", "media", "Subtitle tracks, or one of them converted to WebVTT when `track` or `file` is given", &[
+        VIDEO,
+        query("track", "integer", "Embedded subtitle track"),
+        query("file", "string", "Sidecar subtitle file")
+    ], Ok(200, "Subtitles")),
+    op("get", "/audio/{video}", "media", "The audio track alone", &[VIDEO, query("format", "mp3|aac|opus", "")], Media("audio/*")),
+    op("get", "/clip/{video}", "media", "A trimmed MP4", &[
+        VIDEO,
+        required("start", "number", "Start in seconds"),
+        required("end", "number", "End in seconds")
+    ], Media("video/mp4")),
+    op("get", "/jobs", "jobs", "Transcode jobs", &[], Ok(200, "[Job]")),
+    op("post", "/jobs", "jobs", "Queues a transcode job", &[], Ok(202, "Job")).body("JobSpec"),
+    op("get", "/jobs/{id}", "jobs", "A transcode job", &[ID], Ok(200, "Job")),
+    op("delete", "/jobs/{id}", "jobs", "Cancels a job and deletes its output", &[ID], Empty(204)),
+    op("get", "/jobs/{id}/events", "jobs", "Progress of a job as server-sent events", &[ID], Media("text/event-stream")),
+    op("get", "/jobs/{id}/output", "jobs", "Output of a completed job", &[ID], Media("video/mp4")),
+    op("get", "/hls/{video}/master.m3u8", "hls", "HLS master playlist", &[VIDEO, SUBS, TRACK, AUDIO, PROFILE, TONEMAP], Media("application/vnd.apple.mpegurl")),
+    op("get", "/hls/{video}/{rendition}/index.m3u8", "hls", "HLS playlist of a rendition", &[VIDEO, path("rendition", "string", ""), SUBS, TRACK, AUDIO, PROFILE, TONEMAP], Media("application/vnd.apple.mpegurl")),
+    op("get", "/hls/{video}/{rendition}/{segment}", "hls", "An HLS segment, named like `3.ts`", &[VIDEO, path("rendition", "string", ""), path("segment", "string", ""), SUBS, TRACK, AUDIO, PROFILE, TONEMAP], Media("video/mp2t")),
+    op("get", "/zip/dir/{path}", "download", "A directory as a ZIP", &[path("path", "string", "Directory in the library, slashes included")], Media("application/zip")),
+    op("get", "/zip/collection/{id}", "download", "A collection as a ZIP", &[ID], Media("application/zip")),
+    op("get", "/download/{video}", "download", "A video as an attachment", &[VIDEO], Media("video/*")),
+    op("patch", "/files/{video}", "files", "Moves or renames a video with its sidecars", &[VIDEO], Ok(200, "Video")).body("Move"),
+    op("delete", "/files/{video}", "files", "Deletes a video, to the trash when it's kept", &[VIDEO], Empty(204)),
+    op("get", "/trash", "files", "Deleted videos, most recent first", &[], Ok(200, "[TrashEntry]")),
+    op("post", "/trash/{id}/restore", "files", "Puts a deleted video back", &[path("id", "string", "")], Ok(200, "Video")),
+    op("post", "/upload", "files", "Uploads videos with a multipart form", &[query("dir", "string", "Directory to upload into")], Ok(201, "[Video]")).body("multipart"),
+    op("post", "/upload/tus", "files", "Starts a resumable tus upload", &[], Empty(201)),
+    op("options", "/upload/tus", "files", "tus capabilities", &[], Empty(204)),
+    op("head", "/upload/tus/{id}", "files", "Offset of a tus upload", &[path("id", "string", "")], Empty(200)),
+    op("patch", "/upload/tus/{id}", "files", "Appends to a tus upload", &[path("id", "string", "")], Empty(204)),
+    op("delete", "/upload/tus/{id}", "files", "Cancels a tus upload", &[path("id", "string", "")], Empty(204)),
+    op("post", "/share/{video}", "sharing", "Creates a link that plays the video without logging in", &[VIDEO], Ok(201, "Share")).body("ShareRequest"),
+    op("get", "/collections", "collections", "Collections", &[], Ok(200, "[Collection]")),
+    op("post", "/collections", "collections", "Creates a collection", &[], Ok(201, "Collection")).body("Name"),
+    op("get", "/collections/{id}", "collections", "A collection and its videos", &[ID], Ok(200, "CollectionItems")),
+    op("delete", "/collections/{id}", "collections", "Deletes a collection", &[ID], Empty(204)),
+    op("put", "/collections/{id}/items", "collections", "Replaces the videos of a collection", &[ID], Empty(204)).body("Items"),
+    op("get", "/collections/{id}/playlist.m3u", "collections", "A collection as an M3U playlist", &[ID], Media("audio/x-mpegurl")),
+    op("get", "/playlist.m3u", "library", "A directory as an M3U playlist", &[query("dir", "string", "")], Media("audio/x-mpegurl")),
+    op("get", "/playlists", "playlists", "The user's playlists", &[], Ok(200, "[Playlist]")),
+    op("post", "/playlists", "playlists", "Creates a playlist", &[], Ok(201, "Playlist")).body("Name"),
+    op("get", "/playlists/{id}", "playlists", "A playlist and its items", &[ID], Ok(200, "PlaylistItems")),
+    op("patch", "/playlists/{id}", "playlists", "Renames a playlist", &[ID], Empty(204)).body("Name"),
+    op("delete", "/playlists/{id}", "playlists", "Deletes a playlist", &[ID], Empty(204)),
+    op("put", "/playlists/{id}/items", "playlists", "Replaces the items of a playlist", &[ID], Empty(204)).body("Items"),
+    op("post", "/playlists/{id}/items", "playlists", "Adds items to a playlist", &[ID], Empty(204)).body("Items"),
+    op("delete", "/playlists/{id}/items/{position}", "playlists", "Removes an item", &[ID, path("position", "integer", "")], Empty(204)),
+    op("post", "/playlists/{id}/items/{position}/move", "playlists", "Moves an item", &[ID, path("position", "integer", "")], Empty(204)).body("MoveItem"),
+    op("get", "/playlists/{id}/playlist.m3u", "playlists", "A playlist as M3U", &[ID], Media("audio/x-mpegurl")),
+    op("get", "/feed.xml", "feeds", "RSS feed of the library", &[
+        query("tag", "string", "Only videos with this tag"),
+        query("collection", "integer", "Only videos of this collection"),
+        query("limit", "integer", "")
+    ], Media("application/rss+xml")),
+    op("get", "/feed/{feed}", "feeds", "RSS feed of a directory", &[
+        path("feed", "string", "Directory in the library, slashes included"),
+        query("tag", "string", "Only videos with this tag"),
+        query("collection", "integer", "Only videos of this collection"),
+        query("limit", "integer", "")
+    ], Media("application/rss+xml")),
+    op("get", "/home", "library", "Recently added, continue watching and most played", &[query("limit", "integer", "Videos per row")], Ok(200, "Home")),
+    op("get", "/progress", "progress", "Playback positions of the user", &[], Ok(200, "[Progress]")),
+    op("get", "/favorites", "progress", "The user's favorite videos", &[], Ok(200, "[Video]")),
+    op("post", "/favorites/{video}", "progress", "Adds a favorite", &[VIDEO], Empty(204)),
+    op("delete", "/favorites/{video}", "progress", "Removes a favorite", &[VIDEO], Empty(204)),
+    op("get", "/progress/{video}", "progress", "Playback position in a video", &[VIDEO], Ok(200, "Progress")),
+    op("put", "/progress/{video}", "progress", "Saves the playback position", &[VIDEO], Ok(200, "Progress")).body("ProgressRequest"),
+    op("delete", "/progress/{video}", "progress", "Forgets the playback position", &[VIDEO], Empty(204)),
+    op("get", "/party/{room}", "party", "Joins a watch party over a WebSocket", &[path("room", "string", ""), query("video", "string", "")], Empty(101)),
+    op("get", "/live", "live", "Live streams and whether they're on air", &[], Ok(200, "[LiveStream]")),
+    op("get", "/live/{stream}/playlist.m3u8", "live", "HLS playlist of a live stream, blocking for LL-HLS", &[
+        path("stream", "string", ""),
+        query("_HLS_msn", "integer", ""),
+        query("_HLS_part", "integer", "")
+    ], Media("application/vnd.apple.mpegurl")),
+    op("get", "/live/{stream}/{segment}", "live", "A segment of a live stream", &[path("stream", "string", ""), path("segment", "string", "")], Media("video/*")),
+    op("get", "/cast/devices", "cast", "Chromecasts found on the network", &[], Ok(200, "[CastDevice]")),
+    op("get", "/cast/status", "cast", "What a Chromecast is playing", &[required("device", "string", "")], Ok(200, "CastStatus")),
+    op("post", "/cast/control", "cast", "Plays, pauses, seeks or stops a Chromecast", &[], Ok(200, "CastStatus")).body("CastControl"),
+    op("post", "/cast/{video}", "cast", "Plays a video on a Chromecast", &[VIDEO], Ok(200, "CastStatus")).body("Cast"),
+    op("get", "/admin/sessions", "admin", "Streams being played", &[], Ok(200, "[Session]")),
+    op("delete", "/admin/sessions/{id}", "admin", "Ends a stream", &[ID], Empty(204)),
+    op("get", "/admin/tasks", "admin", "Scheduled tasks with their last and next run", &[], Ok(200, "[Task]")),
+    op("post", "/logout", "auth", "Ends the session", &[], Empty(204)),
+    op("post", "/login", "auth", "Starts a session, also set as a cookie", &[], Ok(200, "Token")).body("Login").public(),
+    op("get", "/dlna/description.xml", "dlna", "UPnP device description, for the local network only", &[], Media("text/xml")).public(),
+    op("get", "/dlna/ContentDirectory.xml", "dlna", "ContentDirectory service description", &[], Media("text/xml")).public(),
+    op("get", "/dlna/ConnectionManager.xml", "dlna", "ConnectionManager service description", &[], Media("text/xml")).public(),
+    op("post", "/dlna/control/ContentDirectory", "dlna", "ContentDirectory SOAP actions", &[], Media("text/xml")).public(),
+    op("post", "/dlna/control/ConnectionManager", "dlna", "ConnectionManager SOAP actions", &[], Media("text/xml")).public(),
+    op("get", "/dlna/media/{video}", "dlna", "Streams a video to a renderer", &[VIDEO], Media("video/*")).public(),
+    op("get", "/dlna/thumb/{video}", "dlna", "The poster of a video, for a renderer", &[VIDEO], Media("image/jpeg")).public(),
+    op("get", "/healthz", "health", "Liveness", &[], Ok(200, "Health")).public(),
+    op("get", "/readyz", "health", "Readiness, 503 when a dependency is down", &[], Ok(200, "Health")).public(),
+    op("get", "/openapi.json", "docs", "This document", &[], Media("application/json")).public(),
+    op("get", "/docs", "docs", "Swagger UI for this document", &[], Media("text/html")).public()
+];
+
+/// A schema for `name`: a JSON type, a component, or an array of either when
+/// in brackets.
+fn schema(name: &str) -> Value {
+    if let Some(item) = name.strip_prefix('[').and_then(|name| name.strip_suffix(']')) {
+        return json!({ "type": "array", "items": schema(item) });
+    }
+    match name {
+        "string" | "integer" | "number" | "boolean" => json!({ "type": name }),
+        name if name.contains('|') || name.chars().next().is_some_and(char::is_lowercase) => {
+            json!({ "type": "string", "enum": name.split('|').collect::<Vec<_>>() })
+        }
+        name => json!({ "$ref": format!("#/components/schemas/{name}") })
+    }
+}
+
+fn nullable(kind: &str) -> Value {
+    json!({ "type": [kind, "null"] })
+}
+
+fn components() -> Value {
+    let video = json!({
+        "type": "object",
+        "required": ["path", "filename", "size", "mtime"],
+        "properties": {
+            "path": { "type": "string" },
+            "filename": { "type": "string" },
+            "size": { "type": "integer" },
+            "mtime": { "type": "integer" },
+            "duration": nullable("number"),
+            "width": nullable("integer"),
+            "height": nullable("integer"),
+            "video_codec": nullable("string"),
+            "audio_codec": nullable("string"),
+            "title": nullable("string"),
+            "tags": nullable("string"),
+            "year": nullable("integer"),
+            "plot": nullable("string"),
+            "poster": nullable("string"),
+            "fanart": nullable("string"),
+            "tmdb": nullable("string"),
+            "added": nullable("integer"),
+            "sha256": nullable("string"),
+            "phash": nullable("string")
+        }
+    });
+    // Types that add to a video
+    let with_video = |properties: Value| json!({
+        "allOf": [schema("Video"), { "type": "object", "properties": properties }]
+    });
+    let stream = json!({
+        "type": "object",
+        "properties": {
+            "index": { "type": "integer" },
+            "codec": nullable("string"),
+            "language": nullable("string"),
+            "title": nullable("string"),
+            "width": { "type": "integer" },
+            "height": { "type": "integer" },
+            "frame_rate": { "type": "number" },
+            "bit_rate": { "type": "integer" },
+            "channels": { "type": "integer" },
+            "sample_rate": { "type": "integer" },
+            "hdr": { "type": "string", "enum": ["hdr10", "hlg"] }
+        }
+    });
+    let named = json!({ "type": "object", "properties": { "id": { "type": "integer" }, "name": { "type": "string" }, "items": { "type": "integer" } } });
+    let state = json!({ "type": "string", "enum": ["queued", "running", "completed", "failed", "cancelled"] });
+
+    // In groups, as a single `json!` would recurse too deep
+    let library = json!({
+        "Video": video,
+        "Entry": with_video(json!({
+            "subtitles": { "type": "array", "items": schema("Sidecar") },
+            "favorite": { "type": "boolean" }
+        })),
+        "Sidecar": { "type": "object", "properties": {
+            "file": { "type": "string" }, "language": nullable("string"), "format": { "type": "string" }
+        } },
+        "Subtitles": { "type": "object", "properties": {
+            "embedded": { "type": "array", "items": { "type": "object", "properties": {
+                "track": { "type": "integer" },
+                "text": { "type": "boolean", "description": "Whether it converts to WebVTT, bitmap subtitles only burn in" },
+                "codec": nullable("string"), "language": nullable("string"), "title": nullable("string")
+            } } },
+            "sidecar": { "type": "array", "items": schema("Sidecar") }
+        } },
+        "SearchResult": with_video(json!({ "score": { "type": "integer" }, "thumb": { "type": "string" } })),
+        "Browse": { "type": "object", "properties": {
+            "path": { "type": "string" },
+            "breadcrumbs": { "type": "array", "items": schema("Folder") },
+            "folders": { "type": "array", "items": schema("Folder") },
+            "files": { "type": "array", "items": schema("Entry") },
+            "total": { "type": "integer" }
+        } },
+        "Folder": { "type": "object", "properties": { "name": { "type": "string" }, "path": { "type": "string" } } },
+        "Library": { "type": "object", "properties": { "name": { "type": "string" }, "read_only": { "type": "boolean" } } },
+        "Info": { "type": "object", "properties": {
+            "duration": nullable("number"),
+            "container": { "type": "string" },
+            "bit_rate": nullable("integer"),
+            "width": nullable("integer"),
+            "height": nullable("integer"),
+            "video": { "type": "array", "items": stream },
+            "audio": { "type": "array", "items": stream },
+            "subtitle": { "type": "array", "items": stream }
+        } },
+        "Chapter": { "type": "object", "properties": { "title": nullable("string"), "start": { "type": "number" }, "end": { "type": "number" } } },
+        "Checksum": { "type": "object", "properties": {
+            "path": { "type": "string" }, "size": { "type": "integer" }, "mtime": { "type": "integer" }, "sha256": { "type": "string" }
+        } },
+        "Waveform": { "type": "object", "properties": {
+            "duration": { "type": "number" }, "peaks": { "type": "array", "items": { "type": "number" } }
+        } },
+        "Duplicates": { "type": "object", "properties": {
+            "reclaimable": { "type": "integer", "description": "Bytes freed by keeping only the first video" },
+            "videos": { "type": "array", "items": schema("Video") }
+        } },
+        "JobSpec": { "type": "object", "required": ["video", "rendition"], "properties": {
+            "video": { "type": "string" },
+            "rendition": { "type": "string" },
+            "start": nullable("number"),
+            "end": nullable("number"),
+            "audio": nullable("integer"),
+            "profile": nullable("string"),
+            "tonemap": nullable("boolean")
+        } },
+        "Job": { "allOf": [schema("JobSpec"), { "type": "object", "properties": {
+            "id": { "type": "integer" },
+            "state": state,
+            "error": { "type": "string" },
+            "progress": { "type": "object", "properties": {
+                "percent": { "type": "number" }, "fps": { "type": "number" }, "speed": { "type": "number" }, "eta": nullable("number")
+            } }
+        } }] }
+    });
+    let files = json!({
+        "Move": { "type": "object", "required": ["to"], "properties": { "to": { "type": "string" } } },
+        "TrashEntry": { "type": "object", "properties": {
+            "id": { "type": "string" }, "path": { "type": "string" }, "deleted": { "type": "integer" },
+            "files": { "type": "array", "items": { "type": "string" } }
+        } },
+        "ShareRequest": { "type": "object", "properties": { "expires_in": { "type": "integer" }, "max_uses": { "type": "integer" } } },
+        "Share": { "type": "object", "properties": { "url": { "type": "string" }, "hls": { "type": "string" }, "expires": { "type": "integer" } } },
+        "Name": { "type": "object", "required": ["name"], "properties": { "name": { "type": "string" } } },
+        "Items": { "type": "object", "required": ["videos"], "properties": {
+            "videos": { "type": "array", "items": { "type": "string" } },
+            "position": { "type": "integer", "description": "Where playlists insert added items, at the end by default" }
+        } },
+        "MoveItem": { "type": "object", "required": ["to"], "properties": { "to": { "type": "integer" } } },
+        "Collection": named,
+        "Playlist": named,
+        "CollectionItems": { "type": "object", "properties": {
+            "id": { "type": "integer" }, "name": { "type": "string" }, "items": { "type": "array", "items": schema("Video") }
+        } },
+        "PlaylistItems": { "type": "object", "properties": {
+            "id": { "type": "integer" }, "name": { "type": "string" },
+            "items": { "type": "array", "items": with_video(json!({ "position": { "type": "integer" } })) }
+        } }
+    });
+    let rest = json!({
+        "Home": { "type": "object", "properties": {
+            "recently_added": { "type": "array", "items": schema("Video") },
+            "continue_watching": { "type": "array", "items": with_video(json!({ "position": { "type": "number" }, "updated": { "type": "integer" } })) },
+            "most_played": { "type": "array", "items": with_video(json!({ "plays": { "type": "integer" } })) }
+        } },
+        "Progress": { "type": "object", "properties": {
+            "video": { "type": "string" }, "position": { "type": "number" }, "watched": { "type": "boolean" }, "updated": { "type": "integer" }
+        } },
+        "ProgressRequest": { "type": "object", "required": ["position"], "properties": {
+            "position": { "type": "number" }, "watched": { "type": "boolean" }
+        } },
+        "LiveStream": { "type": "object", "properties": { "name": { "type": "string" }, "live": { "type": "boolean" } } },
+        "CastDevice": { "type": "object", "properties": { "id": { "type": "string" }, "name": { "type": "string" } } },
+        "CastStatus": { "type": "object", "properties": {
+            "state": { "type": "string" }, "time": { "type": "number" }, "duration": nullable("number")
+        } },
+        "Cast": { "type": "object", "required": ["device"], "properties": { "device": { "type": "string" }, "start": { "type": "number" } } },
+        "CastControl": { "type": "object", "required": ["device", "action"], "properties": {
+            "device": { "type": "string" },
+            "action": { "type": "string", "enum": ["play", "pause", "seek", "stop"] },
+            "time": { "type": "number" }
+        } },
+        "Session": { "type": "object", "properties": {
+            "id": { "type": "integer" }, "user": nullable("string"), "ip": nullable("string"), "video": { "type": "string" },
+            "started": { "type": "integer" }, "idle": { "type": "integer" }, "active": { "type": "integer" },
+            "position": nullable("number"), "bytes": { "type": "integer" }, "bandwidth": { "type": "integer" }
+        } },
+        "Task": { "type": "object", "properties": {
+            "name": { "type": "string" }, "kind": { "type": "string" }, "schedule": { "type": "string" },
+            "running": { "type": "boolean" },
+            "last_run": { "type": ["object", "null"], "properties": {
+                "started": { "type": "integer" }, "finished": { "type": "integer" },
+                "status": { "type": "string", "enum": ["succeeded", "failed"] },
+                "message": { "type": "string" }, "error": { "type": "string" }
+            } },
+            "next_run": nullable("integer")
+        } },
+        "Login": { "type": "object", "required": ["username", "password"], "properties": {
+            "username": { "type": "string" }, "password": { "type": "string" }
+        } },
+        "Token": { "type": "object", "properties": {
+            "token": { "type": "string", "description": "Also usable as a bearer token" }, "user": { "type": "string" }
+        } },
+        "Error": { "type": "object", "required": ["error"], "properties": {
+            "error": { "type": "object", "required": ["code", "message"], "properties": {
+                "code": { "type": "string", "description": "Stable, like `VIDEO_NOT_FOUND`" },
+                "message": { "type": "string", "description": "For people, may change" },
+                "request_id": { "type": "string", "description": "Also sent as `X-Request-Id`" }
+            } }
+        } },
+        "Health": { "type": "object", "properties": {
+            "status": { "type": "string", "enum": ["ok", "error"] },
+            "checks": { "type": "object", "description": "Only for readiness", "additionalProperties": {
+                "type": "object", "properties": { "status": { "type": "string" }, "error": { "type": "string" } }
+            } }
+        } }
+    });
+
+    let mut schemas = Map::new();
+    for group in [library, files, rest] {
+        if let Value::Object(group) = group {
+            schemas.extend(group);
+        }
+    }
+    Value::Object(schemas)
+}
+
+fn operation(operation: &Operation) -> Value {
+    let parameters: Vec<_> = operation.params.iter().map(|param| {
+        let mut value = json!({
+            "name": param.name,
+            "in": if param.location == In::Path { "path" } else { "query" },
+            "required": param.required,
+            "schema": schema(param.schema)
+        });
+        if !param.description.is_empty() {
+            value["description"] = param.description.into();
+        }
+        value
+    }).collect();
+
+    let (status, content) = match operation.returns {
+        Ok(status, name) => (status, Some(json!({ "application/json": { "schema": schema(name) } }))),
+        Media(content_type) => (200, Some(json!({ content_type: {} }))),
+        Empty(status) => (status, None)
+    };
+    let mut response = json!({ "description": "" });
+    if let Some(content) = content {
+        response["content"] = content;
+    }
+
+    let mut value = json!({
+        "tags": [operation.tag],
+        "summary": operation.summary,
+        "parameters": parameters,
+        "responses": {
+            status.to_string(): response,
+            "default": { "description": "", "content": { "application/json": { "schema": schema("Error") } } }
+        }
+    });
+    match operation.body {
+        Some("multipart") => value["requestBody"] = json!({ "required": true, "content": { "multipart/form-data": {} } }),
+        Some(body) => value["requestBody"] = json!({ "required": true, "content": { "application/json": { "schema": schema(body) } } }),
+        None => {}
+    }
+    if operation.public {
+        value["security"] = json!([]);
+    }
+    value
+}
+
+/// The OpenAPI document, with the server at `base_path`.
+fn spec(config: &Config) -> Value {
+    let mut paths = Map::new();
+    for op in OPERATIONS {
+        let path = paths.entry(op.path).or_insert_with(|| json!({}));
+        path[op.method] = operation(op);
+    }
+
+    // Paths start with a slash already
+    let server = url::path(config, "");
+    let server = if server.is_empty() { "/" } else { &*server };
+
+    json!({
+        "openapi": "3.1.0",
+        "info": { "title": "ninja", "version": env!("CARGO_PKG_VERSION") },
+        "servers": [{ "url": server }],
+        "paths": paths,
+        "components": {
+            "schemas": components(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "description": "An API key or session token" },
+                "cookie": { "type": "apiKey", "in": "cookie", "name": "ninja_session" },
+                "token": { "type": "apiKey", "in": "query", "name": "token", "description": "For clients that can't set headers" },
+                "share": { "type": "apiKey", "in": "query", "name": "share", "description": "A shared link, for the video it was made for" }
+            }
+        },
+        "security": [{ "bearer": [] }, { "cookie": [] }, { "token": [] }, { "share": [] }]
+    })
+}
+
+pub async fn serve_spec(extract::State(config): extract::State<&Config>) -> response::Response {
+    response::IntoResponse::into_response(Json(spec(config)))
+}
+
+pub async fn serve_docs(extract::State(config): extract::State<&Config>) -> response::Response {
+    // `<` only appears in strings, where escaping it keeps `</script>` out
+    let spec = serde_json::to_string(&spec(config)).unwrap().replace('<', "\\u003c");
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(http::header::CONTENT_SECURITY_POLICY, DOCS_POLICY)
+        .body(DOCS.replace("%SWAGGER_UI%", SWAGGER_UI).replace("%SPEC%", &spec).into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn references(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(reference)) = map.get("$ref") {
+                    found.push(reference.trim_start_matches("#/components/schemas/").into());
+                }
+                map.values().for_each(|value| references(value, found));
+            }
+            Value::Array(values) => values.iter().for_each(|value| references(value, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn operations() {
+        for (index, op) in OPERATIONS.iter().enumerate() {
+            assert!(!OPERATIONS[..index].iter().any(|other| other.method == op.method && other.path == op.path), "{} {}", op.method, op.path);
+
+            let placeholders: Vec<_> = op.path.split('{').skip(1).filter_map(|rest| rest.split_once('}')).map(|(name, _)| name).collect();
+            let params: Vec<_> = op.params.iter().filter(|param| param.location == In::Path).map(|param| param.name).collect();
+            assert_eq!(placeholders, params, "{}", op.path);
+        }
+    }
+
+    #[test]
+    fn schemas_exist() {
+        let spec = spec(&Config::default());
+        let mut found = Vec::new();
+        references(&spec, &mut found);
+        assert!(!found.is_empty());
+        for name in found {
+            assert!(spec["components"]["schemas"].get(&name).is_some(), "{name}");
+        }
+        assert_eq!(spec["paths"]["/jobs"]["post"]["responses"]["default"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/Error");
+        assert_eq!(spec["paths"]["/jobs"]["post"]["responses"]["202"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/Job");
+    }
+
+    #[tokio::test]
+    async fn docs_are_sandboxed() {
+        let config = Box::leak(Box::new(Config { base_path: "/</script>".into(), ..Config::default() }));
+        let response = serve_docs(extract::State(config)).await;
+        assert!(response.headers()[http::header::CONTENT_SECURITY_POLICY].to_str().unwrap().starts_with("sandbox allow-scripts "));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body.matches("</script>").count(), 2);
+        assert!(!body.contains("swagger-ui-dist@5/"));
+    }
+}
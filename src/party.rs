@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::{extract, response};
+use tokio::sync::broadcast;
+
+use crate::error::{ApiError, Code};
+use crate::users::User;
+use crate::{auth, jail, App};
+
+/// Events a slow member may fall behind by before it misses some. It is sent
+/// the whole state again when that happens.
+const BACKLOG: usize = 64;
+
+/// What the members of a room send.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+enum Command {
+    Play { position: f64 },
+    Pause { position: f64 },
+    Seek { position: f64 },
+    /// Asks for the current state, to correct drift.
+    Sync
+}
+
+/// What the members of a room receive.
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "lowercase", tag = "type")]
+enum Event {
+    State {
+        video: Box<str>,
+        playing: bool,
+        position: f64,
+        members: Vec<Box<str>>
+    },
+    Play { position: f64, from: Box<str> },
+    Pause { position: f64, from: Box<str> },
+    Seek { position: f64, from: Box<str> },
+    Join { member: Box<str> },
+    Leave { member: Box<str> }
+}
+
+struct Room {
+    video: Box<str>,
+    members: BTreeMap<u64, Box<str>>,
+    playing: bool,
+    /// Where playback was at `updated`, it has moved on since if `playing`.
+    position: f64,
+    updated: Instant,
+    /// Events along with the member they came from, who doesn't get them back.
+    events: broadcast::Sender<(u64, Event)>
+}
+
+impl Room {
+    fn position(&self) -> f64 {
+        if self.playing {
+            self.position + self.updated.elapsed().as_secs_f64()
+        } else {
+            self.position
+        }
+    }
+
+    fn state(&self) -> Event {
+        Event::State {
+            video: self.video.clone(),
+            playing: self.playing,
+            position: self.position(),
+            members: self.members.values().cloned().collect()
+        }
+    }
+
+    fn apply(&mut self, command: Command, from: Box<str>) -> Option<Event> {
+        let (playing, position) = match command {
+            Command::Play { position } => (true, position),
+            Command::Pause { position } => (false, position),
+            Command::Seek { position } => (self.playing, position),
+            Command::Sync => return None
+        };
+        let position = position.max(0.0);
+        self.playing = playing;
+        self.position = position;
+        self.updated = Instant::now();
+
+        Some(match command {
+            Command::Play { .. } => Event::Play { position, from },
+            Command::Pause { .. } => Event::Pause { position, from },
+            _ => Event::Seek { position, from }
+        })
+    }
+}
+
+/// Rooms of clients watching a video together. A room exists for as long as
+/// it has members.
+#[derive(Default)]
+pub struct Parties {
+    next_id: AtomicU64,
+    rooms: Mutex<HashMap<Box<str>, Room>>
+}
+
+impl Parties {
+    /// Adds a member to `room`, creating it for `video` if it doesn't exist.
+    /// Members that aren't logged in are named after their id.
+    fn join(&self, room: &str, video: &str, user: Option<Box<str>>) -> (u64, Box<str>, broadcast::Receiver<(u64, Event)>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let name = user.unwrap_or_else(|| format!("guest-{id}").into());
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(room.into()).or_insert_with(|| Room {
+            video: video.into(),
+            members: BTreeMap::new(),
+            playing: false,
+            position: 0.0,
+            updated: Instant::now(),
+            events: broadcast::channel(BACKLOG).0
+        });
+        room.members.insert(id, name.clone());
+        let receiver = room.events.subscribe();
+        let _ = room.events.send((id, Event::Join { member: name.clone() }));
+        (id, name, receiver)
+    }
+
+    fn leave(&self, room: &str, id: u64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(entry) = rooms.get_mut(room) else {
+            return;
+        };
+        let Some(member) = entry.members.remove(&id) else {
+            return;
+        };
+
+        if entry.members.is_empty() {
+            rooms.remove(room);
+        } else {
+            let _ = entry.events.send((id, Event::Leave { member }));
+        }
+    }
+
+    fn with_room<T>(&self, room: &str, f: impl FnOnce(&mut Room) -> T) -> Option<T> {
+        self.rooms.lock().unwrap().get_mut(room).map(f)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct Query {
+    video: Option<Box<str>>
+}
+
+async fn send(socket: &mut WebSocket, event: &Event) -> bool {
+    let text = serde_json::to_string(event).unwrap();
+    socket.send(Message::Text(text)).await.is_ok()
+}
+
+async fn run(app: &'static App, mut socket: WebSocket, room: Box<str>, video: Box<str>, user: Option<Box<str>>) {
+    let parties = &app.parties;
+    let (id, name, mut events) = parties.join(&room, &video, user);
+    if let Some(state) = parties.with_room(&room, |room| room.state()) {
+        if !send(&mut socket, &state).await {
+            parties.leave(&room, id);
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // Pings are answered by axum
+                    Some(Ok(_)) => continue
+                };
+                let Ok(command) = serde_json::from_str::<Command>(&text) else {
+                    continue;
+                };
+
+                let reply = parties.with_room(&room, |room| match room.apply(command, name.clone()) {
+                    Some(event) => {
+                        let _ = room.events.send((id, event));
+                        None
+                    }
+                    None => Some(room.state())
+                });
+                if let Some(Some(state)) = reply {
+                    if !send(&mut socket, &state).await {
+                        break;
+                    }
+                }
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Ok((from, _)) if from == id => continue,
+                    Ok((_, event)) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        let Some(state) = parties.with_room(&room, |room| room.state()) else {
+                            break;
+                        };
+                        state
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break
+                };
+                if !send(&mut socket, &event).await {
+                    break;
+                }
+            }
+        }
+    }
+
+    parties.leave(&room, id);
+}
+
+/// Joins the watch party `room` over a WebSocket. The first member picks the
+/// video with `?video=`, and everyone is kept in sync with its play, pause and
+/// seek events from then on.
+pub async fn serve_party(
+    extract::Path((room, )): extract::Path<(Box<str>, )>,
+    extract::Query(query): extract::Query<Query>,
+    extract::State(app): extract::State<&'static App>,
+    user: Option<extract::Extension<User>>,
+    upgrade: WebSocketUpgrade
+) -> response::Response {
+    let existing = app.parties.with_room(&room, |room| room.video.clone());
+    let video = match (&existing, query.video) {
+        (Some(existing), Some(video)) if *existing != video => {
+            return ApiError::new(Code::Conflict, "The room is watching another video").into();
+        }
+        (Some(existing), _) => existing.clone(),
+        (None, Some(video)) => video,
+        (None, None) => return ApiError::new(Code::BadRequest, "Missing `video` for a new room").into()
+    };
+
+    if user.as_ref().is_some_and(|user| !auth::can_access(&app.config, user, &video)) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+    if existing.is_none() {
+        if let Err(err) = jail::video(&app.config, &*video).await {
+            return err.into_response(Code::VideoNotFound, "Video not found");
+        }
+    }
+
+    let user = user.map(|user| user.name.clone());
+    upgrade.on_upgrade(move |socket| run(app, socket, room, video, user))
+}
@@ -0,0 +1,452 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use axum::{extract, http, response, Json};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+
+use crate::auth::{self, TokenQuery};
+use crate::error::{ApiError, Code};
+use crate::index::Video;
+use crate::users::User;
+use crate::{collections, m3u, App};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS playlists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        owner TEXT
+    );
+    CREATE TABLE IF NOT EXISTS playlist_items (
+        playlist_id INTEGER NOT NULL REFERENCES playlists (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        video TEXT NOT NULL,
+        PRIMARY KEY (playlist_id, position)
+    );
+";
+
+#[derive(serde::Serialize)]
+pub struct Playlist {
+    pub id: i64,
+    pub name: Box<str>,
+    pub items: u64
+}
+
+/// Ordered play queues. Unlike collections, a video can be in a playlist
+/// more than once, and items are addressed by their position. Like them,
+/// only the user that created a playlist and admins can change it.
+pub struct Playlists {
+    conn: Mutex<Connection>
+}
+
+/// What changing a playlist ran into.
+pub enum Change {
+    Done,
+    /// There's no such playlist.
+    NoPlaylist,
+    /// There's no item at the position.
+    NoItem,
+    /// Another playlist has the name.
+    Conflict
+}
+
+fn is_constraint_violation(err: &rusqlite::Error) -> bool {
+    matches!(err, rusqlite::Error::SqliteFailure(err, _) if err.code == ErrorCode::ConstraintViolation)
+}
+
+impl Playlists {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        collections::add_owner(&conn, "playlists")?;
+        Ok(Playlists { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Creates a playlist, or returns `None` if one has the same name.
+    pub fn create(&self, name: &str, owner: Option<&str>) -> rusqlite::Result<Option<i64>> {
+        let conn = self.conn();
+        match conn.execute("INSERT INTO playlists (name, owner) VALUES (?, ?)", params![name, owner]) {
+            Ok(_) => Ok(Some(conn.last_insert_rowid())),
+            Err(err) if is_constraint_violation(&err) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn list(&self) -> rusqlite::Result<Vec<Playlist>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, name, (SELECT COUNT(*) FROM playlist_items WHERE playlist_id = id)
+             FROM playlists ORDER BY name"
+        )?;
+        let playlists = stmt.query_map([], |row| Ok(Playlist {
+            id: row.get(0)?,
+            name: row.get::<_, String>(1)?.into(),
+            items: row.get(2)?
+        }))?.collect();
+        playlists
+    }
+
+    pub fn name(&self, id: i64) -> rusqlite::Result<Option<Box<str>>> {
+        self.conn()
+            .query_row("SELECT name FROM playlists WHERE id = ?", [id], |row| row.get::<_, String>(0))
+            .optional()
+            .map(|name| name.map(Into::into))
+    }
+
+    /// The user that created a playlist, `Some(None)` when it was created
+    /// without users.
+    pub fn owner(&self, id: i64) -> rusqlite::Result<Option<Option<Box<str>>>> {
+        self.conn()
+            .query_row("SELECT owner FROM playlists WHERE id = ?", [id], |row| row.get::<_, Option<String>>(0))
+            .optional()
+            .map(|owner| owner.map(|owner| owner.map(Into::into)))
+    }
+
+    pub fn rename(&self, id: i64, name: &str) -> rusqlite::Result<Change> {
+        match self.conn().execute("UPDATE playlists SET name = ? WHERE id = ?", params![name, id]) {
+            Ok(0) => Ok(Change::NoPlaylist),
+            Ok(_) => Ok(Change::Done),
+            Err(err) if is_constraint_violation(&err) => Ok(Change::Conflict),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn items_in(conn: &Connection, id: i64) -> rusqlite::Result<Vec<Box<str>>> {
+        let mut stmt = conn.prepare_cached("SELECT video FROM playlist_items WHERE playlist_id = ? ORDER BY position")?;
+        let items = stmt.query_map([id], |row| Ok(row.get::<_, String>(0)?.into()))?.collect();
+        items
+    }
+
+    /// The videos in a playlist, in order.
+    pub fn items(&self, id: i64) -> rusqlite::Result<Vec<Box<str>>> {
+        Self::items_in(&self.conn(), id)
+    }
+
+    /// Applies `change` to the items of a playlist, and numbers them again
+    /// from zero. `change` returns whether it applied.
+    pub fn update_items(&self, id: i64, change: impl FnOnce(&mut Vec<Box<str>>) -> bool) -> rusqlite::Result<Change> {
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        let exists = transaction.query_row("SELECT 1 FROM playlists WHERE id = ?", [id], |_| Ok(())).optional()?;
+        if exists.is_none() {
+            return Ok(Change::NoPlaylist);
+        }
+
+        let mut items = Self::items_in(&transaction, id)?;
+        if !change(&mut items) {
+            return Ok(Change::NoItem);
+        }
+
+        transaction.execute("DELETE FROM playlist_items WHERE playlist_id = ?", [id])?;
+        for (position, video) in items.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO playlist_items (playlist_id, position, video) VALUES (?, ?, ?)",
+                params![id, position, video]
+            )?;
+        }
+        transaction.commit()?;
+        Ok(Change::Done)
+    }
+
+    /// Deletes a playlist, returning whether it existed.
+    pub fn delete(&self, id: i64) -> rusqlite::Result<bool> {
+        Ok(self.conn().execute("DELETE FROM playlists WHERE id = ?", [id])? > 0)
+    }
+
+    /// Keeps a video in its playlists, at the same positions, after it was
+    /// moved.
+    pub fn move_video(&self, from: &str, to: &str) -> rusqlite::Result<()> {
+        self.conn().execute("UPDATE playlist_items SET video = ? WHERE video = ?", [to, from])?;
+        Ok(())
+    }
+}
+
+fn no_content() -> response::Response {
+    response::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
+
+fn conflict() -> response::Response {
+    ApiError::new(Code::AlreadyExists, "A playlist with this name already exists").into()
+}
+
+/// Why `user` can't change the playlist `id`, if anything stops them.
+fn refuse_change(app: &App, user: Option<&User>, id: i64) -> Option<response::Response> {
+    match app.playlists.owner(id) {
+        Ok(Some(owner)) if collections::may_change(app, user, owner.as_deref()) => None,
+        Ok(Some(_)) => Some(ApiError::new(Code::Forbidden, "Forbidden").into()),
+        Ok(None) => Some(ApiError::new(Code::PlaylistNotFound, "Playlist not found").into()),
+        Err(err) => Some(ApiError::database("Failed to access playlists", err).into())
+    }
+}
+
+/// Whether `user` can see `video`, items they can't are kept out of their
+/// way.
+fn is_visible(app: &App, user: Option<&User>, video: &str) -> bool {
+    user.is_none_or(|user| auth::can_access(&app.config, user, video))
+}
+
+fn changed(change: rusqlite::Result<Change>) -> response::Response {
+    match change {
+        Ok(Change::Done) => no_content(),
+        Ok(Change::NoPlaylist) => ApiError::new(Code::PlaylistNotFound, "Playlist not found").into(),
+        Ok(Change::NoItem) => ApiError::new(Code::PlaylistItemNotFound, "Playlist item not found").into(),
+        Ok(Change::Conflict) => conflict(),
+        Err(err) => ApiError::database("Failed to access playlists", err).into()
+    }
+}
+
+pub async fn list_playlists(extract::State(app): extract::State<&App>) -> response::Response {
+    match app.playlists.list() {
+        Ok(playlists) => response::IntoResponse::into_response(Json(playlists)),
+        Err(err) => ApiError::database("Failed to access playlists", err).into()
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct NameRequest {
+    name: Box<str>
+}
+
+fn empty_name() -> response::Response {
+    ApiError::new(Code::BadRequest, "Playlist name can't be empty").into()
+}
+
+pub async fn create_playlist(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Json(request): Json<NameRequest>
+) -> response::Response {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return empty_name();
+    }
+
+    match app.playlists.create(name, user.as_ref().map(|user| &*user.name)) {
+        Ok(Some(id)) => {
+            let mut response = response::IntoResponse::into_response(Json(Playlist { id, name: name.into(), items: 0 }));
+            *response.status_mut() = http::StatusCode::CREATED;
+            response
+        }
+        Ok(None) => conflict(),
+        Err(err) => ApiError::database("Failed to access playlists", err).into()
+    }
+}
+
+pub async fn rename_playlist(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Json(request): Json<NameRequest>
+) -> response::Response {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return empty_name();
+    }
+    if let Some(response) = refuse_change(app, user.as_deref(), id) {
+        return response;
+    }
+    changed(app.playlists.rename(id, name))
+}
+
+pub async fn delete_playlist(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if let Some(response) = refuse_change(app, user.as_deref(), id) {
+        return response;
+    }
+    match app.playlists.delete(id) {
+        Ok(true) => no_content(),
+        Ok(false) => ApiError::new(Code::PlaylistNotFound, "Playlist not found").into(),
+        Err(err) => ApiError::database("Failed to access playlists", err).into()
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Item {
+    /// Where the item is in the playlist, counting the ones left out.
+    position: usize,
+    #[serde(flatten)]
+    video: Video
+}
+
+/// The name and items of a playlist that the user can access, or `None` if
+/// there's no such playlist.
+fn items(app: &App, user: Option<&User>, id: i64) -> rusqlite::Result<Option<(Box<str>, Vec<Item>)>> {
+    let Some(name) = app.playlists.name(id)? else {
+        return Ok(None);
+    };
+
+    let mut items = Vec::new();
+    for (position, video) in app.playlists.items(id)?.iter().enumerate() {
+        if user.is_some_and(|user| !auth::can_access(&app.config, user, video)) {
+            continue;
+        }
+        if let Some(video) = app.index.get(video)? {
+            items.push(Item { position, video });
+        }
+    }
+    Ok(Some((name, items)))
+}
+
+/// A playlist with the videos in it that the user can access.
+pub async fn get_playlist(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    match items(app, user.as_deref(), id) {
+        Ok(Some((name, items))) => response::IntoResponse::into_response(Json(serde_json::json!({
+            "id": id,
+            "name": name,
+            "items": items
+        }))),
+        Ok(None) => ApiError::new(Code::PlaylistNotFound, "Playlist not found").into(),
+        Err(err) => ApiError::database("Failed to access playlists", err).into()
+    }
+}
+
+pub async fn serve_playlist_m3u(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::Query(token): extract::Query<TokenQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    request: http::request::Parts
+) -> response::Response {
+    match items(app, user.as_deref(), id) {
+        Ok(Some((_, items))) => {
+            let videos: Vec<_> = items.into_iter().map(|item| item.video).collect();
+            m3u::playlist(&request, app, &token, &videos)
+        }
+        Ok(None) => ApiError::new(Code::PlaylistNotFound, "Playlist not found").into(),
+        Err(err) => ApiError::database("Failed to access playlists", err).into()
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ItemsRequest {
+    videos: Box<[Box<str>]>,
+    /// Where to insert the videos when adding them, at the end by default.
+    position: Option<usize>
+}
+
+/// Replaces the items of a playlist, which is also how they're reordered.
+/// Items the user can't see keep their positions, the videos sent fill the
+/// others in order.
+pub async fn set_items(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Json(request): Json<ItemsRequest>
+) -> response::Response {
+    if let Some(response) = refuse_change(app, user.as_deref(), id) {
+        return response;
+    }
+    let videos = match collections::validate(app, user.as_deref(), &request.videos).await {
+        Ok(videos) => videos,
+        Err(response) => return response
+    };
+    changed(app.playlists.update_items(id, |items| {
+        *items = merge(items, videos, |video| is_visible(app, user.as_deref(), video));
+        true
+    }))
+}
+
+/// `videos` in place of the items of `items` that are `visible`, with the
+/// others where they were. Left over videos go at the end.
+fn merge(items: &[Box<str>], videos: Vec<Box<str>>, visible: impl Fn(&str) -> bool) -> Vec<Box<str>> {
+    let mut videos = videos.into_iter();
+    let mut merged: Vec<_> = items.iter()
+        .filter_map(|item| if visible(item) { videos.next() } else { Some(item.clone()) })
+        .collect();
+    merged.extend(videos);
+    merged
+}
+
+/// Inserts videos at `position`, or appends them.
+pub async fn add_items(
+    extract::Path((id, )): extract::Path<(i64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Json(request): Json<ItemsRequest>
+) -> response::Response {
+    if let Some(response) = refuse_change(app, user.as_deref(), id) {
+        return response;
+    }
+    let videos = match collections::validate(app, user.as_deref(), &request.videos).await {
+        Ok(videos) => videos,
+        Err(response) => return response
+    };
+    changed(app.playlists.update_items(id, |items| {
+        let position = request.position.unwrap_or(items.len()).min(items.len());
+        items.splice(position..position, videos);
+        true
+    }))
+}
+
+pub async fn remove_item(
+    extract::Path((id, position)): extract::Path<(i64, usize)>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if let Some(response) = refuse_change(app, user.as_deref(), id) {
+        return response;
+    }
+    changed(app.playlists.update_items(id, |items| {
+        let found = items.get(position).is_some_and(|video| is_visible(app, user.as_deref(), video));
+        if found {
+            items.remove(position);
+        }
+        found
+    }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct MoveRequest {
+    to: usize
+}
+
+/// Moves the item at `position` to `to`, shifting the ones in between.
+pub async fn move_item(
+    extract::Path((id, position)): extract::Path<(i64, usize)>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    Json(request): Json<MoveRequest>
+) -> response::Response {
+    if let Some(response) = refuse_change(app, user.as_deref(), id) {
+        return response;
+    }
+    changed(app.playlists.update_items(id, |items| {
+        let found = items.get(position).is_some_and(|video| is_visible(app, user.as_deref(), video));
+        if found {
+            let video = items.remove(position);
+            items.insert(request.to.min(items.len()), video);
+        }
+        found
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_around_hidden_items() {
+        let items: Vec<Box<str>> = ["a", "secret/x", "b", "c"].map(Into::into).into();
+        let visible = |video: &str| !video.starts_with("secret/");
+
+        let merged = merge(&items, ["c".into(), "a".into()].into(), visible);
+        assert_eq!(merged, ["c", "secret/x", "a"].map(Box::from));
+
+        let merged = merge(&items, ["b".into(), "a".into(), "c".into(), "d".into()].into(), visible);
+        assert_eq!(merged, ["b", "secret/x", "a", "c", "d"].map(Box::from));
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use axum::{extract, response, Json};
+use tokio::process::Command;
+
+use crate::error::{ApiError, Code};
+use crate::{jail, nfo, remote, Config};
+
+#[derive(serde::Deserialize)]
+struct Output {
+    #[serde(default)]
+    streams: Vec<Stream>,
+    format: Format
+}
+
+#[derive(serde::Deserialize)]
+struct Stream {
+    codec_type: Box<str>,
+    codec_name: Option<Box<str>>,
+    width: Option<u32>,
+    height: Option<u32>,
+    color_transfer: Option<Box<str>>
+}
+
+#[derive(serde::Deserialize)]
+struct Format {
+    duration: Option<Box<str>>,
+    #[serde(default)]
+    tags: HashMap<Box<str>, Box<str>>
+}
+
+/// Container tags worth searching for, besides the title.
+const SEARCH_TAGS: &[&str] = &["show", "genre", "artist", "album_artist", "album", "description", "comment", "keywords"];
+
+impl Format {
+    /// The tag `name`, which containers spell in different cases.
+    fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.trim()).filter(|value| !value.is_empty())
+    }
+}
+
+/// High dynamic range transfer functions, which look washed out when shown as
+/// is on SDR displays.
+#[derive(serde::Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Hdr {
+    Hdr10,
+    Hlg
+}
+
+impl Hdr {
+    fn from_transfer(transfer: Option<&str>) -> Option<Self> {
+        match transfer? {
+            "smpte2084" => Some(Hdr::Hdr10),
+            "arib-std-b67" => Some(Hdr::Hlg),
+            _ => None
+        }
+    }
+}
+
+/// The subset of ffprobe output needed to build playlists and the index.
+pub struct Summary {
+    pub duration: f64,
+    pub width: u32,
+    pub height: u32,
+    pub video_codec: Option<Box<str>>,
+    pub audio_codec: Option<Box<str>>,
+    pub hdr: Option<Hdr>,
+    pub title: Option<Box<str>>,
+    /// The [`SEARCH_TAGS`] that are set, one per line.
+    pub tags: Option<Box<str>>
+}
+
+#[derive(serde::Deserialize)]
+struct FullOutput {
+    #[serde(default)]
+    streams: Vec<FullStream>,
+    format: FullFormat
+}
+
+#[derive(serde::Deserialize)]
+struct FullStream {
+    index: u32,
+    codec_type: Box<str>,
+    codec_name: Option<Box<str>>,
+    width: Option<u32>,
+    height: Option<u32>,
+    r_frame_rate: Option<Box<str>>,
+    bit_rate: Option<Box<str>>,
+    channels: Option<u32>,
+    sample_rate: Option<Box<str>>,
+    color_transfer: Option<Box<str>>,
+    #[serde(default)]
+    tags: Tags
+}
+
+#[derive(serde::Deserialize, Default)]
+struct Tags {
+    language: Option<Box<str>>,
+    title: Option<Box<str>>
+}
+
+#[derive(serde::Deserialize)]
+struct FullFormat {
+    format_name: Box<str>,
+    duration: Option<Box<str>>,
+    bit_rate: Option<Box<str>>
+}
+
+/// Everything a player needs to know about a video, as served by `/info`.
+#[derive(serde::Serialize)]
+pub struct Info {
+    pub duration: Option<f64>,
+    pub container: Box<str>,
+    pub bit_rate: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub video: Vec<StreamInfo>,
+    pub audio: Vec<StreamInfo>,
+    pub subtitle: Vec<StreamInfo>
+}
+
+#[derive(serde::Serialize)]
+pub struct StreamInfo {
+    pub index: u32,
+    pub codec: Option<Box<str>>,
+    pub language: Option<Box<str>>,
+    pub title: Option<Box<str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_rate: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hdr: Option<Hdr>
+}
+
+#[derive(serde::Deserialize)]
+struct ChapterOutput {
+    #[serde(default)]
+    chapters: Vec<RawChapter>
+}
+
+#[derive(serde::Deserialize)]
+struct RawChapter {
+    start_time: Box<str>,
+    end_time: Box<str>,
+    #[serde(default)]
+    tags: Tags
+}
+
+#[derive(serde::Serialize)]
+pub struct Chapter {
+    pub title: Option<Box<str>>,
+    pub start: f64,
+    pub end: f64
+}
+
+/// Parses ffprobe's fractional rates such as `24000/1001`.
+fn parse_rate(rate: &str) -> Option<f64> {
+    let (numerator, denominator) = rate.split_once('/')?;
+    let numerator: f64 = numerator.parse().ok()?;
+    let denominator: f64 = denominator.parse().ok()?;
+    (denominator != 0.0).then(|| numerator / denominator)
+}
+
+async fn stdout(config: &Config, path: &Path, args: &[&str]) -> Option<Vec<u8>> {
+    let output = Command::new(&*config.ffprobe_command)
+        .args(["-v", "error"])
+        .args(args)
+        .arg(path)
+        .output().await;
+
+    match output {
+        Ok(output) if output.status.success() => Some(output.stdout),
+        Ok(output) => {
+            tracing::error!(stderr = %remote::redact(String::from_utf8_lossy(&output.stderr).trim()), "Failed to probe `{}`", remote::display(path));
+            None
+        }
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to probe `{}`", remote::display(path));
+            None
+        }
+    }
+}
+
+async fn run<T: serde::de::DeserializeOwned>(config: &Config, path: &Path, args: &[&str]) -> Option<T> {
+    let output = stdout(config, path, &[args, &["-of", "json"]].concat()).await?;
+
+    match serde_json::from_slice(&output) {
+        Ok(output) => Some(output),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to parse probe output for `{}`", remote::display(path));
+            None
+        }
+    }
+}
+
+pub async fn summary(config: &Config, path: &Path) -> Option<Summary> {
+    let output: Output = run(config, path, &[
+        "-show_entries", "format=duration:format_tags:stream=codec_type,codec_name,width,height,color_transfer"
+    ]).await?;
+
+    let video = output.streams.iter().find(|stream| &*stream.codec_type == "video");
+    let audio = output.streams.iter().find(|stream| &*stream.codec_type == "audio");
+
+    let tags: Vec<_> = SEARCH_TAGS.iter().filter_map(|name| output.format.tag(name)).collect();
+    Some(Summary {
+        duration: output.format.duration.as_deref()?.parse().ok()?,
+        width: video.and_then(|video| video.width).unwrap_or(0),
+        height: video.and_then(|video| video.height).unwrap_or(0),
+        video_codec: video.and_then(|video| video.codec_name.clone()),
+        audio_codec: audio.and_then(|audio| audio.codec_name.clone()),
+        hdr: video.and_then(|video| Hdr::from_transfer(video.color_transfer.as_deref())),
+        title: output.format.tag("title").map(Into::into),
+        tags: (!tags.is_empty()).then(|| tags.join("\n").into())
+    })
+}
+
+pub async fn info(config: &Config, path: &Path) -> Option<Info> {
+    let output: FullOutput = run(config, path, &["-show_format", "-show_streams"]).await?;
+
+    let mut info = Info {
+        duration: output.format.duration.and_then(|duration| duration.parse().ok()),
+        container: output.format.format_name,
+        bit_rate: output.format.bit_rate.and_then(|bit_rate| bit_rate.parse().ok()),
+        width: None,
+        height: None,
+        video: Vec::new(),
+        audio: Vec::new(),
+        subtitle: Vec::new()
+    };
+
+    for stream in output.streams {
+        let streams = match &*stream.codec_type {
+            "video" => &mut info.video,
+            "audio" => &mut info.audio,
+            "subtitle" => &mut info.subtitle,
+            _ => continue
+        };
+
+        streams.push(StreamInfo {
+            index: stream.index,
+            codec: stream.codec_name,
+            language: stream.tags.language,
+            title: stream.tags.title,
+            width: stream.width,
+            height: stream.height,
+            frame_rate: stream.r_frame_rate.as_deref().and_then(parse_rate),
+            bit_rate: stream.bit_rate.and_then(|bit_rate| bit_rate.parse().ok()),
+            channels: stream.channels,
+            sample_rate: stream.sample_rate.and_then(|sample_rate| sample_rate.parse().ok()),
+            hdr: Hdr::from_transfer(stream.color_transfer.as_deref())
+        });
+    }
+
+    if let Some(video) = info.video.first() {
+        info.width = video.width;
+        info.height = video.height;
+    }
+
+    Some(info)
+}
+
+pub async fn chapters(config: &Config, path: &Path) -> Option<Vec<Chapter>> {
+    let output: ChapterOutput = run(config, path, &["-show_chapters"]).await?;
+
+    Some(output.chapters.into_iter().filter_map(|chapter| Some(Chapter {
+        title: chapter.tags.title,
+        start: chapter.start_time.parse().ok()?,
+        end: chapter.end_time.parse().ok()?
+    })).collect())
+}
+
+/// Timestamps of the keyframes of the first video stream, in order and
+/// relative to the start of the file like `-ss` positions are. Every packet is
+/// read, but none decoded.
+pub async fn keyframes(config: &Config, path: &Path) -> Option<Vec<f64>> {
+    let output = stdout(config, path, &[
+        "-select_streams", "v:0",
+        "-show_entries", "packet=pts_time,flags:format=start_time",
+        "-of", "csv=p=0"
+    ]).await?;
+    Some(parse_keyframes(&String::from_utf8_lossy(&output)))
+}
+
+/// Packets come as `pts_time,flags` lines, the format's start time as a line
+/// of its own.
+fn parse_keyframes(csv: &str) -> Vec<f64> {
+    let mut start = 0.0;
+    let mut keyframes = Vec::new();
+    for line in csv.lines() {
+        match line.split_once(',') {
+            Some((time, flags)) if flags.contains('K') => keyframes.extend(time.parse::<f64>().ok()),
+            Some(_) => {}
+            None => start = line.parse().unwrap_or(0.0)
+        }
+    }
+
+    // Packets are in decoding order
+    keyframes.sort_by(f64::total_cmp);
+    keyframes.iter_mut().for_each(|time| *time = f64::max(*time - start, 0.0));
+    keyframes
+}
+
+/// What ffprobe finds in a video, along with what its NFO and artwork say.
+#[derive(serde::Serialize)]
+struct Described {
+    #[serde(flatten)]
+    info: Info,
+    #[serde(flatten)]
+    metadata: nfo::Metadata
+}
+
+pub async fn serve_info(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::State(config): extract::State<&Config>
+) -> response::Response {
+    let video_path = match jail::video(config, video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    match info(config, &video_path).await {
+        Some(info) => response::IntoResponse::into_response(Json(Described { info, metadata: nfo::read(&video_path).await })),
+        None => ApiError::new(Code::VideoNotFound, "Video not found").into()
+    }
+}
+
+pub async fn serve_chapters(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::State(config): extract::State<&Config>
+) -> response::Response {
+    let video_path = match jail::video(config, video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    match chapters(config, &video_path).await {
+        Some(chapters) => response::IntoResponse::into_response(Json(chapters)),
+        None => ApiError::new(Code::VideoNotFound, "Video not found").into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyframes() {
+        let csv = "1.500000,K__\n1.541667,___\n3.500000,K_\nN/A,K_\n2.500000,K__\n1.500000\n";
+        assert_eq!(parse_keyframes(csv), [0.0, 1.0, 2.0]);
+        assert_eq!(parse_keyframes("0.000000,K__\n0.041000,__\n"), [0.0]);
+    }
+}
@@ -0,0 +1,172 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use axum::{extract, http, response, Json};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::error::{ApiError, Code};
+use crate::users::Owner;
+use crate::{jail, unix_now, App};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS progress (
+        owner TEXT NOT NULL,
+        video TEXT NOT NULL,
+        position REAL NOT NULL,
+        watched INTEGER NOT NULL,
+        updated INTEGER NOT NULL,
+        PRIMARY KEY (owner, video)
+    );
+";
+
+/// Videos played this far through count as watched, the credits don't need
+/// to be sat through.
+const WATCHED_RATIO: f64 = 0.9;
+
+#[derive(serde::Serialize)]
+pub struct Progress {
+    pub video: Box<str>,
+    /// Where playback stopped, in seconds.
+    pub position: f64,
+    pub watched: bool,
+    pub updated: u64
+}
+
+impl Progress {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Progress {
+            video: row.get::<_, String>("video")?.into(),
+            position: row.get("position")?,
+            watched: row.get("watched")?,
+            updated: row.get("updated")?
+        })
+    }
+}
+
+/// Where each user, or device, stopped watching each video.
+pub struct Store {
+    conn: Mutex<Connection>
+}
+
+impl Store {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Store { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    pub fn get(&self, owner: &str, video: &str) -> rusqlite::Result<Option<Progress>> {
+        self.conn()
+            .query_row("SELECT * FROM progress WHERE owner = ? AND video = ?", [owner, video], Progress::from_row)
+            .optional()
+    }
+
+    /// Every video `owner` has progress for, most recently watched first.
+    pub fn list(&self, owner: &str) -> rusqlite::Result<Vec<Progress>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached("SELECT * FROM progress WHERE owner = ? ORDER BY updated DESC, video")?;
+        let progress = stmt.query_map([owner], Progress::from_row)?.collect();
+        progress
+    }
+
+    pub fn set(&self, owner: &str, video: &str, position: f64, watched: bool) -> rusqlite::Result<Progress> {
+        let updated = unix_now();
+        self.conn().execute(
+            "INSERT OR REPLACE INTO progress (owner, video, position, watched, updated) VALUES (?, ?, ?, ?, ?)",
+            params![owner, video, position, watched, updated]
+        )?;
+        Ok(Progress { video: video.into(), position, watched, updated })
+    }
+
+    /// Carries the progress on a video over to where it was moved.
+    pub fn move_video(&self, from: &str, to: &str) -> rusqlite::Result<()> {
+        self.conn().execute("UPDATE OR REPLACE progress SET video = ? WHERE video = ?", [to, from])?;
+        Ok(())
+    }
+
+    /// Forgets the progress of `owner` on `video`, returning whether there
+    /// was any.
+    pub fn delete(&self, owner: &str, video: &str) -> rusqlite::Result<bool> {
+        Ok(self.conn().execute("DELETE FROM progress WHERE owner = ? AND video = ?", [owner, video])? > 0)
+    }
+}
+
+/// The videos the user has started or watched, most recent first.
+pub async fn list_progress(
+    extract::State(app): extract::State<&App>,
+    Owner(owner): Owner
+) -> response::Response {
+    match app.progress.list(&owner) {
+        Ok(progress) => response::IntoResponse::into_response(Json(progress)),
+        Err(err) => ApiError::database("Failed to access watch progress", err).into()
+    }
+}
+
+pub async fn get_progress(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    Owner(owner): Owner
+) -> response::Response {
+    match app.progress.get(&owner, video.trim_matches('/')) {
+        Ok(Some(progress)) => response::IntoResponse::into_response(Json(progress)),
+        Ok(None) => ApiError::new(Code::ProgressNotFound, "No progress for this video").into(),
+        Err(err) => ApiError::database("Failed to access watch progress", err).into()
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ProgressRequest {
+    position: f64,
+    /// Whether the video was watched to the end. Worked out from the position
+    /// and the duration when left out.
+    watched: Option<bool>
+}
+
+pub async fn set_progress(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    Owner(owner): Owner,
+    Json(request): Json<ProgressRequest>
+) -> response::Response {
+    if !request.position.is_finite() || request.position < 0.0 {
+        return ApiError::new(Code::BadRequest, "Position must be a number of seconds").into();
+    }
+
+    let video = video.trim_matches('/');
+    if let Err(err) = jail::video(&app.config, video).await {
+        return err.into_response(Code::VideoNotFound, "Video not found");
+    }
+
+    let watched = match request.watched {
+        Some(watched) => watched,
+        None => match app.index.get(video) {
+            Ok(indexed) => indexed.and_then(|indexed| indexed.duration)
+                .is_some_and(|duration| duration > 0.0 && request.position >= duration * WATCHED_RATIO),
+            Err(err) => return ApiError::database("Failed to access watch progress", err).into()
+        }
+    };
+
+    match app.progress.set(&owner, video, request.position, watched) {
+        Ok(progress) => response::IntoResponse::into_response(Json(progress)),
+        Err(err) => ApiError::database("Failed to access watch progress", err).into()
+    }
+}
+
+pub async fn delete_progress(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    Owner(owner): Owner
+) -> response::Response {
+    match app.progress.delete(&owner, video.trim_matches('/')) {
+        Ok(true) => response::Response::builder()
+            .status(http::StatusCode::NO_CONTENT)
+            .body(axum::body::Body::empty())
+            .unwrap(),
+        Ok(false) => ApiError::new(Code::ProgressNotFound, "No progress for this video").into(),
+        Err(err) => ApiError::database("Failed to access watch progress", err).into()
+    }
+}
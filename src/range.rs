@@ -0,0 +1,185 @@
+/// Most ranges accepted in a single request. Legitimate clients ask for a
+/// handful at most, while hundreds of tiny ranges would be an easy way to make
+/// the server do a lot of work.
+const MAX_RANGES: usize = 64;
+
+/// An inclusive byte range within the file.
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+pub struct Range {
+    pub start: u64,
+    pub end: u64
+}
+
+impl Range {
+    pub fn len(&self) -> u64 {
+        self.end + 1 - self.start
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The header is not a valid byte range set.
+    Invalid,
+    /// None of the ranges overlap the file.
+    Unsatisfiable
+}
+
+/// Digits only, saturating instead of overflowing, so that absurdly large
+/// positions are clamped to the end of the file like any other past it.
+fn number(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(value.bytes().fold(0u64, |number, digit| {
+        number.saturating_mul(10).saturating_add((digit - b'0') as u64)
+    }))
+}
+
+/// Parses a single range spec, `None` meaning that it is valid but doesn't
+/// overlap a file of `size` bytes.
+fn spec(spec: &str, size: u64) -> Result<Option<Range>, Error> {
+    let (start, end) = spec.split_once('-').ok_or(Error::Invalid)?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // A suffix range, the last `length` bytes
+        let length = number(end).ok_or(Error::Invalid)?;
+        if length == 0 || size == 0 {
+            return Ok(None);
+        }
+        return Ok(Some(Range { start: size.saturating_sub(length), end: size - 1 }));
+    }
+
+    let start = number(start).ok_or(Error::Invalid)?;
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        number(end).ok_or(Error::Invalid)?
+    };
+
+    if end < start {
+        return Err(Error::Invalid);
+    }
+
+    if start >= size {
+        return Ok(None);
+    }
+
+    Ok(Some(Range { start, end: u64::min(end, size - 1) }))
+}
+
+/// Parses a `Range` header for a file of `size` bytes. Ranges that lie past
+/// the end of the file are dropped, as long as at least one remains.
+pub fn parse(header: &str, size: u64) -> Result<Vec<Range>, Error> {
+    let (unit, set) = header.trim().split_once('=').ok_or(Error::Invalid)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(Error::Invalid);
+    }
+
+    let mut ranges = Vec::new();
+    let mut specs = 0;
+    // Empty list elements are allowed, like in any other comma separated header
+    for part in set.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        specs += 1;
+        if specs > MAX_RANGES {
+            return Err(Error::Invalid);
+        }
+
+        if let Some(range) = spec(part, size)? {
+            ranges.push(range);
+        }
+    }
+
+    match (specs, ranges.is_empty()) {
+        (0, _) => Err(Error::Invalid),
+        (_, true) => Err(Error::Unsatisfiable),
+        _ => Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> Range {
+        Range { start, end }
+    }
+
+    #[test]
+    fn single() {
+        assert_eq!(parse("bytes=0-499", 1000), Ok(vec![range(0, 499)]));
+        assert_eq!(parse("bytes=500-999", 1000), Ok(vec![range(500, 999)]));
+        assert_eq!(parse("bytes=0-0", 1000), Ok(vec![range(0, 0)]));
+    }
+
+    #[test]
+    fn open_ended() {
+        assert_eq!(parse("bytes=1000-", 10000), Ok(vec![range(1000, 9999)]));
+        assert_eq!(parse("bytes=0-", 1), Ok(vec![range(0, 0)]));
+    }
+
+    #[test]
+    fn suffix() {
+        assert_eq!(parse("bytes=-500", 1000), Ok(vec![range(500, 999)]));
+        assert_eq!(parse("bytes=-5000", 1000), Ok(vec![range(0, 999)]));
+        assert_eq!(parse("bytes=-0", 1000), Err(Error::Unsatisfiable));
+    }
+
+    #[test]
+    fn end_past_size_is_clamped() {
+        assert_eq!(parse("bytes=900-5000", 1000), Ok(vec![range(900, 999)]));
+        assert_eq!(parse("bytes=0-99999999999999999999999", 1000), Ok(vec![range(0, 999)]));
+    }
+
+    #[test]
+    fn multiple() {
+        assert_eq!(parse("bytes=0-99,200-299", 1000), Ok(vec![range(0, 99), range(200, 299)]));
+        assert_eq!(parse("bytes=0-0,-1", 1000), Ok(vec![range(0, 0), range(999, 999)]));
+        assert_eq!(parse("bytes=0-1,,2-3,", 1000), Ok(vec![range(0, 1), range(2, 3)]));
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(parse(" bytes = 0-99 , 200-299 ", 1000), Ok(vec![range(0, 99), range(200, 299)]));
+        assert_eq!(parse("bytes=0 - 99", 1000), Ok(vec![range(0, 99)]));
+    }
+
+    #[test]
+    fn unit_is_case_insensitive() {
+        assert_eq!(parse("Bytes=0-1", 1000), Ok(vec![range(0, 1)]));
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        assert_eq!(parse("bytes=1000-", 1000), Err(Error::Unsatisfiable));
+        assert_eq!(parse("bytes=1000-2000", 1000), Err(Error::Unsatisfiable));
+        assert_eq!(parse("bytes=0-", 0), Err(Error::Unsatisfiable));
+        assert_eq!(parse("bytes=-1", 0), Err(Error::Unsatisfiable));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_dropped() {
+        assert_eq!(parse("bytes=2000-3000,0-9", 1000), Ok(vec![range(0, 9)]));
+    }
+
+    #[test]
+    fn malformed() {
+        for header in [
+            "", "b", "bytes", "bytes=", "bytes=,", "bytes=-", "bytes=abc", "bytes=5",
+            "bytes=5-4", "bytes=--5", "bytes=1-2-3", "bytes=+1-2", "bytes=0x10-", "items=0-1",
+            "bytes=0-1,x"
+        ] {
+            assert_eq!(parse(header, 1000), Err(Error::Invalid), "{header:?}");
+        }
+    }
+
+    #[test]
+    fn too_many_ranges() {
+        let header = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&header, 1000), Err(Error::Invalid));
+
+        let header = format!("bytes={}", vec!["0-0"; MAX_RANGES].join(","));
+        assert_eq!(parse(&header, 1000).map(|ranges| ranges.len()), Ok(MAX_RANGES));
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use axum::{extract, http, middleware, response};
+
+use crate::error::{ApiError, Code};
+use crate::forwarded::Client;
+use crate::App;
+
+/// An address range like `10.0.0.0/8` or `::1/128`. A plain address is a
+/// range of one.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u32
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(addr) & mask == u128::from(ip) & mask
+            }
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid address range `{cidr}`");
+        let (addr, prefix) = cidr.split_once('/').unwrap_or((cidr, ""));
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() { max } else { prefix.parse().map_err(|_| invalid())? };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cidr = String::deserialize(deserializer)?;
+        cidr.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl serde::Serialize for Cidr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Requests per client IP, refilled at `requests_per_second` up to `burst`.
+/// Disabled while `requests_per_second` is 0.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub burst: u32,
+    /// Clients that are never limited.
+    pub exempt: Box<[Cidr]>
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            requests_per_second: 0.0,
+            burst: 100,
+            exempt: ["127.0.0.0/8", "::1"].map(|cidr| cidr.parse().unwrap()).into()
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+/// Stops tracking clients once there are this many, forgetting the ones
+/// whose buckets have refilled.
+const MAX_CLIENTS: usize = 10_000;
+
+#[derive(Default)]
+pub struct Limiter {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>
+}
+
+impl Limiter {
+    /// Takes a token from the bucket of `ip`, or returns how many seconds
+    /// until there is one.
+    fn take(&self, config: &RateLimit, ip: IpAddr) -> Result<(), f64> {
+        let now = Instant::now();
+        let burst = config.burst.max(1) as f64;
+        let refill = |bucket: &Bucket| f64::min(burst, bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * config.requests_per_second);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS {
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err((1.0 - bucket.tokens) / config.requests_per_second)
+        }
+    }
+}
+
+pub async fn limit(
+    extract::State(app): extract::State<&'static App>,
+    request: extract::Request,
+    next: middleware::Next
+) -> response::Response {
+    let config = &app.config.rate_limit;
+    let ip = request.extensions().get::<Client>().and_then(|client| client.ip);
+    let Some(ip) = ip.filter(|ip| config.requests_per_second > 0.0 && !config.exempt.iter().any(|cidr| cidr.contains(*ip))) else {
+        return next.run(request).await;
+    };
+
+    match app.limiter.take(config, ip) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => response::IntoResponse::into_response((
+            [(http::header::RETRY_AFTER, wait.ceil().max(1.0).to_string())],
+            ApiError::new(Code::RateLimited, "Too many requests")
+        ))
+    }
+}
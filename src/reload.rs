@@ -0,0 +1,155 @@
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+#[cfg(unix)]
+use tokio::signal;
+use tokio::{sync::mpsc, time};
+
+use crate::{environment, App, Config};
+
+/// Settings that are only read at startup, to bind sockets or size pools and
+/// caches. Changing them takes a restart, so reloading keeps the old values.
+const RESTART_ONLY: &[&str] = &[
+    "video_path", "library", "ip", "port", "listen", "socket_mode", "tls_cert", "tls_key", "redirect_port", "h2c", "http3",
+    "shutdown_timeout", "base_path", "cors", "log", "access_log", "index_path", "cache_path", "max_jobs", "max_ffmpeg_jobs",
+    "ffmpeg_queue_timeout", "ffmpeg_timeout", "frame_cache_size", "segment_cache_size", "remote_cache_size", "watch", "dlna", "live",
+    "telemetry"
+];
+
+/// Changes made to every configuration read, like command line flags.
+pub(crate) type Overrides = Box<dyn Fn(&mut Config) + Send + Sync>;
+
+/// Editors save by writing a temporary file and renaming it over the original,
+/// which comes in as several events.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The configuration in effect, swapped out when its file changes.
+///
+/// Every version is leaked, so that requests keep the `&'static Config` they
+/// started with. Reloads are rare enough for that not to matter.
+pub(crate) struct Live {
+    /// Where the configuration is read from, unless it was handed over by an
+    /// embedding application.
+    path: Option<&'static Path>,
+    overrides: Overrides,
+    current: AtomicPtr<Config>
+}
+
+impl Live {
+    pub fn new(path: Option<&'static Path>, overrides: Overrides, config: Config) -> Self {
+        Live { path, overrides, current: AtomicPtr::new(Box::leak(config.into())) }
+    }
+
+    pub fn get(&self) -> &'static Config {
+        // Only ever set from leaked boxes
+        unsafe { &*self.current.load(Ordering::Acquire) }
+    }
+
+    fn set(&self, config: Config) {
+        self.current.store(Box::leak(config.into()), Ordering::Release);
+    }
+}
+
+impl Deref for Live {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        self.get()
+    }
+}
+
+/// Reads the configuration from `path` again, with `overrides` applied as
+/// they were at startup, keeping the restart only settings of `current`.
+fn read(path: &Path, overrides: &Overrides, current: &Config) -> Result<Config, String> {
+    let config_str = std::fs::read_to_string(path).map_err(|err| format!("Failed to read configuration: {err}"))?;
+    let mut parsed = environment::parse(&config_str).map_err(|err| format!("Failed to parse configuration: {err}"))?;
+    overrides(&mut parsed);
+
+    let toml::Value::Table(current) = toml::Value::try_from(current).unwrap() else { unreachable!() };
+    let toml::Value::Table(mut merged) = toml::Value::try_from(parsed).unwrap() else { unreachable!() };
+    for &key in RESTART_ONLY {
+        if merged.get(key) != current.get(key) {
+            tracing::warn!("Changing `{key}` requires a restart");
+        }
+        match current.get(key) {
+            Some(value) => merged.insert(key.into(), value.clone()),
+            None => merged.remove(key)
+        };
+    }
+
+    toml::Value::Table(merged).try_into().map_err(|err| format!("Failed to apply configuration: {err}"))
+}
+
+fn reload(app: &App, path: &Path) {
+    match read(path, &app.config.overrides, &app.config) {
+        Ok(config) => {
+            app.config.set(config);
+            tracing::info!("Reloaded configuration");
+        }
+        Err(err) => tracing::error!(error = %err, "Keeping the previous configuration")
+    }
+}
+
+/// Reloads the configuration on SIGHUP, and whenever its file changes.
+/// Requests already running finish with the configuration they started with.
+pub async fn run(app: &'static App) {
+    let Some(path) = app.config.path else {
+        return;
+    };
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let (sender, mut changes) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|event| event.paths.iter().any(|changed| changed.file_name() == path.file_name())) {
+            let _ = sender.send(());
+        }
+    });
+
+    // The directory is watched rather than the file, which is replaced when
+    // it's saved
+    let _watcher = match watcher {
+        Ok(mut watcher) => match watcher.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => Some(watcher),
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to watch `{}`", path.display());
+                None
+            }
+        },
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to create configuration watcher");
+            None
+        }
+    };
+
+    #[cfg(unix)]
+    let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to listen for SIGHUP");
+            None
+        }
+    };
+
+    loop {
+        #[cfg(unix)]
+        let hangup = async {
+            match &mut hangup {
+                Some(hangup) => hangup.recv().await,
+                None => std::future::pending().await
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            Some(()) = changes.recv() => {
+                while let Ok(Some(())) = time::timeout(DEBOUNCE, changes.recv()).await {}
+            },
+            Some(()) = hangup => {},
+            else => return
+        }
+        reload(app, path);
+    }
+}
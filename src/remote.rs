@@ -0,0 +1,173 @@
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+use crate::Config;
+
+/// A video hosted elsewhere, declared with `[[remote]]`. It is addressed by
+/// `name` like any video in the library, and ffmpeg reads it from `url`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Remote {
+    pub name: Box<str>,
+    pub url: Box<str>
+}
+
+impl Remote {
+    /// Only HTTP URLs are accepted, anything else would let ffmpeg read local
+    /// files outside the library.
+    pub fn is_valid(&self) -> bool {
+        self.url.starts_with("http://") || self.url.starts_with("https://")
+    }
+}
+
+/// The remote video named `path`, if it is one.
+pub fn find<'a>(config: &'a Config, path: &Path) -> Option<&'a Remote> {
+    let name = path.to_str()?;
+    config.remotes.iter().find(|remote| *remote.name == *name && remote.is_valid())
+}
+
+/// Where ffmpeg and the proxy read the remote video at `url`, through the
+/// chunk cache when there is one.
+pub fn resolve(url: &str) -> PathBuf {
+    #[cfg(feature = "remote")]
+    return crate::chunks::local_url(url).into();
+    #[cfg(not(feature = "remote"))]
+    return url.into();
+}
+
+/// The URL of a remote video, when `path` is what [`crate::jail::video`]
+/// resolved one to.
+pub fn url(path: &Path) -> Option<&str> {
+    path.to_str().filter(|path| path.starts_with("http://") || path.starts_with("https://"))
+}
+
+/// `text` with the query string cut off every URL in it, for logging paths
+/// and errors that may carry a presigned URL and the credentials in it.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let scheme = |text: &str| ["http://", "https://"].iter().filter_map(|scheme| text.find(scheme)).min();
+    if scheme(text).is_none() {
+        return Cow::Borrowed(text);
+    }
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = scheme(rest) {
+        let is_end = |char: char| char.is_whitespace() || "'\"`<>".contains(char);
+        let url_end = rest[start..].find(is_end).map_or(rest.len(), |end| start + end);
+        let Some(query) = rest[start..url_end].find('?').map(|query| start + query) else {
+            redacted.push_str(&rest[..url_end]);
+            rest = &rest[url_end..];
+            continue;
+        };
+        // Signatures are percent-encoded, so a colon ends the query, as in
+        // ffmpeg's `<url>: Server returned 403 Forbidden`
+        let query_end = rest[query..].find(|char| is_end(char) || char == ':').map_or(rest.len(), |end| query + end);
+        redacted.push_str(&rest[..query]);
+        rest = &rest[query_end..];
+    }
+    redacted.push_str(rest);
+    Cow::Owned(redacted)
+}
+
+/// [`redact`] for a path that [`crate::jail::video`] resolved.
+pub fn display(path: &Path) -> Cow<'_, str> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => redact(path),
+        Cow::Owned(path) => Cow::Owned(redact(&path).into_owned())
+    }
+}
+
+#[cfg(feature = "remote")]
+pub use proxy::{client, proxy};
+
+#[cfg(feature = "remote")]
+mod proxy {
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::{http, response};
+    use futures_util::TryStreamExt;
+
+    use crate::error::{ApiError, Code};
+    use crate::App;
+
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Request headers the remote server answers, so that players seek and
+    /// revalidate as they would against a local file.
+    const FORWARDED: [http::HeaderName; 4] = [
+        http::header::RANGE,
+        http::header::IF_RANGE,
+        http::header::IF_NONE_MATCH,
+        http::header::IF_MODIFIED_SINCE
+    ];
+
+    /// Response headers passed back from the remote server.
+    const PASSED: [http::HeaderName; 6] = [
+        http::header::CONTENT_TYPE,
+        http::header::CONTENT_LENGTH,
+        http::header::CONTENT_RANGE,
+        http::header::ACCEPT_RANGES,
+        http::header::ETAG,
+        http::header::LAST_MODIFIED
+    ];
+
+    /// The client for remote videos. Responses are streams that last as long
+    /// as playback does, so only connecting is timed.
+    pub fn client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .user_agent(concat!("ninja/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .unwrap_or_default()
+    }
+
+    /// Passes a request for a remote video through to `url`, ranges and
+    /// conditional requests included.
+    pub async fn proxy(app: &App, url: &str, method: &http::Method, header: &http::HeaderMap) -> response::Response {
+        let mut request = app.remote.request(method.clone(), url);
+        for name in FORWARDED {
+            if let Some(value) = header.get(&name) {
+                request = request.header(name, value);
+            }
+        }
+
+        let remote = match request.send().await {
+            Ok(remote) => remote,
+            Err(err) => {
+                tracing::error!(error = %err.without_url(), "Failed to fetch remote video `{}`", super::redact(url));
+                return ApiError::new(Code::UpstreamFailed, "Failed to fetch remote video").into();
+            }
+        };
+
+        let mut response = response::Response::builder().status(remote.status());
+        for name in PASSED {
+            if let Some(value) = remote.headers().get(&name) {
+                response = response.header(name, value);
+            }
+        }
+        let body = remote.bytes_stream().map_err(std::io::Error::other);
+        response.body(Body::from_stream(body)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert_eq!(url(Path::new("https://example.com/a.mp4")), Some("https://example.com/a.mp4"));
+        assert_eq!(url(Path::new("/srv/videos/a.mp4")), None);
+        assert_eq!(url(Path::new("file:///etc/passwd")), None);
+    }
+
+    #[test]
+    fn redacts() {
+        assert_eq!(redact("/srv/videos/a.mp4"), "/srv/videos/a.mp4");
+        assert_eq!(redact("https://bucket.s3.amazonaws.com/a.mp4?X-Amz-Signature=abc"), "https://bucket.s3.amazonaws.com/a.mp4");
+        assert_eq!(
+            redact("http://a/b.mp4?sig=1: Server returned 403 Forbidden, see `https://c/d?e=f`"),
+            "http://a/b.mp4: Server returned 403 Forbidden, see `https://c/d`"
+        );
+    }
+}
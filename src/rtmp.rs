@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// C0 and C1, then C2, which publishers send before any chunk.
+const HANDSHAKE_SIZE: usize = 1 + 1536 + 1536;
+
+/// Chunk size until the publisher sets its own.
+const DEFAULT_CHUNK_SIZE: usize = 128;
+
+/// Commands before `publish` are small, larger messages are someone else
+/// talking.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+const SET_CHUNK_SIZE: u8 = 1;
+const AMF3_COMMAND: u8 = 17;
+const AMF0_COMMAND: u8 = 20;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    WrongKey,
+    Malformed,
+    /// The publisher didn't get to `publish` in time.
+    TimedOut
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::WrongKey => f.write_str("wrong stream key"),
+            Error::Malformed => f.write_str("malformed RTMP"),
+            Error::TimedOut => f.write_str("no stream key in time")
+        }
+    }
+}
+
+/// The header of the last message on a chunk stream, which later chunks
+/// leave out.
+#[derive(Default)]
+struct ChunkStream {
+    length: usize,
+    kind: u8,
+    extended: bool,
+    payload: Vec<u8>
+}
+
+/// Follows what a publisher sends until its `publish` command, which only
+/// goes through with the stream key as stream name.
+pub struct Gate<'a> {
+    key: &'a str,
+    handshake: usize,
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkStream>,
+    buffer: Vec<u8>,
+    open: bool
+}
+
+fn u24(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
+
+/// Reads an AMF0 string at the start of `data`, returning the rest.
+fn amf_string(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let [0x02, high, low, rest @ ..] = data else {
+        return None;
+    };
+    let length = u16::from_be_bytes([*high, *low]) as usize;
+    (rest.len() >= length).then(|| rest.split_at(length))
+}
+
+/// The stream name of a `publish` command, if `command` is one.
+fn published(command: &[u8]) -> Option<&[u8]> {
+    let (name, rest) = amf_string(command)?;
+    if name != b"publish" {
+        return None;
+    }
+    // The transaction ID, then a null in place of the command object
+    let [0x00, _, _, _, _, _, _, _, _, 0x05, rest @ ..] = rest else {
+        return Some(b"");
+    };
+    Some(amf_string(rest).map_or(b"", |(name, _)| name))
+}
+
+impl<'a> Gate<'a> {
+    pub fn new(key: &'a str) -> Self {
+        Gate {
+            key,
+            handshake: HANDSHAKE_SIZE,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            streams: HashMap::new(),
+            buffer: Vec::new(),
+            open: false
+        }
+    }
+
+    /// Whether the key was given, after which everything goes through.
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Takes what the publisher sent and returns what can go on to ffmpeg.
+    /// Chunks are held back until complete, and the one completing `publish`
+    /// until its stream name is checked.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.open {
+            return Ok(data.to_vec());
+        }
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.extend_from_slice(data);
+
+        let mut passed = self.handshake.min(buffer.len());
+        self.handshake -= passed;
+        while self.handshake == 0 && !self.open {
+            match self.chunk(&buffer[passed..])? {
+                Some(length) => passed += length,
+                None => break
+            }
+        }
+        if self.open {
+            passed = buffer.len();
+        }
+        self.buffer = buffer.split_off(passed);
+        Ok(buffer)
+    }
+
+    /// Reads the chunk at the start of `data`, returning its length, or
+    /// nothing while it's incomplete.
+    fn chunk(&mut self, data: &[u8]) -> Result<Option<usize>, Error> {
+        let Some(&first) = data.first() else {
+            return Ok(None);
+        };
+        let (id, mut position) = match first & 0x3f {
+            0 if data.len() >= 2 => (64 + data[1] as u32, 2),
+            1 if data.len() >= 3 => (64 + data[1] as u32 + 256 * data[2] as u32, 3),
+            0 | 1 => return Ok(None),
+            id => (id as u32, 1)
+        };
+
+        let format = first >> 6;
+        let header = [11, 7, 3, 0][format as usize];
+        let Some(fields) = data.get(position..position + header) else {
+            return Ok(None);
+        };
+        position += header;
+        let stream = self.streams.entry(id).or_default();
+        if format <= 2 {
+            stream.extended = u24(&fields[..3]) == 0xff_ffff;
+        }
+        if format <= 1 {
+            if !stream.payload.is_empty() {
+                return Err(Error::Malformed);
+            }
+            stream.length = u24(&fields[3..6]);
+            stream.kind = fields[6];
+            if stream.length > MAX_MESSAGE_SIZE {
+                return Err(Error::Malformed);
+            }
+        }
+        if stream.extended {
+            position += 4;
+        }
+
+        let length = (stream.length - stream.payload.len()).min(self.chunk_size);
+        let Some(payload) = data.get(position..position + length) else {
+            return Ok(None);
+        };
+        stream.payload.extend_from_slice(payload);
+        position += length;
+        if stream.payload.len() < stream.length {
+            return Ok(Some(position));
+        }
+
+        let message = std::mem::take(&mut stream.payload);
+        match stream.kind {
+            SET_CHUNK_SIZE => {
+                let [a, b, c, d, ..] = message[..] else {
+                    return Err(Error::Malformed);
+                };
+                self.chunk_size = (u32::from_be_bytes([a, b, c, d]) & 0x7fff_ffff).max(1) as usize;
+            }
+            // AMF3 commands start with a byte to switch to AMF0
+            kind @ (AMF0_COMMAND | AMF3_COMMAND) => {
+                let command = if kind == AMF3_COMMAND { message.get(1..).unwrap_or_default() } else { &message[..] };
+                if let Some(name) = published(command) {
+                    // Clients may add parameters after the stream name
+                    let name = name.split(|&byte| byte == b'?').next().unwrap_or_default();
+                    if name != self.key.as_bytes() {
+                        return Err(Error::WrongKey);
+                    }
+                    self.open = true;
+                }
+            }
+            _ => {}
+        }
+        Ok(Some(position))
+    }
+}
+
+/// Passes a publisher through to ffmpeg once it gives `key`. Publishers that
+/// don't within `timeout` of connecting are dropped, however slowly they
+/// trickle in, so that they can't keep the real one waiting.
+pub async fn relay(mut publisher: TcpStream, mut ffmpeg: TcpStream, key: &str, timeout: Duration) -> Result<(), Error> {
+    let deadline = time::Instant::now() + timeout;
+    let mut gate = Gate::new(key);
+    let mut buffer = vec![0; 16 * 1024];
+    let (mut from_ffmpeg, mut to_ffmpeg) = ffmpeg.split();
+    let (mut from_publisher, mut to_publisher) = publisher.split();
+
+    let answers = io::copy(&mut from_ffmpeg, &mut to_publisher);
+    let publish = async {
+        while !gate.is_open() {
+            let read = match time::timeout_at(deadline, from_publisher.read(&mut buffer)).await {
+                Ok(Ok(read)) if read > 0 => read,
+                Ok(_) => return Ok(false),
+                Err(_) => return Err(Error::TimedOut)
+            };
+            let passed = gate.push(&buffer[..read])?;
+            if to_ffmpeg.write_all(&passed).await.is_err() {
+                return Ok(false);
+            }
+        }
+        Ok::<_, Error>(true)
+    };
+    tokio::pin!(answers);
+
+    // ffmpeg answers commands before `publish`, both ways run at once
+    let published = tokio::select! {
+        published = publish => published?,
+        _ = &mut answers => false
+    };
+    if published {
+        let stream = io::copy(&mut from_publisher, &mut to_ffmpeg);
+        let _ = tokio::join!(stream, answers);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amf_string(value: &str) -> Vec<u8> {
+        let mut data = vec![0x02];
+        data.extend((value.len() as u16).to_be_bytes());
+        data.extend(value.as_bytes());
+        data
+    }
+
+    /// `command` on chunk stream 3, in chunks of `chunk_size`.
+    fn command(name: &str, argument: &str, chunk_size: usize) -> Vec<u8> {
+        let mut message = amf_string(name);
+        message.extend([0x00, 0x40, 0x10, 0, 0, 0, 0, 0, 0, 0x05]);
+        message.extend(amf_string(argument));
+
+        let length = (message.len() as u32).to_be_bytes();
+        let mut data = vec![0x03, 0, 0, 0, length[1], length[2], length[3], AMF0_COMMAND, 1, 0, 0, 0];
+        for (index, chunk) in message.chunks(chunk_size).enumerate() {
+            if index > 0 {
+                data.push(0xc3);
+            }
+            data.extend(chunk);
+        }
+        data
+    }
+
+    /// A publish with parameters after the name, long enough to need the
+    /// larger chunk size.
+    fn publish(name: &str) -> Vec<u8> {
+        command("publish", &format!("{name}?padding={}", "x".repeat(200)), 4096)
+    }
+
+    fn session(name: &str) -> Vec<u8> {
+        let mut data = vec![3; HANDSHAKE_SIZE];
+        data.extend(command("connect", "live", DEFAULT_CHUNK_SIZE));
+        data.extend([0x02, 0, 0, 0, 0, 0, 4, SET_CHUNK_SIZE, 0, 0, 0, 0, 0, 0, 0x10, 0]);
+        data.extend(publish(name));
+        data
+    }
+
+    #[test]
+    fn right_key() {
+        let data = session("secret");
+        let mut gate = Gate::new("secret");
+        // Byte by byte, as chunks may arrive split anywhere
+        let mut passed = Vec::new();
+        for byte in &data {
+            passed.extend(gate.push(&[*byte]).unwrap());
+        }
+        assert!(gate.is_open());
+        assert_eq!(passed, data);
+        assert_eq!(gate.push(b"media").unwrap(), b"media");
+    }
+
+    #[test]
+    fn wrong_key() {
+        let data = session("guess");
+        let before = data.len() - publish("guess").len();
+        let mut gate = Gate::new("secret");
+        assert_eq!(gate.push(&data[..before]).unwrap(), &data[..before]);
+        assert_eq!(gate.push(&data[before..]), Err(Error::WrongKey));
+        assert!(!gate.is_open());
+    }
+
+    #[test]
+    fn held_back() {
+        let mut data = vec![3; HANDSHAKE_SIZE];
+        let publish = command("publish", &format!("secret?padding={}", "x".repeat(200)), DEFAULT_CHUNK_SIZE);
+        data.extend(&publish[..publish.len() - 1]);
+        let mut gate = Gate::new("secret");
+        // Chunks go through as they come, but not the one completing
+        // `publish`
+        let passed = gate.push(&data).unwrap();
+        assert!(passed.len() < data.len());
+        assert!(!gate.is_open());
+        assert_eq!(gate.push(&publish[publish.len() - 1..]).unwrap().len(), data.len() + 1 - passed.len());
+        assert!(gate.is_open());
+    }
+
+    /// Both ends of a loopback connection.
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        (client, listener.accept().await.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn relay_wrong_key() {
+        let (mut publisher, accepted) = pair().await;
+        let (connected, mut ffmpeg) = pair().await;
+        let data = session("guess");
+        publisher.write_all(&data).await.unwrap();
+
+        let relayed = relay(accepted, connected, "secret", Duration::from_secs(5)).await;
+        assert_eq!(relayed, Err(Error::WrongKey));
+        // ffmpeg never saw the `publish`, and the connection closed
+        let mut received = Vec::new();
+        ffmpeg.read_to_end(&mut received).await.unwrap();
+        assert!(data.starts_with(&received));
+        assert!(received.len() <= data.len() - publish("guess").len());
+    }
+
+    #[tokio::test]
+    async fn relay_trickle() {
+        let (mut publisher, accepted) = pair().await;
+        let (connected, _ffmpeg) = pair().await;
+        tokio::spawn(async move {
+            for byte in session("secret") {
+                if publisher.write_all(&[byte]).await.is_err() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+
+        let started = time::Instant::now();
+        let relayed = relay(accepted, connected, "secret", Duration::from_millis(200)).await;
+        assert_eq!(relayed, Err(Error::TimedOut));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn malformed() {
+        let mut data = vec![3; HANDSHAKE_SIZE];
+        data.extend([0x03, 0, 0, 0, 0xff, 0xff, 0xff, AMF0_COMMAND, 1, 0, 0, 0]);
+        assert_eq!(Gate::new("secret").push(&data), Err(Error::Malformed));
+    }
+}
@@ -0,0 +1,195 @@
+use std::path::{Component, Path};
+
+use crate::Config;
+
+/// A library in an S3 compatible bucket, declared with `[[bucket]]`. Like
+/// with `[[library]]`, its videos are addressed with the `name` as the first
+/// path component. They are served and read by ffmpeg through presigned URLs,
+/// so that the bucket doesn't need to be public.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Bucket {
+    pub name: Box<str>,
+    pub bucket: Box<str>,
+    /// The server, for anything else than AWS, like `http://minio:9000`.
+    #[serde(default)]
+    pub endpoint: Option<Box<str>>,
+    #[serde(default = "Bucket::default_region")]
+    pub region: Box<str>,
+    pub access_key_id: Box<str>,
+    pub secret_access_key: Box<str>,
+    /// Only the objects below this prefix are part of the library.
+    #[serde(default)]
+    pub prefix: Box<str>
+}
+
+impl Bucket {
+    fn default_region() -> Box<str> {
+        "us-east-1".into()
+    }
+}
+
+/// Splits `path` into the bucket named by its first component and the rest.
+/// Paths with anything else than plain names are never in a bucket.
+pub fn locate<'a>(config: &'a Config, path: &Path) -> Option<(&'a Bucket, String)> {
+    let mut components = path.components().filter(|component| *component != Component::CurDir);
+    let Component::Normal(name) = components.next()? else {
+        return None;
+    };
+    let bucket = config.buckets.iter().find(|bucket| *bucket.name == *name)?;
+
+    let mut relative = Vec::new();
+    for component in components {
+        let Component::Normal(name) = component else {
+            return None;
+        };
+        relative.push(name.to_str()?);
+    }
+    (!relative.is_empty()).then(|| (bucket, relative.join("/")))
+}
+
+#[cfg(feature = "s3")]
+pub use store::{presign, scan};
+
+#[cfg(not(feature = "s3"))]
+pub async fn presign(_bucket: &Bucket, _relative: &str) -> Result<String, &'static str> {
+    Err("ninja was built without the `s3` feature")
+}
+
+#[cfg(feature = "s3")]
+mod store {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use axum::http;
+    use futures_util::StreamExt;
+    use object_store::aws::{AmazonS3, AmazonS3Builder};
+    use object_store::signer::Signer;
+    use object_store::ObjectStore;
+
+    use super::Bucket;
+    use crate::index::Video;
+    use crate::{jail, probe, subtitles, App};
+
+    /// Long enough for ffmpeg to read what it needs, and for players to
+    /// reconnect while seeking.
+    const PRESIGN_EXPIRY: Duration = Duration::from_secs(6 * 3600);
+
+    fn store(bucket: &Bucket) -> object_store::Result<AmazonS3> {
+        let mut builder = AmazonS3Builder::new()
+            .with_bucket_name(&*bucket.bucket)
+            .with_region(&*bucket.region)
+            .with_access_key_id(&*bucket.access_key_id)
+            .with_secret_access_key(&*bucket.secret_access_key);
+        if let Some(endpoint) = &bucket.endpoint {
+            // Self hosted servers are usually reached by path rather than by
+            // subdomain, and often over plain HTTP on a private network
+            builder = builder.with_endpoint(&**endpoint).with_allow_http(true);
+        }
+        builder.build()
+    }
+
+    /// A URL that reads the object for `relative`, a `/` separated path
+    /// inside the library, without credentials.
+    pub async fn presign(bucket: &Bucket, relative: &str) -> object_store::Result<String> {
+        let key = match bucket.prefix.trim_matches('/') {
+            "" => object_store::path::Path::from(relative),
+            prefix => object_store::path::Path::from(format!("{prefix}/{relative}"))
+        };
+        let url = store(bucket)?.signed_url(http::Method::GET, &key, PRESIGN_EXPIRY).await?;
+        Ok(url.into())
+    }
+
+    /// Indexes the videos in every bucket, probing the new and changed ones.
+    pub async fn scan(app: &App, generation: u64) {
+        for bucket in app.config.buckets.iter() {
+            if let Err(err) = scan_bucket(app, bucket, generation).await {
+                tracing::error!(error = %err, "Failed to scan bucket `{}`", bucket.name);
+            }
+        }
+    }
+
+    async fn scan_bucket(app: &App, bucket: &Bucket, generation: u64) -> object_store::Result<()> {
+        let store = store(bucket)?;
+        let prefix = bucket.prefix.trim_matches('/');
+        let prefix = (!prefix.is_empty()).then(|| object_store::path::Path::from(prefix));
+        let mut objects = store.list(prefix.as_ref());
+
+        while let Some(object) = objects.next().await {
+            let object = object?;
+            let key = object.location.as_ref();
+            let relative = prefix.as_ref().map_or(Some(key), |prefix| key.strip_prefix(prefix.as_ref())?.strip_prefix('/'));
+            let Some(relative) = relative else { continue };
+            if subtitles::is_sidecar(Path::new(relative)) || !jail::is_allowed(&app.config, Path::new(relative)) {
+                continue;
+            }
+
+            let path: Box<str> = format!("{}/{relative}", bucket.name).into();
+            let (size, mtime) = (object.size as u64, object.last_modified.timestamp().max(0) as u64);
+            match app.index.get(&path) {
+                Ok(Some(video)) if video.size == size && video.mtime == mtime => {
+                    if let Err(err) = app.index.touch(&path, generation) {
+                        tracing::error!(error = %err, "Failed to update index for `{path}`");
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                Err(err) => tracing::error!(error = %err, "Failed to query index for `{path}`")
+            }
+
+            let url = store.signed_url(http::Method::GET, &object.location, PRESIGN_EXPIRY).await?;
+            let summary = probe::summary(&app.config, Path::new(url.as_str())).await;
+            let video = Video {
+                filename: relative.rsplit('/').next().unwrap_or(relative).into(),
+                path,
+                size,
+                mtime,
+                duration: summary.as_ref().map(|summary| summary.duration),
+                width: summary.as_ref().map(|summary| summary.width).filter(|&width| width > 0),
+                height: summary.as_ref().map(|summary| summary.height).filter(|&height| height > 0),
+                video_codec: summary.as_ref().and_then(|summary| summary.video_codec.clone()),
+                audio_codec: summary.as_ref().and_then(|summary| summary.audio_codec.clone()),
+                title: summary.as_ref().and_then(|summary| summary.title.clone()),
+                tags: summary.and_then(|summary| summary.tags),
+                year: None,
+                plot: None,
+                poster: None,
+                fanart: None,
+                tmdb: None,
+                added: None,
+                sha256: None,
+                phash: None
+            };
+            if let Err(err) = app.index.upsert(&video, generation) {
+                tracing::error!(error = %err, "Failed to index `{}`", video.path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locating() {
+        let config = Config {
+            buckets: Box::new([Bucket {
+                name: "archive".into(),
+                bucket: "media".into(),
+                endpoint: None,
+                region: Bucket::default_region(),
+                access_key_id: "key".into(),
+                secret_access_key: "secret".into(),
+                prefix: "".into()
+            }]),
+            ..Config::default()
+        };
+        let locate = |path: &str| locate(&config, Path::new(path)).map(|(bucket, relative)| (&*bucket.bucket, relative));
+        assert_eq!(locate("archive/shows/a.mkv"), Some(("media", "shows/a.mkv".to_owned())));
+        assert_eq!(locate("./archive/a.mkv"), Some(("media", "a.mkv".to_owned())));
+        assert_eq!(locate("archive/../a.mkv"), None);
+        assert_eq!(locate("archive"), None);
+        assert_eq!(locate("movies/a.mkv"), None);
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::{fs, time};
+
+use crate::index::Video;
+use crate::library::{self, Root};
+use crate::webhooks::{self, Event};
+use crate::{checksum, duplicates, jail, nfo, probe, subtitles, thumb, App};
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+/// Converts a path under `root` into the `/` separated form used by the index.
+fn relative(root: Root, path: &Path) -> Option<Box<str>> {
+    let relative = path.strip_prefix(root.path).ok()?;
+    let components: Option<Vec<_>> = relative.components()
+        .map(|component| component.as_os_str().to_str())
+        .collect();
+
+    Some(root.prefixed(&components?.join("/")))
+}
+
+/// Whether the index already has what the sidecars, and TMDB, say about
+/// `video`. A title missing from them may come from the container.
+fn matches(video: &Video, sidecars: &nfo::Metadata, tmdb: Option<&str>) -> bool {
+    sidecars.title.as_ref().is_none_or(|title| video.title.as_ref() == Some(title))
+        && video.year == sidecars.year
+        && video.plot == sidecars.plot
+        && video.poster == sidecars.poster
+        && video.fanart == sidecars.fanart
+        && video.tmdb.as_deref() == tmdb
+}
+
+/// Indexes the file at `path`, announcing it to webhooks when it is new and
+/// `announce` is set.
+async fn index_file(app: &App, root: Root<'_>, path: &Path, metadata: std::fs::Metadata, generation: u64, announce: bool) {
+    if subtitles::is_sidecar(path) || !jail::is_allowed(&app.config, path) {
+        return;
+    }
+
+    let Some(relative) = relative(root, path) else {
+        return;
+    };
+
+    let size = metadata.len();
+    let mtime = metadata.modified().map_or(0, unix_time);
+    let sidecars = nfo::read(path).await;
+    #[cfg(feature = "tmdb")]
+    let (sidecars, tmdb) = crate::tmdb::describe(app, &relative, sidecars).await;
+    #[cfg(not(feature = "tmdb"))]
+    let tmdb = None;
+
+    let known = match app.index.get(&relative) {
+        Ok(Some(video)) if video.size == size && video.mtime == mtime && matches(&video, &sidecars, tmdb.as_deref()) => {
+            if let Err(err) = app.index.touch(&relative, generation) {
+                tracing::error!(error = %err, "Failed to update index for `{relative}`");
+            }
+            if video.sha256.is_none() {
+                hash(app, &relative, path).await;
+            }
+            if video.phash.is_none() {
+                duplicates::hash(app, &video).await;
+            }
+            return;
+        }
+        Ok(video) => video.is_some(),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to query index for `{relative}`");
+            true
+        }
+    };
+
+    let summary = probe::summary(&app.config, path).await;
+    let video = Video {
+        filename: path.file_name().unwrap().to_string_lossy().into(),
+        path: relative,
+        size,
+        mtime,
+        duration: summary.as_ref().map(|summary| summary.duration),
+        width: summary.as_ref().map(|summary| summary.width).filter(|&width| width > 0),
+        height: summary.as_ref().map(|summary| summary.height).filter(|&height| height > 0),
+        video_codec: summary.as_ref().and_then(|summary| summary.video_codec.clone()),
+        audio_codec: summary.as_ref().and_then(|summary| summary.audio_codec.clone()),
+        // Curated titles win over whatever the container says
+        title: sidecars.title.or_else(|| summary.as_ref().and_then(|summary| summary.title.clone())),
+        tags: summary.and_then(|summary| summary.tags),
+        year: sidecars.year,
+        plot: sidecars.plot,
+        poster: sidecars.poster,
+        fanart: sidecars.fanart,
+        tmdb,
+        added: None,
+        sha256: None,
+        phash: None
+    };
+
+    if let Err(err) = app.index.upsert(&video, generation) {
+        tracing::error!(error = %err, "Failed to index `{}`", video.path);
+        webhooks::emit(app, Event::ScanError { path: &video.path, error: &err.to_string() });
+        return;
+    }
+    if announce && !known {
+        webhooks::emit(app, Event::VideoAdded { video: &video.path, title: video.title.as_deref() });
+    }
+
+    if app.config.scan_thumbnails && video.duration.is_some() {
+        thumb::poster(app, &video.path).await;
+    }
+    hash(app, &video.path, path).await;
+    duplicates::hash(app, &video).await;
+}
+
+/// Computes the checksum of a video ahead of time, when `scan_checksums` is
+/// on.
+async fn hash(app: &App, relative: &str, path: &Path) {
+    if !app.config.scan_checksums {
+        return;
+    }
+    if let Err(err) = checksum::checksum(app, relative, path).await {
+        tracing::error!(error = %err, "Failed to hash `{}`", path.display());
+    }
+}
+
+async fn walk(app: &App, root: Root<'_>, dir: PathBuf, generation: u64, announce: bool) {
+    let canonical_root = match fs::canonicalize(root.path).await {
+        Ok(canonical_root) => canonical_root,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to resolve `{}`", root.path.display());
+            webhooks::emit(app, Event::ScanError { path: &root.path.to_string_lossy(), error: &err.to_string() });
+            return;
+        }
+    };
+
+    // Symlinks can make the same directory show up more than once, or even
+    // inside itself
+    let mut visited = HashSet::new();
+    let mut dirs = vec![dir];
+
+    while let Some(dir) = dirs.pop() {
+        if !fs::canonicalize(&dir).await.is_ok_and(|canonical| visited.insert(canonical)) {
+            continue;
+        }
+
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to read directory `{}`", dir.display());
+                webhooks::emit(app, Event::ScanError { path: &dir.to_string_lossy(), error: &err.to_string() });
+                continue;
+            }
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let path = entry.path();
+            let is_symlink = entry.file_type().await.is_ok_and(|file_type| file_type.is_symlink());
+            if is_symlink && !jail::follows(&app.config, &canonical_root, &path).await {
+                continue;
+            }
+
+            match fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_dir() => dirs.push(path),
+                Ok(metadata) if metadata.is_file() => index_file(app, root, &path, metadata, generation, announce).await,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Adds the `[[remote]]` videos to the index, probing the ones it doesn't have
+/// yet.
+async fn index_remotes(app: &App, generation: u64) {
+    for remote in app.config.remotes.iter().filter(|remote| remote.is_valid()) {
+        match app.index.get(&remote.name) {
+            Ok(Some(_)) => {
+                if let Err(err) = app.index.touch(&remote.name, generation) {
+                    tracing::error!(error = %err, "Failed to update index for `{}`", remote.name);
+                }
+                continue;
+            }
+            Ok(None) => {}
+            Err(err) => tracing::error!(error = %err, "Failed to query index for `{}`", remote.name)
+        }
+
+        let summary = probe::summary(&app.config, Path::new(&*remote.url)).await;
+        let video = Video {
+            path: remote.name.clone(),
+            filename: remote.name.rsplit('/').next().unwrap_or(&remote.name).into(),
+            size: 0,
+            mtime: 0,
+            duration: summary.as_ref().map(|summary| summary.duration),
+            width: summary.as_ref().map(|summary| summary.width).filter(|&width| width > 0),
+            height: summary.as_ref().map(|summary| summary.height).filter(|&height| height > 0),
+            video_codec: summary.as_ref().and_then(|summary| summary.video_codec.clone()),
+            audio_codec: summary.as_ref().and_then(|summary| summary.audio_codec.clone()),
+            title: summary.as_ref().and_then(|summary| summary.title.clone()),
+            tags: summary.and_then(|summary| summary.tags),
+            year: None,
+            plot: None,
+            poster: None,
+            fanart: None,
+            tmdb: None,
+            added: None,
+            sha256: None,
+            phash: None
+        };
+        if let Err(err) = app.index.upsert(&video, generation) {
+            tracing::error!(error = %err, "Failed to index `{}`", video.path);
+        }
+    }
+}
+
+/// Walks every library and brings the index up to date. Files whose size and
+/// modification time are unchanged aren't probed again, and neither are
+/// remote videos.
+pub async fn scan(app: &App) {
+    // Scheduled scans can overlap with the periodic one
+    static SCANNING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let _scanning = SCANNING.lock().await;
+
+    let generation = unix_time(SystemTime::now());
+    // Filling an empty index would announce the whole library
+    let announce = app.index.recent(1, |_| true).is_ok_and(|videos| !videos.is_empty());
+    for root in library::roots(&app.config) {
+        walk(app, root, root.path.to_path_buf(), generation, announce).await;
+    }
+    index_remotes(app, generation).await;
+    #[cfg(feature = "s3")]
+    crate::s3::scan(app, generation).await;
+
+    match app.index.prune(generation) {
+        Ok(0) => {}
+        Ok(removed) => tracing::info!("Removed {removed} missing videos from the index"),
+        Err(err) => tracing::error!(error = %err, "Failed to prune index")
+    }
+}
+
+/// Brings a single changed path up to date, whether it was added, modified or
+/// removed. Directories are walked recursively.
+pub async fn update(app: &App, root: Root<'_>, path: &Path) {
+    let Some(relative) = relative(root, path) else {
+        return;
+    };
+
+    if relative.split('/').any(|component| component.starts_with('.')) {
+        return;
+    }
+
+    let generation = unix_time(SystemTime::now());
+    // The videos next to an NFO or artwork pick up its changes
+    if nfo::is_sidecar(path) {
+        if let Some(dir) = path.parent() {
+            walk(app, root, dir.to_path_buf(), generation, true).await;
+        }
+        return;
+    }
+
+    match fs::metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => walk(app, root, path.to_path_buf(), generation, true).await,
+        Ok(metadata) if metadata.is_file() => index_file(app, root, path, metadata, generation, true).await,
+        Ok(_) => {}
+        Err(_) => if let Err(err) = app.index.remove(&relative) {
+            tracing::error!(error = %err, "Failed to remove `{relative}` from the index");
+        }
+    }
+}
+
+/// Rescans the library every `scan_interval` seconds, or only once at startup
+/// when the interval is zero.
+pub async fn run(app: &'static App) {
+    loop {
+        scan(app).await;
+        if app.config.scan_interval == 0 {
+            break;
+        }
+        time::sleep(Duration::from_secs(app.config.scan_interval)).await;
+    }
+}
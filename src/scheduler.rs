@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{extract, response, Json};
+use tokio::time;
+
+use crate::error::{ApiError, Code};
+use crate::users::User;
+use crate::{access_log, auth, cache, checksum, hls, scanner, trash, unix_now, App};
+
+/// Pre-transcoded videos are the ones added this recently, in seconds.
+const DEFAULT_WITHIN: u64 = 24 * 3600;
+
+/// Segments pre-transcoded from the start of each video.
+const DEFAULT_SEGMENTS: u32 = 3;
+
+/// Upper bound on how far ahead the next run is looked for, an expression like
+/// `0 0 30 2 *` never matches.
+const MAX_LOOKAHEAD: u64 = 5 * 366 * 24 * 3600;
+
+/// A cron expression: the minute, hour, day of the month, month and day of the
+/// week, evaluated in UTC. Fields take `*`, numbers, ranges and lists, each
+/// with an optional `/step`. `@hourly`, `@daily`, `@weekly`, `@monthly` and
+/// `@yearly` are accepted as well.
+pub struct Schedule {
+    source: Box<str>,
+    /// One bit per allowed value, for each field.
+    fields: [u64; 5],
+    /// Whether the day of the month and the day of the week are both
+    /// restricted, in which case either matching is enough.
+    either_day: bool
+}
+
+/// Allowed values of each field, Sunday being both 0 and 7.
+const RANGES: [(u32, u32); 5] = [(0, 59), (0, 23), (1, 31), (1, 12), (0, 7)];
+
+fn parse_field(field: &str, (min, max): (u32, u32)) -> Result<u64, String> {
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&step| step > 0).ok_or_else(|| format!("invalid step in `{item}`"))?),
+            None => (item, 1)
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => {
+                let number = |value: &str| value.parse::<u32>().ok().filter(|value| (min..=max).contains(value)).ok_or_else(|| format!("`{value}` is out of range"));
+                match range.split_once('-') {
+                    Some((start, end)) => (number(start)?, number(end)?),
+                    // `5/15` runs from 5 to the end of the range
+                    None if item.contains('/') => (number(range)?, max),
+                    None => (number(range)?, number(range)?)
+                }
+            }
+        };
+        if start > end {
+            return Err(format!("`{range}` is backwards"));
+        }
+        bits |= (start..=end).step_by(step as usize).fold(0, |bits, value| bits | 1 << value);
+    }
+    Ok(bits)
+}
+
+impl Schedule {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let expression = match source.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression
+        };
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("schedule `{source}` doesn't have 5 fields"));
+        }
+
+        let mut bits = [0; 5];
+        for ((bits, field), range) in bits.iter_mut().zip(&fields).zip(RANGES) {
+            *bits = parse_field(field, range).map_err(|err| format!("invalid schedule `{source}`: {err}"))?;
+        }
+        // Sunday
+        if bits[4] & 1 << 7 != 0 {
+            bits[4] |= 1;
+        }
+        Ok(Schedule {
+            source: source.into(),
+            fields: bits,
+            either_day: !fields[2].starts_with('*') && !fields[4].starts_with('*')
+        })
+    }
+
+    fn matches_day(&self, days: u64) -> bool {
+        let (_, month, day) = access_log::civil(days);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4) % 7;
+        let (day, weekday) = (self.fields[2] & 1 << day != 0, self.fields[4] & 1 << weekday != 0);
+        self.fields[3] & 1 << month != 0 && if self.either_day { day || weekday } else { day && weekday }
+    }
+
+    /// Whether the minute starting at `time` is scheduled.
+    pub fn matches(&self, time: u64) -> bool {
+        self.matches_day(time / 86400)
+            && self.fields[1] & 1 << (time / 3600 % 24) != 0
+            && self.fields[0] & 1 << (time / 60 % 60) != 0
+    }
+
+    /// The first scheduled minute after `time`, skipping whole days and hours
+    /// that don't match.
+    pub fn next(&self, time: u64) -> Option<u64> {
+        let mut time = (time / 60 + 1) * 60;
+        let limit = time + MAX_LOOKAHEAD;
+        while time < limit {
+            if !self.matches_day(time / 86400) {
+                time = (time / 86400 + 1) * 86400;
+            } else if self.fields[1] & 1 << (time / 3600 % 24) == 0 {
+                time = (time / 3600 + 1) * 3600;
+            } else if self.fields[0] & 1 << (time / 60 % 60) == 0 {
+                time += 60;
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+impl serde::Serialize for Schedule {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Schedule {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = <Box<str> as serde::Deserialize>::deserialize(deserializer)?;
+        Schedule::parse(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+fn default_within() -> u64 {
+    DEFAULT_WITHIN
+}
+
+fn default_segments() -> u32 {
+    DEFAULT_SEGMENTS
+}
+
+/// What a task does when it runs.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Action {
+    /// A full rescan of the libraries.
+    Scan,
+    /// Removes the cached thumbnails, storyboards and waveforms of videos that
+    /// are no longer in the index.
+    EvictCache,
+    /// Deletes what has been in the trash for longer than `trash_retention`.
+    PurgeTrash,
+    /// Hashes the videos with a stored checksum again to catch silent
+    /// corruption.
+    VerifyChecksums,
+    /// Transcodes the first segments of recently added videos into the segment
+    /// cache, so that they start playing right away.
+    Pretranscode {
+        rendition: Box<str>,
+        #[serde(default = "default_segments")]
+        segments: u32,
+        /// Videos added this many seconds ago or less are transcoded.
+        #[serde(default = "default_within")]
+        within: u64
+    }
+}
+
+impl Action {
+    fn kind(&self) -> &'static str {
+        match self {
+            Action::Scan => "scan",
+            Action::EvictCache => "evict_cache",
+            Action::PurgeTrash => "purge_trash",
+            Action::VerifyChecksums => "verify_checksums",
+            Action::Pretranscode { .. } => "pretranscode"
+        }
+    }
+}
+
+/// A recurring task, declared with `[[task]]`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Task {
+    /// Tells tasks of the same kind apart, defaults to the kind.
+    name: Option<Box<str>>,
+    schedule: Schedule,
+    #[serde(flatten)]
+    action: Action
+}
+
+impl Task {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.action.kind())
+    }
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "lowercase", tag = "status")]
+enum Outcome {
+    Succeeded { message: Box<str> },
+    Failed { error: Box<str> }
+}
+
+#[derive(serde::Serialize, Clone)]
+struct Run {
+    started: u64,
+    finished: u64,
+    #[serde(flatten)]
+    outcome: Outcome
+}
+
+#[derive(Default)]
+struct State {
+    running: bool,
+    last_run: Option<Run>
+}
+
+/// What the tasks did last, by name.
+#[derive(Default)]
+pub struct Tasks {
+    states: Mutex<HashMap<Box<str>, State>>
+}
+
+async fn evict_cache(app: &App) -> Result<Box<str>, Box<str>> {
+    let exists = |relative: &str| matches!(app.index.get(relative), Ok(Some(_)) | Err(_));
+    let mut removed = 0;
+    for dir in ["thumbs", "storyboards", "waveforms"] {
+        removed += cache::remove_orphans(&app.config.cache_path.join(dir), exists).await;
+    }
+    Ok(format!("Removed {removed} cached files").into())
+}
+
+async fn verify_checksums(app: &App) -> Result<Box<str>, Box<str>> {
+    match checksum::verify(app).await {
+        Ok((verified, corrupted)) if corrupted.is_empty() => Ok(format!("Verified {verified} videos").into()),
+        Ok((verified, corrupted)) => Err(format!("{} of {verified} videos changed: {}", corrupted.len(), corrupted.join(", ")).into()),
+        Err(err) => Err(format!("Failed to read the index: {err}").into())
+    }
+}
+
+async fn pretranscode(app: &App, rendition: &str, segments: u32, within: u64) -> Result<Box<str>, Box<str>> {
+    let since = unix_now().saturating_sub(within);
+    let videos = app.index.all().map_err(|err| format!("Failed to read the index: {err}"))?;
+    let (mut transcoded, mut failed) = (0, 0);
+    for video in videos.iter().filter(|video| video.added.is_some_and(|added| added >= since) && video.duration.is_some()) {
+        match hls::pretranscode(app, &video.path, rendition, segments).await {
+            Ok(()) => transcoded += 1,
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to pre-transcode `{}`", video.path);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(format!("Pre-transcoded {transcoded} videos").into()),
+        _ => Err(format!("Failed to pre-transcode {failed} of {} videos", transcoded + failed).into())
+    }
+}
+
+async fn execute(app: &App, action: &Action) -> Result<Box<str>, Box<str>> {
+    match action {
+        Action::Scan => {
+            scanner::scan(app).await;
+            Ok("Scanned the libraries".into())
+        }
+        Action::EvictCache => evict_cache(app).await,
+        Action::PurgeTrash => Ok(format!("Purged {} videos", trash::purge(app).await).into()),
+        Action::VerifyChecksums => verify_checksums(app).await,
+        Action::Pretranscode { rendition, segments, within } => pretranscode(app, rendition, *segments, *within).await
+    }
+}
+
+/// Runs `task` in the background, unless its previous run hasn't finished.
+fn start(app: &'static App, task: &'static Task) {
+    {
+        let mut states = app.tasks.states.lock().unwrap();
+        let state = states.entry(task.name().into()).or_default();
+        if state.running {
+            tracing::warn!("Skipping task `{}`, its previous run is still going", task.name());
+            return;
+        }
+        state.running = true;
+    }
+
+    tokio::spawn(async move {
+        tracing::info!("Running task `{}`", task.name());
+        let started = unix_now();
+        let outcome = match execute(app, &task.action).await {
+            Ok(message) => Outcome::Succeeded { message },
+            Err(error) => {
+                tracing::error!(error = %error, "Task `{}` failed", task.name());
+                Outcome::Failed { error }
+            }
+        };
+        let run = Run { started, finished: unix_now(), outcome };
+        let mut states = app.tasks.states.lock().unwrap();
+        let state = states.entry(task.name().into()).or_default();
+        state.running = false;
+        state.last_run = Some(run);
+    });
+}
+
+/// Starts the `[[task]]`s at the minutes they are scheduled for. Tasks are
+/// read from the configuration every minute, so reloads take effect right
+/// away.
+pub async fn run(app: &'static App) {
+    let mut minute = unix_now() / 60;
+    loop {
+        minute += 1;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        time::sleep(Duration::from_secs(minute * 60).saturating_sub(now)).await;
+        // Minutes skipped while the system was suspended are not caught up on
+        minute = minute.max(unix_now() / 60);
+
+        let config = app.config.get();
+        for task in config.tasks.iter().filter(|task| task.schedule.matches(minute * 60)) {
+            start(app, task);
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct TaskStatus<'a> {
+    name: &'a str,
+    kind: &'static str,
+    schedule: &'a Schedule,
+    running: bool,
+    last_run: Option<Run>,
+    next_run: Option<u64>
+}
+
+/// The configured tasks with their last outcome and when they run next, times
+/// being Unix timestamps.
+pub async fn list_tasks(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if !auth::is_admin(&app.config, user.as_deref()) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+
+    let config = app.config.get();
+    let now = unix_now();
+    let states = app.tasks.states.lock().unwrap();
+    let tasks: Vec<_> = config.tasks.iter().map(|task| {
+        let state = states.get(task.name());
+        TaskStatus {
+            name: task.name(),
+            kind: task.action.kind(),
+            schedule: &task.schedule,
+            running: state.is_some_and(|state| state.running),
+            last_run: state.and_then(|state| state.last_run.clone()),
+            next_run: task.schedule.next(now)
+        }
+    }).collect();
+    response::IntoResponse::into_response(Json(tasks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-03-15 10:30 UTC, a Friday
+    const FRIDAY: u64 = 1_710_498_600;
+
+    #[test]
+    fn fields() {
+        assert_eq!(parse_field("*/15", RANGES[0]).unwrap(), 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(parse_field("1-3,5", RANGES[1]).unwrap(), 0b101110);
+        assert_eq!(parse_field("10/20", RANGES[0]).unwrap(), 1 << 10 | 1 << 30 | 1 << 50);
+        assert!(parse_field("60", RANGES[0]).is_err());
+        assert!(parse_field("5-1", RANGES[0]).is_err());
+        assert!(parse_field("*/0", RANGES[0]).is_err());
+        assert!(Schedule::parse("* * * *").is_err());
+    }
+
+    #[test]
+    fn next_runs() {
+        let daily = Schedule::parse("@daily").unwrap();
+        assert_eq!(daily.next(FRIDAY), Some(1_710_547_200));
+        assert!(daily.matches(1_710_547_200));
+        assert!(!daily.matches(FRIDAY));
+
+        let quarter = Schedule::parse("*/15 * * * *").unwrap();
+        assert_eq!(quarter.next(FRIDAY), Some(FRIDAY + 15 * 60));
+
+        // Sunday 03:00, given as 7
+        let sunday = Schedule::parse("0 3 * * 7").unwrap();
+        assert_eq!(sunday.next(FRIDAY), Some(1_710_644_400));
+
+        // The 1st of the month or any Monday
+        let either = Schedule::parse("0 0 1 * 1").unwrap();
+        assert_eq!(either.next(FRIDAY), Some(1_710_720_000));
+
+        assert_eq!(Schedule::parse("0 0 30 2 *").unwrap().next(FRIDAY), None);
+    }
+}
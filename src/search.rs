@@ -0,0 +1,191 @@
+use axum::{extract, response, Json};
+
+use crate::error::{ApiError, Code};
+use crate::index::Video;
+use crate::users::User;
+use crate::{auth, url, App};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+/// Terms shorter than this only match as substrings, scattered letters of
+/// short terms match nearly anything.
+const FUZZY_MIN_LENGTH: usize = 3;
+
+#[derive(serde::Deserialize)]
+pub struct SearchQuery {
+    q: Box<str>,
+    limit: Option<usize>
+}
+
+/// How well `term` matches `field`, both lowercase: best when equal, then as
+/// a prefix, at the start of a word, anywhere, and lowest when its letters
+/// appear in order with few others in between.
+fn field_score(field: &str, term: &str) -> u32 {
+    if field == term {
+        return 100;
+    }
+    if let Some(index) = field.find(term) {
+        let word_start = field[..index].chars().next_back().is_none_or(|char| !char.is_alphanumeric());
+        return match (index, word_start) {
+            (0, _) => 80,
+            (_, true) => 60,
+            _ => 40
+        };
+    }
+    if term.chars().count() < FUZZY_MIN_LENGTH {
+        return 0;
+    }
+
+    fuzzy_span(field, term).map_or(0, |(start, end)| {
+        let skipped = field[start..end].chars().count() - term.chars().count();
+        20u32.saturating_sub(skipped as u32)
+    })
+}
+
+/// The byte range of `field` from the first to the last letter of `term`,
+/// matched in order as early as possible.
+fn fuzzy_span(field: &str, term: &str) -> Option<(usize, usize)> {
+    let mut chars = field.char_indices();
+    let mut span: Option<(usize, usize)> = None;
+    for wanted in term.chars() {
+        let (index, char) = chars.find(|&(_, char)| char == wanted)?;
+        let start = span.map_or(index, |(start, _)| start);
+        span = Some((start, index + char.len_utf8()));
+    }
+    span
+}
+
+/// The fields searched, each with its weight.
+fn fields(video: &Video) -> [(String, u32); 4] {
+    [
+        (video.title.as_deref().unwrap_or_default().to_lowercase(), 3),
+        (video.filename.to_lowercase(), 2),
+        (video.tags.as_deref().unwrap_or_default().to_lowercase(), 1),
+        (video.dir().to_lowercase(), 1)
+    ]
+}
+
+/// The score of `video` for all of `terms`, or `None` unless every term
+/// matches one of its fields.
+fn score(video: &Video, terms: &[String]) -> Option<u32> {
+    let fields = fields(video);
+    terms.iter().try_fold(0, |total, term| {
+        let best = fields.iter()
+            // Tags are several values, matching within one of them is what
+            // counts
+            .flat_map(|(field, weight)| field.split('\n').map(move |value| field_score(value, term) * weight))
+            .max()
+            .filter(|&best| best > 0)?;
+        Some(total + best)
+    })
+}
+
+#[derive(serde::Serialize)]
+struct Result {
+    #[serde(flatten)]
+    video: Video,
+    score: u32,
+    thumb: String
+}
+
+/// Searches titles, filenames, tags and folders of the indexed videos, best
+/// matches first.
+pub async fn serve_search(
+    extract::Query(query): extract::Query<SearchQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    let terms: Vec<String> = query.q.split_whitespace().map(str::to_lowercase).collect();
+    if terms.is_empty() {
+        return ApiError::new(Code::BadRequest, "Missing search terms").into();
+    }
+
+    let videos = match app.index.all() {
+        Ok(videos) => videos,
+        Err(err) => return ApiError::database("Failed to search", err).into()
+    };
+
+    let mut results: Vec<_> = videos.into_iter()
+        .filter(|video| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, &video.path)))
+        .filter_map(|video| Some((score(&video, &terms)?, video)))
+        .collect();
+    // Ties keep the order of the index, by path
+    results.sort_by(|(a, _), (b, _)| b.cmp(a));
+    results.truncate(query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT));
+
+    let results: Vec<_> = results.into_iter().map(|(score, video)| Result {
+        thumb: url::path(&app.config, &format!("/thumb/{}", url::encode_path(&video.path))),
+        video,
+        score
+    }).collect();
+    response::IntoResponse::into_response(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(path: &str, title: Option<&str>, tags: Option<&str>) -> Video {
+        Video {
+            path: path.into(),
+            filename: path.rsplit('/').next().unwrap().into(),
+            size: 0,
+            mtime: 0,
+            duration: None,
+            width: None,
+            height: None,
+            video_codec: None,
+            audio_codec: None,
+            title: title.map(Into::into),
+            tags: tags.map(Into::into),
+            year: None,
+            plot: None,
+            poster: None,
+            fanart: None,
+            tmdb: None,
+            added: None,
+            sha256: None,
+            phash: None
+        }
+    }
+
+    fn terms(query: &str) -> Vec<String> {
+        query.split_whitespace().map(str::to_lowercase).collect()
+    }
+
+    #[test]
+    fn substrings() {
+        assert_eq!(field_score("alien", "alien"), 100);
+        assert_eq!(field_score("alien.mkv", "alien"), 80);
+        assert_eq!(field_score("the alien.mkv", "alien"), 60);
+        assert_eq!(field_score("thealien.mkv", "alien"), 40);
+    }
+
+    #[test]
+    fn fuzzy() {
+        assert_eq!(field_score("blade runner", "bldrnr"), 14);
+        assert_eq!(field_score("blade runner", "rb"), 0);
+        assert_eq!(field_score("blade runner", "zzz"), 0);
+        // Letters need to be in order
+        assert_eq!(field_score("blade runner", "rnrbld"), 0);
+    }
+
+    #[test]
+    fn ranking() {
+        let titled = video("movies/x.mkv", Some("Alien"), None);
+        let named = video("movies/Alien.mkv", None, None);
+        let tagged = video("movies/y.mkv", None, Some("Sci-Fi\nAliens"));
+        let query = terms("ALIEN");
+        assert!(score(&titled, &query) > score(&named, &query));
+        assert!(score(&named, &query) > score(&tagged, &query));
+        assert_eq!(score(&video("movies/z.mkv", None, None), &query), None);
+    }
+
+    #[test]
+    fn every_term_matches() {
+        let episode = video("shows/Lost/Season 1/pilot.mkv", None, None);
+        assert!(score(&episode, &terms("lost pilot")).is_some());
+        assert_eq!(score(&episode, &terms("lost finale")), None);
+    }
+}
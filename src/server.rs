@@ -0,0 +1,413 @@
+use std::net::{IpAddr, SocketAddr};
+use std::{fs, io};
+use std::path::Path;
+#[cfg(unix)]
+use std::pin::pin;
+use std::process;
+use std::time::Duration;
+
+use axum::{http, response, Router};
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{signal, time};
+use tokio_util::sync::CancellationToken;
+#[cfg(unix)]
+use tokio_util::task::TaskTracker;
+
+use crate::error::{ApiError, Code};
+use crate::{forwarded, Config};
+
+/// The connection preface every HTTP/2 client starts with.
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n";
+
+/// Turns away cleartext HTTP/2 (h2c) connections. hyper picks the protocol
+/// from the first bytes it reads, so they have to be checked before it gets
+/// the connection.
+#[derive(Clone, Copy)]
+struct Http1Only;
+
+impl<S: Send + 'static> Accept<TcpStream, S> for Http1Only {
+    type Stream = TcpStream;
+    type Service = S;
+    type Future = BoxFuture<'static, io::Result<(TcpStream, S)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        Box::pin(async move {
+            let mut preface = [0; H2_PREFACE.len()];
+            loop {
+                let read = stream.peek(&mut preface).await?;
+                if !H2_PREFACE.starts_with(&preface[..read]) {
+                    return Ok((stream, service));
+                }
+                if read == 0 || read == preface.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP/2 without TLS is disabled"));
+                }
+
+                // Peeking doesn't wait for more data than what already arrived
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+    }
+}
+
+enum Listen {
+    Tcp(SocketAddr),
+    Unix(&'static Path)
+}
+
+/// The addresses in `listen`, each either `ip:port` or `unix:/path/to/socket`,
+/// or `ip` and `port` when it's empty.
+fn addresses(config: &'static Config) -> Vec<Listen> {
+    if config.listen.is_empty() {
+        return vec![Listen::Tcp(SocketAddr::from((config.ip, config.port)))];
+    }
+
+    config.listen.iter().map(|listen| match listen.strip_prefix("unix:") {
+        Some(path) => Listen::Unix(Path::new(path)),
+        None => match listen.parse() {
+            Ok(addr) => Listen::Tcp(addr),
+            Err(err) => {
+                tracing::error!(error = %err, "Invalid listen address `{listen}`");
+                process::exit(1);
+            }
+        }
+    }).collect()
+}
+
+/// Where devices on the network reach the server at `ip`, an address of this
+/// machine, if any TCP address it listens on covers it.
+pub fn reachable(config: &Config, ip: IpAddr) -> Option<SocketAddr> {
+    let addresses: Vec<SocketAddr> = match &*config.listen {
+        [] => vec![SocketAddr::from((config.ip, config.port))],
+        listen => listen.iter().filter_map(|listen| listen.parse().ok()).collect()
+    };
+    // `[::]` takes IPv4 connections too, unless IPv4 addresses are listed
+    let v6_only = addresses.iter().any(SocketAddr::is_ipv4);
+    addresses.iter()
+        .find(|addr| match addr.ip() {
+            bound if bound.is_unspecified() => bound.is_ipv4() == ip.is_ipv4() || (bound.is_ipv6() && !v6_only),
+            bound => bound == ip
+        })
+        .map(|addr| SocketAddr::new(ip, addr.port()))
+}
+
+/// Creates a socket bound to `addr`. Linux makes IPv6 sockets accept IPv4 as
+/// well by default, which takes the port from an IPv4 socket bound next to
+/// it, so `v6_only` turns that off.
+fn bind(addr: SocketAddr, kind: socket2::Type, v6_only: bool) -> io::Result<socket2::Socket> {
+    let protocol = if kind == socket2::Type::STREAM { socket2::Protocol::TCP } else { socket2::Protocol::UDP };
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    if kind == socket2::Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+fn bind_tcp(addr: SocketAddr, v6_only: bool) -> io::Result<std::net::TcpListener> {
+    let socket = bind(addr, socket2::Type::STREAM, v6_only)?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Serves `app` on every configured address until the process exits, over
+/// HTTPS when a certificate is configured.
+pub async fn run(config: &'static Config, app: Router) {
+    let addresses = addresses(config);
+    let v6_only = addresses.iter().any(|listen| matches!(listen, Listen::Tcp(addr) if addr.is_ipv4()));
+
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            match RustlsConfig::from_pem_file(cert, key).await {
+                Ok(tls) => Some((tls, &**cert, &**key)),
+                Err(err) => {
+                    tracing::error!(error = %err, "Failed to load TLS certificate");
+                    process::exit(1);
+                }
+            }
+        }
+        _ => {
+            if config.http3 {
+                tracing::error!("HTTP/3 requires `tls_cert` and `tls_key`");
+            }
+            None
+        }
+    };
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn(wait_for_signal(shutdown.clone()));
+
+    let servers = addresses.into_iter().map(|listen| match listen {
+        Listen::Tcp(addr) => tokio::spawn(serve_tcp(config, addr, v6_only, tls.clone(), app.clone(), shutdown.clone())),
+        Listen::Unix(path) => tokio::spawn(serve_unix(config, path, app.clone(), shutdown.clone()))
+    });
+    futures_util::future::join_all(servers).await;
+}
+
+/// Cancels `shutdown` on Ctrl-C or SIGTERM, which is what systemd sends.
+async fn wait_for_signal(shutdown: CancellationToken) {
+    #[cfg(unix)]
+    {
+        let Ok(mut terminate) = signal::unix::signal(signal::unix::SignalKind::terminate()) else {
+            return;
+        };
+        tokio::select! {
+            _ = signal::ctrl_c() => {},
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
+
+    tracing::info!("Shutting down, waiting for requests to finish");
+    shutdown.cancel();
+}
+
+async fn serve_tcp(
+    config: &'static Config,
+    addr: SocketAddr,
+    v6_only: bool,
+    tls: Option<(RustlsConfig, &'static Path, &'static Path)>,
+    app: Router,
+    shutdown: CancellationToken
+) {
+    let handle = axum_server::Handle::new();
+    let server = match bind_tcp(addr, v6_only) {
+        Ok(listener) => axum_server::from_tcp(listener).handle(handle.clone()),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to bind socket {addr}");
+            process::exit(1);
+        }
+    };
+
+    // Stops accepting connections, and lets the open ones finish for up to
+    // `shutdown_timeout` seconds
+    let drain = Duration::from_secs(config.shutdown_timeout);
+    tokio::spawn(shutdown.clone().cancelled_owned().map(move |()| handle.graceful_shutdown(Some(drain))));
+
+    let Some((tls, cert, key)) = tls else {
+        // Browsers only speak HTTP/2 over TLS, cleartext HTTP/2 is for reverse
+        // proxies and other clients that know to expect it
+        tracing::info!("Server listening on {addr}");
+        let result = if config.h2c {
+            server.serve(app.into_make_service_with_connect_info::<SocketAddr>()).await
+        } else {
+            server.acceptor(Http1Only).serve(app.into_make_service_with_connect_info::<SocketAddr>()).await
+        };
+        if let Err(err) = result {
+            tracing::error!(error = %err, "Failed to start server");
+            process::exit(1);
+        }
+        return;
+    };
+
+    if let Some(redirect_port) = config.redirect_port {
+        tokio::spawn(redirect_http(SocketAddr::new(addr.ip(), redirect_port), v6_only, addr.port(), shutdown.clone()));
+    }
+
+    let app = if config.http3 { with_http3(config, addr, v6_only, cert, key, app, shutdown) } else { app };
+
+    // HTTP/2 is negotiated over ALPN, falling back to HTTP/1.1
+    tracing::info!("Server listening on https://{addr}");
+    if let Err(err) = server.acceptor(RustlsAcceptor::new(tls)).serve(app.into_make_service_with_connect_info::<SocketAddr>()).await {
+        tracing::error!(error = %err, "Failed to start server");
+        process::exit(1);
+    }
+}
+
+/// Serves `app` over the Unix socket at `path`, for sitting behind a reverse
+/// proxy on the same machine. TLS is left to the proxy.
+#[cfg(unix)]
+async fn serve_unix(config: &'static Config, path: &'static Path, app: Router, shutdown: CancellationToken) {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // A socket left behind by a server that didn't shut down cleanly would
+    // make binding fail
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        let _ = fs::remove_file(path);
+    }
+
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to bind socket `{}`", path.display());
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = fs::set_permissions(path, fs::Permissions::from_mode(config.socket_mode)) {
+        tracing::error!(error = %err, "Failed to set permissions of `{}`", path.display());
+    }
+
+    tracing::info!("Server listening on unix:{}", path.display());
+    let app = app.layer(axum::Extension(forwarded::UnixSocket));
+    let connections = TaskTracker::new();
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    tracing::error!(error = %err, "Failed to accept connection");
+                    time::sleep(Duration::from_millis(50)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break
+        };
+
+        // Errors are clients going away, nothing to be done about them.
+        // Shutting down lets the response in flight finish, then closes the
+        // connection.
+        let io = TokioIo::new(stream);
+        let service = TowerToHyperService::new(app.clone());
+        let shutdown = shutdown.clone();
+        if config.h2c {
+            connections.spawn(async move {
+                let builder = auto::Builder::new(TokioExecutor::new());
+                let mut connection = pin!(builder.serve_connection_with_upgrades(io, service));
+                tokio::select! {
+                    _ = connection.as_mut() => return,
+                    _ = shutdown.cancelled() => connection.as_mut().graceful_shutdown()
+                }
+                let _ = connection.await;
+            });
+        } else {
+            connections.spawn(async move {
+                let mut connection = pin!(http1::Builder::new().serve_connection(io, service).with_upgrades());
+                tokio::select! {
+                    _ = connection.as_mut() => return,
+                    _ = shutdown.cancelled() => connection.as_mut().graceful_shutdown()
+                }
+                let _ = connection.await;
+            });
+        }
+    }
+
+    drop(listener);
+    let _ = fs::remove_file(path);
+    connections.close();
+    let _ = time::timeout(Duration::from_secs(config.shutdown_timeout), connections.wait()).await;
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_: &'static Config, _: &'static Path, _: Router, _: CancellationToken) {
+    tracing::error!("Unix sockets aren't supported on this platform");
+    process::exit(1);
+}
+
+/// Starts the HTTP/3 listener on the same port over UDP, and returns `app`
+/// advertising it to clients connecting over TCP.
+#[cfg(feature = "http3")]
+fn with_http3(
+    config: &'static Config,
+    addr: SocketAddr,
+    v6_only: bool,
+    cert: &'static Path,
+    key: &'static Path,
+    app: Router,
+    shutdown: CancellationToken
+) -> Router {
+    match bind(addr, socket2::Type::DGRAM, v6_only) {
+        Ok(socket) => {
+            let drain = Duration::from_secs(config.shutdown_timeout);
+            tokio::spawn(crate::http3::run(socket.into(), cert, key, app.clone(), shutdown, drain));
+        }
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to bind HTTP/3 socket {addr}");
+            return app;
+        }
+    }
+
+    let alt_svc = http::HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", addr.port())).unwrap();
+    app.layer(axum::middleware::map_response(move |mut response: response::Response| {
+        response.headers_mut().insert(http::header::ALT_SVC, alt_svc.clone());
+        async { response }
+    }))
+}
+
+#[cfg(not(feature = "http3"))]
+fn with_http3(
+    _: &Config,
+    _: SocketAddr,
+    _: bool,
+    _: &Path,
+    _: &Path,
+    app: Router,
+    _: CancellationToken
+) -> Router {
+    tracing::error!("HTTP/3 is enabled, but ninja was built without the `http3` feature");
+    app
+}
+
+/// Redirects plain HTTP requests on `addr` to the HTTPS server on `https_port`.
+async fn redirect_http(addr: SocketAddr, v6_only: bool, https_port: u16, shutdown: CancellationToken) {
+    let redirect = move |uri: http::Uri, headers: http::HeaderMap| async move {
+        let host = headers.get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<http::uri::Authority>().ok());
+        let Some(host) = host else {
+            return ApiError::new(Code::BadRequest, "Missing Host header").into();
+        };
+
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        let location = match https_port {
+            443 => format!("https://{}{path}", host.host()),
+            port => format!("https://{}:{port}{path}", host.host())
+        };
+
+        response::Response::builder()
+            .status(http::StatusCode::PERMANENT_REDIRECT)
+            .header(http::header::LOCATION, location)
+            .body(axum::body::Body::empty())
+            .unwrap()
+    };
+
+    let listener = match bind_tcp(addr, v6_only).and_then(tokio::net::TcpListener::from_std) {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to bind redirect socket");
+            return;
+        }
+    };
+    tracing::info!("Redirecting http://{addr} to HTTPS");
+    let server = axum::serve(listener, Router::new().fallback(redirect));
+    if let Err(err) = server.with_graceful_shutdown(shutdown.cancelled_owned()).await {
+        tracing::error!(error = %err, "Failed to start redirect server");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reachable_addresses() {
+        let lan: IpAddr = [192, 168, 1, 2].into();
+        let mut config = Config { port: 8080, ..Config::default() };
+        assert_eq!(reachable(&config, lan), Some(SocketAddr::new(lan, 8080)));
+
+        config.listen = ["127.0.0.1:3000".into(), "unix:/run/ninja.sock".into()].into();
+        assert_eq!(reachable(&config, lan), None);
+        config.listen = ["127.0.0.1:3000".into(), "192.168.1.2:4000".into()].into();
+        assert_eq!(reachable(&config, lan), Some(SocketAddr::new(lan, 4000)));
+        config.listen = ["[::]:5000".into()].into();
+        assert_eq!(reachable(&config, lan), Some(SocketAddr::new(lan, 5000)));
+        // Listing an IPv4 address makes `[::]` IPv6 only
+        config.listen = ["[::]:5000".into(), "127.0.0.1:3000".into()].into();
+        assert_eq!(reachable(&config, lan), None);
+    }
+}
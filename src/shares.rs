@@ -0,0 +1,180 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use axum::{extract, http, response, Json};
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::Sha256;
+
+use crate::error::{ApiError, Code};
+use crate::users::User;
+use crate::{encoding, jail, unix_now, url, App};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS secrets (
+        name TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS shares (
+        id INTEGER PRIMARY KEY,
+        video TEXT NOT NULL,
+        expires INTEGER NOT NULL,
+        max_uses INTEGER,
+        uses INTEGER NOT NULL DEFAULT 0
+    );
+";
+
+/// Default lifetime of a share, 48 hours.
+const DEFAULT_LIFETIME: u64 = 48 * 3600;
+
+/// Longest lifetime of a share, 10 years.
+const MAX_LIFETIME: u64 = 10 * 365 * 86400;
+
+/// When a share living `lifetime` seconds from now expires, unless that's
+/// longer than allowed.
+pub fn expiry(lifetime: u64) -> Option<u64> {
+    unix_now().checked_add(lifetime).filter(|_| lifetime <= MAX_LIFETIME)
+}
+
+/// Links to a single video that work without logging in, until they expire
+/// or have been used `max_uses` times. Tokens are signed, so that they can't
+/// be forged for other videos or extended, and the signing key persists in
+/// the database so that restarts don't invalidate them.
+pub struct Shares {
+    conn: Mutex<Connection>,
+    secret: Vec<u8>
+}
+
+impl Shares {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        let mut secret = [0; 32];
+        OsRng.fill_bytes(&mut secret);
+        conn.execute("INSERT OR IGNORE INTO secrets (name, value) VALUES ('share', ?)", [&secret[..]])?;
+        let secret = conn.query_row("SELECT value FROM secrets WHERE name = 'share'", [], |row| row.get(0))?;
+
+        Ok(Shares { conn: Mutex::new(conn), secret })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    fn mac(&self, id: i64, expires: u64, video: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(format!("{id}:{expires}:{video}").as_bytes());
+        mac
+    }
+
+    /// Creates a share of `video` expiring at `expires` and returns its
+    /// token.
+    pub fn create(&self, video: &str, expires: u64, max_uses: Option<u32>) -> rusqlite::Result<String> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM shares WHERE expires <= ?", [unix_now()])?;
+        conn.execute(
+            "INSERT INTO shares (video, expires, max_uses) VALUES (?1, ?2, ?3)",
+            params![video, expires, max_uses]
+        )?;
+        let id = conn.last_insert_rowid();
+
+        let signature = encoding::hex(&self.mac(id, expires, video).finalize().into_bytes());
+        Ok(format!("{id}-{expires}-{signature}"))
+    }
+
+    /// Whether `token` grants access to `video`. `start` marks the request
+    /// that starts playing it, which counts as a use, while the requests that
+    /// follow (ranges, playlists, segments) are free.
+    pub fn verify(&self, token: &str, video: &str, start: bool) -> bool {
+        let mut parts = token.splitn(3, '-');
+        let (Some(id), Some(expires), Some(signature)) = (parts.next(), parts.next(), parts.next()) else {
+            return false;
+        };
+        let (Ok(id), Ok(expires)) = (id.parse::<i64>(), expires.parse::<u64>()) else {
+            return false;
+        };
+        let Some(signature) = encoding::decode_hex(signature) else {
+            return false;
+        };
+
+        if self.mac(id, expires, video).verify_slice(&signature).is_err() || expires <= unix_now() {
+            return false;
+        }
+
+        let conn = self.conn();
+        let share = conn.query_row(
+            "SELECT max_uses, uses FROM shares WHERE id = ?",
+            [id],
+            |row| Ok((row.get::<_, Option<u32>>(0)?, row.get::<_, u32>(1)?))
+        ).optional();
+
+        match share {
+            Ok(Some((Some(max_uses), uses))) if start => {
+                uses < max_uses && conn.execute("UPDATE shares SET uses = uses + 1 WHERE id = ?", [id]).is_ok()
+            }
+            Ok(Some(_)) => true,
+            Ok(None) => false,
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to look up share {id}");
+                false
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+pub struct ShareRequest {
+    /// Lifetime in seconds.
+    expires_in: Option<u64>,
+    max_uses: Option<u32>
+}
+
+pub async fn create_share(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    request: Option<Json<ShareRequest>>
+) -> response::Response {
+    if let Err(err) = jail::video(&app.config, &*video).await {
+        return err.into_response(Code::VideoNotFound, "Video not found");
+    }
+
+    let Json(request) = request.unwrap_or_default();
+    let Some(expires) = expiry(request.expires_in.unwrap_or(DEFAULT_LIFETIME)) else {
+        return ApiError::new(Code::BadRequest, "Shares can't last more than 10 years").into();
+    };
+    let token = match app.shares.create(&video, expires, request.max_uses) {
+        Ok(token) => token,
+        Err(err) => return ApiError::database("Failed to create share", err).into()
+    };
+
+    if let Some(extract::Extension(user)) = user {
+        tracing::info!("User `{}` shared `{video}` until {expires}", user.name);
+    }
+
+    let video = url::encode_component(&video);
+    let mut response = response::IntoResponse::into_response(Json(serde_json::json!({
+        "url": url::path(&app.config, &format!("/video/{video}?share={token}")),
+        "hls": url::path(&app.config, &format!("/hls/{video}/master.m3u8?share={token}")),
+        "expires": expires
+    })));
+    *response.status_mut() = http::StatusCode::CREATED;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifetimes() {
+        let now = unix_now();
+        assert!(expiry(DEFAULT_LIFETIME).is_some_and(|expires| expires >= now + DEFAULT_LIFETIME));
+        assert!(expiry(MAX_LIFETIME).is_some());
+        assert_eq!(expiry(MAX_LIFETIME + 1), None);
+        assert_eq!(expiry(u64::MAX), None);
+    }
+}
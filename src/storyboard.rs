@@ -0,0 +1,126 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use axum::{extract, http, response};
+use tokio::{fs, process::Command};
+
+use crate::error::{ApiError, Code};
+use crate::{cache, jail, library, probe, App};
+
+/// Width of a single tile in the sprite sheet.
+const TILE_WIDTH: u32 = 160;
+
+/// Number of tiles per row in the sprite sheet.
+const COLUMNS: u32 = 10;
+
+fn timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+pub fn cache_paths(app: &App, relative: &str) -> (PathBuf, PathBuf) {
+    let dir = app.config.cache_path.join("storyboards");
+    (dir.join(format!("{relative}.jpg")), dir.join(format!("{relative}.vtt")))
+}
+
+/// Generates the sprite sheet and its WebVTT track for `relative`, reusing the
+/// cached pair as long as it is newer than the video.
+async fn generate(app: &App, relative: &str) -> Option<(PathBuf, PathBuf)> {
+    let video_path = library::file(&app.config, relative)?;
+    let (sprite_path, vtt_path) = cache_paths(app, relative);
+
+    let video_mtime = fs::metadata(&video_path).await.ok()?.modified().ok()?;
+    if cache::is_fresh(&sprite_path, video_mtime).await && cache::is_fresh(&vtt_path, video_mtime).await {
+        return Some((sprite_path, vtt_path));
+    }
+
+    let summary = probe::summary(&app.config, &video_path).await?;
+    if summary.width == 0 || summary.height == 0 {
+        return None;
+    }
+
+    let interval = app.config.storyboard_interval as f64;
+    let tiles = u32::max(1, (summary.duration / interval).ceil() as u32);
+    let columns = u32::min(tiles, COLUMNS);
+    let rows = tiles.div_ceil(columns);
+    let tile_height = (TILE_WIDTH * summary.height / summary.width + 1) & !1;
+
+    // Only keyframes are decoded, which is far faster than decoding the whole
+    // video and precise enough for seek previews.
+    let mut command = Command::new(&*app.config.ffmpeg_command);
+    command
+        .args(["-v", "error", "-skip_frame", "nokey"])
+        .args(app.config.hwaccel.decode_args())
+        .args([
+            "-i", video_path.to_str()?,
+            "-vf", &format!("fps=1/{interval},scale={TILE_WIDTH}:{tile_height},tile={columns}x{rows}"),
+            "-frames:v", "1",
+            "-f", "image2pipe",
+            "-vcodec", "mjpeg",
+            "-"
+        ]);
+
+    let sprite = match app.ffmpeg.output(&mut command).await {
+        Ok(sprite) => sprite,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to generate storyboard for `{relative}`");
+            return None;
+        }
+    };
+
+    let mut vtt = String::from("WEBVTT\n");
+    for tile in 0..tiles {
+        let start = tile as f64 * interval;
+        let end = f64::min(start + interval, summary.duration);
+        let x = tile % columns * TILE_WIDTH;
+        let y = tile / columns * tile_height;
+        write!(vtt, "\n{} --> {}\nsprite.jpg#xywh={x},{y},{TILE_WIDTH},{tile_height}\n", timestamp(start), timestamp(end)).unwrap();
+    }
+
+    for (path, data) in [(&sprite_path, &sprite[..]), (&vtt_path, vtt.as_bytes())] {
+        if let Err(err) = cache::write_atomic(path, data).await {
+            tracing::error!(error = %err, "Failed to cache storyboard `{}`", path.display());
+            return None;
+        }
+    }
+
+    Some((sprite_path, vtt_path))
+}
+
+async fn serve(app: &App, relative: &str, sprite: bool) -> response::Response {
+    if let Err(err) = jail::video(&app.config, relative).await {
+        return err.into_response(Code::StoryboardNotFound, "Storyboard not found");
+    }
+
+    let (path, content_type) = match generate(app, relative).await {
+        Some((sprite_path, _)) if sprite => (sprite_path, "image/jpeg"),
+        Some((_, vtt_path)) => (vtt_path, "text/vtt"),
+        None => return ApiError::new(Code::StoryboardNotFound, "Storyboard not found").into()
+    };
+
+    match fs::read(&path).await {
+        Ok(data) => response::Response::builder()
+            .status(http::StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, content_type)
+            .body(data.into())
+            .unwrap(),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to read storyboard `{}`", path.display());
+            ApiError::new(Code::InternalError, "Failed to read storyboard").into()
+        }
+    }
+}
+
+pub async fn serve_vtt(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    serve(app, &video, false).await
+}
+
+pub async fn serve_sprite(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    serve(app, &video, true).await
+}
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::body::Body;
+use axum::{extract, http, middleware, response, Json};
+use futures_util::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{ApiError, Code};
+use crate::forwarded::Client;
+use crate::users::User;
+use crate::webhooks::{self, Event};
+use crate::{auth, App};
+
+/// How long a stream stays counted after its last request. Players fetch HLS
+/// segments and byte ranges one after the other, with pauses in between.
+const IDLE: Duration = Duration::from_secs(30);
+
+/// Bandwidth is averaged over windows of this length.
+const WINDOW: Duration = Duration::from_secs(5);
+
+/// Where playback is, as far as the requests tell.
+#[derive(Clone, Copy)]
+enum Position {
+    /// Byte offset into the file, from the ranges of `/video`.
+    Bytes(u64),
+    /// Seconds into the video, from the HLS segments.
+    Seconds(f64)
+}
+
+struct Stream {
+    id: u64,
+    user: Option<Box<str>>,
+    ip: Option<IpAddr>,
+    started: SystemTime,
+    /// Responses still being sent.
+    active: usize,
+    last: Instant,
+    position: Option<Position>,
+    bytes: u64,
+    window_start: Instant,
+    window_bytes: u64,
+    /// Bytes per second over the last full window.
+    bandwidth: u64,
+    /// Cancelled when an administrator ends the stream. Its requests are
+    /// refused from then on, until it has been idle for long enough.
+    kick: CancellationToken
+}
+
+/// A user, or a client IP without accounts, and the video it plays.
+type Key = (Box<str>, Box<str>);
+
+/// Videos being played by each client.
+#[derive(Default)]
+pub struct Streams {
+    next_id: AtomicU64,
+    streams: Mutex<HashMap<Key, Stream>>
+}
+
+/// Marks a response as finished once its body is dropped.
+struct Guard {
+    app: &'static App,
+    key: Key
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(stream) = self.app.streams.streams.lock().unwrap().get_mut(&self.key) {
+            stream.active -= 1;
+            stream.last = Instant::now();
+        }
+    }
+}
+
+impl Guard {
+    /// Counts `sent` bytes of a response that started at `offset`.
+    fn sent(&self, offset: Option<u64>, sent: u64, total: u64) {
+        let mut streams = self.app.streams.streams.lock().unwrap();
+        let Some(stream) = streams.get_mut(&self.key) else {
+            return;
+        };
+
+        let now = Instant::now();
+        stream.last = now;
+        stream.bytes += sent;
+        stream.window_bytes += sent;
+        let elapsed = now.duration_since(stream.window_start);
+        if elapsed >= WINDOW {
+            stream.bandwidth = (stream.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+            stream.window_start = now;
+            stream.window_bytes = 0;
+        }
+        if let Some(offset) = offset {
+            stream.position = Some(Position::Bytes(offset + total));
+        }
+    }
+}
+
+struct Start<'a> {
+    user: Option<&'a User>,
+    ip: Option<IpAddr>,
+    position: Option<Position>,
+    max: usize
+}
+
+enum Refused {
+    TooMany(Vec<Box<str>>),
+    Kicked
+}
+
+impl Streams {
+    /// Starts a response for `video`, along with whether it starts a new
+    /// stream, or returns the videos `client` is already playing if that
+    /// would exceed `max`.
+    fn start(&self, key: &Key, start: Start) -> Result<(CancellationToken, bool), Refused> {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(stream) = streams.get(key) {
+            if stream.kick.is_cancelled() {
+                return Err(Refused::Kicked);
+            }
+        } else if start.max > 0 {
+            let playing: Vec<_> = streams.keys().filter(|(client, _)| *client == key.0).map(|(_, video)| video.clone()).collect();
+            if playing.len() >= start.max {
+                return Err(Refused::TooMany(playing));
+            }
+        }
+
+        let now = Instant::now();
+        let new = !streams.contains_key(key);
+        let stream = streams.entry(key.clone()).or_insert_with(|| Stream {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            user: start.user.map(|user| user.name.clone()),
+            ip: start.ip,
+            started: SystemTime::now(),
+            active: 0,
+            last: now,
+            position: None,
+            bytes: 0,
+            window_start: now,
+            window_bytes: 0,
+            bandwidth: 0,
+            kick: CancellationToken::new()
+        });
+        stream.active += 1;
+        stream.last = now;
+        stream.position = start.position.or(stream.position);
+        Ok((stream.kick.clone(), new))
+    }
+
+    /// Ends the stream `id`, returning whether it exists.
+    fn kick(&self, id: u64) -> bool {
+        let streams = self.streams.lock().unwrap();
+        let stream = streams.values().find(|stream| stream.id == id && !stream.kick.is_cancelled());
+        stream.inspect(|stream| stream.kick.cancel()).is_some()
+    }
+}
+
+/// Forgets the streams that have been idle for too long, announcing that they
+/// stopped.
+fn expire(app: &App) {
+    let expired: Vec<_> = {
+        let mut streams = app.streams.streams.lock().unwrap();
+        let idle: Vec<Key> = streams.iter()
+            .filter(|(_, stream)| stream.active == 0 && stream.last.elapsed() >= IDLE)
+            .map(|(key, _)| key.clone())
+            .collect();
+        idle.into_iter().filter_map(|key| streams.remove_entry(&key)).collect()
+    };
+
+    for ((_, video), stream) in &expired {
+        // Up to the last request, not counting the idle time
+        let duration = stream.started.elapsed().unwrap_or_default().saturating_sub(stream.last.elapsed());
+        webhooks::emit(app, Event::PlaybackStopped {
+            video: video.trim_matches('/'),
+            user: stream.user.as_deref(),
+            ip: stream.ip,
+            duration: duration.as_secs(),
+            bytes: stream.bytes
+        });
+    }
+}
+
+/// Expires idle streams even when no other request comes in to do it.
+pub async fn run(app: &'static App) {
+    loop {
+        tokio::time::sleep(IDLE).await;
+        expire(app);
+    }
+}
+
+/// The first byte asked for by a `Range` header, or the start of the file.
+fn range_start(headers: &http::HeaderMap) -> u64 {
+    headers.get(http::header::RANGE)
+        .and_then(|range| range.to_str().ok()?.trim().strip_prefix("bytes=")?.split(['-', ',']).next()?.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Keeps track of the videos each client plays, and rejects playing more than
+/// `max_streams` at once per user, or per client IP for requests that aren't
+/// logged in.
+pub async fn track(
+    extract::State(app): extract::State<&'static App>,
+    extract::Path(params): extract::Path<HashMap<String, String>>,
+    request: extract::Request,
+    next: middleware::Next
+) -> response::Response {
+    let max = app.config.max_streams;
+    let user = request.extensions().get::<User>();
+    let ip = request.extensions().get::<Client>().and_then(|client| client.ip);
+    let client = user.map(|user| format!("user:{}", user.name)).or(ip.map(|ip| ip.to_string()));
+    let (Some(client), Some(video)) = (client, params.get("video")) else {
+        return next.run(request).await;
+    };
+
+    // Segments tell the position exactly, byte ranges only once the video's
+    // size and duration are known
+    let segment = params.get("segment").and_then(|segment| segment.strip_suffix(".ts")?.parse::<u32>().ok());
+    let is_video = request.extensions().get::<extract::MatchedPath>().is_some_and(|path| path.as_str().ends_with("/video/*video"));
+    let offset = is_video.then(|| range_start(request.headers()));
+    let position = match (segment, offset) {
+        (Some(segment), _) => Some(Position::Seconds(segment as f64 * app.config.segment_duration as f64)),
+        (None, Some(offset)) => Some(Position::Bytes(offset)),
+        (None, None) => None
+    };
+
+    let key: Key = (client.into(), video.as_str().into());
+    let name = user.map(|user| user.name.clone());
+    expire(app);
+    let (kick, new) = match app.streams.start(&key, Start { user, ip, position, max }) {
+        Ok(started) => started,
+        Err(Refused::TooMany(playing)) => {
+            return ApiError::new(Code::TooManyStreams, format!("Too many simultaneous streams, at most {max} are allowed"))
+                .detail("limit", max)
+                .detail("playing", playing)
+                .into();
+        }
+        Err(Refused::Kicked) => return ApiError::new(Code::StreamEnded, "Stream was ended by an administrator").into()
+    };
+
+    let guard = Guard { app, key };
+    let (parts, body) = next.run(request).await.into_parts();
+    // Every new stream counts as a play, for the most played on the home page
+    if new && parts.status.is_success() {
+        if let Err(err) = app.index.count_play(video.trim_matches('/')) {
+            tracing::error!(error = %err, "Failed to count a play of `{video}`");
+        }
+        webhooks::emit(app, Event::PlaybackStarted { video: video.trim_matches('/'), user: name.as_deref(), ip });
+    }
+    let mut total = 0;
+    let body = body.into_data_stream().take_until(kick.cancelled_owned()).map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            total += chunk.len() as u64;
+            guard.sent(offset, chunk.len() as u64, total);
+        }
+        chunk
+    });
+    response::Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[derive(serde::Serialize)]
+struct Session<'a> {
+    id: u64,
+    user: Option<&'a str>,
+    ip: Option<IpAddr>,
+    video: &'a str,
+    started: u64,
+    /// Seconds since the last request.
+    idle: u64,
+    active: usize,
+    /// Estimated playback position in seconds.
+    position: Option<f64>,
+    bytes: u64,
+    /// Bytes per second.
+    bandwidth: u64
+}
+
+fn forbidden() -> response::Response {
+    ApiError::new(Code::Forbidden, "Forbidden").into()
+}
+
+/// Lists the streams being played, for administrators.
+pub async fn list_sessions(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if !auth::is_admin(&app.config, user.as_deref()) {
+        return forbidden();
+    }
+
+    let streams = app.streams.streams.lock().unwrap();
+    let mut sessions: Vec<_> = streams.iter()
+        .filter(|(_, stream)| (stream.active > 0 || stream.last.elapsed() < IDLE) && !stream.kick.is_cancelled())
+        .map(|((_, video), stream)| Session {
+            id: stream.id,
+            user: stream.user.as_deref(),
+            ip: stream.ip,
+            video,
+            started: stream.started.duration_since(UNIX_EPOCH).map_or(0, |started| started.as_secs()),
+            idle: if stream.active > 0 { 0 } else { stream.last.elapsed().as_secs() },
+            active: stream.active,
+            position: match stream.position {
+                Some(Position::Seconds(seconds)) => Some(seconds),
+                Some(Position::Bytes(offset)) => app.index.get(video).ok().flatten()
+                    .and_then(|video| Some(offset as f64 / video.size.max(1) as f64 * video.duration?))
+                    .map(|position| position.round()),
+                None => None
+            },
+            bytes: stream.bytes,
+            bandwidth: stream.bandwidth
+        })
+        .collect();
+    sessions.sort_by_key(|session| session.id);
+
+    response::IntoResponse::into_response(Json(sessions))
+}
+
+/// Ends a stream, for administrators.
+pub async fn delete_session(
+    extract::Path((id, )): extract::Path<(u64, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if !auth::is_admin(&app.config, user.as_deref()) {
+        return forbidden();
+    }
+
+    if !app.streams.kick(id) {
+        return ApiError::new(Code::SessionNotFound, "Session not found").into();
+    }
+
+    response::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+}
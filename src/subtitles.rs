@@ -0,0 +1,177 @@
+use std::path::Path;
+
+use axum::{extract, http, response, Json};
+use tokio::process::Command;
+
+use crate::error::{ApiError, Code};
+use crate::{ffmpeg, jail, probe, App, Config};
+
+/// Image based subtitle codecs, which can't be converted to WebVTT.
+pub const BITMAP_CODECS: &[&str] = &["hdmv_pgs_subtitle", "dvd_subtitle", "dvb_subtitle", "xsub"];
+
+/// Extensions of subtitle files that are picked up next to videos.
+const SIDECAR_EXTENSIONS: &[&str] = &["srt", "vtt", "ass", "ssa"];
+
+#[derive(serde::Deserialize)]
+pub struct SubtitleQuery {
+    track: Option<u32>,
+    file: Option<Box<str>>
+}
+
+/// A subtitle file next to a video, named either `movie.srt` or with a
+/// language suffix like `movie.en.srt`.
+#[derive(serde::Serialize)]
+pub struct Sidecar {
+    pub file: Box<str>,
+    pub language: Option<Box<str>>,
+    pub format: Box<str>
+}
+
+impl Sidecar {
+    pub fn matching(video_stem: &str, filename: &str) -> Option<Sidecar> {
+        let (name, extension) = filename.rsplit_once('.')?;
+        let format = SIDECAR_EXTENSIONS.iter().find(|format| format.eq_ignore_ascii_case(extension))?;
+
+        let language = if name == video_stem {
+            None
+        } else {
+            let suffix = name.strip_prefix(video_stem)?.strip_prefix('.')?;
+            Some(suffix.split('.').next()?.into())
+        };
+
+        Some(Sidecar { file: filename.into(), language, format: (*format).into() })
+    }
+}
+
+pub fn is_sidecar(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| SIDECAR_EXTENSIONS.iter().any(|format| format.eq_ignore_ascii_case(extension)))
+}
+
+/// Names of the files in `dir`, for matching sidecars against several videos
+/// without reading the directory again.
+pub async fn filenames(dir: &Path) -> Vec<Box<str>> {
+    let mut filenames = Vec::new();
+    if let Ok(mut entries) = tokio::fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if let Some(filename) = entry.file_name().to_str() {
+                filenames.push(filename.into());
+            }
+        }
+    }
+    filenames.sort();
+    filenames
+}
+
+pub fn sidecars(video: &Path, filenames: &[Box<str>]) -> Vec<Sidecar> {
+    let Some(stem) = video.file_stem().and_then(|stem| stem.to_str()) else {
+        return Vec::new();
+    };
+
+    filenames.iter().filter_map(|filename| Sidecar::matching(stem, filename)).collect()
+}
+
+#[derive(serde::Serialize)]
+struct Track {
+    track: u32,
+    codec: Option<Box<str>>,
+    language: Option<Box<str>>,
+    title: Option<Box<str>>,
+    text: bool
+}
+
+fn vtt(body: Vec<u8>) -> response::Response {
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "text/vtt; charset=utf-8")
+        .body(body.into())
+        .unwrap()
+}
+
+async fn list(config: &Config, video_path: &Path) -> response::Response {
+    let Some(info) = probe::info(config, video_path).await else {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into();
+    };
+
+    let embedded: Vec<_> = info.subtitle.into_iter().enumerate().map(|(track, stream)| Track {
+        track: track as u32,
+        text: stream.codec.as_deref().is_none_or(|codec| !BITMAP_CODECS.contains(&codec)),
+        codec: stream.codec,
+        language: stream.language,
+        title: stream.title
+    }).collect();
+
+    let filenames = filenames(video_path.parent().unwrap()).await;
+    let sidecar = sidecars(video_path, &filenames);
+
+    response::IntoResponse::into_response(Json(serde_json::json!({
+        "embedded": embedded,
+        "sidecar": sidecar
+    })))
+}
+
+async fn extract_track(app: &App, video_path: &Path, track: u32) -> response::Response {
+    if !matches!(tokio::fs::try_exists(video_path).await, Ok(true)) {
+        return ApiError::new(Code::VideoNotFound, "Video not found").into();
+    }
+
+    match app.ffmpeg.output(Command::new(&*app.config.ffmpeg_command).args([
+        "-v", "error",
+        "-i", video_path.to_str().unwrap(),
+        "-map", &format!("0:s:{track}"),
+        "-f", "webvtt",
+        "-"
+    ])).await {
+        Ok(body) => vtt(body),
+        Err(err @ ffmpeg::Error::Busy) => err.into_response("Server is busy"),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to extract subtitle track {track}");
+            ApiError::new(Code::SubtitlesUnconvertible, "Failed to extract subtitles").into()
+        }
+    }
+}
+
+async fn serve_sidecar(app: &App, video_path: &Path, file: &str) -> response::Response {
+    let filenames = filenames(video_path.parent().unwrap()).await;
+    let Some(sidecar) = sidecars(video_path, &filenames).into_iter().find(|sidecar| &*sidecar.file == file) else {
+        return ApiError::new(Code::SubtitlesNotFound, "Subtitles not found").into();
+    };
+
+    let path = video_path.with_file_name(&*sidecar.file);
+    let converted = if &*sidecar.format == "vtt" {
+        tokio::fs::read(&path).await.map_err(ffmpeg::Error::Spawn)
+    } else {
+        app.ffmpeg.output(Command::new(&*app.config.ffmpeg_command).args([
+            "-v", "error",
+            "-i", path.to_str().unwrap(),
+            "-f", "webvtt",
+            "-"
+        ])).await
+    };
+
+    match converted {
+        Ok(body) => vtt(body),
+        Err(err @ ffmpeg::Error::Busy) => err.into_response("Server is busy"),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to convert subtitles `{}`", path.display());
+            ApiError::new(Code::SubtitlesUnconvertible, "Failed to convert subtitles").into()
+        }
+    }
+}
+
+pub async fn serve_subtitles(
+    extract::Path((video, )): extract::Path<(Box<Path>, )>,
+    extract::Query(params): extract::Query<SubtitleQuery>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    let video_path = match jail::video(&app.config, video).await {
+        Ok(path) => path,
+        Err(err) => return err.into_response(Code::VideoNotFound, "Video not found")
+    };
+    match (params.track, params.file) {
+        (Some(track), _) => extract_track(app, &video_path, track).await,
+        (None, Some(file)) => serve_sidecar(app, &video_path, &file).await,
+        (None, None) => list(&app.config, &video_path).await
+    }
+}
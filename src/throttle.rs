@@ -0,0 +1,34 @@
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::{extract, response};
+use futures_util::{stream, StreamExt};
+use tokio::time;
+
+use crate::Config;
+
+/// Paces response bodies to `max_stream_bitrate` kbit/s, so that a single
+/// client downloading as fast as it can doesn't starve everyone else. The
+/// first second's worth is sent right away, so that playback starts without
+/// delay.
+pub async fn throttle(
+    extract::State(config): extract::State<&'static Config>,
+    response: response::Response
+) -> response::Response {
+    if config.max_stream_bitrate == 0 {
+        return response;
+    }
+
+    let rate = config.max_stream_bitrate * 1000 / 8;
+    let start = Instant::now();
+    let (parts, body) = response.into_parts();
+    let body = stream::unfold((body.into_data_stream(), 0), move |(mut body, sent)| async move {
+        let chunk = body.next().await?;
+        let sent = sent + chunk.as_ref().map_or(0, |chunk| chunk.len() as u64);
+        let due = start + Duration::from_secs_f64(sent.saturating_sub(rate) as f64 / rate as f64);
+        time::sleep_until(due.into()).await;
+        Some((chunk, (body, sent)))
+    });
+
+    response::Response::from_parts(parts, Body::from_stream(body))
+}
@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+
+use axum::{extract, http, response};
+use tokio::{fs, process::Command};
+
+use crate::error::{ApiError, Code};
+use crate::{cache, conditional, jail, library, probe, App};
+
+/// Posters are taken at this fraction of the video's duration, which skips
+/// past most intros and black leaders.
+const POSTER_POSITION: f64 = 0.1;
+
+pub fn cache_path(app: &App, relative: &str) -> PathBuf {
+    app.config.cache_path.join("thumbs").join(format!("{relative}.jpg"))
+}
+
+/// Returns the cached poster of `relative`, generating it when it is missing or
+/// older than the video itself.
+pub async fn poster(app: &App, relative: &str) -> Option<PathBuf> {
+    let video_path = library::file(&app.config, relative)?;
+    let thumb_path = cache_path(app, relative);
+
+    let video_mtime = fs::metadata(&video_path).await.ok()?.modified().ok()?;
+    if cache::is_fresh(&thumb_path, video_mtime).await {
+        return Some(thumb_path);
+    }
+
+    let duration = match app.index.get(relative) {
+        Ok(Some(video)) if video.duration.is_some() => video.duration,
+        _ => probe::summary(&app.config, &video_path).await.map(|summary| summary.duration)
+    };
+    let position = duration.unwrap_or(0.0) * POSTER_POSITION;
+
+    let mut command = Command::new(&*app.config.ffmpeg_command);
+    command
+        .args(["-v", "error"])
+        .args(app.config.hwaccel.decode_args())
+        .args([
+            "-ss", &format!("{position:.3}"),
+            "-i", video_path.to_str()?,
+            "-vframes", "1",
+            "-vf", &format!("scale=-2:{}", app.config.thumbnail_height),
+            "-f", "image2pipe",
+            "-vcodec", "mjpeg",
+            "-"
+        ]);
+
+    let image = match app.ffmpeg.output(&mut command).await {
+        Ok(image) => image,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to generate poster for `{relative}`");
+            return None;
+        }
+    };
+
+    if let Err(err) = cache::write_atomic(&thumb_path, &image).await {
+        tracing::error!(error = %err, "Failed to cache poster `{}`", thumb_path.display());
+        return None;
+    }
+
+    Some(thumb_path)
+}
+
+async fn read(path: &Path) -> Option<(Vec<u8>, conditional::Validators)> {
+    let metadata = fs::metadata(path).await.ok()?;
+    let image = fs::read(path).await.ok()?;
+    Some((image, conditional::Validators::new(&metadata)))
+}
+
+pub async fn serve_thumb(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    headers: http::HeaderMap,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    if let Err(err) = jail::video(&app.config, &*video).await {
+        return err.into_response(Code::ThumbnailNotFound, "Thumbnail not found");
+    }
+
+    let image = match poster(app, &video).await {
+        Some(thumb_path) => read(&thumb_path).await,
+        None => None
+    };
+
+    match image {
+        Some((_, validators)) if conditional::is_not_modified(&headers, &validators) => {
+            conditional::not_modified(&validators)
+        }
+        Some((image, validators)) => validators.headers(response::Response::builder())
+            .status(http::StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "image/jpeg")
+            .body(image.into())
+            .unwrap(),
+        None => ApiError::new(Code::ThumbnailNotFound, "Thumbnail not found").into()
+    }
+}
@@ -0,0 +1,406 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use tokio::fs;
+
+use crate::{cache, nfo, unix_now, App, Config};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS tmdb (
+        query TEXT PRIMARY KEY,
+        key TEXT,
+        title TEXT,
+        year INTEGER,
+        plot TEXT,
+        poster TEXT,
+        fetched INTEGER NOT NULL
+    );
+";
+
+const API: &str = "https://api.themoviedb.org/3";
+const IMAGES: &str = "https://image.tmdb.org/t/p/w500";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Filenames that matched nothing are looked up again after this long, in
+/// case TMDB gained the title since.
+const RETRY_AFTER: u64 = 7 * 24 * 3600;
+
+/// Release tags that end the title part of a filename.
+const RELEASE_TAGS: &[&str] = &[
+    "bluray", "bdrip", "brrip", "webrip", "web", "webdl", "hdtv", "dvdrip", "hdrip", "remux",
+    "x264", "x265", "h264", "h265", "hevc", "avc", "xvid", "aac", "ac3", "dts",
+    "proper", "repack", "extended", "unrated", "remastered", "hdr", "10bit", "uhd", "4k"
+];
+
+/// What a filename says about the video, to search TMDB with.
+#[derive(PartialEq, Debug)]
+pub enum Guess {
+    Movie { title: String, year: Option<u32> },
+    Episode { show: String, year: Option<u32>, season: u32, episode: u32 }
+}
+
+impl Guess {
+    /// Identifies the lookup in the cache.
+    fn query(&self, language: &str) -> String {
+        match self {
+            Guess::Movie { title, year } => format!("movie:{language}:{}:{}", title.to_lowercase(), year.unwrap_or(0)),
+            Guess::Episode { show, year, season, episode } => {
+                format!("episode:{language}:{}:{}:{season}:{episode}", show.to_lowercase(), year.unwrap_or(0))
+            }
+        }
+    }
+}
+
+fn is_year(token: &str) -> Option<u32> {
+    let year: u32 = token.parse().ok().filter(|_| token.len() == 4)?;
+    (1900..2100).contains(&year).then_some(year)
+}
+
+fn is_release_tag(token: &str) -> bool {
+    let token = token.to_ascii_lowercase();
+    // Resolutions like `1080p`
+    let resolution = token.strip_suffix('p').is_some_and(|height| height.len() >= 3 && height.bytes().all(|byte| byte.is_ascii_digit()));
+    resolution || RELEASE_TAGS.contains(&&*token)
+}
+
+/// Parses `S01E02` or `1x02`.
+fn episode_number(token: &str) -> Option<(u32, u32)> {
+    let lower = token.to_ascii_lowercase();
+    let (season, episode) = match lower.strip_prefix('s') {
+        Some(rest) => rest.split_once('e')?,
+        None => lower.split_once('x')?
+    };
+    let digits = |text: &str| !text.is_empty() && text.bytes().all(|byte| byte.is_ascii_digit());
+    if !digits(season) || !digits(episode) {
+        return None;
+    }
+    Some((season.parse().ok()?, episode.parse().ok()?))
+}
+
+fn tokens(name: &str) -> Vec<&str> {
+    name.split(|char: char| char.is_whitespace() || "._-()[]{}".contains(char))
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+/// Splits a trailing year off `tokens`, except when it's all there is, as
+/// in `1917`.
+fn title_and_year(tokens: &[&str]) -> (String, Option<u32>) {
+    match tokens.iter().rposition(|token| is_year(token).is_some()).filter(|&index| index > 0) {
+        Some(index) => (tokens[..index].join(" "), is_year(tokens[index])),
+        None => (tokens.join(" "), None)
+    }
+}
+
+/// Guesses the movie or episode from a video path like
+/// `Movies/The.Matrix.1999.1080p.BluRay.mkv` or
+/// `Shows/Lost/Season 1/Lost.S01E02.mkv`. Episodes named only by their
+/// number take the show from the folders above.
+pub fn guess(path: &str) -> Option<Guess> {
+    let (dirs, filename) = path.rsplit_once('/').unwrap_or(("", path));
+    let stem = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+    let tokens = tokens(stem);
+
+    if let Some((index, (season, episode))) = tokens.iter().enumerate().find_map(|(index, token)| Some((index, episode_number(token)?))) {
+        let (mut show, mut year) = title_and_year(&tokens[..index]);
+        if show.is_empty() {
+            let dir = dirs.rsplit('/').find(|dir| {
+                let lower = dir.to_lowercase();
+                !lower.is_empty() && !lower.starts_with("season") && lower != "specials"
+            })?;
+            (show, year) = title_and_year(&self::tokens(dir));
+        }
+        return Some(Guess::Episode { show, year, season, episode });
+    }
+
+    let end = tokens.iter().position(|token| is_release_tag(token)).unwrap_or(tokens.len());
+    let (title, year) = title_and_year(&tokens[..end]);
+    (!title.is_empty()).then_some(Guess::Movie { title, year })
+}
+
+/// What TMDB knows about a video. `key` names the movie or show, and its
+/// cached poster.
+struct Match {
+    key: Box<str>,
+    title: Box<str>,
+    year: Option<u32>,
+    plot: Option<Box<str>>,
+    poster: Option<Box<str>>
+}
+
+#[derive(serde::Deserialize)]
+struct Results<T> {
+    results: Vec<T>
+}
+
+#[derive(serde::Deserialize)]
+struct Movie {
+    id: u64,
+    title: Box<str>,
+    release_date: Option<Box<str>>,
+    overview: Option<Box<str>>,
+    poster_path: Option<Box<str>>
+}
+
+#[derive(serde::Deserialize)]
+struct Show {
+    id: u64,
+    poster_path: Option<Box<str>>
+}
+
+#[derive(serde::Deserialize)]
+struct Episode {
+    name: Box<str>,
+    air_date: Option<Box<str>>,
+    overview: Option<Box<str>>
+}
+
+fn year_of(date: Option<&str>) -> Option<u32> {
+    date?.get(..4)?.parse().ok()
+}
+
+fn non_empty(text: Option<Box<str>>) -> Option<Box<str>> {
+    text.filter(|text| !text.trim().is_empty())
+}
+
+/// Where the poster of `key` is cached.
+pub fn poster_path(config: &Config, key: &str) -> PathBuf {
+    config.cache_path.join("tmdb").join(format!("{key}.jpg"))
+}
+
+/// Looks up videos on TMDB, remembering the answers so that scans don't ask
+/// again.
+pub struct Tmdb {
+    http: reqwest::Client,
+    conn: Mutex<Connection>
+}
+
+impl Tmdb {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .user_agent(concat!("ninja/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .unwrap_or_default();
+        Ok(Tmdb { http, conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// The remembered answer to `query`, or `None` if there's no recent one.
+    fn cached(&self, query: &str) -> rusqlite::Result<Option<Option<Match>>> {
+        let row = self.conn().query_row(
+            "SELECT key, title, year, plot, poster, fetched FROM tmdb WHERE query = ?",
+            [query],
+            |row| {
+                let key: Option<String> = row.get(0)?;
+                let found = match key {
+                    Some(key) => Some(Match {
+                        key: key.into(),
+                        title: row.get::<_, String>(1)?.into(),
+                        year: row.get(2)?,
+                        plot: row.get::<_, Option<String>>(3)?.map(Into::into),
+                        poster: row.get::<_, Option<String>>(4)?.map(Into::into)
+                    }),
+                    None => None
+                };
+                Ok((found, row.get::<_, u64>(5)?))
+            }
+        ).optional()?;
+
+        Ok(row.and_then(|(found, fetched)| match found {
+            Some(found) => Some(Some(found)),
+            None => (unix_now().saturating_sub(fetched) < RETRY_AFTER).then_some(None)
+        }))
+    }
+
+    fn remember(&self, query: &str, found: Option<&Match>) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO tmdb (query, key, title, year, plot, poster, fetched) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                query,
+                found.map(|found| &found.key),
+                found.map(|found| &found.title),
+                found.and_then(|found| found.year),
+                found.and_then(|found| found.plot.as_ref()),
+                found.and_then(|found| found.poster.as_ref()),
+                unix_now()
+            ]
+        )?;
+        Ok(())
+    }
+
+    /// Errors drop their URL, which carries `api_key` and would otherwise
+    /// end up in the logs.
+    async fn get<T: DeserializeOwned>(&self, api_key: &str, path: &str, query: &[(&str, &str)]) -> reqwest::Result<T> {
+        let response = async {
+            self.http.get(format!("{API}{path}"))
+                .query(&[("api_key", api_key)])
+                .query(query)
+                .send().await?
+                .error_for_status()?
+                .json().await
+        };
+        response.await.map_err(reqwest::Error::without_url)
+    }
+
+    /// Asks TMDB about `guess`, taking its best match.
+    async fn fetch(&self, config: &Config, api_key: &str, guess: &Guess) -> reqwest::Result<Option<Match>> {
+        let language = &*config.tmdb_language;
+        match guess {
+            Guess::Movie { title, year } => {
+                let year = year.map(|year| year.to_string());
+                let mut query = vec![("query", &**title), ("language", language)];
+                query.extend(year.as_deref().map(|year| ("year", year)));
+                let results: Results<Movie> = self.get(api_key, "/search/movie", &query).await?;
+
+                Ok(results.results.into_iter().next().map(|movie| Match {
+                    key: format!("movie-{}", movie.id).into(),
+                    year: year_of(movie.release_date.as_deref()),
+                    title: movie.title,
+                    plot: non_empty(movie.overview),
+                    poster: movie.poster_path
+                }))
+            }
+            Guess::Episode { show, year, season, episode } => {
+                let year = year.map(|year| year.to_string());
+                let mut query = vec![("query", &**show), ("language", language)];
+                query.extend(year.as_deref().map(|year| ("first_air_date_year", year)));
+                let results: Results<Show> = self.get(api_key, "/search/tv", &query).await?;
+                let Some(show) = results.results.into_iter().next() else {
+                    return Ok(None);
+                };
+
+                let path = format!("/tv/{}/season/{season}/episode/{episode}", show.id);
+                let episode: Episode = match self.get(api_key, &path, &[("language", language)]).await {
+                    Ok(episode) => episode,
+                    // The show exists, but not this episode
+                    Err(err) if err.status() == Some(reqwest::StatusCode::NOT_FOUND) => return Ok(None),
+                    Err(err) => return Err(err)
+                };
+
+                Ok(Some(Match {
+                    key: format!("tv-{}", show.id).into(),
+                    title: episode.name,
+                    year: year_of(episode.air_date.as_deref()),
+                    plot: non_empty(episode.overview),
+                    poster: show.poster_path
+                }))
+            }
+        }
+    }
+
+    /// Downloads the poster of `found` unless it's cached already, returning
+    /// whether it is now.
+    async fn download_poster(&self, config: &Config, found: &Match) -> bool {
+        let path = poster_path(config, &found.key);
+        if fs::try_exists(&path).await.unwrap_or(false) {
+            return true;
+        }
+        let Some(poster) = &found.poster else {
+            return false;
+        };
+
+        let image = match self.http.get(format!("{IMAGES}{poster}")).send().await.and_then(|response| response.error_for_status()) {
+            Ok(response) => response.bytes().await,
+            Err(err) => Err(err)
+        };
+        match image {
+            Ok(image) => match cache::write_atomic(&path, &image).await {
+                Ok(()) => true,
+                Err(err) => {
+                    tracing::error!(error = %err, "Failed to write `{}`", path.display());
+                    false
+                }
+            },
+            Err(err) => {
+                tracing::warn!(error = %err, "Failed to download TMDB poster for `{}`", found.key);
+                false
+            }
+        }
+    }
+}
+
+/// Fills in the title, year and plot that the sidecars of the video at
+/// `relative` lack from TMDB, while `tmdb_api_key` is set. Also returns the
+/// key of the match when its poster is cached.
+pub async fn describe(app: &App, relative: &str, mut metadata: nfo::Metadata) -> (nfo::Metadata, Option<Box<str>>) {
+    let key = lookup(app, relative, &mut metadata).await;
+    (metadata, key)
+}
+
+async fn lookup(app: &App, relative: &str, metadata: &mut nfo::Metadata) -> Option<Box<str>> {
+    let api_key = app.config.tmdb_api_key.as_deref()?;
+    // Curated NFOs know best
+    if metadata.title.is_some() && metadata.plot.is_some() {
+        return None;
+    }
+
+    let guess = guess(relative)?;
+    let query = guess.query(&app.config.tmdb_language);
+    let found = match app.tmdb.cached(&query) {
+        Ok(Some(found)) => found,
+        Ok(None) => match app.tmdb.fetch(&app.config, api_key, &guess).await {
+            Ok(found) => {
+                if let Err(err) = app.tmdb.remember(&query, found.as_ref()) {
+                    tracing::error!(error = %err, "Failed to cache TMDB match for `{relative}`");
+                }
+                found
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "Failed to look up `{relative}` on TMDB");
+                return None;
+            }
+        },
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to query TMDB cache for `{relative}`");
+            return None;
+        }
+    }?;
+
+    metadata.title.get_or_insert(found.title.clone());
+    metadata.year = metadata.year.or(found.year);
+    if metadata.plot.is_none() {
+        metadata.plot = found.plot.clone();
+    }
+
+    let has_poster = metadata.poster.is_none() && app.tmdb.download_poster(&app.config, &found).await;
+    has_poster.then_some(found.key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn movie(title: &str, year: Option<u32>) -> Option<Guess> {
+        Some(Guess::Movie { title: title.into(), year })
+    }
+
+    fn episode(show: &str, year: Option<u32>, season: u32, episode: u32) -> Option<Guess> {
+        Some(Guess::Episode { show: show.into(), year, season, episode })
+    }
+
+    #[test]
+    fn movies() {
+        assert_eq!(guess("Movies/The.Matrix.1999.1080p.BluRay.x264.mkv"), movie("The Matrix", Some(1999)));
+        assert_eq!(guess("Blade Runner 2049 (2017).mkv"), movie("Blade Runner 2049", Some(2017)));
+        assert_eq!(guess("2001 A Space Odyssey.mp4"), movie("2001 A Space Odyssey", None));
+        assert_eq!(guess("1917.mkv"), movie("1917", None));
+        assert_eq!(guess("Alien [Director's Cut] 720p.mkv"), movie("Alien Director's Cut", None));
+    }
+
+    #[test]
+    fn episodes() {
+        assert_eq!(guess("Shows/Lost.S01E02.720p.mkv"), episode("Lost", None, 1, 2));
+        assert_eq!(guess("Doctor Who (2005) - 3x10 - Blink.mkv"), episode("Doctor Who", Some(2005), 3, 10));
+        assert_eq!(guess("Shows/The Wire/Season 2/s02e05.mkv"), episode("The Wire", None, 2, 5));
+        assert_eq!(guess("S01E01.mkv"), None);
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::{extract, http, middleware, response};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::encoding::{self, hex};
+
+const X_REQUEST_ID: http::HeaderName = http::HeaderName::from_static("x-request-id");
+
+/// Longer `X-Request-Id` headers are replaced rather than logged.
+const MAX_REQUEST_ID: usize = 128;
+
+tokio::task_local! {
+    pub static REQUEST_ID: Arc<str>;
+}
+
+/// Where spans are exported, with OTLP over HTTP. Disabled while `endpoint`
+/// isn't set.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Telemetry {
+    /// Base URL of the collector, like `http://localhost:4318`. Spans are
+    /// POSTed to `/v1/traces` under it.
+    pub endpoint: Option<Box<str>>,
+    pub service_name: Box<str>,
+    /// Sent with every export, for collectors that want an API key.
+    pub headers: BTreeMap<Box<str>, Box<str>>
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Telemetry { endpoint: None, service_name: "ninja".into(), headers: BTreeMap::new() }
+    }
+}
+
+impl Telemetry {
+    pub fn is_valid(&self) -> bool {
+        self.endpoint.as_ref().is_none_or(|endpoint| endpoint.starts_with("http://") || endpoint.starts_with("https://"))
+    }
+}
+
+/// The W3C trace context of a request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Context {
+    pub trace_id: [u8; 16],
+    /// The span of the caller, when the request came with `traceparent`.
+    pub parent_id: Option<[u8; 8]>,
+    pub sampled: bool
+}
+
+impl Context {
+    /// Starts a trace.
+    pub fn start() -> Self {
+        let mut trace_id = [0; 16];
+        OsRng.fill_bytes(&mut trace_id);
+        Context { trace_id, parent_id: None, sampled: true }
+    }
+
+    /// Parses `traceparent`, like
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let (version, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        // Later versions may append fields
+        let [version] = unhex::<1>(version)?;
+        if version == 0xff || (version == 0 && parts.next().is_some()) {
+            return None;
+        }
+
+        let trace_id = unhex::<16>(trace_id).filter(|id| *id != [0; 16])?;
+        let parent_id = unhex::<8>(parent_id).filter(|id| *id != [0; 8])?;
+        let [flags] = unhex::<1>(flags)?;
+        Some(Context { trace_id, parent_id: Some(parent_id), sampled: flags & 1 == 1 })
+    }
+}
+
+/// Reads lowercase hex, the only case trace context allows.
+fn unhex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != 2 * N || !hex.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+
+    encoding::decode_hex(hex)?.try_into().ok()
+}
+
+/// The ID of a request, also sent back as `X-Request-Id`.
+#[derive(Clone)]
+pub struct RequestId(pub Arc<str>);
+
+/// The ID the client picked, if it's short and printable, or else the trace
+/// ID, so that a request can be found in traces by its ID.
+fn request_id(headers: &http::HeaderMap, context: &Context) -> Arc<str> {
+    headers.get(X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID && id.bytes().all(|byte| byte.is_ascii_graphic()))
+        .map_or_else(|| hex(&context.trace_id).into(), Into::into)
+}
+
+/// The ID of the request being handled, unless called from a spawned task.
+pub fn current() -> Option<Arc<str>> {
+    REQUEST_ID.try_with(Arc::clone).ok()
+}
+
+/// Names each request, for its log lines, its error responses and its trace.
+pub async fn assign(mut request: extract::Request, next: middleware::Next) -> response::Response {
+    let context = request.headers()
+        .get("traceparent")
+        .and_then(|header| header.to_str().ok())
+        .and_then(Context::parse)
+        .unwrap_or_else(Context::start);
+    let id = request_id(request.headers(), &context);
+    let span = tracing::Span::current();
+    span.record("request_id", &*id);
+    #[cfg(feature = "otel")]
+    export::adopt(&span, context);
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).await;
+    span.record("status", response.status().as_u16());
+    if let Ok(id) = http::HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID, id);
+    }
+    response
+}
+
+/// Sends spans to an OpenTelemetry collector, as OTLP JSON.
+#[cfg(feature = "otel")]
+pub mod export {
+    use std::fmt;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+    use tracing::field::{Field, Visit};
+    use tracing::{span, Level, Subscriber};
+    use tracing_subscriber::layer::{self, Layer};
+    use tracing_subscriber::registry::LookupSpan;
+
+    use super::{Context, Telemetry};
+    use crate::encoding::hex;
+
+    /// Spans are sent once this many are waiting, or every `FLUSH_INTERVAL`.
+    const BATCH_SIZE: usize = 512;
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+    /// Spans finished while this many wait are dropped, rather than piling
+    /// up while the collector is down.
+    const QUEUE_SIZE: usize = 4096;
+    /// Events kept per span, as a stalled stream can log for hours.
+    const MAX_EVENTS: usize = 128;
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    const SPAN_KIND_INTERNAL: u8 = 1;
+    const SPAN_KIND_SERVER: u8 = 2;
+    const STATUS_CODE_ERROR: u8 = 2;
+
+    fn unix_nanos() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_nanos() as u64)
+    }
+
+    fn span_id() -> [u8; 8] {
+        let mut id = [0; 8];
+        OsRng.fill_bytes(&mut id);
+        id
+    }
+
+    /// The OpenTelemetry names of the fields of request spans.
+    fn attribute(field: &str) -> &str {
+        match field {
+            "method" => "http.request.method",
+            "path" => "url.path",
+            "client" => "client.address",
+            "status" => "http.response.status_code",
+            field => field
+        }
+    }
+
+    /// Collects fields as OTLP attributes, the last value of each winning.
+    struct Attributes<'a>(&'a mut Vec<Value>);
+
+    impl Attributes<'_> {
+        fn push(&mut self, field: &Field, value: Value) {
+            let key = attribute(field.name());
+            let attribute = json!({ "key": key, "value": value });
+            match self.0.iter_mut().find(|attribute| attribute["key"] == key) {
+                Some(existing) => *existing = attribute,
+                None => self.0.push(attribute)
+            }
+        }
+    }
+
+    impl Visit for Attributes<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.push(field, json!({ "stringValue": value }));
+        }
+
+        // 64-bit integers are strings in OTLP JSON
+        fn record_i64(&mut self, field: &Field, value: i64) {
+            self.push(field, json!({ "intValue": value.to_string() }));
+        }
+
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.push(field, json!({ "intValue": value.to_string() }));
+        }
+
+        fn record_f64(&mut self, field: &Field, value: f64) {
+            self.push(field, json!({ "doubleValue": value }));
+        }
+
+        fn record_bool(&mut self, field: &Field, value: bool) {
+            self.push(field, json!({ "boolValue": value }));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.push(field, json!({ "stringValue": format!("{value:?}") }));
+        }
+    }
+
+    /// What's known of an open span, kept in its extensions.
+    struct Data {
+        trace_id: [u8; 16],
+        span_id: [u8; 8],
+        parent_id: Option<[u8; 8]>,
+        sampled: bool,
+        /// Whether the span is that of a request, rather than work done for
+        /// one.
+        server: bool,
+        start: u64,
+        attributes: Vec<Value>,
+        events: Vec<Value>,
+        failed: bool
+    }
+
+    impl Data {
+        fn attribute(&self, key: &str) -> Option<&Value> {
+            self.attributes.iter().find(|attribute| attribute["key"] == key).map(|attribute| &attribute["value"])
+        }
+
+        /// The span as OTLP JSON. Request spans are named by their method,
+        /// as paths would make too many names.
+        fn encode(&self, name: &str, end: u64) -> Value {
+            let name = match self.attribute("http.request.method").and_then(|method| method["stringValue"].as_str()) {
+                Some(method) if self.server => method,
+                _ => name
+            };
+            let status = self.attribute("http.response.status_code")
+                .and_then(|status| status["intValue"].as_str()?.parse::<u16>().ok());
+            let mut span = json!({
+                "traceId": hex(&self.trace_id),
+                "spanId": hex(&self.span_id),
+                "name": name,
+                "kind": if self.server { SPAN_KIND_SERVER } else { SPAN_KIND_INTERNAL },
+                "startTimeUnixNano": self.start.to_string(),
+                "endTimeUnixNano": end.to_string(),
+                "attributes": self.attributes,
+                "events": self.events
+            });
+            if let Some(parent_id) = self.parent_id {
+                span["parentSpanId"] = hex(&parent_id).into();
+            }
+            if self.failed || status.is_some_and(|status| status >= 500) {
+                span["status"] = json!({ "code": STATUS_CODE_ERROR });
+            }
+            span
+        }
+    }
+
+    /// Records spans and the events in them, and queues them once closed.
+    pub struct Exporter {
+        sender: mpsc::Sender<Value>
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Exporter {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: layer::Context<'_, S>) {
+            let Some(span) = ctx.span(id) else {
+                return;
+            };
+            let parent = span.parent().and_then(|parent| {
+                let extensions = parent.extensions();
+                let parent = extensions.get::<Data>()?;
+                Some(Context { trace_id: parent.trace_id, parent_id: Some(parent.span_id), sampled: parent.sampled })
+            });
+            let context = parent.unwrap_or_else(Context::start);
+
+            let mut attributes = Vec::new();
+            attrs.record(&mut Attributes(&mut attributes));
+            span.extensions_mut().insert(Data {
+                trace_id: context.trace_id,
+                span_id: span_id(),
+                parent_id: context.parent_id,
+                sampled: context.sampled,
+                server: false,
+                start: unix_nanos(),
+                attributes,
+                events: Vec::new(),
+                failed: false
+            });
+        }
+
+        fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: layer::Context<'_, S>) {
+            if let Some(span) = ctx.span(id) {
+                if let Some(data) = span.extensions_mut().get_mut::<Data>() {
+                    values.record(&mut Attributes(&mut data.attributes));
+                }
+            }
+        }
+
+        fn on_event(&self, event: &tracing::Event<'_>, ctx: layer::Context<'_, S>) {
+            let Some(span) = ctx.event_span(event) else {
+                return;
+            };
+            let mut extensions = span.extensions_mut();
+            let Some(data) = extensions.get_mut::<Data>() else {
+                return;
+            };
+            data.failed |= *event.metadata().level() == Level::ERROR;
+            if data.events.len() >= MAX_EVENTS {
+                return;
+            }
+
+            let mut attributes = Vec::new();
+            event.record(&mut Attributes(&mut attributes));
+            // The message is the name of the event
+            let message = attributes.iter().position(|attribute| attribute["key"] == "message");
+            let name = match message {
+                Some(index) => attributes.remove(index)["value"]["stringValue"].take(),
+                None => event.metadata().name().into()
+            };
+            data.events.push(json!({ "timeUnixNano": unix_nanos().to_string(), "name": name, "attributes": attributes }));
+        }
+
+        fn on_close(&self, id: span::Id, ctx: layer::Context<'_, S>) {
+            let Some(span) = ctx.span(&id) else {
+                return;
+            };
+            let Some(data) = span.extensions_mut().remove::<Data>() else {
+                return;
+            };
+            if data.sampled {
+                let _ = self.sender.try_send(data.encode(span.name(), unix_nanos()));
+            }
+        }
+    }
+
+    /// Puts `span`, that of a request, in the trace of `context`.
+    pub fn adopt(span: &tracing::Span, context: Context) {
+        span.with_subscriber(|(id, dispatch)| {
+            let Some(registry) = dispatch.downcast_ref::<tracing_subscriber::Registry>() else {
+                return;
+            };
+            let Some(span) = registry.span(id) else {
+                return;
+            };
+            let mut extensions = span.extensions_mut();
+            if let Some(data) = extensions.get_mut::<Data>() {
+                data.trace_id = context.trace_id;
+                data.parent_id = context.parent_id;
+                data.sampled = context.sampled;
+                data.server = true;
+            }
+        });
+    }
+
+    /// The layer recording spans, if an endpoint is set, along with the task
+    /// sending them. Needs a Tokio runtime.
+    pub fn layer(telemetry: &Telemetry) -> Option<Exporter> {
+        let endpoint = telemetry.endpoint.as_deref().filter(|_| telemetry.is_valid())?;
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in &telemetry.headers {
+            match (reqwest::header::HeaderName::try_from(&**name), reqwest::header::HeaderValue::try_from(&**value)) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => eprintln!("ERROR: Ignoring invalid telemetry header `{name}`")
+            }
+        }
+        let resource = json!({ "attributes": [
+            { "key": "service.name", "value": { "stringValue": telemetry.service_name } },
+            { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } }
+        ] });
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(url, headers, resource, receiver));
+        Some(Exporter { sender })
+    }
+
+    async fn send(client: &reqwest::Client, url: &str, headers: &reqwest::header::HeaderMap, resource: &Value, spans: Vec<Value>) {
+        let count = spans.len();
+        let body = json!({ "resourceSpans": [{
+            "resource": resource,
+            "scopeSpans": [{ "scope": { "name": "ninja", "version": env!("CARGO_PKG_VERSION") }, "spans": spans }]
+        }] });
+        let response = client.post(url).headers(headers.clone()).timeout(TIMEOUT).json(&body).send().await;
+        if let Err(err) = response.and_then(reqwest::Response::error_for_status) {
+            tracing::warn!(error = %err, "Failed to export {count} spans");
+        }
+    }
+
+    async fn run(url: String, headers: reqwest::header::HeaderMap, resource: Value, mut receiver: mpsc::Receiver<Value>) {
+        let client = reqwest::Client::new();
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        let mut batch = Vec::new();
+        loop {
+            tokio::select! {
+                span = receiver.recv() => {
+                    let Some(span) = span else {
+                        return;
+                    };
+                    batch.push(span);
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                }
+                _ = interval.tick() => {
+                    if batch.is_empty() {
+                        continue;
+                    }
+                }
+            }
+            send(&client, &url, &headers, &resource, std::mem::take(&mut batch)).await;
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn encoding() {
+            let data = Data {
+                trace_id: [0xab; 16],
+                span_id: [1; 8],
+                parent_id: Some([2; 8]),
+                sampled: true,
+                server: true,
+                start: 1,
+                attributes: vec![
+                    json!({ "key": "http.request.method", "value": { "stringValue": "GET" } }),
+                    json!({ "key": "http.response.status_code", "value": { "intValue": "503" } })
+                ],
+                events: Vec::new(),
+                failed: false
+            };
+            let span = data.encode("request", 2);
+            assert_eq!(span["name"], "GET");
+            assert_eq!(span["traceId"], "ab".repeat(16));
+            assert_eq!(span["parentSpanId"], "0202020202020202");
+            assert_eq!(span["kind"], SPAN_KIND_SERVER);
+            assert_eq!(span["endTimeUnixNano"], "2");
+            assert_eq!(span["status"]["code"], STATUS_CODE_ERROR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent() {
+        let context = Context::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(hex(&context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.parent_id.map(|id| hex(&id)).as_deref(), Some("00f067aa0ba902b7"));
+        assert!(context.sampled);
+        assert!(!Context::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap().sampled);
+        // Later versions may have more fields
+        assert!(Context::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_some());
+
+        assert_eq!(Context::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"), None);
+        assert_eq!(Context::parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(Context::parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(Context::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+        assert_eq!(Context::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01"), None);
+        assert_eq!(Context::parse("00-4bf92f3577b34da6-00f067aa0ba902b7-01"), None);
+    }
+
+    #[test]
+    fn request_ids() {
+        let context = Context::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(&*request_id(&headers, &context), "4bf92f3577b34da6a3ce929d0e0e4736");
+        headers.insert(X_REQUEST_ID, http::HeaderValue::from_static("report-42"));
+        assert_eq!(&*request_id(&headers, &context), "report-42");
+        headers.insert(X_REQUEST_ID, http::HeaderValue::from_static("has spaces"));
+        assert_eq!(&*request_id(&headers, &context), "4bf92f3577b34da6a3ce929d0e0e4736");
+    }
+
+    #[tokio::test]
+    async fn current_id() {
+        assert!(current().is_none());
+        let id = REQUEST_ID.scope("report-42".into(), async { current() }).await;
+        assert_eq!(id.as_deref(), Some("report-42"));
+    }
+}
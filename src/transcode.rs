@@ -0,0 +1,274 @@
+use std::path::Path;
+
+use crate::{ffmpeg, probe, subtitles, Config};
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleMode {
+    Burn
+}
+
+/// Converts HDR10 and HLG to SDR BT.709 with the Hable curve, which keeps
+/// highlights from clipping.
+pub const TONEMAP: &str = "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p";
+
+/// EBU R128 loudness normalization to -16 LUFS, the usual target for
+/// streaming. loudnorm resamples to 192 kHz, so the rate is brought back down.
+const LOUDNORM: [&str; 4] = ["-af", "loudnorm=I=-16:TP=-1.5:LRA=11", "-ar", "48000"];
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    H264,
+    Hevc,
+    Vp9,
+    Av1
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    #[default]
+    Mp4,
+    Webm,
+    Mkv
+}
+
+impl Container {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Container::Mp4 => "video/mp4",
+            Container::Webm => "video/webm",
+            Container::Mkv => "video/x-matroska"
+        }
+    }
+
+    /// Muxer arguments for output to a pipe.
+    pub fn format_args(self) -> &'static [&'static str] {
+        match self {
+            Container::Mp4 => &["-movflags", "frag_keyframe+empty_moov", "-f", "mp4"],
+            Container::Webm => &["-f", "webm"],
+            Container::Mkv => &["-f", "matroska"]
+        }
+    }
+}
+
+/// Named encoder settings, declared with `[[profile]]` and picked per request
+/// with `?profile=`. Bitrates are in kbit/s, unset ones are left to the
+/// rendition or the encoder.
+#[derive(serde::Serialize, serde::Deserialize, Hash)]
+pub struct Profile {
+    pub name: Box<str>,
+    #[serde(default)]
+    pub codec: Codec,
+    /// Caps the output height, sources are never upscaled.
+    pub height: Option<u32>,
+    pub video_bitrate: Option<u32>,
+    pub audio_bitrate: Option<u32>,
+    /// Replaces the encoder's default preset, e.g. `slow` for libx264 or
+    /// `p6` for NVENC.
+    pub preset: Option<Box<str>>,
+    /// Evens out the volume across videos. Done in a single pass, segment by
+    /// segment for HLS.
+    #[serde(default)]
+    pub loudnorm: bool,
+    /// Container of whole file transcodes, HLS segments are always MPEG-TS.
+    #[serde(default)]
+    pub container: Container,
+    /// Replaces every other setting for whole file transcodes, which then
+    /// can't be used for HLS, nor burn subtitles or pick an audio track.
+    pub args: Option<ffmpeg::Template>
+}
+
+impl Profile {
+    /// Names end up in query strings, and WebM only takes VP9 and AV1.
+    pub fn is_valid(&self) -> bool {
+        let name = !self.name.is_empty() && self.name.chars().all(|char| char.is_ascii_alphanumeric() || matches!(char, '-' | '_'));
+        let container = self.container != Container::Webm || matches!(self.codec, Codec::Vp9 | Codec::Av1);
+        name && container
+    }
+
+    /// Whether segments can be muxed into MPEG-TS.
+    pub fn supports_hls(&self) -> bool {
+        matches!(self.codec, Codec::H264 | Codec::Hevc) && self.args.is_none()
+    }
+
+    pub fn encode_args(&self, config: &Config) -> Vec<String> {
+        let mut args: Vec<String> = config.hwaccel.encoder_args(self.codec).into_iter().map(Into::into).collect();
+        if let Some(preset) = &self.preset {
+            match args.iter().position(|arg| arg == "-preset") {
+                Some(index) => args[index + 1] = preset.to_string(),
+                None => args.extend(["-preset".into(), preset.to_string()])
+            }
+        }
+        args
+    }
+
+    /// Arguments for the audio filter, if any.
+    pub fn audio_filter_args(&self) -> &'static [&'static str] {
+        if self.loudnorm { &LOUDNORM } else { &[] }
+    }
+
+    pub fn audio_codec(&self) -> &'static str {
+        match self.container {
+            Container::Webm => "libopus",
+            _ => "aac"
+        }
+    }
+}
+
+/// Per request transcoding options, shared by every route that re-encodes.
+#[derive(serde::Deserialize, Default)]
+pub struct Options {
+    pub subs: Option<SubtitleMode>,
+    #[serde(default)]
+    pub track: u32,
+    pub audio: Option<u32>,
+    pub profile: Option<Box<str>>,
+    /// Overrides the `tonemap` setting, as `1` or `0`.
+    #[serde(default, deserialize_with = "flag")]
+    pub tonemap: Option<bool>
+}
+
+/// Accepts `1` and `0` besides `true` and `false`.
+fn flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    match &*<Box<str> as serde::Deserialize>::deserialize(deserializer)? {
+        "1" | "true" => Ok(Some(true)),
+        "0" | "false" => Ok(Some(false)),
+        value => Err(serde::de::Error::invalid_value(serde::de::Unexpected::Str(value), &"1 or 0"))
+    }
+}
+
+impl Options {
+    /// The options as a query string, so that URIs in generated playlists
+    /// keep requesting the same output.
+    pub fn query(&self) -> String {
+        let mut params = Vec::new();
+        if self.subs == Some(SubtitleMode::Burn) {
+            params.push(format!("subs=burn&track={}", self.track));
+        }
+        if let Some(audio) = self.audio {
+            params.push(format!("audio={audio}"));
+        }
+        if let Some(profile) = &self.profile {
+            params.push(format!("profile={profile}"));
+        }
+        if let Some(tonemap) = self.tonemap {
+            params.push(format!("tonemap={}", u8::from(tonemap)));
+        }
+
+        if params.is_empty() {
+            String::new()
+        } else {
+            format!("?{}", params.join("&"))
+        }
+    }
+
+    /// The requested profile, or the configured default when `fallback` is
+    /// set. `Err` when the requested profile doesn't exist.
+    pub fn profile<'a>(&self, config: &'a Config, fallback: bool) -> Result<Option<&'a Profile>, ()> {
+        let name = match (&self.profile, &config.default_profile) {
+            (Some(name), _) => name,
+            (None, Some(name)) if fallback => name,
+            (None, _) => return Ok(None)
+        };
+        config.profiles.iter().find(|profile| profile.name == *name && profile.is_valid()).map(Some).ok_or(())
+    }
+
+    /// Whether HDR sources should be tone mapped.
+    pub fn tonemap(&self, config: &Config) -> bool {
+        self.tonemap.unwrap_or(config.tonemap)
+    }
+
+    /// The `-map` specifier for the selected audio track, falling back to
+    /// `default` when none was requested.
+    pub fn audio_map(&self, default: &str) -> String {
+        match self.audio {
+            Some(audio) => format!("0:a:{audio}"),
+            None => default.into()
+        }
+    }
+}
+
+/// Builds the `-filter_complex` graph producing the `[v]` output for a
+/// transcode starting at `start` seconds, or `None` when the requested
+/// subtitle track doesn't exist.
+pub async fn video_filter(
+    config: &Config,
+    video_path: &Path,
+    options: &Options,
+    start: f64,
+    scale: &str,
+    codec: Codec
+) -> Option<String> {
+    let hdr = options.tonemap(config) && probe::summary(config, video_path).await.is_some_and(|summary| summary.hdr.is_some());
+    let scale = if hdr { format!("{scale},{TONEMAP}") } else { scale.into() };
+    let scale = if config.hwaccel.encodes(codec) { config.hwaccel.with_upload(&scale) } else { scale };
+    if options.subs != Some(SubtitleMode::Burn) {
+        return Some(format!("[0:v:0]{scale}[v]"));
+    }
+
+    let info = probe::info(config, video_path).await?;
+    let stream = info.subtitle.get(options.track as usize)?;
+    let bitmap = stream.codec.as_deref().is_some_and(|codec| subtitles::BITMAP_CODECS.contains(&codec));
+
+    if bitmap {
+        return Some(format!("[0:v:0][0:s:{}]overlay,{scale}[v]", options.track));
+    }
+
+    // Input seeking resets timestamps to zero, the subtitles filter needs the
+    // original ones to pick the right cues.
+    let path = ffmpeg::escape_filter_value(video_path.to_str()?);
+    Some(format!(
+        "[0:v:0]setpts=PTS+{start}/TB,subtitles={path}:si={},setpts=PTS-{start}/TB,{scale}[v]",
+        options.track
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str, codec: Codec, container: Container) -> Profile {
+        Profile {
+            name: name.into(),
+            codec,
+            height: None,
+            video_bitrate: None,
+            audio_bitrate: None,
+            preset: None,
+            loudnorm: false,
+            container,
+            args: None
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        let config = Config {
+            profiles: [
+                profile("tv", Codec::Hevc, Container::Mkv),
+                profile("web", Codec::H264, Container::Webm),
+                profile("bad name", Codec::H264, Container::Mp4)
+            ].into(),
+            default_profile: Some("tv".into()),
+            ..Config::default()
+        };
+        let options = |name: Option<&str>| Options { profile: name.map(Into::into), ..Default::default() };
+
+        assert_eq!(options(None).profile(&config, false).map(|profile| profile.is_some()), Ok(false));
+        assert_eq!(options(None).profile(&config, true).unwrap().map(|profile| &*profile.name), Some("tv"));
+        assert_eq!(options(Some("tv")).query(), "?profile=tv");
+        assert!(options(Some("low")).profile(&config, true).is_err());
+        assert!(options(Some("web")).profile(&config, false).is_err());
+        assert!(options(Some("bad name")).profile(&config, false).is_err());
+
+        assert_eq!(Options { tonemap: Some(false), ..options(Some("tv")) }.query(), "?profile=tv&tonemap=0");
+
+        let tv = Profile { preset: Some("slow".into()), ..profile("tv", Codec::Hevc, Container::Mkv) };
+        assert_eq!(tv.encode_args(&config), ["-c:v", "libx265", "-preset", "slow"]);
+        assert!(tv.audio_filter_args().is_empty());
+        assert_eq!(Profile { loudnorm: true, ..tv }.audio_filter_args()[..2], ["-af", "loudnorm=I=-16:TP=-1.5:LRA=11"]);
+    }
+}
@@ -0,0 +1,206 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::{extract, http, response, Json};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tokio::{fs, time};
+
+use crate::error::{ApiError, Code};
+use crate::library::{self, Root};
+use crate::users::User;
+use crate::{auth, cache, encoding, files, scanner, unix_now, App};
+
+/// Hidden, so that the scanner skips it, and inside each library so that
+/// deleting is a rename on the same file system.
+const TRASH_DIR: &str = ".trash";
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A deleted video, kept as `.trash/<id>.json` with its files in
+/// `.trash/<id>/`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    id: Box<str>,
+    /// Where the video was, to restore it to.
+    path: Box<str>,
+    deleted: u64,
+    /// The video and its sidecars, named as they were.
+    files: Vec<Box<str>>
+}
+
+fn trash_dir(root: Root) -> PathBuf {
+    root.path.join(TRASH_DIR)
+}
+
+/// Entry IDs are generated here and end up in paths, anything else is
+/// refused.
+fn is_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Moves the video `relative`, at `path` on disk, and its `sidecars` into the
+/// trash of its library.
+pub async fn trash(app: &App, relative: &str, path: &Path, sidecars: &[PathBuf]) -> io::Result<()> {
+    let Some((root, _)) = library::locate(&app.config, Path::new(relative)) else {
+        return Err(io::ErrorKind::NotFound.into());
+    };
+
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+    let id = encoding::hex(&bytes);
+    let dir = trash_dir(root).join(&id);
+    fs::create_dir_all(&dir).await?;
+
+    let name = |path: &Path| path.file_name().map_or_else(Default::default, |name| name.to_string_lossy().into_owned());
+    if let Err(err) = files::move_file(path, &dir.join(name(path))).await {
+        let _ = fs::remove_dir_all(&dir).await;
+        return Err(err);
+    }
+    let mut files = vec![name(path).into()];
+    for sidecar in sidecars {
+        match files::move_file(sidecar, &dir.join(name(sidecar))).await {
+            Ok(()) => files.push(name(sidecar).into()),
+            Err(err) => tracing::warn!(error = %err, "Failed to move `{}` to the trash", sidecar.display())
+        }
+    }
+
+    let entry = Entry { id: id.into(), path: relative.into(), deleted: unix_now(), files };
+    cache::write_atomic(&trash_dir(root).join(format!("{}.json", entry.id)), &serde_json::to_vec(&entry).unwrap()).await
+}
+
+/// Every entry in the trash of every library.
+async fn entries(app: &App) -> Vec<(Root<'_>, Entry)> {
+    let mut entries = Vec::new();
+    for root in library::roots(&app.config) {
+        let Ok(mut dir) = fs::read_dir(trash_dir(root)).await else {
+            continue;
+        };
+        while let Ok(Some(file)) = dir.next_entry().await {
+            let path = file.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            match fs::read(&path).await.map(|json| serde_json::from_slice::<Entry>(&json)) {
+                Ok(Ok(entry)) if is_id(&entry.id) => entries.push((root, entry)),
+                _ => tracing::warn!("Ignoring invalid trash entry `{}`", path.display())
+            }
+        }
+    }
+    entries
+}
+
+async fn remove(root: Root<'_>, id: &str) -> io::Result<()> {
+    match fs::remove_dir_all(trash_dir(root).join(id)).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    fs::remove_file(trash_dir(root).join(format!("{id}.json"))).await
+}
+
+/// Deletes the entries older than `trash_retention` for good, returning how
+/// many were.
+pub async fn purge(app: &App) -> usize {
+    let now = unix_now();
+    let mut purged = 0;
+    for (root, entry) in entries(app).await {
+        if entry.deleted.saturating_add(app.config.trash_retention) > now {
+            continue;
+        }
+        match remove(root, &entry.id).await {
+            Ok(()) => {
+                tracing::info!("Purged `{}` from the trash", entry.path);
+                purged += 1;
+            }
+            Err(err) => tracing::error!(error = %err, "Failed to purge `{}` from the trash", entry.path)
+        }
+    }
+    purged
+}
+
+/// Purges the trash every hour.
+pub async fn run(app: &'static App) {
+    loop {
+        purge(app).await;
+        time::sleep(PURGE_INTERVAL).await;
+    }
+}
+
+/// The deleted videos the user can access, most recently deleted first.
+pub async fn list_trash(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if !auth::is_admin(&app.config, user.as_deref()) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+
+    let mut entries: Vec<Entry> = entries(app).await.into_iter()
+        .map(|(_, entry)| entry)
+        .filter(|entry| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, &entry.path)))
+        .collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.deleted));
+    response::IntoResponse::into_response(Json(entries))
+}
+
+/// Puts a deleted video back where it was, recreating its directory if that
+/// was removed since.
+pub async fn restore(
+    extract::Path((id, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>
+) -> response::Response {
+    if !auth::is_admin(&app.config, user.as_deref()) {
+        return ApiError::new(Code::Forbidden, "Forbidden").into();
+    }
+
+    let found = entries(app).await.into_iter().find(|(_, entry)| *entry.id == *id);
+    let Some((root, entry)) = found.filter(|(_, entry)| user.as_ref().is_none_or(|user| auth::can_access(&app.config, user, &entry.path))) else {
+        return ApiError::new(Code::TrashEntryNotFound, "Not in the trash").into();
+    };
+    if library::is_read_only(&app.config, &*entry.path) {
+        return ApiError::new(Code::LibraryReadOnly, "Library is read-only").into();
+    }
+    let Some(target) = library::file(&app.config, &*entry.path) else {
+        return ApiError::new(Code::LibraryNotFound, "Library not found").into();
+    };
+    if fs::symlink_metadata(&target).await.is_ok() {
+        return ApiError::new(Code::AlreadyExists, "File already exists").into();
+    }
+
+    let dir = trash_dir(root).join(&*entry.id);
+    let parent = target.parent().unwrap_or(&target);
+    if let Err(err) = fs::create_dir_all(parent).await {
+        tracing::error!(error = %err, "Failed to create `{}`", parent.display());
+        return ApiError::new(Code::InternalError, "Failed to restore").into();
+    }
+    // The video comes first, the sidecars are only worth restoring with it
+    for (index, file) in entry.files.iter().enumerate() {
+        let restored = parent.join(&**file);
+        if index > 0 && fs::symlink_metadata(&restored).await.is_ok() {
+            tracing::warn!("Not restoring `{}` over an existing file", restored.display());
+            continue;
+        }
+        if let Err(err) = files::move_file(&dir.join(&**file), &restored).await {
+            tracing::error!(error = %err, "Failed to restore `{}`", restored.display());
+            if index == 0 {
+                return ApiError::new(Code::InternalError, "Failed to restore").into();
+            }
+        }
+    }
+    if let Err(err) = remove(root, &entry.id).await {
+        tracing::warn!(error = %err, "Failed to remove trash entry {}", entry.id);
+    }
+    tracing::info!("Restored `{}` from the trash", entry.path);
+
+    scanner::update(app, root, &target).await;
+    match app.index.get(&entry.path) {
+        Ok(Some(video)) => response::IntoResponse::into_response(Json(video)),
+        Ok(None) => response::Response::builder()
+            .status(http::StatusCode::NO_CONTENT)
+            .body(axum::body::Body::empty())
+            .unwrap(),
+        Err(err) => ApiError::database("Failed to restore", err).into()
+    }
+}
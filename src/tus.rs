@@ -0,0 +1,352 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use axum::body::Body;
+use axum::{extract, http, response};
+use futures_util::StreamExt;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::error::{ApiError, Code};
+use crate::users::User;
+use crate::{encoding, jail, upload, url, App};
+
+const VERSION: &str = "1.0.0";
+const EXTENSIONS: &str = "creation,termination";
+
+/// Uploads that haven't received anything for this long are deleted.
+const EXPIRY: Duration = Duration::from_secs(24 * 3600);
+
+/// What an upload will become, kept next to its data in the staging
+/// directory so that it survives restarts.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Info {
+    dir: Box<str>,
+    name: Box<str>,
+    length: u64,
+    /// Who created the upload, only they can continue it.
+    user: Option<Box<str>>
+}
+
+/// The uploads being written to, a second `PATCH` has to wait for the first
+/// to finish.
+#[derive(Default)]
+pub struct Uploads {
+    busy: Mutex<HashSet<Box<str>>>
+}
+
+struct Busy<'a> {
+    uploads: &'a Uploads,
+    id: &'a str
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.uploads.busy.lock().unwrap().remove(self.id);
+    }
+}
+
+impl Uploads {
+    fn lock<'a>(&'a self, id: &'a str) -> Option<Busy<'a>> {
+        self.busy.lock().unwrap().insert(id.into()).then_some(Busy { uploads: self, id })
+    }
+}
+
+fn staging(app: &App) -> PathBuf {
+    app.config.cache_path.join("uploads")
+}
+
+/// Upload IDs are generated here and end up in paths, anything else is
+/// refused.
+fn is_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// The value of `key` in an `Upload-Metadata` header, a comma separated list
+/// of keys and base64 encoded values.
+fn metadata(header: &str, key: &str) -> Option<String> {
+    header.split(',').find_map(|pair| {
+        let (name, value) = pair.trim().split_once(' ').unwrap_or((pair.trim(), ""));
+        (name == key).then(|| String::from_utf8(encoding::decode_base64(value.trim())?).ok())?
+    })
+}
+
+fn header<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name)?.to_str().ok()
+}
+
+fn builder(status: http::StatusCode) -> http::response::Builder {
+    response::Response::builder()
+        .status(status)
+        .header("tus-resumable", VERSION)
+        .header(http::header::CACHE_CONTROL, "no-store")
+}
+
+fn error(code: Code, message: &'static str) -> response::Response {
+    response::IntoResponse::into_response(([("tus-resumable", VERSION), ("cache-control", "no-store")], ApiError::new(code, message)))
+}
+
+fn storage_error(err: std::io::Error, path: &Path) -> response::Response {
+    tracing::error!(error = %err, "Failed to write upload `{}`", path.display());
+    error(Code::InternalError, "Failed to store upload")
+}
+
+/// Whether the client speaks the protocol version implemented here, every
+/// request but `OPTIONS` has to say so.
+fn is_supported(headers: &http::HeaderMap) -> bool {
+    header(headers, "tus-resumable") == Some(VERSION)
+}
+
+fn unsupported() -> response::Response {
+    response::IntoResponse::into_response(([("tus-version", VERSION)], ApiError::new(Code::UnsupportedVersion, "Unsupported tus version")))
+}
+
+/// Loads the upload `id`, which only its creator may see.
+async fn info(app: &App, user: Option<&User>, id: &str) -> Option<Info> {
+    if !is_id(id) {
+        return None;
+    }
+    let info: Info = serde_json::from_slice(&fs::read(staging(app).join(format!("{id}.json"))).await.ok()?).ok()?;
+    (info.user.as_deref() == user.map(|user| &*user.name)).then_some(info)
+}
+
+async fn remove(app: &App, id: &str) {
+    let staging = staging(app);
+    let _ = fs::remove_file(staging.join(format!("{id}.part"))).await;
+    let _ = fs::remove_file(staging.join(format!("{id}.json"))).await;
+}
+
+/// Deletes the uploads abandoned for longer than [`EXPIRY`].
+async fn expire(app: &App) {
+    let Ok(mut entries) = fs::read_dir(staging(app)).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).filter(|stem| is_id(stem)) else {
+            continue;
+        };
+        let modified = entry.metadata().await.and_then(|metadata| metadata.modified());
+        if modified.is_ok_and(|modified| SystemTime::now().duration_since(modified).unwrap_or_default() > EXPIRY) {
+            tracing::info!("Deleting abandoned upload {id}");
+            remove(app, id).await;
+        }
+    }
+}
+
+/// Moves the finished upload `id` into the library. The directory may have
+/// gone, the file may exist by now, or the user may have lost access since
+/// the upload was created. The data is kept then, for the client to retry
+/// with an empty `PATCH` or to give up with `DELETE`.
+async fn complete(app: &App, user: Option<&User>, id: &str, info: &Info) -> Result<(), response::Response> {
+    let data = staging(app).join(format!("{id}.part"));
+    let destination = upload::destination(app, user, &info.dir).await?;
+    upload::finish(app, &destination, &info.dir, &info.name, &data).await?;
+    remove(app, id).await;
+    Ok(())
+}
+
+/// Tells clients what the server supports.
+pub async fn options(extract::State(app): extract::State<&App>) -> response::Response {
+    builder(http::StatusCode::NO_CONTENT)
+        .header("tus-version", VERSION)
+        .header("tus-extension", EXTENSIONS)
+        .header("tus-max-size", app.config.max_upload_size)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Creates an upload of `Upload-Length` bytes, named by the `filename` in
+/// `Upload-Metadata` and stored in its `dir` once complete.
+pub async fn create(
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    headers: http::HeaderMap
+) -> response::Response {
+    if !is_supported(&headers) {
+        return unsupported();
+    }
+
+    let Some(length) = header(&headers, "upload-length").and_then(|length| length.parse::<u64>().ok()) else {
+        return error(Code::BadRequest, "Upload-Length is required");
+    };
+    let upload_metadata = header(&headers, "upload-metadata").unwrap_or_default();
+    let dir = metadata(upload_metadata, "dir").unwrap_or_default();
+    let dir = dir.trim_matches('/');
+    let Some(name) = metadata(upload_metadata, "filename").filter(|name| upload::file_name(name) == Some(name.as_str())) else {
+        return error(Code::InvalidFileName, "Invalid file name");
+    };
+
+    let destination = match upload::destination(app, user.as_deref(), dir).await {
+        Ok(destination) => destination,
+        Err(response) => return response
+    };
+    if length > app.config.max_upload_size {
+        return error(Code::PayloadTooLarge, "Upload is too large");
+    }
+    if !jail::is_allowed(&app.config, Path::new(&name)) {
+        return error(Code::FileTypeNotAllowed, "File type isn't allowed");
+    }
+    if fs::symlink_metadata(destination.1.join(&name)).await.is_ok() {
+        return error(Code::AlreadyExists, "File already exists");
+    }
+
+    expire(app).await;
+
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+    let id = encoding::hex(&bytes);
+    let staging = staging(app);
+    let info = Info { dir: dir.into(), name: name.into(), length, user: user.as_ref().map(|user| user.name.clone()) };
+    let data = staging.join(format!("{id}.part"));
+    let created = async {
+        fs::create_dir_all(&staging).await?;
+        fs::write(&data, b"").await?;
+        fs::write(staging.join(format!("{id}.json")), serde_json::to_vec(&info).unwrap()).await
+    };
+    if let Err(err) = created.await {
+        remove(app, &id).await;
+        return storage_error(err, &data);
+    }
+
+    // Empty uploads have nothing worth keeping, and the client doesn't get
+    // their location to retry
+    if length == 0 {
+        if let Err(response) = complete(app, user.as_deref(), &id, &info).await {
+            remove(app, &id).await;
+            return response;
+        }
+    }
+
+    builder(http::StatusCode::CREATED)
+        .header(http::header::LOCATION, url::path(&app.config, &format!("/upload/tus/{id}")))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// How much of the upload the server has, for the client to resume from.
+pub async fn head(
+    extract::Path((id, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    headers: http::HeaderMap
+) -> response::Response {
+    if !is_supported(&headers) {
+        return unsupported();
+    }
+    let Some(info) = info(app, user.as_deref(), &id).await else {
+        return error(Code::UploadNotFound, "Upload not found");
+    };
+
+    let offset = fs::metadata(staging(app).join(format!("{id}.part"))).await.map_or(0, |metadata| metadata.len());
+    builder(http::StatusCode::OK)
+        .header("upload-offset", offset)
+        .header("upload-length", info.length)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Appends the body at `Upload-Offset`, which has to be where the upload
+/// stopped. Whatever arrives before the connection drops is kept.
+pub async fn patch(
+    extract::Path((id, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    headers: http::HeaderMap,
+    body: Body
+) -> response::Response {
+    if !is_supported(&headers) {
+        return unsupported();
+    }
+    if header(&headers, http::header::CONTENT_TYPE.as_str()) != Some("application/offset+octet-stream") {
+        return error(Code::UnsupportedMediaType, "Content-Type must be application/offset+octet-stream");
+    }
+    let Some(info) = info(app, user.as_deref(), &id).await else {
+        return error(Code::UploadNotFound, "Upload not found");
+    };
+    let Some(_busy) = app.uploads.lock(&id) else {
+        return error(Code::UploadBusy, "Upload is being written to");
+    };
+
+    let data = staging(app).join(format!("{id}.part"));
+    let mut file = match fs::OpenOptions::new().append(true).open(&data).await {
+        Ok(file) => file,
+        Err(err) => return storage_error(err, &data)
+    };
+    let mut offset = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(err) => return storage_error(err, &data)
+    };
+    if header(&headers, "upload-offset").and_then(|offset| offset.parse().ok()) != Some(offset) {
+        return error(Code::OffsetMismatch, "Upload-Offset doesn't match");
+    }
+
+    let mut body = body.into_data_stream();
+    while let Some(chunk) = body.next().await {
+        let Ok(chunk) = chunk else {
+            break;
+        };
+        if offset + chunk.len() as u64 > info.length {
+            let _ = file.flush().await;
+            return error(Code::PayloadTooLarge, "Upload is larger than its Upload-Length");
+        }
+        if let Err(err) = file.write_all(&chunk).await {
+            return storage_error(err, &data);
+        }
+        offset += chunk.len() as u64;
+    }
+    if let Err(err) = file.sync_all().await {
+        return storage_error(err, &data);
+    }
+    drop(file);
+
+    if offset == info.length {
+        if let Err(response) = complete(app, user.as_deref(), &id, &info).await {
+            return response;
+        }
+    }
+
+    builder(http::StatusCode::NO_CONTENT)
+        .header("upload-offset", offset)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Abandons an upload.
+pub async fn delete(
+    extract::Path((id, )): extract::Path<(Box<str>, )>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    headers: http::HeaderMap
+) -> response::Response {
+    if !is_supported(&headers) {
+        return unsupported();
+    }
+    if info(app, user.as_deref(), &id).await.is_none() {
+        return error(Code::UploadNotFound, "Upload not found");
+    }
+    let Some(_busy) = app.uploads.lock(&id) else {
+        return error(Code::UploadBusy, "Upload is being written to");
+    };
+
+    remove(app, &id).await;
+    builder(http::StatusCode::NO_CONTENT).body(Body::empty()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_metadata() {
+        let header = "filename bXkgY2xpcC5tcDQ=,dir cmVjb3JkaW5ncw==, empty";
+        assert_eq!(metadata(header, "filename").as_deref(), Some("my clip.mp4"));
+        assert_eq!(metadata(header, "dir").as_deref(), Some("recordings"));
+        assert_eq!(metadata(header, "empty").as_deref(), Some(""));
+        assert_eq!(metadata(header, "missing"), None);
+    }
+}
@@ -0,0 +1,33 @@
+use axum::{extract, http, response};
+
+use crate::{url, Config};
+
+const INDEX: &str = include_str!("../ui/index.html");
+const SCRIPT: &str = include_str!("../ui/app.js");
+const STYLE: &str = include_str!("../ui/style.css");
+
+fn asset(content_type: &'static str, body: String) -> response::Response {
+    // The assets change with the binary, revalidating is cheap next to
+    // serving a stale UI after an upgrade
+    response::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .body(body.into())
+        .unwrap()
+}
+
+/// The web UI. Its URLs are relative to a `<base>` pointing at `base_path`, so
+/// it works wherever the server is mounted.
+pub async fn serve_index(extract::State(config): extract::State<&Config>) -> response::Response {
+    let base = url::path(config, "/").replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;");
+    asset("text/html; charset=utf-8", INDEX.replace("%BASE%", &base))
+}
+
+pub async fn serve_script() -> response::Response {
+    asset("text/javascript; charset=utf-8", SCRIPT.into())
+}
+
+pub async fn serve_style() -> response::Response {
+    asset("text/css; charset=utf-8", STYLE.into())
+}
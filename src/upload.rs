@@ -0,0 +1,191 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::extract::multipart::{Field, Multipart};
+use axum::{extract, http, response, Json};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::error::{ApiError, Code};
+use crate::index::Video;
+use crate::users::User;
+use crate::library::{self, Root};
+use crate::{auth, files, jail, scanner, App};
+
+#[derive(serde::Deserialize)]
+pub struct UploadQuery {
+    #[serde(default)]
+    dir: Box<str>
+}
+
+/// The name to store an upload under, without whatever directories the
+/// client put in front of it. Hidden names are refused, the scanner would
+/// skip them anyway.
+pub fn file_name(name: &str) -> Option<&str> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    (!name.is_empty() && !name.starts_with('.')).then_some(name)
+}
+
+/// Writes `field` to the hidden `temp` file, failing once it gets larger than
+/// `max_size`.
+async fn receive(field: &mut Field<'_>, temp: &Path, max_size: u64) -> Result<(), response::Response> {
+    let mut file = match fs::File::create(temp).await {
+        Ok(file) => file,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to create `{}`", temp.display());
+            return Err(ApiError::new(Code::InternalError, "Failed to store upload").into());
+        }
+    };
+
+    let mut size = 0;
+    loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => return Err(response::IntoResponse::into_response(err))
+        };
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(ApiError::new(Code::PayloadTooLarge, "Upload is too large").into());
+        }
+        if let Err(err) = file.write_all(&chunk).await {
+            tracing::error!(error = %err, "Failed to write `{}`", temp.display());
+            return Err(ApiError::new(Code::InternalError, "Failed to store upload").into());
+        }
+    }
+
+    if let Err(err) = file.sync_all().await {
+        tracing::error!(error = %err, "Failed to write `{}`", temp.display());
+        return Err(ApiError::new(Code::InternalError, "Failed to store upload").into());
+    }
+    Ok(())
+}
+
+/// The root of `dir` in the library and where it is on disk, unresolved so
+/// that the scanner can tell where in the library the uploads land.
+pub async fn destination<'a>(app: &'a App, user: Option<&User>, dir: &str) -> Result<(Root<'a>, PathBuf), response::Response> {
+    if app.config.max_upload_size == 0 {
+        return Err(ApiError::new(Code::UploadsDisabled, "Uploads are disabled").into());
+    }
+    if user.is_some_and(|user| !auth::can_access(&app.config, user, dir)) {
+        return Err(ApiError::new(Code::Forbidden, "Forbidden").into());
+    }
+    if library::is_read_only(&app.config, dir) {
+        return Err(ApiError::new(Code::LibraryReadOnly, "Library is read-only").into());
+    }
+    if let Err(err) = jail::directory(&app.config, dir).await {
+        return Err(err.into_response(Code::DirectoryNotFound, "Directory not found"));
+    }
+
+    let (Some((root, _)), Some(target_dir)) = (library::locate(&app.config, Path::new(dir)), library::file(&app.config, dir)) else {
+        return Err(ApiError::new(Code::DirectoryNotFound, "Directory not found").into());
+    };
+    if !fs::metadata(&target_dir).await.is_ok_and(|metadata| metadata.is_dir()) {
+        return Err(ApiError::new(Code::DirectoryNotFound, "Directory not found").into());
+    }
+    Ok((root, target_dir))
+}
+
+/// Moves the complete upload at `staged` to `name` in `dir`, and indexes it.
+pub async fn finish(
+    app: &App,
+    (root, target_dir): &(Root<'_>, PathBuf),
+    dir: &str,
+    name: &str,
+    staged: &Path
+) -> Result<Option<Video>, response::Response> {
+    let target = target_dir.join(name);
+    if fs::symlink_metadata(&target).await.is_ok() {
+        return Err(ApiError::new(Code::AlreadyExists, "File already exists").into());
+    }
+
+    if let Err(err) = files::move_file(staged, &target).await {
+        tracing::error!(error = %err, "Failed to store upload `{}`", target.display());
+        return Err(ApiError::new(Code::InternalError, "Failed to store upload").into());
+    }
+
+    tracing::info!("Uploaded `{}`", target.display());
+    scanner::update(app, *root, &target).await;
+    let path = if dir.is_empty() { name.to_owned() } else { format!("{dir}/{name}") };
+    match app.index.get(&path) {
+        Ok(video) => Ok(video),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to query index for `{path}`");
+            Ok(None)
+        }
+    }
+}
+
+/// Stores the files of a multipart request in `dir`, at most
+/// `max_upload_size` bytes each, and indexes them right away. Files are
+/// written under a hidden name first and renamed once complete, so that the
+/// scanner never sees half of one. Existing files are never replaced.
+pub async fn upload(
+    extract::Query(query): extract::Query<UploadQuery>,
+    extract::State(app): extract::State<&App>,
+    user: Option<extract::Extension<User>>,
+    mut multipart: Multipart
+) -> response::Response {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let dir = query.dir.trim_matches('/');
+    let destination = match destination(app, user.as_deref(), dir).await {
+        Ok(destination) => destination,
+        Err(response) => return response
+    };
+
+    let mut uploaded = Vec::new();
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return response::IntoResponse::into_response(err)
+        };
+        // Plain form fields carry no file
+        let Some(name) = field.file_name() else {
+            continue;
+        };
+        let Some(name) = file_name(name).map(str::to_owned) else {
+            return ApiError::new(Code::InvalidFileName, "Invalid file name").into();
+        };
+        if !jail::is_allowed(&app.config, Path::new(&name)) {
+            return ApiError::new(Code::FileTypeNotAllowed, "File type isn't allowed").into();
+        }
+        if fs::symlink_metadata(destination.1.join(&name)).await.is_ok() {
+            return ApiError::new(Code::AlreadyExists, "File already exists").into();
+        }
+
+        let temp = destination.1.join(format!(".{name}.{}.upload", COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let result = match receive(&mut field, &temp, app.config.max_upload_size).await {
+            // Another upload of the same name may have finished in the meantime
+            Ok(()) => finish(app, &destination, dir, &name, &temp).await,
+            Err(response) => Err(response)
+        };
+        match result {
+            Ok(video) => uploaded.extend(video),
+            Err(response) => {
+                let _ = fs::remove_file(&temp).await;
+                return response;
+            }
+        }
+    }
+
+    let mut response = response::IntoResponse::into_response(Json::<Vec<Video>>(uploaded));
+    *response.status_mut() = http::StatusCode::CREATED;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name("clip.mp4"), Some("clip.mp4"));
+        assert_eq!(file_name(r"C:\Users\me\clip.mp4"), Some("clip.mp4"));
+        assert_eq!(file_name("../../clip.mp4"), Some("clip.mp4"));
+        assert_eq!(file_name(".hidden.mp4"), None);
+        assert_eq!(file_name("videos/"), None);
+        assert_eq!(file_name(".."), None);
+    }
+}
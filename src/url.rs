@@ -0,0 +1,55 @@
+use axum::http;
+
+use crate::forwarded::Client;
+use crate::Config;
+
+/// Percent-encodes `value` for use as a single path segment or query value.
+pub fn encode_component(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Percent-encodes each segment of a `/` separated `path`, keeping the
+/// separators.
+pub fn encode_path(path: &str) -> String {
+    path.split('/').map(encode_component).collect::<Vec<_>>().join("/")
+}
+
+/// `path` under the configured `base_path`, for URLs handed out to clients.
+pub fn path(config: &Config, path: &str) -> String {
+    format!("{}{path}", config.base_path.trim_end_matches('/'))
+}
+
+/// `path` as an absolute URL on the host `request` was sent to, for clients
+/// that fetch URLs outside of a page, like podcast apps.
+pub fn absolute(config: &Config, request: &http::request::Parts, path: &str) -> String {
+    // HTTP/2 sends the host as the authority instead of a header
+    let host = request.uri.authority().map(|authority| authority.as_str())
+        .or_else(|| request.headers.get(http::header::HOST)?.to_str().ok())
+        .unwrap_or("localhost");
+    let https = request.extensions.get::<Client>().is_some_and(|client| client.https);
+    let scheme = if https { "https" } else { "http" };
+    format!("{scheme}://{host}{}", self::path(config, path))
+}
+
+/// Mounts `app` under the configured `base_path`, for running behind a
+/// reverse proxy at a subpath.
+pub fn mount(config: &Config, app: axum::Router) -> axum::Router {
+    match config.base_path.trim_end_matches('/') {
+        "" => app,
+        base_path => {
+            // Nesting only matches the base path without the trailing slash
+            let target = base_path.to_owned();
+            axum::Router::new()
+                .nest(base_path, app)
+                .route(&format!("{base_path}/"), axum::routing::get(|| async move { axum::response::Redirect::permanent(&target) }))
+        }
+    }
+}
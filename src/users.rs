@@ -0,0 +1,154 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+
+use crate::{encoding, unix_now};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        password TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+        token TEXT PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        expires INTEGER NOT NULL
+    );
+";
+
+#[derive(serde::Serialize, Clone)]
+pub struct User {
+    pub id: i64,
+    pub name: Box<str>
+}
+
+/// Header identifying a device, for keeping personal state apart without
+/// users.
+const DEVICE_HEADER: &str = "x-device-id";
+
+#[derive(serde::Deserialize)]
+struct DeviceQuery {
+    device: Option<Box<str>>
+}
+
+/// Whose watch progress or favorites a request is about: the logged in user,
+/// or else the device from the `X-Device-Id` header or `?device=` parameter.
+/// Requests with neither share the same state.
+pub struct Owner(pub Box<str>);
+
+#[axum::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for Owner {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _: &S) -> Result<Self, Self::Rejection> {
+        if let Some(user) = parts.extensions.get::<User>() {
+            return Ok(Owner(format!("user:{}", user.name).into()));
+        }
+
+        let query = axum::extract::Query::<DeviceQuery>::try_from_uri(&parts.uri).ok().and_then(|query| query.0.device);
+        let device = parts.headers.get(DEVICE_HEADER)
+            .and_then(|device| device.to_str().ok())
+            .or(query.as_deref())
+            .unwrap_or_default();
+        Ok(Owner(format!("device:{}", device.trim()).into()))
+    }
+}
+
+/// Session tokens are only stored hashed, so that a leaked database doesn't
+/// hand out logged in sessions.
+fn hash_token(token: &str) -> String {
+    encoding::hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// Accounts and their login sessions, stored next to the index.
+pub struct Users {
+    conn: Mutex<Connection>
+}
+
+impl Users {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Users { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Whether any account exists, in which case logging in is required.
+    pub fn any(&self) -> rusqlite::Result<bool> {
+        self.conn().query_row("SELECT EXISTS (SELECT 1 FROM users)", [], |row| row.get(0))
+    }
+
+    /// Creates the account `name`, or changes its password if it exists.
+    /// `password_hash` comes from [`hash_password`].
+    pub fn set(&self, name: &str, password_hash: &str) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "INSERT INTO users (name, password) VALUES (?1, ?2)
+            ON CONFLICT (name) DO UPDATE SET password = excluded.password",
+            params![name, password_hash]
+        )?;
+        Ok(())
+    }
+
+    /// Checks `password` against the account `name`. Hashing is slow on
+    /// purpose, so this must not run on the async executor.
+    pub fn verify(&self, name: &str, password: &str) -> rusqlite::Result<Option<User>> {
+        let account = self.conn().query_row(
+            "SELECT id, name, password FROM users WHERE name = ?",
+            [name],
+            |row| Ok((User { id: row.get(0)?, name: row.get::<_, String>(1)?.into() }, row.get::<_, String>(2)?))
+        ).optional()?;
+
+        Ok(account.filter(|(_, hash)| verify_password(password, hash)).map(|(user, _)| user))
+    }
+
+    /// Starts a session for `user` lasting `lifetime` seconds, returning its
+    /// token.
+    pub fn create_session(&self, user: &User, lifetime: u64) -> rusqlite::Result<String> {
+        let mut bytes = [0; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = encoding::hex(&bytes);
+
+        let conn = self.conn();
+        conn.execute("DELETE FROM sessions WHERE expires <= ?", [unix_now()])?;
+        conn.execute(
+            "INSERT INTO sessions (token, user_id, expires) VALUES (?1, ?2, ?3)",
+            params![hash_token(&token), user.id, unix_now() + lifetime]
+        )?;
+        Ok(token)
+    }
+
+    /// The user logged in with `token`, if the session hasn't expired.
+    pub fn session(&self, token: &str) -> rusqlite::Result<Option<User>> {
+        self.conn().query_row(
+            "SELECT users.id, users.name FROM sessions JOIN users ON users.id = sessions.user_id
+            WHERE sessions.token = ?1 AND sessions.expires > ?2",
+            params![hash_token(token), unix_now()],
+            |row| Ok(User { id: row.get(0)?, name: row.get::<_, String>(1)?.into() })
+        ).optional()
+    }
+
+    pub fn delete_session(&self, token: &str) -> rusqlite::Result<()> {
+        self.conn().execute("DELETE FROM sessions WHERE token = ?", [hash_token(token)])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+use tokio::{fs, sync::mpsc, time};
+
+use crate::{library, scanner, App};
+
+/// Copying a large file produces a burst of modify events, so changes are only
+/// applied once the library has been quiet for this long.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Watches every library and incrementally updates the index as files are
+/// added, modified, renamed or removed.
+pub async fn run(app: &'static App) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            Ok(event) => for path in event.paths {
+                let _ = sender.send(path);
+            },
+            Err(err) => tracing::error!(error = %err, "Failed to watch library")
+        }
+    }) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to create library watcher");
+            return;
+        }
+    };
+
+    // Events are reported with absolute paths, the index works relative to
+    // the libraries as configured.
+    let mut roots = Vec::new();
+    for root in library::roots(&app.config) {
+        if let Err(err) = watcher.watch(root.path, RecursiveMode::Recursive) {
+            tracing::error!(error = %err, "Failed to watch `{}`", root.path.display());
+            continue;
+        }
+
+        match fs::canonicalize(root.path).await {
+            Ok(canonical) => roots.push((canonical, root)),
+            Err(err) => tracing::error!(error = %err, "Failed to resolve `{}`", root.path.display())
+        }
+    }
+
+    while let Some(path) = receiver.recv().await {
+        let mut paths = HashSet::from([path]);
+        loop {
+            match time::timeout(DEBOUNCE, receiver.recv()).await {
+                Ok(Some(path)) => { paths.insert(path); }
+                Ok(None) => return,
+                Err(_) => break
+            }
+        }
+
+        for path in paths {
+            for (canonical, root) in &roots {
+                if let Ok(relative) = path.strip_prefix(canonical) {
+                    scanner::update(app, *root, &root.path.join(relative)).await;
+                }
+            }
+        }
+    }
+}
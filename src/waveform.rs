@@ -0,0 +1,180 @@
+use std::path::PathBuf;
+
+use axum::body::Bytes;
+use axum::{extract, response, Json};
+use tokio::{fs, process::Command};
+
+use crate::error::{ApiError, Code};
+use crate::{cache, jail, library, App};
+
+/// Rate the audio is decoded at, plenty for peaks.
+const SAMPLE_RATE: u32 = 8000;
+
+/// Peaks kept per second of audio, requests are downsampled from these.
+const PEAKS_PER_SECOND: u32 = 20;
+
+/// Most peaks a request can ask for.
+const MAX_SAMPLES: usize = 10_000;
+
+pub fn cache_path(app: &App, relative: &str) -> PathBuf {
+    app.config.cache_path.join("waveforms").join(format!("{relative}.peaks"))
+}
+
+/// The peaks of the first audio track of `relative`, a byte each, reusing the
+/// cached ones as long as they are newer than the video. The decoded audio is
+/// folded into peaks as it streams in, a whole movie of it would take hundreds
+/// of megabytes. Peaks are only cached once ffmpeg got through all of it.
+async fn peaks(app: &App, relative: &str) -> Option<Bytes> {
+    let video_path = library::file(&app.config, relative)?;
+    let peaks_path = cache_path(app, relative);
+
+    let video_mtime = fs::metadata(&video_path).await.ok()?.modified().ok()?;
+    if cache::is_fresh(&peaks_path, video_mtime).await {
+        if let Ok(peaks) = fs::read(&peaks_path).await {
+            return Some(peaks.into());
+        }
+    }
+
+    let mut command = Command::new(&*app.config.ffmpeg_command);
+    command.args([
+        "-v", "error",
+        "-i", video_path.to_str()?,
+        "-map", "0:a:0",
+        "-ac", "1",
+        "-ar", &SAMPLE_RATE.to_string(),
+        "-f", "s16le",
+        "-"
+    ]);
+
+    // Opening the player asks for the waveform of the same video at once
+    let key = cache::Lru::key(("waveform", relative));
+    let decoded = app.inflight.run(&key, async {
+        let mut folder = Peaks::default();
+        app.ffmpeg.fold(&mut command, |chunk| folder.add(chunk)).await?;
+        let peaks = Bytes::from(folder.finish());
+        // No audio to speak of
+        if !peaks.is_empty() {
+            if let Err(err) = cache::write_atomic(&peaks_path, &peaks).await {
+                tracing::error!(error = %err, "Failed to cache waveform `{}`", peaks_path.display());
+            }
+        }
+        Ok(peaks)
+    }).await;
+
+    match decoded {
+        Ok(peaks) => (!peaks.is_empty()).then_some(peaks),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to decode audio of `{relative}`");
+            None
+        }
+    }
+}
+
+/// Folds 16 bit mono samples into peaks, [`PEAKS_PER_SECOND`] of them.
+#[derive(Default)]
+struct Peaks {
+    peaks: Vec<u8>,
+    peak: u16,
+    count: u32,
+    /// Samples can straddle chunks.
+    low: Option<u8>
+}
+
+impl Peaks {
+    fn add(&mut self, chunk: &[u8]) {
+        for &byte in chunk {
+            let Some(low) = self.low.take() else {
+                self.low = Some(byte);
+                continue;
+            };
+            self.peak = self.peak.max(i16::from_le_bytes([low, byte]).unsigned_abs());
+            self.count += 1;
+            if self.count == SAMPLE_RATE / PEAKS_PER_SECOND {
+                self.peaks.push((u32::from(self.peak) * 255 / 32768) as u8);
+                (self.peak, self.count) = (0, 0);
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.peaks.push((u32::from(self.peak) * 255 / 32768) as u8);
+        }
+        self.peaks
+    }
+}
+
+/// `samples` peaks out of `peaks`, each the loudest of its range, scaled so
+/// that the loudest of all is 1.
+fn downsample(peaks: &[u8], samples: usize) -> Vec<f32> {
+    let samples = samples.min(peaks.len());
+    let loudest = f32::from(peaks.iter().copied().max().unwrap_or(0).max(1));
+    (0..samples).map(|index| {
+        let range = &peaks[index * peaks.len() / samples..(index + 1) * peaks.len() / samples];
+        let peak = f32::from(range.iter().copied().max().unwrap_or(0)) / loudest;
+        (peak * 1000.0).round() / 1000.0
+    }).collect()
+}
+
+#[derive(serde::Deserialize)]
+pub struct WaveformQuery {
+    #[serde(default = "WaveformQuery::default_samples")]
+    samples: usize
+}
+
+impl WaveformQuery {
+    fn default_samples() -> usize { 1000 }
+}
+
+#[derive(serde::Serialize)]
+struct Waveform {
+    duration: f64,
+    peaks: Vec<f32>
+}
+
+pub async fn serve_waveform(
+    extract::Path((video, )): extract::Path<(Box<str>, )>,
+    extract::Query(params): extract::Query<WaveformQuery>,
+    extract::State(app): extract::State<&App>
+) -> response::Response {
+    if params.samples == 0 || params.samples > MAX_SAMPLES {
+        return ApiError::new(Code::BadRequest, "Invalid number of samples").into();
+    }
+
+    if let Err(err) = jail::video(&app.config, &*video).await {
+        return err.into_response(Code::VideoNotFound, "Video not found");
+    }
+
+    match peaks(app, &video).await {
+        Some(peaks) => response::IntoResponse::into_response(Json(Waveform {
+            duration: peaks.len() as f64 / PEAKS_PER_SECOND as f64,
+            peaks: downsample(&peaks, params.samples)
+        })),
+        None => ApiError::new(Code::AudioNotFound, "Audio not found").into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downsampling() {
+        assert_eq!(downsample(&[0, 10, 50, 20, 100, 0], 3), [0.1, 0.5, 1.0]);
+        assert_eq!(downsample(&[0, 10, 50], 10), [0.0, 0.2, 1.0]);
+        assert_eq!(downsample(&[0, 0], 1), [0.0]);
+    }
+
+    #[test]
+    fn folding() {
+        let samples: Vec<u8> = (0..SAMPLE_RATE / PEAKS_PER_SECOND + 1)
+            .flat_map(|index| if index == 7 { i16::MIN.to_le_bytes() } else { 1000i16.to_le_bytes() })
+            .collect();
+        let mut peaks = Peaks::default();
+        // Split inside a sample
+        let (first, rest) = samples.split_at(15);
+        peaks.add(first);
+        peaks.add(rest);
+        assert_eq!(peaks.finish(), [255, 7]);
+    }
+}
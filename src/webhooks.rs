@@ -0,0 +1,170 @@
+use std::net::IpAddr;
+#[cfg(feature = "webhooks")]
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{encoding, unix_now, App};
+
+/// Deliveries are attempted again after each of these delays, as long as the
+/// endpoint fails or can't be reached.
+#[cfg(feature = "webhooks")]
+const RETRY_DELAYS: [Duration; 3] = [Duration::from_secs(5), Duration::from_secs(60), Duration::from_secs(600)];
+
+#[cfg(feature = "webhooks")]
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const EVENTS: &[&str] = &["video.added", "job.finished", "playback.started", "playback.stopped", "scan.error"];
+
+/// An endpoint notified of server events with JSON POSTs, declared with
+/// `[[webhook]]`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Webhook {
+    pub url: Box<str>,
+    /// Signs every body with HMAC-SHA256, sent hex encoded as
+    /// `X-Ninja-Signature: sha256=...`.
+    #[serde(default)]
+    secret: Option<Box<str>>,
+    /// Events to send, all of them when empty.
+    #[serde(default)]
+    events: Box<[Box<str>]>
+}
+
+impl Webhook {
+    /// Only HTTP URLs and known events are accepted.
+    pub fn is_valid(&self) -> bool {
+        (self.url.starts_with("http://") || self.url.starts_with("https://"))
+            && self.events.iter().all(|event| EVENTS.contains(&&**event))
+    }
+
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|wanted| **wanted == *event)
+    }
+}
+
+/// Something that happened, sent as the body of the webhook along with
+/// `event` and `timestamp`.
+#[derive(serde::Serialize)]
+#[serde(tag = "event")]
+pub enum Event<'a> {
+    /// A video showed up in a library, outside of the first scan.
+    #[serde(rename = "video.added")]
+    VideoAdded { video: &'a str, title: Option<&'a str> },
+    /// A transcode job completed or failed. Cancelled jobs aren't sent.
+    #[serde(rename = "job.finished")]
+    JobFinished { id: u64, video: &'a str, rendition: &'a str, error: Option<&'a str> },
+    #[serde(rename = "playback.started")]
+    PlaybackStarted { video: &'a str, user: Option<&'a str>, ip: Option<IpAddr> },
+    /// A stream went idle, `duration` being the seconds since it started.
+    #[serde(rename = "playback.stopped")]
+    PlaybackStopped { video: &'a str, user: Option<&'a str>, ip: Option<IpAddr>, duration: u64, bytes: u64 },
+    #[serde(rename = "scan.error")]
+    ScanError { path: &'a str, error: &'a str }
+}
+
+impl Event<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::VideoAdded { .. } => "video.added",
+            Event::JobFinished { .. } => "job.finished",
+            Event::PlaybackStarted { .. } => "playback.started",
+            Event::PlaybackStopped { .. } => "playback.stopped",
+            Event::ScanError { .. } => "scan.error"
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+    timestamp: u64
+}
+
+fn body(event: &Event) -> Vec<u8> {
+    serde_json::to_vec(&Payload { event, timestamp: unix_now() }).unwrap()
+}
+
+/// The value of `X-Ninja-Signature` for `body`.
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", encoding::hex(&mac.finalize().into_bytes()))
+}
+
+/// Sends `body` to `webhook`, retrying while it fails with a server error, too
+/// many requests or not at all. Other client errors won't go away by trying
+/// again.
+#[cfg(feature = "webhooks")]
+async fn deliver(client: reqwest::Client, webhook: &Webhook, event: &'static str, body: Vec<u8>, signature: Option<String>) {
+    let mut delays = RETRY_DELAYS.iter();
+    loop {
+        let mut request = client.post(&*webhook.url)
+            .timeout(TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Ninja-Event", event)
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header("X-Ninja-Signature", signature);
+        }
+
+        let error = match request.send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) if response.status().is_client_error() && response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                tracing::error!("Webhook `{}` refused `{event}` with {}", webhook.url, response.status());
+                return;
+            }
+            Ok(response) => response.status().to_string(),
+            Err(err) => err.to_string()
+        };
+        let Some(&delay) = delays.next() else {
+            tracing::error!(%error, "Failed to deliver `{event}` to webhook `{}`, giving up", webhook.url);
+            return;
+        };
+        tracing::warn!(%error, "Failed to deliver `{event}` to webhook `{}`, retrying in {}s", webhook.url, delay.as_secs());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Sends `event` to every webhook that wants it, in the background.
+pub fn emit(app: &App, event: Event) {
+    let config = app.config.get();
+    let mut webhooks = config.webhooks.iter().filter(|webhook| webhook.is_valid() && webhook.wants(event.name())).peekable();
+    if webhooks.peek().is_none() {
+        return;
+    }
+
+    let body = body(&event);
+    for webhook in webhooks {
+        let signature = webhook.secret.as_deref().map(|secret| signature(secret, &body));
+        #[cfg(feature = "webhooks")]
+        tokio::spawn(deliver(app.webhooks.clone(), webhook, event.name(), body.clone(), signature));
+        // Declaring webhooks without the feature is reported at startup
+        #[cfg(not(feature = "webhooks"))]
+        let _ = signature;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads() {
+        let event = Event::ScanError { path: "a.mkv", error: "denied" };
+        let payload: serde_json::Value = serde_json::from_slice(&body(&event)).unwrap();
+        assert_eq!(payload["event"], "scan.error");
+        assert_eq!(payload["path"], "a.mkv");
+        assert!(payload["timestamp"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn signatures() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
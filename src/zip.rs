@@ -0,0 +1,287 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Entries are followed by a data descriptor, as their CRC is only known
+/// once they have been streamed, and names are UTF-8.
+const FLAGS: u16 = 1 << 3 | 1 << 11;
+
+/// Sizes and offsets from here on need the ZIP64 extensions.
+const LIMIT: u64 = u32::MAX as u64;
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb8_8320 ^ crc >> 1 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+/// Continues the CRC-32 `crc` of the previous data with `data`, starting
+/// from 0.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, &byte| CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ crc >> 8)
+}
+
+/// The MS-DOS time and date of `unix`, in UTC as archives carry no time
+/// zone. Times before 1980 can't be represented and are clamped.
+fn dos_time(unix: u64) -> (u16, u16) {
+    let days = (unix / 86400) as i64;
+    let seconds = unix % 86400;
+
+    // Civil date from days since the epoch, after Howard Hinnant
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    if year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let time = (seconds / 3600) << 11 | (seconds / 60 % 60) << 5 | (seconds % 60 / 2);
+    let date = ((year - 1980).min(127) as u64) << 9 | (month as u64) << 5 | day as u64;
+    (time as u16, date as u16)
+}
+
+struct Central {
+    name: Box<str>,
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u64,
+    offset: u64
+}
+
+impl Central {
+    fn is_zip64(&self) -> bool {
+        self.size >= LIMIT || self.offset >= LIMIT
+    }
+}
+
+/// The length of the archive [`Writer`] writes for entries with these names
+/// and sizes, known before reading any of them.
+pub fn archive_size<'a>(entries: impl IntoIterator<Item = (&'a str, u64)>) -> u64 {
+    let (mut offset, mut directory, mut count) = (0, 0, 0);
+    for (name, size) in entries {
+        let zip64 = size >= LIMIT || offset >= LIMIT;
+        offset += 30 + name.len() as u64 + size + if zip64 { 20 + 24 } else { 16 };
+        directory += 46 + name.len() as u64 + if zip64 { 28 } else { 0 };
+        count += 1;
+    }
+
+    let zip64 = count >= 0xffff || directory >= LIMIT || offset >= LIMIT;
+    offset + directory + if zip64 { 56 + 20 } else { 0 } + 22
+}
+
+/// Writes a ZIP archive of stored, uncompressed, entries as it goes, for
+/// streaming videos that wouldn't compress anyway.
+pub struct Writer<W> {
+    inner: W,
+    offset: u64,
+    entries: Vec<Central>
+}
+
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Writer { inner, offset: 0, entries: Vec::new() }
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data).await?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Adds `name` with the `size` bytes of `reader`, modified at the unix
+    /// time `mtime`. Failing if `reader` ends early, which would leave the
+    /// archive broken.
+    pub async fn add(&mut self, name: &str, mtime: u64, size: u64, mut reader: impl AsyncRead + Unpin) -> io::Result<()> {
+        let (time, date) = dos_time(mtime);
+        let zip64 = size >= LIMIT || self.offset >= LIMIT;
+        let mut entry = Central { name: name.into(), time, date, crc: 0, size, offset: self.offset };
+
+        let mut header = Vec::with_capacity(50 + name.len());
+        header.extend(0x0403_4b50u32.to_le_bytes());
+        header.extend((if zip64 { 45u16 } else { 20u16 }).to_le_bytes());
+        header.extend(FLAGS.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(time.to_le_bytes());
+        header.extend(date.to_le_bytes());
+        // CRC and sizes follow in the data descriptor
+        header.extend(0u32.to_le_bytes());
+        header.extend((if zip64 { u32::MAX } else { 0 }).to_le_bytes());
+        header.extend((if zip64 { u32::MAX } else { 0 }).to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend((if zip64 { 20u16 } else { 0 }).to_le_bytes());
+        header.extend(name.as_bytes());
+        if zip64 {
+            header.extend(1u16.to_le_bytes());
+            header.extend(16u16.to_le_bytes());
+            header.extend([0; 16]);
+        }
+        self.write(&header).await?;
+
+        let mut buffer = vec![0; 1 << 16];
+        let mut remaining = size;
+        while remaining > 0 {
+            let read = reader.read(&mut buffer[..remaining.min(1 << 16) as usize]).await?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            entry.crc = crc32(entry.crc, &buffer[..read]);
+            self.write(&buffer[..read]).await?;
+            remaining -= read as u64;
+        }
+
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend(0x0807_4b50u32.to_le_bytes());
+        descriptor.extend(entry.crc.to_le_bytes());
+        if zip64 {
+            descriptor.extend(size.to_le_bytes());
+            descriptor.extend(size.to_le_bytes());
+        } else {
+            descriptor.extend((size as u32).to_le_bytes());
+            descriptor.extend((size as u32).to_le_bytes());
+        }
+        self.write(&descriptor).await?;
+
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the central directory that ends the archive.
+    pub async fn finish(mut self) -> io::Result<W> {
+        let start = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            let zip64 = entry.is_zip64();
+            let version: u16 = if zip64 { 45 } else { 20 };
+            directory.extend(0x0201_4b50u32.to_le_bytes());
+            // Made on Unix, for the permissions in the external attributes
+            directory.extend((3 << 8 | version).to_le_bytes());
+            directory.extend(version.to_le_bytes());
+            directory.extend(FLAGS.to_le_bytes());
+            directory.extend(0u16.to_le_bytes());
+            directory.extend(entry.time.to_le_bytes());
+            directory.extend(entry.date.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            let size = if zip64 { u32::MAX } else { entry.size as u32 };
+            directory.extend(size.to_le_bytes());
+            directory.extend(size.to_le_bytes());
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            directory.extend((if zip64 { 28u16 } else { 0 }).to_le_bytes());
+            // Comment, disk and internal attributes
+            directory.extend([0; 6]);
+            directory.extend((0o100_644u32 << 16).to_le_bytes());
+            directory.extend((if zip64 { u32::MAX } else { entry.offset as u32 }).to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+            if zip64 {
+                directory.extend(1u16.to_le_bytes());
+                directory.extend(24u16.to_le_bytes());
+                directory.extend(entry.size.to_le_bytes());
+                directory.extend(entry.size.to_le_bytes());
+                directory.extend(entry.offset.to_le_bytes());
+            }
+        }
+        self.write(&directory).await?;
+
+        let count = self.entries.len() as u64;
+        let size = directory.len() as u64;
+        let zip64 = count >= 0xffff || size >= LIMIT || start >= LIMIT;
+        let mut end = Vec::new();
+        if zip64 {
+            let record = self.offset;
+            end.extend(0x0606_4b50u32.to_le_bytes());
+            end.extend(44u64.to_le_bytes());
+            end.extend((3u16 << 8 | 45).to_le_bytes());
+            end.extend(45u16.to_le_bytes());
+            end.extend([0; 8]);
+            end.extend(count.to_le_bytes());
+            end.extend(count.to_le_bytes());
+            end.extend(size.to_le_bytes());
+            end.extend(start.to_le_bytes());
+
+            end.extend(0x0706_4b50u32.to_le_bytes());
+            end.extend(0u32.to_le_bytes());
+            end.extend(record.to_le_bytes());
+            end.extend(1u32.to_le_bytes());
+        }
+        end.extend(0x0605_4b50u32.to_le_bytes());
+        end.extend([0; 4]);
+        let count = if zip64 { u16::MAX } else { count as u16 };
+        end.extend(count.to_le_bytes());
+        end.extend(count.to_le_bytes());
+        end.extend((if zip64 { u32::MAX } else { size as u32 }).to_le_bytes());
+        end.extend((if zip64 { u32::MAX } else { start as u32 }).to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+        self.write(&end).await?;
+
+        self.inner.flush().await?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+        assert_eq!(crc32(0, b""), 0);
+    }
+
+    #[test]
+    fn dos_times() {
+        // 2024-02-29 13:45:30 UTC
+        assert_eq!(dos_time(1_709_214_330), (13 << 11 | 45 << 5 | 15, 44 << 9 | 2 << 5 | 29));
+        assert_eq!(dos_time(0), (0, 1 << 5 | 1));
+    }
+
+    #[test]
+    fn archive() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let archive = runtime.block_on(async {
+            let mut writer = Writer::new(Vec::new());
+            writer.add("a.txt", 1_709_214_330, 5, &b"hello"[..]).await.unwrap();
+            writer.add("dir/b.txt", 1_709_214_330, 0, &b""[..]).await.unwrap();
+            // Readers that end early fail rather than leave a broken entry
+            assert!(writer.add("c.txt", 1_709_214_330, 10, &b"short"[..]).await.is_err());
+            writer.finish().await.unwrap()
+        });
+
+        assert_eq!(&archive[..4], b"PK\x03\x04");
+        assert_eq!(&archive[35..40], b"hello");
+        let end = &archive[archive.len() - 22..];
+        assert_eq!(&end[..4], b"PK\x05\x06");
+        assert_eq!(u16::from_le_bytes([end[10], end[11]]), 2);
+    }
+
+    #[test]
+    fn sizes() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let archive = runtime.block_on(async {
+            let mut writer = Writer::new(Vec::new());
+            writer.add("a.txt", 0, 5, &b"hello"[..]).await.unwrap();
+            writer.add("dir/b.txt", 0, 3, &b"bye"[..]).await.unwrap();
+            writer.finish().await.unwrap()
+        });
+        assert_eq!(archive.len() as u64, archive_size([("a.txt", 5), ("dir/b.txt", 3)]));
+    }
+}
@@ -0,0 +1,369 @@
+"use strict";
+
+// Every URL is relative, the page sets <base> to wherever the server is
+// mounted.
+
+const $ = (id) => document.getElementById(id);
+
+// Paths keep their slashes, every route takes the rest of the URL
+const encodePath = (path) => path.split("/").map(encodeURIComponent).join("/");
+
+function show(view) {
+  for (const id of ["login", "browser", "player"]) {
+    $(id).hidden = id !== view;
+  }
+  if (view !== "player") {
+    $("video").pause();
+    $("video").removeAttribute("src");
+    $("video").load();
+  }
+}
+
+function fail(message) {
+  $("error").textContent = message;
+}
+
+// Keeps watch progress apart per browser when nobody is logged in
+function deviceId() {
+  let id = localStorage.getItem("ninja-device");
+  if (!id) {
+    // Unlike randomUUID, this also works over plain HTTP
+    id = Array.from(crypto.getRandomValues(new Uint8Array(16)), (byte) => byte.toString(16).padStart(2, "0")).join("");
+    localStorage.setItem("ninja-device", id);
+  }
+  return id;
+}
+
+async function request(url, options = {}) {
+  const headers = { "X-Device-Id": deviceId(), ...options.headers };
+  const response = await fetch(url, { credentials: "same-origin", ...options, headers });
+  if (response.status === 401) {
+    show("login");
+    throw new Error("Unauthorized");
+  }
+  if (!response.ok) {
+    const body = await response.text();
+    let message = body;
+    try {
+      message = JSON.parse(body).error.message;
+    } catch {}
+    throw new Error(message || response.statusText);
+  }
+  return response;
+}
+
+function duration(seconds) {
+  if (seconds == null) {
+    return "";
+  }
+  const total = Math.round(seconds);
+  const [hours, minutes, rest] = [Math.floor(total / 3600), Math.floor(total / 60) % 60, total % 60];
+  const pad = (value) => String(value).padStart(2, "0");
+  return hours > 0 ? `${hours}:${pad(minutes)}:${pad(rest)}` : `${minutes}:${pad(rest)}`;
+}
+
+function card(href, name) {
+  const card = document.createElement("a");
+  card.className = "card";
+  card.href = href;
+  const label = document.createElement("div");
+  label.className = "name";
+  label.textContent = name;
+  label.title = name;
+  card.append(label);
+  return card;
+}
+
+function folderCard(folder) {
+  const element = card(`#/browse/${encodePath(folder.path)}`, folder.name);
+  const icon = document.createElement("div");
+  icon.className = "folder";
+  icon.textContent = "\u{1F4C1}";
+  element.prepend(icon);
+  return element;
+}
+
+function videoCard(video) {
+  const name = video.title ? (video.year ? `${video.title} (${video.year})` : video.title) : video.filename;
+  const element = card(`#/watch/${encodePath(video.path)}`, name);
+  const thumb = document.createElement("img");
+  thumb.loading = "lazy";
+  thumb.alt = "";
+  thumb.src = video.poster || video.tmdb ? `artwork/poster/${encodePath(video.path)}` : `thumb/${encodePath(video.path)}`;
+  const length = document.createElement("div");
+  length.className = "duration";
+  length.textContent = duration(video.duration);
+  element.prepend(thumb);
+  element.append(length);
+  return element;
+}
+
+function breadcrumb(name, path) {
+  const link = document.createElement("a");
+  link.href = `#/browse/${encodePath(path)}`;
+  link.textContent = name;
+  return link;
+}
+
+async function showBrowser(path) {
+  const listing = await (await request(path === "" ? "browse" : `browse/${encodePath(path)}`)).json();
+  const crumbs = [breadcrumb("Home", "")];
+  for (const folder of listing.breadcrumbs) {
+    crumbs.push(" / ", breadcrumb(folder.name, folder.path));
+  }
+  $("breadcrumbs").replaceChildren(...crumbs);
+
+  const grid = $("grid");
+  grid.replaceChildren(...listing.folders.map(folderCard), ...listing.files.map(videoCard));
+  if (listing.folders.length === 0 && listing.files.length === 0) {
+    grid.textContent = "No videos here yet.";
+  }
+  show("browser");
+}
+
+async function showFavorites() {
+  const videos = await (await request("favorites")).json();
+  $("breadcrumbs").replaceChildren("My list");
+  const grid = $("grid");
+  grid.replaceChildren(...videos.map(videoCard));
+  if (videos.length === 0) {
+    grid.textContent = "Nothing on your list yet.";
+  }
+  show("browser");
+}
+
+function setFavorite(favorite) {
+  const button = $("favorite");
+  button.dataset.favorite = favorite;
+  button.textContent = favorite ? "\u2605 On my list" : "\u2606 Add to my list";
+}
+
+function track(kind, label, language, src) {
+  const element = document.createElement("track");
+  element.kind = kind;
+  element.label = label;
+  element.src = src;
+  if (language) {
+    element.srclang = language;
+  }
+  return element;
+}
+
+async function showPlayer(path) {
+  const video = $("video");
+  const name = path.split("/").pop();
+  video.replaceChildren();
+  video.src = `video/${encodePath(path)}`;
+  video.poster = `thumb/${encodePath(path)}`;
+  $("download").href = `download/${encodePath(path)}`;
+  setCasting(null);
+  $("video-title").textContent = name;
+  $("video-plot").textContent = "";
+  document.title = `${name} - ninja`;
+  show("player");
+
+  const [info, subtitles, progress, favorites] = await Promise.all([
+    request(`info/${encodePath(path)}`).then((response) => response.json()).catch(() => null),
+    request(`subtitles/${encodePath(path)}`).then((response) => response.json()).catch(() => null),
+    request(`progress/${encodePath(path)}`).then((response) => response.json()).catch(() => null),
+    request("favorites").then((response) => response.json()).catch(() => [])
+  ]);
+
+  setFavorite(favorites.some((favorite) => favorite.path === path));
+
+  if (progress && !progress.watched && progress.position > 0) {
+    const resume = () => {
+      video.currentTime = progress.position;
+    };
+    if (video.readyState >= HTMLMediaElement.HAVE_METADATA) {
+      resume();
+    } else {
+      video.addEventListener("loadedmetadata", resume, { once: true });
+    }
+  }
+
+  if (info) {
+    if (info.title) {
+      $("video-title").textContent = info.year ? `${info.title} (${info.year})` : info.title;
+    }
+    if (info.fanart) {
+      video.poster = `artwork/fanart/${encodePath(path)}`;
+    }
+    $("video-plot").textContent = info.plot ?? "";
+    const parts = [info.year, duration(info.duration)];
+    if (info.width) {
+      parts.push(`${info.width}×${info.height}`);
+    }
+    $("video-info").textContent = parts.filter(Boolean).join(" · ");
+  }
+  if (subtitles) {
+    for (const sidecar of subtitles.sidecar) {
+      const src = `subtitles/${encodePath(path)}?file=${encodeURIComponent(sidecar.file)}`;
+      video.append(track("subtitles", sidecar.language || sidecar.file, sidecar.language, src));
+    }
+    for (const embedded of subtitles.embedded.filter((embedded) => embedded.text)) {
+      const label = embedded.title || embedded.language || `Track ${embedded.track + 1}`;
+      video.append(track("subtitles", label, embedded.language, `subtitles/${encodePath(path)}?track=${embedded.track}`));
+    }
+  }
+}
+
+let lastSaved = 0;
+
+function saveProgress(force) {
+  const match = location.hash.match(/^#\/watch\/(.+)$/);
+  const video = $("video");
+  if (!match || !video.currentSrc || (!force && Date.now() - lastSaved < 10000)) {
+    return;
+  }
+  lastSaved = Date.now();
+  const body = { position: video.currentTime };
+  if (video.ended) {
+    body.watched = true;
+  }
+  request(`progress/${match[1]}`, {
+    method: "PUT",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(body)
+  }).catch(() => {});
+}
+
+function updateSnapshot() {
+  const match = location.hash.match(/^#\/watch\/(.+)$/);
+  if (match) {
+    $("snapshot").href = `frame/${match[1]}?t=${$("video").currentTime.toFixed(3)}&precise=true`;
+  }
+}
+
+// The cast device playing this video, commands from here go to it
+let castDevice = null;
+
+function setCasting(device, status) {
+  castDevice = device;
+  $("cast-controls").hidden = !device;
+  $("cast-devices").hidden = true;
+  if (status) {
+    $("cast-toggle").textContent = status.state === "PAUSED" ? "Resume" : "Pause";
+  }
+}
+
+async function castCommand(action, time) {
+  if (!castDevice) {
+    return;
+  }
+  try {
+    const response = await request("cast/control", {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ device: castDevice, action, time })
+    });
+    const status = await response.json();
+    setCasting(status.state === "IDLE" ? null : castDevice, status);
+  } catch (error) {
+    fail(error.message);
+  }
+}
+
+async function route() {
+  fail("");
+  document.title = "ninja";
+  try {
+    // The session cookie is HttpOnly, so whether there is one is only known
+    // from having logged in here
+    $("logout").hidden = !sessionStorage.getItem("ninja-user");
+    const [, view, path] = location.hash.match(/^#\/(watch|browse|favorites)\/?(.*)$/) || [];
+    const decoded = decodeURIComponent(path || "");
+    if (view === "watch") {
+      await showPlayer(decoded);
+    } else if (view === "favorites") {
+      await showFavorites();
+    } else {
+      await showBrowser(decoded);
+    }
+  } catch (error) {
+    if (error.message !== "Unauthorized") {
+      fail(error.message);
+    }
+  }
+}
+
+$("login").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const form = new FormData(event.target);
+  const response = await fetch("login", {
+    method: "POST",
+    credentials: "same-origin",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ username: form.get("username"), password: form.get("password") })
+  });
+  if (!response.ok) {
+    $("login-error").textContent = "Wrong username or password";
+    return;
+  }
+  $("login-error").textContent = "";
+  sessionStorage.setItem("ninja-user", form.get("username"));
+  event.target.reset();
+  route();
+});
+
+$("logout").addEventListener("click", async () => {
+  await fetch("logout", { method: "POST", credentials: "same-origin" });
+  sessionStorage.removeItem("ninja-user");
+  $("logout").hidden = true;
+  show("login");
+});
+
+$("favorite").addEventListener("click", async () => {
+  const match = location.hash.match(/^#\/watch\/(.+)$/);
+  if (!match) {
+    return;
+  }
+  const favorite = $("favorite").dataset.favorite !== "true";
+  try {
+    await request(`favorites/${match[1]}`, { method: favorite ? "POST" : "DELETE" });
+    setFavorite(favorite);
+  } catch (error) {
+    fail(error.message);
+  }
+});
+
+$("cast").addEventListener("click", async () => {
+  try {
+    const devices = await (await request("cast/devices")).json();
+    const prompt = devices.length > 0 ? "Cast to\u2026" : "No cast devices found";
+    $("cast-devices").replaceChildren(new Option(prompt, ""), ...devices.map((device) => new Option(device.name, device.id)));
+    $("cast-devices").hidden = false;
+  } catch (error) {
+    fail(error.message);
+  }
+});
+
+$("cast-devices").addEventListener("change", async (event) => {
+  const match = location.hash.match(/^#\/watch\/(.+)$/);
+  const device = event.target.value;
+  if (!match || !device) {
+    return;
+  }
+  try {
+    const response = await request(`cast/${match[1]}`, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ device, start: $("video").currentTime })
+    });
+    $("video").pause();
+    setCasting(device, await response.json());
+  } catch (error) {
+    fail(error.message);
+  }
+});
+
+$("cast-toggle").addEventListener("click", () => castCommand($("cast-toggle").textContent === "Pause" ? "pause" : "play"));
+$("cast-stop").addEventListener("click", () => castCommand("stop"));
+// Seeking the local player seeks the cast too
+$("video").addEventListener("seeked", () => castCommand("seek", $("video").currentTime));
+$("video").addEventListener("timeupdate", updateSnapshot);
+$("video").addEventListener("timeupdate", () => saveProgress(false));
+$("video").addEventListener("pause", () => saveProgress(true));
+$("video").addEventListener("ended", () => saveProgress(true));
+window.addEventListener("hashchange", route);
+route();